@@ -0,0 +1,126 @@
+// Client address based access control for sites and the admin interface.
+// Tunnelled connections from denied clients are dropped while terminated requests get a 403.
+
+use std::net::IpAddr;
+
+use crate::configuration::ConfigWrapper;
+use crate::tcp_proxy::ReverseTcpProxyTarget;
+
+/// An address range such as "10.0.0.0/8" or "fd00::/8". A plain address is treated as a range containing only itself.
+/// Ranges are written as strings in the configuration and parsed when it is loaded, so invalid ones are rejected right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None)
+        };
+        let network : IpAddr = addr.parse().map_err(|e| format!("invalid address in '{s}': {e}"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max_len).ok_or(format!("invalid prefix length in '{s}'"))?,
+            None => max_len
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let max_len = if self.network.is_ipv4() { 32 } else { 128 };
+        if self.prefix_len == max_len {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix_len)
+        }
+    }
+}
+
+impl serde::Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as serde::Deserialize>::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl schemars::JsonSchema for Cidr {
+    fn schema_name() -> String {
+        "Cidr".into()
+    }
+    fn is_referenceable() -> bool {
+        false
+    }
+    fn json_schema(generator: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        <String as schemars::JsonSchema>::json_schema(generator)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // dual stack listeners report ipv4 clients as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len),
+            _ => false
+        }
+    }
+}
+
+fn prefix_matches(a: u128, b: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true
+    }
+    let shift = bits - prefix_len;
+    a >> shift == b >> shift
+}
+
+pub struct AccessRules<'a> {
+    pub allow: Option<&'a [Cidr]>,
+    pub deny: Option<&'a [Cidr]>,
+}
+
+impl AccessRules<'_> {
+    /// Deny rules win over allow rules. When an allow list is set, only clients that match it are let in.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let matches = |list: &[Cidr]| list.iter().any(|c| c.contains(ip));
+        if self.deny.is_some_and(|list| matches(list)) {
+            return false
+        }
+        self.allow.map_or(true, |list| matches(list))
+    }
+}
+
+fn rules<'a>(allow: &'a Option<Vec<Cidr>>, deny: &'a Option<Vec<Cidr>>) -> Option<AccessRules<'a>> {
+    if allow.is_none() && deny.is_none() {
+        return None
+    }
+    Some(AccessRules { allow: allow.as_deref(), deny: deny.as_deref() })
+}
+
+/// Finds the access rules of whichever site handles the given host name.
+pub fn rules_for_host<'a>(configuration: &'a ConfigWrapper, host_name: &str) -> Option<AccessRules<'a>> {
+    let (allow, deny) = configuration.find_site(host_name)?.cidrs();
+    if allow.is_none() && deny.is_none() {
+        return None
+    }
+    Some(AccessRules { allow, deny })
+}
+
+pub fn rules_for_target(target: &ReverseTcpProxyTarget) -> Option<AccessRules<'_>> {
+    match (&target.remote_target_config, &target.hosted_target_config) {
+        (Some(x), _) => rules(&x.allow_cidrs, &x.deny_cidrs),
+        (None, Some(x)) => rules(&x.allow_cidrs, &x.deny_cidrs),
+        _ => None
+    }
+}
@@ -0,0 +1,338 @@
+// Access log for terminated http requests and tunnelled connections.
+// Entries are handed over to a background worker so that slow disks never hold up the proxy itself.
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::configuration::{AccessLog, AccessLogFormat, AccessLogRotation};
+use crate::global_state::GlobalState;
+use crate::types::proc_info::BgTaskInfo;
+
+// kept in sync with the configuration by the worker so that we do not have to look at the config for each request
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+type EntryReceiver = tokio::sync::mpsc::Receiver<AccessLogEntry>;
+
+lazy_static::lazy_static! {
+    static ref CHANNEL: (tokio::sync::mpsc::Sender<AccessLogEntry>, std::sync::Mutex<Option<EntryReceiver>>) = {
+        let (tx, rx) = tokio::sync::mpsc::channel(10_000);
+        (tx, std::sync::Mutex::new(Some(rx)))
+    };
+}
+
+// stdout belongs to the tui while it runs, set at startup before the configuration is loaded
+static TUI_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_tui_active(active: bool) {
+    TUI_ACTIVE.store(active, Ordering::Relaxed);
+}
+
+pub fn is_tui_active() -> bool {
+    TUI_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Lines written to stdout would end up in the middle of the tui, so a path is required while it runs.
+pub fn validate(settings: &AccessLog, tui_active: bool) -> anyhow::Result<()> {
+    if tui_active && settings.path.is_none() {
+        anyhow::bail!("The access_log needs a path while the TUI is running, as it would otherwise be written to stdout. Set a path or start odd-box with --tui=false.");
+    }
+    Ok(())
+}
+
+/// Attached to responses from backends so that the access log can tell which backend served the request.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    /// address:port
+    pub backend: String,
+    /// Time from sending the request until the response headers arrived.
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessKind {
+    Http,
+    Tunnel,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(skip)]
+    started: Instant,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub kind: AccessKind,
+    pub client_ip: String,
+    pub host: String,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// HTTP/1.1, HTTP/2.0 etc for requests, TLS or TCP for tunnels.
+    pub protocol: String,
+    pub status: Option<u16>,
+    pub bytes_sent: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub upstream: Option<String>,
+    pub upstream_latency_ms: Option<f64>,
+    pub total_latency_ms: f64,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl AccessLogEntry {
+
+    pub fn for_request<B>(req: &hyper::Request<B>, client_ip: Option<IpAddr>) -> Self {
+        let header = |name: hyper::header::HeaderName| req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+        let host = header(hyper::header::HOST)
+            .or_else(|| req.uri().authority().map(|a| a.to_string()))
+            .map(|h| h.split(':').next().unwrap_or_default().to_string())
+            .unwrap_or_default();
+        Self {
+            started: Instant::now(),
+            timestamp: chrono::Local::now(),
+            kind: AccessKind::Http,
+            client_ip: client_ip.map(|ip| ip.to_string()).unwrap_or("-".into()),
+            host,
+            method: Some(req.method().to_string()),
+            path: Some(req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(req.uri().path()).to_string()),
+            protocol: format!("{:?}", req.version()),
+            status: None,
+            bytes_sent: 0,
+            referer: header(hyper::header::REFERER),
+            user_agent: header(hyper::header::USER_AGENT),
+            request_id: header(crate::http_proxy::X_REQUEST_ID.clone()),
+            upstream: None,
+            upstream_latency_ms: None,
+            total_latency_ms: 0.0,
+        }
+    }
+
+    pub fn for_tunnel(host: &str, client_ip: IpAddr, tls: bool) -> Self {
+        Self {
+            started: Instant::now(),
+            timestamp: chrono::Local::now(),
+            kind: AccessKind::Tunnel,
+            client_ip: client_ip.to_string(),
+            host: host.to_string(),
+            method: None,
+            path: None,
+            protocol: if tls { "TLS".into() } else { "TCP".into() },
+            status: None,
+            bytes_sent: 0,
+            referer: None,
+            user_agent: None,
+            request_id: None,
+            upstream: None,
+            upstream_latency_ms: None,
+            total_latency_ms: 0.0,
+        }
+    }
+
+    pub fn set_upstream(&mut self, upstream: &Upstream) {
+        self.upstream = Some(upstream.backend.clone());
+        self.upstream_latency_ms = Some(millis(upstream.latency));
+    }
+
+    /// Hands the entry over to the access log writer.
+    pub fn finish(mut self, bytes_sent: u64) {
+        self.bytes_sent = bytes_sent;
+        self.total_latency_ms = millis(self.started.elapsed());
+        // if the writer cannot keep up we would rather lose entries than buffer them without limit
+        _ = CHANNEL.0.try_send(self);
+    }
+
+    pub(crate) fn format(&self, format: &AccessLogFormat) -> String {
+        let quoted = |v: &Option<String>| v.as_deref().unwrap_or("-").replace('"', "\\\"");
+        let request_line = match self.kind {
+            AccessKind::Http => format!("{} {} {}", quoted(&self.method), quoted(&self.path), self.protocol),
+            AccessKind::Tunnel => format!("TUNNEL {} {}", self.host, self.protocol),
+        };
+        let common = format!("{} - - [{}] \"{request_line}\" {} {}",
+            self.client_ip,
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.status.map(|s| s.to_string()).unwrap_or("-".into()),
+            self.bytes_sent
+        );
+        match format {
+            AccessLogFormat::Common => common,
+            AccessLogFormat::Combined => format!("{common} \"{}\" \"{}\"", quoted(&self.referer), quoted(&self.user_agent)),
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+enum Destination {
+    Stdout,
+    File(std::io::BufWriter<std::fs::File>),
+}
+
+struct Output {
+    settings: AccessLog,
+    destination: Destination,
+    size: u64,
+    opened_at: chrono::DateTime<chrono::Local>,
+    failed: bool,
+}
+
+impl Output {
+
+    fn open(settings: AccessLog) -> anyhow::Result<Self> {
+        let (destination, size) = match &settings.path {
+            None => (Destination::Stdout, 0),
+            Some(path) => {
+                if let Some(parent) = std::path::Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                (Destination::File(std::io::BufWriter::new(file)), size)
+            }
+        };
+        Ok(Self { settings, destination, size, opened_at: chrono::Local::now(), failed: false })
+    }
+
+    fn write(&mut self, entry: &AccessLogEntry) {
+        let line = entry.format(&self.settings.format());
+        let result = match &mut self.destination {
+            Destination::Stdout => writeln!(std::io::stdout().lock(), "{line}"),
+            Destination::File(f) => writeln!(f, "{line}"),
+        };
+        match result {
+            Ok(_) => {
+                self.size += line.len() as u64 + 1;
+                self.failed = false;
+            },
+            Err(e) => {
+                // only complain once until writing works again so that we do not flood the log
+                if !self.failed {
+                    tracing::warn!("Failed to write to the access log: {e:?}");
+                }
+                self.failed = true;
+            }
+        }
+        if let Err(e) = self.rotate_if_needed() {
+            tracing::warn!("Failed to rotate the access log: {e:?}");
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Destination::File(f) = &mut self.destination {
+            _ = f.flush();
+        }
+    }
+
+    fn rotate_if_needed(&mut self) -> anyhow::Result<()> {
+        let Some(path) = self.settings.path.clone() else { return Ok(()) };
+        let now = chrono::Local::now();
+        let too_big = self.settings.max_size_in_megabytes.is_some_and(|mb| self.size >= mb * 1024 * 1024);
+        let period_passed = match self.settings.rotation {
+            None | Some(AccessLogRotation::Never) => false,
+            Some(AccessLogRotation::Hourly) => now.format("%Y%m%d%H").to_string() != self.opened_at.format("%Y%m%d%H").to_string(),
+            Some(AccessLogRotation::Daily) => now.date_naive() != self.opened_at.date_naive(),
+        };
+        if !too_big && !period_passed {
+            return Ok(())
+        }
+        self.flush();
+        let mut rotated = format!("{path}.{}", now.format("%Y%m%d-%H%M%S"));
+        let mut n = 1;
+        while std::path::Path::new(&rotated).exists() {
+            rotated = format!("{path}.{}-{n}", now.format("%Y%m%d-%H%M%S"));
+            n += 1;
+        }
+        std::fs::rename(&path, &rotated)?;
+        *self = Self::open(self.settings.clone())?;
+        remove_old_files(&path, self.settings.max_files());
+        Ok(())
+    }
+}
+
+pub(crate) fn remove_old_files(path: &str, max_files: u32) {
+    let path = std::path::Path::new(path);
+    let Some(file_name) = path.file_name().and_then(|x| x.to_str()) else { return };
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+    let Ok(entries) = std::fs::read_dir(dir) else { return };
+    let prefix = format!("{file_name}.");
+    let mut rotated : Vec<std::path::PathBuf> = entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| p.file_name().and_then(|x| x.to_str()).is_some_and(|n| n.starts_with(&prefix)))
+        .collect();
+    // the timestamps in the names sort chronologically
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(max_files as usize);
+    for old in rotated.into_iter().take(excess) {
+        if let Err(e) = std::fs::remove_file(&old) {
+            tracing::warn!("Failed to remove old access log {old:?}: {e:?}");
+        }
+    }
+}
+
+pub async fn bg_worker_for_access_log(state: Arc<GlobalState>) {
+    let Some(mut receiver) = CHANNEL.1.lock().ok().and_then(|mut x| x.take()) else {
+        tracing::warn!("The access log worker was started more than once.");
+        return
+    };
+
+    let liveness_token = Arc::new(true);
+    let mut settings : Option<AccessLog> = None;
+    let mut output : Option<Output> = None;
+    let mut refresh = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        if state.app_state.exit.load(Ordering::Relaxed) {
+            break
+        }
+        tokio::select! {
+            _ = refresh.tick() => {
+                let new_settings = {
+                    let guard = state.config.read().await;
+                    guard.access_log.clone()
+                };
+                if new_settings != settings {
+                    if let Some(o) = output.as_mut() { o.flush() }
+                    output = new_settings.clone().and_then(|s| match Output::open(s) {
+                        Ok(o) => Some(o),
+                        Err(e) => {
+                            tracing::error!("Failed to open the access log: {e:?}");
+                            None
+                        }
+                    });
+                    ENABLED.store(output.is_some(), Ordering::Relaxed);
+                    settings = new_settings;
+                }
+                if let Some(o) = output.as_mut() { o.flush() }
+                crate::BG_WORKER_THREAD_MAP.insert("Access Log".into(), BgTaskInfo {
+                    liveness_ptr: Arc::downgrade(&liveness_token),
+                    status: match (&output, &settings) {
+                        (Some(o), _) => format!("Writing to {}", o.settings.path.as_deref().unwrap_or("stdout")),
+                        (None, Some(_)) => "Failed to open the access log".into(),
+                        (None, None) => "Disabled".into()
+                    }
+                });
+            },
+            entry = receiver.recv() => {
+                let Some(entry) = entry else { break };
+                let Some(o) = output.as_mut() else { continue };
+                let skip = state.config.read().await.find_site(&entry.host).is_some_and(|site| site.disable_access_log());
+                if !skip {
+                    o.write(&entry);
+                }
+            }
+        }
+    }
+
+    if let Some(o) = output.as_mut() {
+        o.flush();
+    }
+}
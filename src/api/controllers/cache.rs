@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use super::*;
+use axum::extract::Query;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize,IntoParams)]
+#[into_params(
+    parameter_in=Query
+)]
+pub struct PurgeQueryParams {
+    /// The requested host name that the responses were cached for.
+    #[param(example = json!("my_site.com"))]
+    pub hostname: String,
+    /// Only purge urls that start with this path, for example /assets/. Everything for the host is purged if not set.
+    #[param(example = json!("/assets/"))]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PurgeResult {
+    /// How many urls were removed from the cache, counting all cached variants of a url as one.
+    pub purged: usize,
+}
+
+/// Remove cached responses of remote sites that have enable_response_cache set
+#[utoipa::path(
+    operation_id="purge-cache",
+    delete,
+    tag = "Cache",
+    params(PurgeQueryParams),
+    path = "/api/cache",
+    responses(
+        (status = 200, description = "Successful Response", body = PurgeResult),
+    )
+)]
+pub async fn purge_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<GlobalState>>,
+    Query(query): Query<PurgeQueryParams>,
+) -> impl IntoResponse {
+    let purged = global_state.app_state.response_cache.purge(&query.hostname, query.path.as_deref()).await;
+    tracing::info!("Purged {purged} cached urls for {}{} thru the admin api", query.hostname, query.path.as_deref().unwrap_or_default());
+    Json(PurgeResult { purged })
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use super::*;
+use utoipa::ToSchema;
+
+use crate::letsencrypt::RenewalStatus;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CertificateInfo {
+    pub domain: String,
+    /// One of lets_encrypt, self_signed or custom.
+    pub kind: String,
+    /// Unix timestamp at which the certificate expires, not set for domains that are still waiting for their first certificate.
+    pub not_after: Option<i64>,
+    pub days_remaining: Option<i64>,
+    /// Set while issuing or renewing the certificate is failing.
+    pub renewal: Option<RenewalStatus>,
+}
+
+/// List the certificates currently in use along with their expiry
+#[utoipa::path(
+    operation_id="list-certificates",
+    get,
+    tag = "Certificates",
+    path = "/api/certificates",
+    responses(
+        (status = 200, description = "Successful Response", body = [CertificateInfo]),
+    )
+)]
+pub async fn list_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<GlobalState>>,
+) -> impl IntoResponse {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let mut certificates : Vec<CertificateInfo> = global_state.cert_resolver.cached_cert_expiry().into_iter().map(|(domain, kind, not_after)| {
+        CertificateInfo {
+            renewal: crate::letsencrypt::FAILING_RENEWALS.get(&domain).map(|x| x.clone()),
+            domain,
+            kind: kind.to_string(),
+            not_after: Some(not_after),
+            days_remaining: Some((not_after - now).div_euclid(86400)),
+        }
+    }).collect();
+    // domains that have not gotten a lets-encrypt certificate yet only show up here once it has failed
+    for failing in crate::letsencrypt::FAILING_RENEWALS.iter() {
+        if !certificates.iter().any(|c| &c.domain == failing.key() && c.kind == "lets_encrypt") {
+            certificates.push(CertificateInfo {
+                domain: failing.key().clone(),
+                kind: "lets_encrypt".into(),
+                not_after: None,
+                days_remaining: None,
+                renewal: Some(failing.value().clone()),
+            });
+        }
+    }
+    certificates.sort_by(|a, b| a.not_after.cmp(&b.not_after).then_with(|| a.domain.cmp(&b.domain)));
+    Json(certificates)
+}
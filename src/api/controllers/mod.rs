@@ -6,6 +6,9 @@ use crate::global_state::GlobalState;
 
 pub mod sites;
 pub mod settings;
+pub mod shutdown;
+pub mod cache;
+pub mod certificates;
 
 pub fn routes(state:Arc<GlobalState>) -> Router {
 
@@ -22,6 +25,17 @@ pub fn routes(state:Arc<GlobalState>) -> Router {
         .route("/api/settings", axum::routing::post(settings::set_settings_handler)).with_state(state.clone())
         .route("/api/settings", axum::routing::get(settings::get_settings_handler)).with_state(state.clone());
 
-    sites.merge(settings)
+    let shutdown = Router::new()
+        .route("/api/shutdown", axum::routing::post(shutdown::shutdown_handler)).with_state(state.clone())
+        .route("/api/shutdown", axum::routing::get(shutdown::status_handler)).with_state(state.clone())
+        .route("/api/upgrade", axum::routing::post(shutdown::upgrade_handler)).with_state(state.clone());
+
+    let cache = Router::new()
+        .route("/api/cache", axum::routing::delete(cache::purge_handler)).with_state(state.clone());
+
+    let certificates = Router::new()
+        .route("/api/certificates", axum::routing::get(certificates::list_handler)).with_state(state.clone());
+
+    sites.merge(settings).merge(shutdown).merge(cache).merge(certificates)
 
 } 
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use super::*;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ShutdownStatus {
+    /// True once a graceful shutdown has been requested.
+    pub shutting_down: bool,
+    /// Connections that are still open. odd-box waits for these (up to the drain timeout) before stopping hosted processes.
+    pub open_connections: usize,
+    /// Seconds until the remaining connections are closed, set while connections are being drained.
+    pub seconds_until_deadline: Option<u64>,
+    /// True once draining is done and the hosted processes are being stopped.
+    pub stopping_processes: bool,
+}
+
+fn status(state: &GlobalState) -> ShutdownStatus {
+    ShutdownStatus {
+        shutting_down: state.app_state.shutdown.is_draining(),
+        open_connections: crate::shutdown::open_connections(state),
+        seconds_until_deadline: state.app_state.shutdown.time_left().map(|d| d.as_secs()),
+        stopping_processes: state.app_state.exit.load(std::sync::atomic::Ordering::SeqCst),
+    }
+}
+
+/// Get the progress of a shutdown
+#[utoipa::path(
+    operation_id="shutdown-status",
+    get,
+    tag = "Shutdown",
+    path = "/api/shutdown",
+    responses(
+        (status = 200, description = "Successful Response", body = ShutdownStatus),
+    )
+)]
+pub async fn status_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<GlobalState>>,
+) -> impl IntoResponse {
+    Json(status(&global_state))
+}
+
+/// Shut down odd-box gracefully.
+/// New connections are no longer accepted, open connections get until drain_timeout_in_seconds to finish
+/// and the hosted processes are stopped after that. Calling this again while draining skips the rest of the drain.
+#[utoipa::path(
+    operation_id="shutdown",
+    post,
+    tag = "Shutdown",
+    path = "/api/shutdown",
+    responses(
+        (status = 200, description = "Successful Response", body = ShutdownStatus),
+    )
+)]
+pub async fn shutdown_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<GlobalState>>,
+) -> impl IntoResponse {
+    tracing::warn!("Shutdown requested thru the admin api");
+    crate::shutdown::request(&global_state);
+    Json(status(&global_state))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpgradeStatus {
+    /// Process id of the odd-box instance that has taken over.
+    pub new_pid: u32,
+    /// Progress of the drain in this instance, which exits once it is done.
+    pub shutdown: ShutdownStatus,
+}
+
+/// Hand over to a new odd-box started from the current binary, for example after running odd-box --update.
+/// The new instance takes over the listening sockets and the running hosted processes, after which this one
+/// drains its connections and exits. Only supported on unix systems and when the tui is not in use.
+#[utoipa::path(
+    operation_id="upgrade",
+    post,
+    tag = "Shutdown",
+    path = "/api/upgrade",
+    responses(
+        (status = 200, description = "The new instance has taken over", body = UpgradeStatus),
+        (status = 500, description = "The upgrade failed and this instance keeps running", body = String),
+    )
+)]
+pub async fn upgrade_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<GlobalState>>,
+) -> axum::response::Result<impl IntoResponse,(StatusCode,String)> {
+    tracing::warn!("Hot upgrade requested thru the admin api");
+    let new_pid = crate::hot_upgrade::upgrade(&global_state).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Hot upgrade failed: {e:?}")))?;
+    Ok(Json(UpgradeStatus { new_pid, shutdown: status(&global_state) }))
+}
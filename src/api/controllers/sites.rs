@@ -46,7 +46,9 @@ pub struct StatusResponse {
 
 pub struct StatusItem {
     pub hostname: String,
-    pub state: BasicProcState
+    pub state: BasicProcState,
+    /// Only populated for sites that have a health check configured
+    pub backend_health: Vec<crate::types::proxy_state::BackendHealth>
 }
 
 use crate::types::site_status::State as BasicProcState;
@@ -120,10 +122,11 @@ pub async fn status_handler(state: axum::extract::State<Arc<GlobalState>>) -> ax
 
     Ok(Json(StatusResponse {
         items: state.app_state.site_status_map.iter().map(|guard|{
-            let (site,state) = guard.pair();
+            let (site,site_state) = guard.pair();
             StatusItem {
                 hostname: site.clone(),
-                state: state.clone().into()
+                state: site_state.clone().into(),
+                backend_health: crate::health_check::backends_for_host(&state, site)
             }
         }).collect()
     }))
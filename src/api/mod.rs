@@ -23,7 +23,7 @@ pub struct WebSocketGlobalState {
 }
 
 
-mod controllers;
+pub(crate) mod controllers;
 
 use utoipauto::utoipauto;
 
@@ -400,6 +400,8 @@ impl OddBoxAPI {
                 })
                 .with_state(websocket_state.clone())
             )
+            // the metrics endpoint does its own auth so that scrapers can use the metrics_token
+            .route("/metrics", get(metrics_handler).with_state(state.clone()))
             .route("/", get(|| async { serve_static_file(axum::extract::Path("index.html".to_string())).await }))
             .route("/*file", get(serve_static_file));
         if let Some(cors_var) = cors_env_var { 
@@ -493,6 +495,32 @@ impl OddBoxAPI {
 }
 
 
+/// Prometheus metrics in the text exposition format.
+/// Accepts either the metrics_token as a bearer token or the odd_box_password, and is open if neither is configured.
+async fn metrics_handler(
+    axum::extract::State(global_state): axum::extract::State<Arc<crate::GlobalState>>,
+    headers: hyper::HeaderMap
+) -> Response {
+    let (metrics_token, password) = {
+        let cfg = global_state.config.read().await;
+        (cfg.metrics_token.clone(), cfg.odd_box_password.clone())
+    };
+    if metrics_token.is_some() || password.is_some() {
+        let authorization = headers.get(hyper::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let valid_token = metrics_token.as_deref()
+            .is_some_and(|t| authorization.and_then(|a| a.strip_prefix("Bearer ")) == Some(t));
+        let valid_password = password.as_deref().is_some_and(|p| authorization == Some(p));
+        if !valid_token && !valid_password {
+            tracing::warn!("Invalid metrics token or password, denying request");
+            return StatusCode::FORBIDDEN.into_response()
+        }
+    }
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(crate::metrics::render(&global_state)))
+        .expect("must be able to create response")
+}
+
 // note: not sure if we should keep these start/stop handlers around at all..
 // the /START and /STOP commands have always worked like this
 // so it seems irresponsible to just drop them but perhaps 
@@ -11,6 +11,7 @@ pub struct DynamicCertResolver {
     enable_lets_encrypt: Mutex<bool>,
     self_signed_cert_cache: DashMap<String, std::sync::Arc<tokio_rustls::rustls::sign::CertifiedKey>>,
     lets_encrypt_signed_certs: DashMap<String, std::sync::Arc<tokio_rustls::rustls::sign::CertifiedKey>>,
+    custom_certs: DashMap<crate::custom_certs::CertificateSource, crate::custom_certs::LoadedCertificate>,
     pub lets_encrypt_manager: tokio::sync::RwLock<Option<crate::letsencrypt::LECertManager>>
 }
 
@@ -32,6 +33,52 @@ impl DynamicCertResolver {
     pub fn add_lets_encrypt_signed_cert_to_mem_cache(&self, domain: &str, cert:tokio_rustls::rustls::sign::CertifiedKey) {        
         self.lets_encrypt_signed_certs.insert(domain.to_string(), Arc::new(cert));
     }
+    pub fn clear_lets_encrypt_signed_certs(&self) {
+        self.lets_encrypt_signed_certs.clear();
+    }
+    pub fn add_custom_cert(&self, cert: crate::custom_certs::LoadedCertificate) {
+        self.custom_certs.insert(cert.source.clone(), cert);
+    }
+    pub fn get_custom_cert_by_source(&self, source: &crate::custom_certs::CertificateSource) -> Option<crate::custom_certs::LoadedCertificate> {
+        self.custom_certs.get(source).map(|x| x.value().clone())
+    }
+    pub fn retain_custom_certs(&self, keep: impl Fn(&crate::custom_certs::CertificateSource) -> bool) {
+        self.custom_certs.retain(|k, _| keep(k));
+    }
+
+    /// Picks one of the certificates configured by the user for the given host name.
+    /// The certificate of the site with that exact host name wins, then certificates that have the name itself, then wildcards.
+    /// Certificates that have expired since they were loaded are skipped.
+    pub fn get_custom_cert(&self, domain: &str) -> Option<Arc<tokio_rustls::rustls::sign::CertifiedKey>> {
+        let now = chrono::Utc::now().timestamp();
+        let usable : Vec<crate::custom_certs::LoadedCertificate> = self.custom_certs.iter()
+            .filter(|x| x.not_after > now)
+            .map(|x| x.value().clone())
+            .collect();
+        // among certificates that are equally good, those configured on a site are preferred over the global ones
+        let best = |f: &dyn Fn(&crate::custom_certs::LoadedCertificate) -> bool| {
+            usable.iter().filter(|x| f(x)).min_by_key(|x| x.source.site.is_none()).map(|x| x.key.clone())
+        };
+        best(&|x| x.source.site.as_ref().is_some_and(|s| s.eq_ignore_ascii_case(domain)))
+            .or_else(|| best(&|x| x.names.iter().any(|n| n.eq_ignore_ascii_case(domain))))
+            .or_else(|| best(&|x| x.covers(domain)))
+    }
+
+    /// Lists (domain, kind, not_after as unix timestamp) for all certificates currently held in memory.
+    pub fn cached_cert_expiry(&self) -> Vec<(String, &'static str, i64)> {
+        let not_after = |c: &tokio_rustls::rustls::sign::CertifiedKey| {
+            let der = c.end_entity_cert().ok()?;
+            let (_, cert) = X509Certificate::from_der(&*der).ok()?;
+            Some(cert.tbs_certificate.validity.not_after.timestamp())
+        };
+        let self_signed = self.self_signed_cert_cache.iter()
+            .filter_map(|x| not_after(x.value()).map(|t| (x.key().clone(), "self_signed", t)));
+        let lets_encrypt = self.lets_encrypt_signed_certs.iter()
+            .filter_map(|x| not_after(x.value()).map(|t| (x.key().clone(), "lets_encrypt", t)));
+        let custom = self.custom_certs.iter()
+            .map(|x| (x.source.site.clone().unwrap_or_else(|| x.names.join(", ")), "custom", x.not_after));
+        self_signed.chain(lets_encrypt).chain(custom).collect()
+    }
 
     #[tracing::instrument]
     pub fn get_self_signed_cert_from_cache(&self, domain: &str) -> Option<Arc<tokio_rustls::rustls::sign::CertifiedKey>> {    
@@ -85,16 +132,10 @@ impl DynamicCertResolver {
                 Ok(v) => {
                     if let Ok(ccc) = X509Certificate::from_der(&*v) {
                         match ccc.1.tbs_certificate.validity.time_to_expiration() {
+                            // renewals are up to the lets-encrypt worker, until then the current certificate is still good to use
                             Some(v)  => {
-                                let days = v.whole_days();
-                                if days < 30 {
-                                    tracing::info!("Generating a new LE cert for {domain} due to less than 30 days remaining: {days} days.");
-                                    self.lets_encrypt_signed_certs.remove(domain);
-                                    None
-                                } else {
-                                    tracing::trace!("The LE certificate for {domain} is valid for {days} days. will keep using.");
-                                    Some(c)
-                                }
+                                tracing::trace!("The LE certificate for {domain} is valid for {} days. will keep using.", v.whole_days());
+                                Some(c)
                             },
                             None => {
                                 tracing::warn!("The LE certificate for {domain} has expired. Will generate a new one.");
@@ -103,13 +144,13 @@ impl DynamicCertResolver {
                             }
                         }
                     } else {
-                        tracing::warn!("Failed to parse LE cert for {} as X509Certificate!. If this persists, try removing the domain from inside the .odd_box_cache/lets_encrypt dir.",domain);
+                        tracing::warn!("Failed to parse LE cert for {} as X509Certificate!. If this persists, try removing the domain from inside the .odd_box_cache/acme dir.",domain);
                         self.lets_encrypt_signed_certs.remove(domain);
                         None
                     }
                 },
                 Err(e) => {
-                    tracing::warn!("Failed to parse LE end_entity_cert for {}: {:?}. If this persists, try removing the domain from inside the .odd_box_cache/lets_encrypt dir.",domain,e);
+                    tracing::warn!("Failed to parse LE end_entity_cert for {}: {:?}. If this persists, try removing the domain from inside the .odd_box_cache/acme dir.",domain,e);
                     self.lets_encrypt_signed_certs.remove(domain);
                     None
                 },
@@ -126,6 +167,7 @@ impl DynamicCertResolver {
             enable_lets_encrypt: Mutex::new(enable_lets_encrypt),
             self_signed_cert_cache: DashMap::new(),
             lets_encrypt_signed_certs: DashMap::new(),            
+            custom_certs: DashMap::new(),
             lets_encrypt_manager: tokio::sync::RwLock::new(None)
         }
     }
@@ -140,12 +182,30 @@ impl ResolvesServerCert for DynamicCertResolver {
         if server_name == "localhost" {
             server_name = "odd-box.localhost";
         }
+
+        // lets-encrypt connecting to validate a tls-alpn-01 challenge, it must get the validation certificate and nothing else
+        if client_hello.alpn().is_some_and(|mut protocols| protocols.any(|p| p == crate::letsencrypt::ACME_TLS_ALPN_PROTOCOL)) {
+            tracing::info!("Lets-encrypt is validating the tls-alpn-01 challenge for {server_name}");
+            return crate::letsencrypt::TLS_ALPN_CHALLENGE_CERTS.get(server_name).map(|x| x.value().clone());
+        }
+
+        if let Some(certified_key) = self.get_custom_cert(server_name) {
+            tracing::trace!("Returning a configured certificate for {:?}",server_name);
+            return Some(certified_key);
+        }
         
         if self.enable_lets_encrypt.lock().unwrap().clone() && !server_name.to_lowercase().ends_with("localhost") {
             if let Some(certified_key) = self.get_lets_encrypt_signed_cert_from_mem_cache(server_name) {
                 tracing::trace!("Returning a cached lets-encrypt certificate for {:?}",server_name);
                 return Some(certified_key.clone());
             }
+            // subdomains of sites with capture_subdomains are covered by the wildcard certificate of the site, if it has one
+            if let Some((_, parent)) = server_name.split_once('.') {
+                if let Some(certified_key) = self.get_lets_encrypt_signed_cert_from_mem_cache(&format!("*.{parent}")) {
+                    tracing::trace!("Returning a cached lets-encrypt wildcard certificate for {:?}",server_name);
+                    return Some(certified_key.clone());
+                }
+            }
         }
 
         if let Some(certified_key) = self.get_self_signed_cert_from_cache(server_name) {
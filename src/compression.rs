@@ -0,0 +1,189 @@
+// On-the-fly response compression, negotiated with clients thru Accept-Encoding.
+// Used for terminated proxy responses here, and by the directory server which also caches the compressed files.
+
+use std::pin::Pin;
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use http_body::Frame;
+use http_body_util::BodyExt;
+use hyper::header::{HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY};
+use hyper::{HeaderMap, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::configuration::{Compression, CompressionAlgorithm, ConfigWrapper, ConfiguredSite};
+use crate::http_proxy::EpicResponse;
+use crate::CustomError;
+
+const DEFAULT_ALGORITHMS: [CompressionAlgorithm; 3] = [CompressionAlgorithm::Brotli, CompressionAlgorithm::Zstd, CompressionAlgorithm::Gzip];
+const DEFAULT_MIN_SIZE: u64 = 1024;
+const DEFAULT_CONTENT_TYPES: [&str; 13] = [
+    "text/html", "text/css", "text/plain", "text/xml", "text/javascript", "text/markdown", "text/csv",
+    "application/javascript", "application/json", "application/xml", "application/manifest+json", "application/wasm",
+    "image/svg+xml",
+];
+
+impl CompressionAlgorithm {
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Zstd => "zstd",
+            CompressionAlgorithm::Gzip => "gzip",
+        }
+    }
+    /// Extension of precompressed files, as in index.html.br
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Zstd => "zst",
+            CompressionAlgorithm::Gzip => "gz",
+        }
+    }
+}
+
+/// Finds the compression settings of the proxied site that handles the given host name.
+/// Directory servers are not included as they compress (and cache) their own responses.
+pub fn for_host(configuration: &ConfigWrapper, host_name: &str) -> Option<Compression> {
+    match configuration.find_site(host_name)? {
+        ConfiguredSite::DirServer(_) => None,
+        site => site.compression().cloned(),
+    }
+}
+
+/// Picks the encoding to use from those the client accepts, preferring the ones with the highest q-value
+/// and then the order of the given algorithms.
+pub fn negotiate(accept_encoding: &str, algorithms: &[CompressionAlgorithm]) -> Option<CompressionAlgorithm> {
+    let mut wildcard = None;
+    let mut accepted = vec![];
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = parts.filter_map(|p| p.trim().strip_prefix("q=")).find_map(|q| q.trim().parse::<f32>().ok()).unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else {
+            accepted.push((name, q));
+        }
+    }
+    let q_for = |algorithm: &CompressionAlgorithm| {
+        let encoding = algorithm.content_encoding();
+        accepted.iter()
+            .find(|(name, _)| name == encoding || *algorithm == CompressionAlgorithm::Gzip && name == "x-gzip")
+            .map(|(_, q)| *q).or(wildcard).unwrap_or(0.0)
+    };
+    let mut best : Option<(CompressionAlgorithm, f32)> = None;
+    for algorithm in algorithms {
+        let q = q_for(algorithm);
+        if q > 0.0 && !best.is_some_and(|(_, best_q)| q <= best_q) {
+            best = Some((*algorithm, q));
+        }
+    }
+    best.map(|(algorithm, _)| algorithm)
+}
+
+/// The encoding to use for a response to a request with the given headers, before looking at the response itself.
+pub fn negotiate_for_request(settings: &Compression, request_headers: &HeaderMap) -> Option<CompressionAlgorithm> {
+    let accept_encoding = request_headers.get(hyper::header::ACCEPT_ENCODING)?.to_str().ok()?;
+    negotiate(accept_encoding, settings.algorithms.as_deref().unwrap_or(&DEFAULT_ALGORITHMS))
+}
+
+fn is_compressible_type(settings: &Compression, content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let matches = |t: &str| {
+        let t = t.to_ascii_lowercase();
+        if t.ends_with('/') { media_type.starts_with(&t) } else { media_type == t }
+    };
+    match &settings.content_types {
+        Some(types) => types.iter().any(|t| matches(t)),
+        None => DEFAULT_CONTENT_TYPES.iter().any(|t| matches(t)),
+    }
+}
+
+/// Tells if a body of the given type and size (when known) is worth compressing.
+pub fn should_compress(settings: &Compression, content_type: &str, size: Option<u64>) -> bool {
+    is_compressible_type(settings, content_type) && !size.is_some_and(|s| s < settings.min_size_in_bytes.unwrap_or(DEFAULT_MIN_SIZE))
+}
+
+/// Compresses the data read from `reader` as it is read.
+pub fn compress_reader<R>(algorithm: CompressionAlgorithm, reader: R) -> Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = tokio::io::BufReader::new(reader);
+    match algorithm {
+        CompressionAlgorithm::Brotli => Box::pin(ReaderStream::new(BrotliEncoder::new(reader))),
+        CompressionAlgorithm::Zstd => Box::pin(ReaderStream::new(ZstdEncoder::new(reader))),
+        CompressionAlgorithm::Gzip => Box::pin(ReaderStream::new(GzipEncoder::new(reader))),
+    }
+}
+
+pub async fn compress_bytes(algorithm: CompressionAlgorithm, data: &[u8]) -> std::io::Result<Bytes> {
+    let mut compressed = Vec::with_capacity(data.len() / 2);
+    match algorithm {
+        CompressionAlgorithm::Brotli => BrotliEncoder::new(data).read_to_end(&mut compressed).await?,
+        CompressionAlgorithm::Zstd => ZstdEncoder::new(data).read_to_end(&mut compressed).await?,
+        CompressionAlgorithm::Gzip => GzipEncoder::new(data).read_to_end(&mut compressed).await?,
+    };
+    Ok(Bytes::from(compressed))
+}
+
+/// Sends the chunks of a compressed stream thru a response channel, so that it can be used as a response body.
+pub fn spawn_sender(mut compressed: Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>, tx: tokio::sync::mpsc::Sender<Result<Frame<Bytes>, CustomError>>) {
+    tokio::spawn(async move {
+        while let Some(chunk) = compressed.next().await {
+            let frame = chunk.map(Frame::data).map_err(|e| CustomError(format!("compression failed: {e:?}")));
+            let failed = frame.is_err();
+            if tx.send(frame).await.is_err() || failed {
+                break
+            }
+        }
+    });
+}
+
+/// Marks the headers of a response as holding a body compressed with the given algorithm.
+pub fn set_encoding_headers(headers: &mut HeaderMap, algorithm: CompressionAlgorithm) {
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(algorithm.content_encoding()));
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(ACCEPT_RANGES);
+    // the compressed body is not byte for byte the same as the original, so strong etags must not be reused for it
+    if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|v| v.starts_with('"')).map(|v| format!("W/{v}")) {
+        if let Ok(v) = HeaderValue::from_str(&etag) {
+            headers.insert(ETAG, v);
+        }
+    }
+}
+
+/// Compresses a proxied response on its way to the client, unless it is already compressed or not worth compressing.
+pub fn compress_response(settings: &Compression, algorithm: Option<CompressionAlgorithm>, mut response: EpicResponse) -> EpicResponse {
+
+    // the response can differ by Accept-Encoding whenever compression is enabled, so caches must know about it
+    let varies_by_encoding = response.headers().get_all(VARY).iter()
+        .any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("accept-encoding")));
+    if !varies_by_encoding && (response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED) {
+        response.headers_mut().append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    let Some(algorithm) = algorithm else { return response };
+
+    let headers = response.headers();
+    let has_body = !(response.status().is_informational() || response.status() == StatusCode::NO_CONTENT || response.status() == StatusCode::NOT_MODIFIED);
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let size = headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    let no_transform = headers.get_all(CACHE_CONTROL).iter().any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("no-transform")));
+    let already_encoded = headers.contains_key(CONTENT_ENCODING) || headers.contains_key(CONTENT_RANGE);
+    if !has_body || no_transform || already_encoded || !should_compress(settings, content_type, size) {
+        return response
+    }
+
+    let (mut parts, body) = response.into_parts();
+    set_encoding_headers(&mut parts.headers, algorithm);
+
+    let reader = StreamReader::new(Box::pin(body.into_data_stream().map(|r| r.map_err(std::io::Error::other))));
+    let (tx, rx) = crate::http_proxy::create_response_channel(4);
+    spawn_sender(compress_reader(algorithm, reader), tx);
+
+    let (_, body) = crate::http_proxy::create_stream_response(rx).into_parts();
+    EpicResponse::from_parts(parts, body)
+}
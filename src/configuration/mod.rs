@@ -200,6 +200,92 @@ impl std::ops::DerefMut for ConfigWrapper {
     }
 }
 
+/// The site that handles a host name, as found by ConfigWrapper::find_site.
+#[derive(Debug, Clone)]
+pub enum ConfiguredSite<'a> {
+    HostedProcess(&'a InProcessSiteConfig),
+    RemoteSite(&'a RemoteSiteConfig),
+    DirServer(&'a DirServer),
+    /// Docker containers only have the settings that can be given thru labels, none of which are looked up here.
+    DockerContainer(String),
+}
+
+impl<'a> ConfiguredSite<'a> {
+    pub fn host_name(&self) -> &str {
+        match self {
+            ConfiguredSite::HostedProcess(x) => &x.host_name,
+            ConfiguredSite::RemoteSite(x) => &x.host_name,
+            ConfiguredSite::DirServer(x) => &x.host_name,
+            ConfiguredSite::DockerContainer(host_name) => host_name,
+        }
+    }
+    pub fn routes(&self) -> Option<&'a [Route]> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.routes.as_deref(),
+            ConfiguredSite::RemoteSite(x) => x.routes.as_deref(),
+            ConfiguredSite::DirServer(x) => x.routes.as_deref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+    pub fn rate_limit(&self) -> Option<&'a RateLimit> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.rate_limit.as_ref(),
+            ConfiguredSite::RemoteSite(x) => x.rate_limit.as_ref(),
+            ConfiguredSite::DirServer(x) => x.rate_limit.as_ref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+    pub fn timeouts(&self) -> Option<&'a Timeouts> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.timeouts.as_ref(),
+            ConfiguredSite::RemoteSite(x) => x.timeouts.as_ref(),
+            ConfiguredSite::DirServer(x) => x.timeouts.as_ref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+    pub fn compression(&self) -> Option<&'a Compression> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.compression.as_ref(),
+            ConfiguredSite::RemoteSite(x) => x.compression.as_ref(),
+            ConfiguredSite::DirServer(x) => x.compression.as_ref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+    pub fn disable_access_log(&self) -> bool {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.disable_access_log.unwrap_or_default(),
+            ConfiguredSite::RemoteSite(x) => x.disable_access_log.unwrap_or_default(),
+            ConfiguredSite::DirServer(x) => x.disable_access_log.unwrap_or_default(),
+            ConfiguredSite::DockerContainer(_) => false,
+        }
+    }
+    /// The allow_cidrs and deny_cidrs of the site.
+    pub fn cidrs(&self) -> (Option<&'a [crate::access_control::Cidr]>, Option<&'a [crate::access_control::Cidr]>) {
+        match self {
+            ConfiguredSite::HostedProcess(x) => (x.allow_cidrs.as_deref(), x.deny_cidrs.as_deref()),
+            ConfiguredSite::RemoteSite(x) => (x.allow_cidrs.as_deref(), x.deny_cidrs.as_deref()),
+            ConfiguredSite::DirServer(x) => (x.allow_cidrs.as_deref(), x.deny_cidrs.as_deref()),
+            ConfiguredSite::DockerContainer(_) => (None, None),
+        }
+    }
+    pub fn basic_auth(&self) -> Option<&'a BasicAuth> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.basic_auth.as_ref(),
+            ConfiguredSite::RemoteSite(x) => x.basic_auth.as_ref(),
+            ConfiguredSite::DirServer(x) => x.basic_auth.as_ref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+    pub fn forward_auth(&self) -> Option<&'a ForwardAuth> {
+        match self {
+            ConfiguredSite::HostedProcess(x) => x.forward_auth.as_ref(),
+            ConfiguredSite::RemoteSite(x) => x.forward_auth.as_ref(),
+            ConfiguredSite::DirServer(x) => x.forward_auth.as_ref(),
+            ConfiguredSite::DockerContainer(_) => None,
+        }
+    }
+}
+
 
 // This is meant to simplify the process of upgrading from one configuration version to another.
 // It is also used as a runtime-cache for the configuration such that one can change the config
@@ -399,6 +485,197 @@ impl ConfigWrapper {
                 conflict_details.join("; ")
             ));
         }
+
+        let sites_with_routes = self.dir_server.iter().flatten().map(|x| (&x.host_name, &x.routes))
+            .chain(self.remote_target.iter().flatten().map(|x| (&x.host_name, &x.routes)))
+            .chain(self.hosted_process.iter().flatten().map(|x| (&x.host_name, &x.routes)));
+
+        for (host_name, routes) in sites_with_routes {
+            for route in routes.iter().flatten() {
+                match (&route.path_prefix, &route.path_regex) {
+                    (Some(_), Some(_)) | (None, None) => {
+                        anyhow::bail!("Invalid route in '{host_name}'. Each route must have either a path_prefix or a path_regex.");
+                    },
+                    (None, Some(r)) => {
+                        if let Err(e) = regex::Regex::new(r) {
+                            anyhow::bail!("Invalid path_regex '{r}' in '{host_name}': {e}");
+                        }
+                    },
+                    _ => {}
+                }
+                if &route.target == host_name {
+                    anyhow::bail!("Invalid route in '{host_name}'. A route cannot point back to the site itself.");
+                }
+            }
+        }
+
+        let sites_with_header_rules = self.dir_server.iter().flatten().map(|x| (&x.host_name, None, &x.response_headers))
+            .chain(self.remote_target.iter().flatten().map(|x| (&x.host_name, x.request_headers.as_ref(), &x.response_headers)))
+            .chain(self.hosted_process.iter().flatten().map(|x| (&x.host_name, x.request_headers.as_ref(), &x.response_headers)));
+
+        for (host_name, request_rules, response_rules) in sites_with_header_rules {
+            for rules in request_rules.into_iter().chain(response_rules.as_ref()) {
+                let names = rules.remove.iter().flatten()
+                    .chain(rules.set.iter().flatten().map(|h| &h.name))
+                    .chain(rules.add.iter().flatten().map(|h| &h.name));
+                for name in names {
+                    if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                        anyhow::bail!("Invalid header name '{name}' in the header rules of '{host_name}'.");
+                    }
+                }
+            }
+        }
+
+        let sites_with_rate_limits = self.dir_server.iter().flatten().map(|x| (&x.host_name, &x.rate_limit))
+            .chain(self.remote_target.iter().flatten().map(|x| (&x.host_name, &x.rate_limit)))
+            .chain(self.hosted_process.iter().flatten().map(|x| (&x.host_name, &x.rate_limit)));
+
+        for (host_name, rate_limit) in sites_with_rate_limits {
+            let Some(rate_limit) = rate_limit else { continue };
+            if rate_limit.requests == 0 {
+                anyhow::bail!("Invalid rate_limit in '{host_name}'. requests must be at least 1.");
+            }
+            match (&rate_limit.key, &rate_limit.header) {
+                (Some(v3::RateLimitKey::Header), None) => {
+                    anyhow::bail!("Invalid rate_limit in '{host_name}'. A header name is required when the key is Header.");
+                },
+                (Some(v3::RateLimitKey::Header), Some(name)) if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() => {
+                    anyhow::bail!("Invalid header name '{name}' in the rate_limit of '{host_name}'.");
+                },
+                _ => {}
+            }
+        }
+
+        // address ranges (allow_cidrs, deny_cidrs, odd_box_allow_cidrs and proxy_protocol_trusted_cidrs) are parsed along with
+        // the rest of the configuration, so invalid ones never get this far.
+
+        let sites_with_auth = self.dir_server.iter().flatten().map(|x| (&x.host_name, &x.basic_auth, &x.forward_auth))
+            .chain(self.remote_target.iter().flatten().map(|x| (&x.host_name, &x.basic_auth, &x.forward_auth)))
+            .chain(self.hosted_process.iter().flatten().map(|x| (&x.host_name, &x.basic_auth, &x.forward_auth)));
+
+        for (host_name, basic_auth, forward_auth) in sites_with_auth {
+            for user in basic_auth.iter().flat_map(|x| &x.users) {
+                match user.split_once(':') {
+                    Some((name, hash)) if !name.is_empty() && (hash.starts_with("$2") || hash.starts_with("$argon2")) => {},
+                    _ => anyhow::bail!("Invalid basic_auth user in '{host_name}'. Users must be given as 'name:hash' using a bcrypt or argon2 hash.")
+                }
+            }
+            if let Some(forward_auth) = forward_auth {
+                match url::Url::parse(&forward_auth.address) {
+                    Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {},
+                    _ => anyhow::bail!("Invalid forward_auth address '{}' in '{host_name}'. It must be a http or https url.", forward_auth.address)
+                }
+                for name in forward_auth.copy_response_headers.iter().flatten() {
+                    if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                        anyhow::bail!("Invalid header name '{name}' in the forward_auth of '{host_name}'.");
+                    }
+                }
+            }
+        }
+
+        let mut listener_addresses = std::collections::HashSet::new();
+        for listener in self.listeners.iter().flatten() {
+            if !listener_addresses.insert((listener.ip.or(self.ip), listener.port)) {
+                anyhow::bail!("Invalid listeners. There is more than one listener for port {}.", listener.port);
+            }
+            if listener.sites.as_ref().is_some_and(|sites| sites.is_empty()) {
+                anyhow::bail!("Invalid listener for port {}. The sites list cannot be empty, leave it out to serve all sites.", listener.port);
+            }
+        }
+
+        let mut stream_names = std::collections::HashSet::new();
+        let mut tcp_stream_ports = std::collections::HashMap::new();
+        let reserved_tcp_ports : Vec<u16> = match &self.listeners {
+            Some(listeners) => listeners.iter().map(|l| l.port).collect(),
+            None => vec![self.http_port.unwrap_or(8080), self.tls_port.unwrap_or(4343)]
+        };
+        for site in self.tcp_stream.iter().flatten() {
+            if !stream_names.insert(&site.name) {
+                anyhow::bail!("Duplicate stream site name '{}'.", site.name);
+            }
+            if reserved_tcp_ports.contains(&site.listen_port) {
+                anyhow::bail!("Invalid tcp_stream '{}'. Port {} is already used by the http or tls listener.", site.name, site.listen_port);
+            }
+            if let Some(other) = tcp_stream_ports.insert(site.listen_port, &site.name) {
+                anyhow::bail!("Invalid tcp_stream '{}'. Port {} is already used by '{other}'.", site.name, site.listen_port);
+            }
+            if site.backends.is_empty() && site.sni_routes.iter().flatten().next().is_none() {
+                anyhow::bail!("Invalid tcp_stream '{}'. At least one backend or sni route is required.", site.name);
+            }
+            for route in site.sni_routes.iter().flatten() {
+                if route.backends.is_empty() {
+                    anyhow::bail!("Invalid sni route '{}' in tcp_stream '{}'. At least one backend is required.", route.sni, site.name);
+                }
+            }
+        }
+
+        let mut udp_stream_ports = std::collections::HashMap::new();
+        for site in self.udp_stream.iter().flatten() {
+            if !stream_names.insert(&site.name) {
+                anyhow::bail!("Duplicate stream site name '{}'.", site.name);
+            }
+            if let Some(other) = udp_stream_ports.insert(site.listen_port, &site.name) {
+                anyhow::bail!("Invalid udp_stream '{}'. Port {} is already used by '{other}'.", site.name, site.listen_port);
+            }
+            if site.backends.is_empty() {
+                anyhow::bail!("Invalid udp_stream '{}'. At least one backend is required.", site.name);
+            }
+        }
+
+        if let Some(url) = &self.acme_directory_url {
+            match url::Url::parse(url) {
+                Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {},
+                _ => anyhow::bail!("Invalid acme_directory_url '{url}'. It must be a http or https url.")
+            }
+        }
+
+        if self.acme_renew_before_days == Some(0) {
+            anyhow::bail!("Invalid acme_renew_before_days. Certificates must be renewed at least one day before they expire.");
+        }
+
+        if self.acme_eab_key_id.is_some() != self.acme_eab_hmac_key.is_some() {
+            anyhow::bail!("The acme_eab_key_id and acme_eab_hmac_key must be given together.");
+        }
+
+        if let Some(provider) = &self.dns_provider {
+            if let Err(e) = crate::dns_provider::from_config(provider) {
+                anyhow::bail!("Invalid dns_provider. {e}");
+            }
+        } else {
+            let uses_dns_challenge = self.lets_encrypt_challenge == Some(v3::AcmeChallenge::Dns01)
+                || self.dir_server.iter().flatten().any(|x| x.lets_encrypt_challenge == Some(v3::AcmeChallenge::Dns01))
+                || self.remote_target.iter().flatten().any(|x| x.lets_encrypt_challenge == Some(v3::AcmeChallenge::Dns01))
+                || self.hosted_process.iter().flatten().any(|x| x.lets_encrypt_challenge == Some(v3::AcmeChallenge::Dns01));
+            if uses_dns_challenge {
+                anyhow::bail!("The Dns01 lets_encrypt_challenge requires a dns_provider to be configured.");
+            }
+        }
+
+        let site_tls_paths = self.hosted_process.iter().flatten().map(|x| (&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path))
+            .chain(self.remote_target.iter().flatten().map(|x| (&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path)))
+            .chain(self.dir_server.iter().flatten().map(|x| (&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path)));
+        for (host_name, cert_path, key_path, chain_path) in site_tls_paths {
+            if cert_path.is_some() != key_path.is_some() {
+                anyhow::bail!("The tls_cert_path and tls_key_path of {host_name} must be given together.");
+            }
+            if chain_path.is_some() && cert_path.is_none() {
+                anyhow::bail!("The tls_chain_path of {host_name} is only used together with tls_cert_path and tls_key_path.");
+            }
+        }
+
+        if let Some(access_log) = &self.access_log {
+            crate::access_log::validate(access_log, crate::access_log::is_tui_active())?;
+        }
+
+        // only the shape is checked here, the files themselves are loaded (and their problems logged) by the custom certificates worker.
+        for source in crate::custom_certs::sources_from_config(self) {
+            if source.cert_path.trim().is_empty() || source.key_path.trim().is_empty() || source.chain_path.as_ref().is_some_and(|x| x.trim().is_empty()) {
+                match &source.site {
+                    Some(host_name) => anyhow::bail!("The certificate paths of {host_name} must not be empty."),
+                    None => anyhow::bail!("The certificate paths in tls_certificates must not be empty.")
+                }
+            }
+        }
     
         Ok(())
     }
@@ -435,6 +712,24 @@ impl ConfigWrapper {
     
    
 
+    /// Finds the site that handles the given host name, which may include a port. Host names are compared without regard to case,
+    /// the same way as when routing requests. A site with the exact host name wins over one that captures it as a subdomain.
+    pub fn find_site(&self, host_name: &str) -> Option<ConfiguredSite<'_>> {
+        let filter_fun = crate::global_state::GlobalState::filter_fun;
+        let sites = || self.hosted_process.iter().flatten().map(|x| (ConfiguredSite::HostedProcess(x), x.capture_subdomains))
+            .chain(self.remote_target.iter().flatten().map(|x| (ConfiguredSite::RemoteSite(x), x.capture_subdomains)))
+            .chain(self.dir_server.iter().flatten().map(|x| (ConfiguredSite::DirServer(x), x.capture_subdomains)))
+            // docker containers always capture their subdomains when routing
+            .chain(self.docker_containers.iter().map(|x| (ConfiguredSite::DockerContainer(x.key().clone()), Some(true))))
+            .map(|(site, capture_subdomains)| {
+                let matched = filter_fun(host_name, site.host_name(), capture_subdomains.unwrap_or_default());
+                (site, matched)
+            });
+        sites().find(|(_, matched)| matches!(matched, Some(None)))
+            .or_else(|| sites().find(|(_, matched)| matched.is_some()))
+            .map(|(site, _)| site)
+    }
+
     pub fn busy_ports(&self) -> Vec<(ProcId,u16)> {
         self.hosted_process.iter().flatten().flat_map(|x| {
             
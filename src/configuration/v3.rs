@@ -24,9 +24,40 @@ pub struct DirServer {
     /// Instead of only listening to yourdomain.com, you can capture subdomains which means this site will also respond to requests for *.yourdomain.com
     pub capture_subdomains : Option<bool>,
     pub enable_lets_encrypt: Option<bool>,
+    /// How lets-encrypt verifies that we control this site, overrides the global lets_encrypt_challenge.
+    pub lets_encrypt_challenge: Option<AcmeChallenge>,
+    /// PEM file with a certificate of your own to use for this site instead of lets-encrypt or a self-signed one.
+    /// The file may hold the full chain, otherwise put the intermediates in tls_chain_path.
+    pub tls_cert_path: Option<String>,
+    /// PEM file with the private key of tls_cert_path.
+    pub tls_key_path: Option<String>,
+    /// PEM file with intermediate certificates to send along with tls_cert_path.
+    pub tls_chain_path: Option<String>,
     pub enable_directory_browsing: Option<bool>,
     pub redirect_to_https: Option<bool>,
     //pub rules: Option<Vec<ReqRule>>,
+    /// Send parts of this site to other sites based on the request path.
+    pub routes: Option<Vec<Route>>,
+    /// Changes to make to the headers of responses sent to clients.
+    pub response_headers: Option<HeaderRules>,
+    /// Do not write access log entries for this site.
+    pub disable_access_log: Option<bool>,
+    /// Limit how many requests clients can make to this site.
+    pub rate_limit: Option<RateLimit>,
+    /// Only accept clients from these address ranges, for example ["10.0.0.0/8", "192.168.1.0/24"].
+    #[schema(value_type = Option<Vec<String>>)]
+    pub allow_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Reject clients from these address ranges. Takes precedence over allow_cidrs.
+    #[schema(value_type = Option<Vec<String>>)]
+    pub deny_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Require a user name and password for this site.
+    pub basic_auth: Option<BasicAuth>,
+    /// Let an external auth service decide which requests are allowed.
+    pub forward_auth: Option<ForwardAuth>,
+    /// Overrides the global timeouts for this site.
+    pub timeouts: Option<Timeouts>,
+    /// Compress responses for clients that support it. Only applies when terminating http.
+    pub compression: Option<Compression>,
     // --- todo --------------------------------------
     pub render_markdown: Option<bool>,
 
@@ -36,6 +67,45 @@ pub struct DirServer {
     // TODO etags and last-modified headers
 }
 
+/// Sends requests for part of a site to another configured site (hosted process, remote site, dir server or docker container).
+/// Routes are checked in order and the first match wins. Requests that match no route are handled by the site itself.
+/// Sites that have routes are always served using http termination.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Hash, JsonSchema, PartialEq, Eq, Default)]
+pub struct Route {
+    /// Match requests whose path starts with this prefix, for example "/api/".
+    /// A trailing '*' is allowed and ignored, so "/api/*" means the same thing.
+    pub path_prefix: Option<String>,
+    /// Match requests whose path matches this regular expression instead, for example "^/v[0-9]+/".
+    pub path_regex: Option<String>,
+    /// The host_name of the site that should handle matching requests.
+    pub target: String,
+    /// Remove the matched part of the path before forwarding: /api/users -> /users
+    pub strip_prefix: Option<bool>,
+    /// Replace the matched part of the path with this value: /api/users -> /v2/users when rewrite is "/v2/".
+    /// When using path_regex, capture groups can be referenced using $1, $2 or $name.
+    pub rewrite: Option<String>,
+}
+
+/// Changes to make to the headers of requests or responses passing thru a site.
+/// Removals are applied first, then set and finally add.
+/// Values may contain the placeholders {client_ip}, {host} and {request_id}.
+/// Sites with header rules are always served using http termination.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Hash, JsonSchema, PartialEq, Eq, Default)]
+pub struct HeaderRules {
+    /// Names of headers to remove, for example "Server".
+    pub remove: Option<Vec<String>>,
+    /// Headers to set, replacing any existing values.
+    pub set: Option<Vec<Header>>,
+    /// Headers to add while keeping any existing values.
+    pub add: Option<Vec<Header>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Hash, JsonSchema, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
 // note: there is no implementation using these yet..
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Hash, JsonSchema, PartialEq, Eq)]
 pub struct ReqRule {
@@ -100,11 +170,44 @@ pub struct InProcessSiteConfig {
     /// If you want to use lets-encrypt for generating certificates automatically for this site.
     /// Defaults to false. This feature will disable tcp tunnel mode.
     pub enable_lets_encrypt: Option<bool>,
+    /// How lets-encrypt verifies that we control this site, overrides the global lets_encrypt_challenge.
+    pub lets_encrypt_challenge: Option<AcmeChallenge>,
+    /// PEM file with a certificate of your own to use for this site instead of lets-encrypt or a self-signed one.
+    /// The file may hold the full chain, otherwise put the intermediates in tls_chain_path.
+    pub tls_cert_path: Option<String>,
+    /// PEM file with the private key of tls_cert_path.
+    pub tls_key_path: Option<String>,
+    /// PEM file with intermediate certificates to send along with tls_cert_path.
+    pub tls_chain_path: Option<String>,
     /// If you wish to set a specific loglevel for this hosted process.
     /// Defaults to "Info".
     /// If this level is lower than the global log_level you will get the message elevated to the global log level instead but tagged with the actual log level.
     pub log_level: Option<LogLevel>,
     pub redirect_to_https: Option<bool>,
+    /// Send parts of this site to other sites based on the request path.
+    pub routes: Option<Vec<Route>>,
+    /// Changes to make to the headers of requests sent to the process.
+    pub request_headers: Option<HeaderRules>,
+    /// Changes to make to the headers of responses sent to clients.
+    pub response_headers: Option<HeaderRules>,
+    /// Do not write access log entries for this site.
+    pub disable_access_log: Option<bool>,
+    /// Limit how many requests or connections clients can make to this site.
+    pub rate_limit: Option<RateLimit>,
+    /// Only accept clients from these address ranges, for example ["10.0.0.0/8", "192.168.1.0/24"].
+    #[schema(value_type = Option<Vec<String>>)]
+    pub allow_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Reject clients from these address ranges. Takes precedence over allow_cidrs.
+    #[schema(value_type = Option<Vec<String>>)]
+    pub deny_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Require a user name and password for this site.
+    pub basic_auth: Option<BasicAuth>,
+    /// Let an external auth service decide which requests are allowed.
+    pub forward_auth: Option<ForwardAuth>,
+    /// Overrides the global timeouts for this site.
+    pub timeouts: Option<Timeouts>,
+    /// Compress responses for clients that support it. Only applies when terminating http.
+    pub compression: Option<Compression>,
 }
 impl InProcessSiteConfig {
     pub fn set_id(&mut self,id:ProcId){
@@ -157,7 +260,22 @@ impl PartialEq for InProcessSiteConfig {
         self.https == other.https &&
         compare_option_bool(self.capture_subdomains, other.capture_subdomains) &&
         compare_option_bool(self.forward_subdomains, other.forward_subdomains) &&
-        compare_option_bool(self.exclude_from_start_all, other.exclude_from_start_all)
+        compare_option_bool(self.exclude_from_start_all, other.exclude_from_start_all) &&
+        self.routes == other.routes &&
+        self.request_headers == other.request_headers &&
+        self.response_headers == other.response_headers &&
+        compare_option_bool(self.disable_access_log, other.disable_access_log) &&
+        self.rate_limit == other.rate_limit &&
+        self.allow_cidrs == other.allow_cidrs &&
+        self.deny_cidrs == other.deny_cidrs &&
+        self.basic_auth == other.basic_auth &&
+        self.forward_auth == other.forward_auth &&
+        self.timeouts == other.timeouts &&
+        self.compression == other.compression &&
+        self.lets_encrypt_challenge == other.lets_encrypt_challenge &&
+        self.tls_cert_path == other.tls_cert_path &&
+        self.tls_key_path == other.tls_key_path &&
+        self.tls_chain_path == other.tls_chain_path
         
     }
 }
@@ -172,6 +290,198 @@ fn compare_option_bool(a: Option<bool>, b: Option<bool>) -> bool {
     result
 }
 
+fn header_rules_to_toml(key: &str, rules: &HeaderRules) -> String {
+    let headers = |list: &[Header]| list.iter()
+        .map(|h| format!("{{ name = {:?}, value = {:?} }}", h.name, h.value))
+        .collect::<Vec<String>>().join(", ");
+    let mut parts = vec![];
+    if let Some(names) = &rules.remove {
+        parts.push(format!("remove = [{}]", names.iter().map(|n| format!("{:?}", n)).collect::<Vec<String>>().join(", ")));
+    }
+    if let Some(list) = &rules.set {
+        parts.push(format!("set = [{}]", headers(list)));
+    }
+    if let Some(list) = &rules.add {
+        parts.push(format!("add = [{}]", headers(list)));
+    }
+    format!("{key} = {{ {} }}", parts.join(", "))
+}
+
+fn routes_to_toml(routes: &[Route]) -> Vec<String> {
+    let mut lines = vec!["routes = [".to_string()];
+    for r in routes {
+        let mut parts = vec![];
+        if let Some(p) = &r.path_prefix {
+            parts.push(format!("path_prefix = {:?}", p));
+        }
+        if let Some(p) = &r.path_regex {
+            parts.push(format!("path_regex = {:?}", p));
+        }
+        parts.push(format!("target = {:?}", r.target));
+        if let Some(v) = r.strip_prefix {
+            parts.push(format!("strip_prefix = {v}"));
+        }
+        if let Some(v) = &r.rewrite {
+            parts.push(format!("rewrite = {:?}", v));
+        }
+        lines.push(format!("\t{{ {} }},", parts.join(", ")));
+    }
+    lines.push("]".to_string());
+    lines
+}
+
+fn tls_certificates_to_toml(certificates: &[TlsCertificate]) -> Vec<String> {
+    let mut lines = vec!["tls_certificates = [".to_string()];
+    for c in certificates {
+        let mut parts = vec![format!("cert_path = {:?}", c.cert_path), format!("key_path = {:?}", c.key_path)];
+        if let Some(p) = &c.chain_path {
+            parts.push(format!("chain_path = {:?}", p));
+        }
+        lines.push(format!("\t{{ {} }},", parts.join(", ")));
+    }
+    lines.push("]".to_string());
+    lines
+}
+
+fn string_list_to_toml<T: std::fmt::Display>(key: &str, list: &[T]) -> String {
+    format!("{key} = [{}]", list.iter().map(|x| format!("{:?}", x.to_string())).collect::<Vec<String>>().join(", "))
+}
+
+fn backend_to_toml(b: &Backend) -> String {
+    let https = if let Some(true) = b.https { format!("https = true, ") } else { format!("") };
+    
+    let hints = if let Some(hints) = &b.hints {
+        format!(", hints = [{}]",hints.iter().map(|h|format!("'{h:?}'")).collect::<Vec<String>>().join(", "))
+    } else {
+        String::new()
+    };
+    
+    let weight = if let Some(w) = b.weight { format!(", weight = {w}") } else { String::new() };
+    let proxy_protocol = if let Some(v) = b.proxy_protocol { format!(", proxy_protocol = \"{v:?}\"") } else { String::new() };
+
+    format!("{{ {}address=\"{}\", port={}{weight}{proxy_protocol}{hints}}}",https,b.address, b.port)
+}
+
+fn listener_to_toml(l: &Listener) -> String {
+    let mut parts = vec![];
+    if let Some(ip) = l.ip {
+        parts.push(format!("ip = \"{ip}\""));
+    }
+    parts.push(format!("port = {}", l.port));
+    if let Some(p) = l.protocol {
+        parts.push(format!("protocol = \"{p:?}\""));
+    }
+    if let Some(v) = l.ipv6_only {
+        parts.push(format!("ipv6_only = {v}"));
+    }
+    if let Some(sites) = &l.sites {
+        parts.push(string_list_to_toml("sites", sites));
+    }
+    format!("{{ {} }}", parts.join(", "))
+}
+
+fn stream_backends_to_toml(backends: &[Backend]) -> String {
+    format!("[{}]", backends.iter().map(backend_to_toml).collect::<Vec<String>>().join(", "))
+}
+
+fn basic_auth_to_toml(auth: &BasicAuth) -> String {
+    let mut parts = vec![];
+    if let Some(r) = &auth.realm {
+        parts.push(format!("realm = {:?}", r));
+    }
+    parts.push(string_list_to_toml("users", &auth.users));
+    format!("basic_auth = {{ {} }}", parts.join(", "))
+}
+
+fn forward_auth_to_toml(auth: &ForwardAuth) -> String {
+    let mut parts = vec![format!("address = {:?}", auth.address)];
+    if let Some(h) = &auth.copy_response_headers {
+        parts.push(string_list_to_toml("copy_response_headers", h));
+    }
+    if let Some(v) = auth.timeout_in_milliseconds {
+        parts.push(format!("timeout_in_milliseconds = {v}"));
+    }
+    format!("forward_auth = {{ {} }}", parts.join(", "))
+}
+
+fn timeouts_to_toml(timeouts: &Timeouts) -> String {
+    let mut parts = vec![];
+    if let Some(v) = timeouts.connect_in_seconds {
+        parts.push(format!("connect_in_seconds = {v}"));
+    }
+    if let Some(v) = timeouts.request_headers_in_seconds {
+        parts.push(format!("request_headers_in_seconds = {v}"));
+    }
+    if let Some(v) = timeouts.idle_in_seconds {
+        parts.push(format!("idle_in_seconds = {v}"));
+    }
+    if let Some(v) = timeouts.max_request_duration_in_seconds {
+        parts.push(format!("max_request_duration_in_seconds = {v}"));
+    }
+    format!("timeouts = {{ {} }}", parts.join(", "))
+}
+
+fn dns_provider_to_toml(provider: &DnsProviderConfig) -> String {
+    let mut parts = vec![format!("kind = \"{:?}\"", provider.kind)];
+    if let Some(v) = &provider.server {
+        parts.push(format!("server = {:?}", v));
+    }
+    if let Some(v) = &provider.zone {
+        parts.push(format!("zone = {:?}", v));
+    }
+    if let Some(v) = &provider.tsig_key_name {
+        parts.push(format!("tsig_key_name = {:?}", v));
+    }
+    if let Some(v) = &provider.tsig_algorithm {
+        parts.push(format!("tsig_algorithm = \"{:?}\"", v));
+    }
+    if let Some(v) = &provider.tsig_secret {
+        parts.push(format!("tsig_secret = {:?}", v));
+    }
+    if let Some(v) = &provider.command {
+        parts.push(format!("command = {:?}", v));
+    }
+    if let Some(args) = &provider.args {
+        parts.push(string_list_to_toml("args", args));
+    }
+    if let Some(v) = provider.propagation_delay_in_seconds {
+        parts.push(format!("propagation_delay_in_seconds = {v}"));
+    }
+    format!("dns_provider = {{ {} }}", parts.join(", "))
+}
+
+fn compression_to_toml(compression: &Compression) -> String {
+    let mut parts = vec![];
+    if let Some(algorithms) = &compression.algorithms {
+        parts.push(format!("algorithms = [{}]", algorithms.iter().map(|a| format!("\"{a:?}\"")).collect::<Vec<String>>().join(", ")));
+    }
+    if let Some(v) = compression.min_size_in_bytes {
+        parts.push(format!("min_size_in_bytes = {v}"));
+    }
+    if let Some(types) = &compression.content_types {
+        parts.push(string_list_to_toml("content_types", types));
+    }
+    format!("compression = {{ {} }}", parts.join(", "))
+}
+
+fn rate_limit_to_toml(limit: &RateLimit) -> String {
+    let mut parts = vec![];
+    if let Some(k) = &limit.key {
+        parts.push(format!("key = \"{:?}\"", k));
+    }
+    if let Some(h) = &limit.header {
+        parts.push(format!("header = {:?}", h));
+    }
+    parts.push(format!("requests = {}", limit.requests));
+    if let Some(v) = limit.per_seconds {
+        parts.push(format!("per_seconds = {v}"));
+    }
+    if let Some(v) = limit.burst {
+        parts.push(format!("burst = {v}"));
+    }
+    format!("rate_limit = {{ {} }}", parts.join(", "))
+}
+
 fn compare_option_log_format(a: &Option<LogFormat>, b: &Option<LogFormat>) -> bool {
     let result = match (a, b) {
         (None, Some(LogFormat::standard)) | (Some(LogFormat::standard), None) => true,
@@ -204,6 +514,377 @@ pub struct Backend {
     pub https : Option<bool>,
     /// H2C,H2,H2CPK - used to signal use of prior knowledge http2 or http2 over clear text. 
     pub hints : Option<Vec<Hint>>,
+    /// Relative share of traffic this backend receives when the site uses WeightedRoundRobin. Defaults to 1.
+    pub weight : Option<u32>,
+    /// Send a PROXY protocol header with the real client address when tunnelling connections to this backend.
+    /// Only used for tunnelled (non-terminated) connections, the backend must be configured to expect it.
+    pub proxy_protocol : Option<ProxyProtocolVersion>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum ProxyProtocolVersion {
+    /// The human readable text format.
+    V1,
+    /// The binary format.
+    V2
+}
+
+/// Listens on a port of its own and forwards each tcp connection as is to one of the backends, in round robin order.
+/// Nothing is terminated, so the https and hints settings of the backends are not used.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct TcpStreamSite {
+    /// Identifies the site in logs and the connections view.
+    pub name : String,
+    /// Defaults to the global ip setting.
+    #[schema(value_type = Option<String>)]
+    pub listen_ip : Option<IpAddr>,
+    pub listen_port : u16,
+    /// Used for connections that do not match any of the sni_routes.
+    pub backends : Vec<Backend>,
+    /// Sends tls connections to other backends depending on the SNI of the client. The tls session is passed thru untouched.
+    pub sni_routes : Option<Vec<SniRoute>>,
+    /// Only the connect and idle timeouts apply to tcp streams. Settings left out are taken from the global timeouts.
+    pub timeouts : Option<Timeouts>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct SniRoute {
+    pub sni : String,
+    pub backends : Vec<Backend>,
+}
+
+/// Listens on a port of its own and forwards udp datagrams to one of the backends.
+/// Each client address gets a session that sticks to one backend, replies from that backend are sent back to the client.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct UdpStreamSite {
+    /// Identifies the site in logs and the connections view.
+    pub name : String,
+    /// Defaults to the global ip setting.
+    #[schema(value_type = Option<String>)]
+    pub listen_ip : Option<IpAddr>,
+    pub listen_port : u16,
+    pub backends : Vec<Backend>,
+    /// Sessions without any traffic in either direction for this long are closed. Defaults to 60.
+    pub session_timeout_in_seconds : Option<u64>,
+}
+
+/// An address to accept http and https connections on, used instead of http_port and tls_port when the listeners list is set.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct Listener {
+    /// Defaults to the global ip setting. Listening on "::" accepts both ipv6 and ipv4 clients unless ipv6_only is set.
+    #[schema(value_type = Option<String>)]
+    pub ip : Option<IpAddr>,
+    pub port : u16,
+    /// Defaults to Auto.
+    pub protocol : Option<ListenerProtocol>,
+    /// Only accept ipv6 clients when listening on an ipv6 address. Defaults to false.
+    pub ipv6_only : Option<bool>,
+    /// Only serve these host names (and their subdomains) on this listener, connections for anything else are dropped.
+    /// The admin interface is served when its host name (odd_box_url or localhost) is included here.
+    pub sites : Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum ListenerProtocol {
+    /// Clear text http, same as the http_port listener.
+    Http,
+    /// TLS, same as the tls_port listener. The admin interface is only served on Https and Auto listeners.
+    Https,
+    /// Handles connections that start with a tls handshake as Https and everything else as Http.
+    Auto
+}
+
+impl UdpStreamSite {
+    pub fn session_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.session_timeout_in_seconds.unwrap_or(60).max(1))
+    }
+}
+
+#[derive(Debug, Eq,PartialEq,Hash, Clone, Serialize, Deserialize, ToSchema, JsonSchema)]
+pub enum LoadBalancing {
+    /// Cycle through the backends in order. This is the default.
+    RoundRobin,
+    /// Like RoundRobin but each backend receives traffic in proportion to its weight.
+    WeightedRoundRobin,
+    /// Pick the backend with the fewest active tunnels and in-flight requests.
+    LeastConnections,
+    /// Pick two backends at random and use the one with the fewest active connections.
+    RandomOfTwo,
+    /// Always send the same client to the same backend for as long as that backend is available.
+    /// Hashes on the header configured in consistent_hash_header, or on the client ip.
+    ConsistentHash
+}
+
+#[derive(Debug, Eq,PartialEq,Hash, Clone, Serialize, Deserialize, ToSchema, JsonSchema)]
+pub enum HealthCheckKind {
+    /// The backend is considered healthy if a TCP connection can be established
+    Tcp,
+    /// The backend is considered healthy if a GET request to the configured path returns a 2xx or 3xx status
+    Http
+}
+
+/// Active health checking for the backends of a remote site.
+/// Backends that fail `unhealthy_threshold` consecutive checks are taken out of rotation
+/// until they pass `healthy_threshold` consecutive checks again.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct HealthCheck {
+    pub kind : HealthCheckKind,
+    /// The path to request when using http checks. Defaults to "/".
+    pub path : Option<String>,
+    /// How often each backend is checked. Defaults to 10 seconds.
+    pub interval_in_seconds : Option<u64>,
+    /// How long to wait for a single check to complete. Defaults to 2000 ms.
+    pub timeout_in_milliseconds : Option<u64>,
+    /// Number of consecutive successful checks before an unhealthy backend is used again. Defaults to 2.
+    pub healthy_threshold : Option<u32>,
+    /// Number of consecutive failed checks before a backend is taken out of rotation. Defaults to 3.
+    pub unhealthy_threshold : Option<u32>,
+}
+
+impl HealthCheck {
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_in_seconds.unwrap_or(10).max(1))
+    }
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_in_milliseconds.unwrap_or(2000).max(1))
+    }
+    pub fn healthy_threshold(&self) -> u32 {
+        self.healthy_threshold.unwrap_or(2).max(1)
+    }
+    pub fn unhealthy_threshold(&self) -> u32 {
+        self.unhealthy_threshold.unwrap_or(3).max(1)
+    }
+}
+
+/// Retrying failed requests against another backend of the same site.
+/// A request is retried after a connection error or a 502, 503 or 504 response.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct RetryPolicy {
+    /// How many other backends to try after the first one failed. Defaults to 1.
+    pub attempts : Option<u32>,
+    /// GET, HEAD and OPTIONS requests are always eligible for retries as long as their body fits within this limit.
+    /// Setting this also makes requests with other methods eligible, which is only safe if your backends can handle
+    /// seeing the same request twice. Defaults to 0, meaning only GET, HEAD and OPTIONS requests without a body are retried.
+    pub max_body_size_in_bytes : Option<u64>,
+}
+
+impl RetryPolicy {
+    pub fn attempts(&self) -> u32 {
+        self.attempts.unwrap_or(1)
+    }
+    pub fn max_body_size(&self) -> u64 {
+        self.max_body_size_in_bytes.unwrap_or(0)
+    }
+}
+
+/// Passive outlier detection based on real traffic.
+/// A backend that fails this many times in a row is taken out of rotation for the cool-down period,
+/// after which it gets traffic again. Failures are connection errors and 502, 503 or 504 responses.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct CircuitBreaker {
+    /// Defaults to 5.
+    pub consecutive_failures : Option<u32>,
+    /// Defaults to 30 seconds.
+    pub cool_down_in_seconds : Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.unwrap_or(5).max(1)
+    }
+    pub fn cool_down(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cool_down_in_seconds.unwrap_or(30))
+    }
+}
+
+/// Keeps clients on the same backend across connections and requests.
+/// Terminated http traffic is pinned using a signed cookie, tunnelled connections are pinned by client ip.
+/// If the pinned backend is removed or becomes unhealthy, a new backend is picked using the normal load balancing strategy.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct SessionAffinity {
+    /// Name of the cookie used to remember the backend. Defaults to "odd_box_backend".
+    pub cookie_name : Option<String>,
+    /// Lifetime of the cookie. If not set, the cookie only lives for the browser session.
+    pub cookie_max_age_in_seconds : Option<u64>,
+}
+
+impl SessionAffinity {
+    pub fn cookie_name(&self) -> &str {
+        self.cookie_name.as_deref().unwrap_or("odd_box_backend")
+    }
+}
+
+/// Token bucket rate limiting for a site. Each bucket holds up to `burst` tokens and gets `requests` new tokens
+/// every `per_seconds` seconds. Terminated http requests take one token each, while tunnelled connections take
+/// one token per connection. Requests that find the bucket empty get a 429 response with a Retry-After header.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct RateLimit {
+    /// What each bucket is shared by. Defaults to ClientIp.
+    pub key : Option<RateLimitKey>,
+    /// Request header to use as the key when key is Header, for example "X-Api-Key".
+    /// Requests without the header, and tunnelled connections, fall back to using the client ip.
+    pub header : Option<String>,
+    /// Number of tokens added to the bucket every `per_seconds`.
+    pub requests : u32,
+    /// Defaults to 1 second.
+    pub per_seconds : Option<u64>,
+    /// Size of the bucket, ie. how many requests can be made in a quick burst. Defaults to `requests`.
+    pub burst : Option<u32>,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.requests).max(1) as f64
+    }
+    pub fn tokens_per_second(&self) -> f64 {
+        self.requests.max(1) as f64 / self.per_seconds.unwrap_or(1).max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum RateLimitKey {
+    /// One bucket per client ip address.
+    ClientIp,
+    /// One bucket shared by all clients of the site.
+    Site,
+    /// One bucket per value of the configured request header.
+    Header,
+}
+
+/// Require clients to log in with a user name and password before requests are passed on to the site.
+/// Sites with basic_auth are always served using http termination.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct BasicAuth {
+    /// Shown by browsers in the login prompt. Defaults to the host_name of the site.
+    pub realm : Option<String>,
+    /// Users in htpasswd format ("name:hash"), using bcrypt ($2y$...) or argon2 ($argon2id$...) hashes.
+    /// For example the output of `htpasswd -nbB alice secret`.
+    pub users : Vec<String>,
+}
+
+/// Ask an external service whether each request should be let thru, using a GET request to `address`
+/// that carries the headers of the original request along with X-Forwarded-Method, X-Forwarded-Proto,
+/// X-Forwarded-Host, X-Forwarded-Uri and X-Forwarded-For. Any 2xx response allows the request,
+/// anything else is sent back to the client as is (typically a redirect to a login page).
+/// Sites with forward_auth are always served using http termination.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct ForwardAuth {
+    /// Url of the auth service, for example "http://127.0.0.1:9091/api/verify".
+    pub address : String,
+    /// Headers from a successful auth response to add to the request sent to the site, for example ["Remote-User", "Remote-Groups"].
+    pub copy_response_headers : Option<Vec<String>>,
+    /// Defaults to 5000 ms.
+    pub timeout_in_milliseconds : Option<u64>,
+}
+
+impl ForwardAuth {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_in_milliseconds.unwrap_or(5000).max(1))
+    }
+}
+
+/// Limits on how long connections and requests may take. Settings left out of a site are taken from the global timeouts.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct Timeouts {
+    /// How long to wait for a connection to a backend. Defaults to 10.
+    pub connect_in_seconds : Option<u64>,
+    /// How long clients get to send the headers of each request. Defaults to 30.
+    pub request_headers_in_seconds : Option<u64>,
+    /// Close tunnels and keep-alive connections after this long without any traffic in either direction. No limit by default.
+    pub idle_in_seconds : Option<u64>,
+    /// How long a backend gets to send the response headers of a terminated http request. No limit by default.
+    pub max_request_duration_in_seconds : Option<u64>,
+}
+
+/// How lets-encrypt verifies that we control a domain before issuing a certificate for it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum AcmeChallenge {
+    /// Lets-encrypt fetches a token from the site over plain http, which requires port 80 to be reachable.
+    Http01,
+    /// Lets-encrypt connects to the site over tls and checks a special certificate, which only requires port 443.
+    TlsAlpn01,
+    /// Lets-encrypt looks up a TXT record that we create thru the configured dns_provider.
+    /// The site does not need to be reachable at all, and sites with capture_subdomains also get a wildcard certificate.
+    Dns01,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum DnsProviderKind {
+    /// Sends RFC 2136 dynamic updates to a dns server, optionally signed with a TSIG key.
+    Rfc2136,
+    /// Runs a command of your own as `<command> <args..> present|cleanup <record name> <record value>`.
+    Exec
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+/// Where the TXT records of the Dns01 challenge are created.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct DnsProviderConfig {
+    pub kind : DnsProviderKind,
+    /// Rfc2136: the dns server to send updates to, as host or host:port. The port defaults to 53.
+    pub server : Option<String>,
+    /// Rfc2136: the zone to update. Defaults to the domain of the certificate.
+    pub zone : Option<String>,
+    /// Rfc2136: name of the TSIG key used to sign the updates.
+    pub tsig_key_name : Option<String>,
+    /// Rfc2136: defaults to HmacSha256.
+    pub tsig_algorithm : Option<TsigAlgorithm>,
+    /// Rfc2136: the base64 encoded TSIG secret.
+    pub tsig_secret : Option<String>,
+    /// Exec: the command to run.
+    pub command : Option<String>,
+    /// Exec: arguments to pass before the action, record name and record value.
+    pub args : Option<Vec<String>>,
+    /// How long to wait after creating a record before asking lets-encrypt to look it up. Defaults to 30.
+    pub propagation_delay_in_seconds : Option<u64>,
+}
+
+/// A certificate of your own, used for every site whose host name it covers.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub struct TlsCertificate {
+    /// PEM file with the certificate, optionally followed by its intermediates.
+    pub cert_path : String,
+    /// PEM file with the private key of the certificate.
+    pub key_path : String,
+    /// PEM file with intermediate certificates, if they are not already in cert_path.
+    pub chain_path : Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum CompressionAlgorithm {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+/// Size limits and storage of the cache used by remote sites that have enable_response_cache set.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct ResponseCache {
+    /// Memory used for cached responses, the least recently used ones are evicted first. Defaults to 64.
+    pub max_memory_size_in_mb : Option<u64>,
+    /// Responses larger than this are never cached. Defaults to 8.
+    pub max_response_size_in_mb : Option<u64>,
+    /// Also keep cached responses in this directory, so that they survive restarts and being evicted from memory.
+    pub disk_dir : Option<String>,
+    /// Disk space used for cached responses when disk_dir is set. Defaults to 1024.
+    pub max_disk_size_in_mb : Option<u64>,
+}
+
+/// Compress responses for clients that support it. Use `compression = {}` for the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct Compression {
+    /// Encodings to use, in order of preference when a client accepts several of them equally. Defaults to ["Brotli", "Zstd", "Gzip"].
+    pub algorithms : Option<Vec<CompressionAlgorithm>>,
+    /// Responses smaller than this are sent uncompressed. Defaults to 1024.
+    pub min_size_in_bytes : Option<u64>,
+    /// Content types to compress. Entries ending with "/" match all types starting with them, for example "text/".
+    /// Defaults to common text based types like html, css, javascript, json, xml and svg.
+    pub content_types : Option<Vec<String>>,
 }
 
 #[derive(Debug, Hash, Clone, Serialize, Deserialize, ToSchema, JsonSchema,Default)]
@@ -228,6 +909,15 @@ pub struct RemoteSiteConfig{
     /// If you want to use lets-encrypt for generating certificates automatically for this site.
     /// Defaults to false. This feature will disable tcp tunnel mode.
     pub enable_lets_encrypt: Option<bool>,
+    /// How lets-encrypt verifies that we control this site, overrides the global lets_encrypt_challenge.
+    pub lets_encrypt_challenge: Option<AcmeChallenge>,
+    /// PEM file with a certificate of your own to use for this site instead of lets-encrypt or a self-signed one.
+    /// The file may hold the full chain, otherwise put the intermediates in tls_chain_path.
+    pub tls_cert_path: Option<String>,
+    /// PEM file with the private key of tls_cert_path.
+    pub tls_key_path: Option<String>,
+    /// PEM file with intermediate certificates to send along with tls_cert_path.
+    pub tls_chain_path: Option<String>,
 
     /// If you wish to pass along the incoming request host header to the backend
     /// rather than the host name of the backends. Defaults to false.
@@ -239,6 +929,57 @@ pub struct RemoteSiteConfig{
     pub keep_original_host_header: Option<bool>,
 
     pub redirect_to_https: Option<bool>,
+
+    /// Optionally probe the backends of this site periodically.
+    /// Backends that are found to be unhealthy will not receive any traffic until they recover.
+    pub health_check: Option<HealthCheck>,
+
+    /// How to pick a backend for each connection or request. Defaults to RoundRobin.
+    pub load_balancing: Option<LoadBalancing>,
+    /// Request header to hash on when using ConsistentHash, for example "X-Api-Key".
+    /// The client ip is used when this is not set, when the header is missing, or when the connection is tunnelled.
+    pub consistent_hash_header: Option<String>,
+
+    /// Opt-in to keep each client on the same backend. Use `session_affinity = {}` for the defaults.
+    pub session_affinity: Option<SessionAffinity>,
+
+    /// Retry idempotent requests against another backend when one fails. Only applies when terminating http.
+    pub retries: Option<RetryPolicy>,
+
+    /// Temporarily stop sending traffic to backends that keep failing.
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    /// Send parts of this site to other sites based on the request path.
+    pub routes: Option<Vec<Route>>,
+
+    /// Changes to make to the headers of requests sent to the backends.
+    pub request_headers: Option<HeaderRules>,
+    /// Changes to make to the headers of responses sent to clients.
+    pub response_headers: Option<HeaderRules>,
+
+    /// Do not write access log entries for this site.
+    pub disable_access_log: Option<bool>,
+
+    /// Limit how many requests or connections clients can make to this site.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Only accept clients from these address ranges, for example ["10.0.0.0/8", "192.168.1.0/24"].
+    #[schema(value_type = Option<Vec<String>>)]
+    pub allow_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Reject clients from these address ranges. Takes precedence over allow_cidrs.
+    #[schema(value_type = Option<Vec<String>>)]
+    pub deny_cidrs: Option<Vec<crate::access_control::Cidr>>,
+
+    /// Require a user name and password for this site.
+    pub basic_auth: Option<BasicAuth>,
+    /// Let an external auth service decide which requests are allowed.
+    pub forward_auth: Option<ForwardAuth>,
+    /// Overrides the global timeouts for this site.
+    pub timeouts: Option<Timeouts>,
+    /// Compress responses for clients that support it. Only applies when terminating http.
+    pub compression: Option<Compression>,
+    /// Cache responses from the backends as allowed by their Cache-Control headers. See response_cache for the cache settings.
+    pub enable_response_cache: Option<bool>,
 }
 
 impl PartialEq for RemoteSiteConfig {
@@ -247,10 +988,32 @@ impl PartialEq for RemoteSiteConfig {
         self.backends == other.backends &&
         compare_option_bool(self.keep_original_host_header,other.keep_original_host_header) &&
         compare_option_bool(self.enable_lets_encrypt,other.enable_lets_encrypt) &&
+        self.lets_encrypt_challenge == other.lets_encrypt_challenge &&
+        self.tls_cert_path == other.tls_cert_path &&
+        self.tls_key_path == other.tls_key_path &&
+        self.tls_chain_path == other.tls_chain_path &&
         compare_option_bool(self.capture_subdomains, other.capture_subdomains) &&
         compare_option_bool(self.terminate_tls, other.terminate_tls) &&
         compare_option_bool(self.forward_subdomains, other.forward_subdomains) &&
-        compare_option_bool(self.terminate_http,other.terminate_http)
+        compare_option_bool(self.terminate_http,other.terminate_http) &&
+        self.health_check == other.health_check &&
+        self.load_balancing == other.load_balancing &&
+        self.consistent_hash_header == other.consistent_hash_header &&
+        self.session_affinity == other.session_affinity &&
+        self.retries == other.retries &&
+        self.circuit_breaker == other.circuit_breaker &&
+        self.routes == other.routes &&
+        self.request_headers == other.request_headers &&
+        self.response_headers == other.response_headers &&
+        compare_option_bool(self.disable_access_log, other.disable_access_log) &&
+        self.rate_limit == other.rate_limit &&
+        self.allow_cidrs == other.allow_cidrs &&
+        self.deny_cidrs == other.deny_cidrs &&
+        self.basic_auth == other.basic_auth &&
+        self.forward_auth == other.forward_auth &&
+        self.timeouts == other.timeouts &&
+        self.compression == other.compression &&
+        compare_option_bool(self.enable_response_cache, other.enable_response_cache)
     }
 }
 
@@ -268,7 +1031,11 @@ pub enum BackendFilter {
 }
 
 
-fn filter_backend(backend: &Backend, filter: &BackendFilter) -> bool {
+fn filter_backend(state:&GlobalState, host_name: &str, backend: &Backend, filter: &BackendFilter) -> bool {
+
+    if !crate::health_check::is_healthy(state, host_name, backend) {
+        return false
+    }
 
     let hints = backend.hints.iter().flatten().collect::<Vec<&Hint>>();
     
@@ -333,10 +1100,12 @@ impl InProcessSiteConfig {
             port: port,
             https: self.https,
             hints: self.hints.clone(),
+            weight: None,
+            proxy_protocol: None
         }];
 
           
-        let filtered_backends = backends.iter().filter(|x|filter_backend(x,&backend_filter))
+        let filtered_backends = backends.iter().filter(|x|filter_backend(state,&self.host_name,x,&backend_filter))
             .collect::<Vec<&Backend>>();
 
         if filtered_backends.len() == 1 { return Some(filtered_backends[0].clone()) };
@@ -363,21 +1132,30 @@ impl InProcessSiteConfig {
 impl RemoteSiteConfig {
 
 
-    pub async fn next_backend(&self,state:&GlobalState, backend_filter: BackendFilter) -> Option<Backend> {
+    pub async fn next_backend(&self,state:&GlobalState, backend_filter: BackendFilter, ctx: &crate::load_balancing::SelectionContext<'_>) -> Option<Backend> {
             
-        let filtered_backends = self.backends.iter().filter(|x|filter_backend(x,&backend_filter))
+        let usable = |x: &&Backend| !ctx.excluded.contains(x) && filter_backend(state,&self.host_name,x,&backend_filter);
+        let mut filtered_backends = self.backends.iter()
+            .filter(usable)
+            .filter(|x|!crate::outlier_detection::is_ejected(state,&self.host_name,x))
             .collect::<Vec<&Backend>>();
 
+        // once the circuit breaker has ejected every backend that could take the request, failing all requests protects nothing.
+        // the ejected backends are used again until one of them recovers, the same as the panic threshold of other proxies.
+        if filtered_backends.is_empty() {
+            filtered_backends = self.backends.iter().filter(usable).collect();
+            if !filtered_backends.is_empty() {
+                tracing::debug!("All usable backends for {} have been ejected by the circuit breaker, using them anyway.", self.host_name);
+            }
+        }
+
         if filtered_backends.len() == 1 { return Some(filtered_backends[0].clone()) };
         if filtered_backends.len() == 0 { return None };
         
-        let current_req_count_for_target_host_name = {
-            state.app_state.statistics.lb_access_count_per_hostname
-            .get(&self.host_name).and_then(|x|Some(x.load(std::sync::atomic::Ordering::SeqCst)))
-            .unwrap_or(0)
-        };
-
-        let selected_backend = filtered_backends.get((current_req_count_for_target_host_name % (filtered_backends.len() as usize)) as usize );
+        let selected_backend = crate::session_affinity::select_pinned(state, self, &filtered_backends, ctx).or_else(|| {
+            let selector = crate::load_balancing::selector_for(self.load_balancing.as_ref());
+            selector.select(state, self, &filtered_backends, ctx)
+        });
 
         if let Some(b) = selected_backend{
             Some((*b).clone())
@@ -429,6 +1207,9 @@ pub struct OddBoxV3Config {
     /// The port on which to listen for https requests. Defaults to 4343.
     #[serde(default = "default_https_port_4343")]
     pub tls_port : Option<u16>,
+    /// Addresses to accept connections on, each with settings of its own. When this is set, no listeners are
+    /// started for http_port and tls_port. The tls_port is still used when redirecting clients to https.
+    pub listeners : Option<Vec<Listener>>,
     /// If this is set to false, odd-box will not start any hosted processes automatically when it starts
     /// unless they are set to auto_start individually. Same with true, it will start all processes that
     /// have not been specifically configured with auto_start=false.
@@ -445,8 +1226,31 @@ pub struct OddBoxV3Config {
     pub hosted_process : Option<Vec<InProcessSiteConfig>>,
     /// Used for static websites.
     pub dir_server : Option<Vec<DirServer>>,
+    /// Forwards raw tcp connections from ports of their own, for things like databases and message brokers.
+    pub tcp_stream : Option<Vec<TcpStreamSite>>,
+    /// Forwards udp datagrams from ports of their own.
+    pub udp_stream : Option<Vec<UdpStreamSite>>,
     /// If you want to use lets-encrypt for generating certificates automatically for your sites
     pub lets_encrypt_account_email: Option<String>,
+    /// How lets-encrypt verifies that we control the domains of our sites. Defaults to Http01.
+    /// Use TlsAlpn01 if port 80 is not reachable from the internet. Sites can override this individually.
+    pub lets_encrypt_challenge: Option<AcmeChallenge>,
+    /// Creates the TXT records needed for the Dns01 challenge.
+    pub dns_provider: Option<DnsProviderConfig>,
+    /// The directory of the acme server to get certificates from. Defaults to the lets-encrypt production directory.
+    /// Use https://acme-staging-v02.api.letsencrypt.org/directory for testing, or the directory of another CA such as ZeroSSL or step-ca.
+    pub acme_directory_url: Option<String>,
+    /// Key id for External Account Binding, required by some CAs (ZeroSSL for example) when registering the account.
+    pub acme_eab_key_id: Option<String>,
+    /// The base64url encoded HMAC key that goes with acme_eab_key_id.
+    pub acme_eab_hmac_key: Option<String>,
+    /// A PEM file with CA certificates to trust when talking to the acme server, for internal CAs like step-ca or Pebble.
+    pub acme_trusted_ca_cert_path: Option<String>,
+    /// Renew certificates when they have fewer than this many days left. Defaults to 30.
+    pub acme_renew_before_days: Option<u64>,
+    /// Certificates of your own, used for sites whose host name matches one of their subject alternative names (wildcards included).
+    /// Sites with tls_cert_path set use that certificate instead. The files are reloaded when they change.
+    pub tls_certificates: Option<Vec<TlsCertificate>>,
     /// If you want to use a specific odd-box url for the admin api and web-interface you can 
     /// configure the host_name to listen on here. This is useful if you want to use a specific domain
     /// for the admin interface and the api. If you do not set this, the admin interface will be available
@@ -455,12 +1259,73 @@ pub struct OddBoxV3Config {
     pub odd_box_url : Option<String>,
     /// Used for securing the admin api and web-interface. If you do not set this, anyone can access the admin api.
     pub odd_box_password: Option<String>,
+    /// Token for scraping the /metrics endpoint of the admin api, sent as "Authorization: Bearer <token>".
+    /// Lets you give a monitoring system read access to the metrics without sharing the odd_box_password.
+    pub metrics_token: Option<String>,
+    /// Only accept connections to the admin api and web-interface from these address ranges, for example ["127.0.0.1", "10.0.0.0/8"].
+    #[schema(value_type = Option<Vec<String>>)]
+    pub odd_box_allow_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// Accept PROXY protocol (v1 or v2) headers on connections from these address ranges, typically a load balancer in front of odd-box.
+    /// The client address from the header is then used for X-Forwarded-For, access rules, rate limits and logs.
+    /// Connections from anywhere else are never parsed for PROXY headers.
+    #[schema(value_type = Option<Vec<String>>)]
+    pub proxy_protocol_trusted_cidrs: Option<Vec<crate::access_control::Cidr>>,
+    /// How long open connections get to finish when odd-box is shutting down, before hosted processes are stopped. Defaults to 30.
+    pub drain_timeout_in_seconds: Option<u64>,
+    /// Connect, header, idle and request duration limits for all sites. Sites can override these individually.
+    pub timeouts: Option<Timeouts>,
+    /// Settings for the cache of remote sites that have enable_response_cache set.
+    pub response_cache: Option<ResponseCache>,
 
     /// Always use 127.0.0.1 (ipv4) when proxying to locally hosted processes.
     /// (ie. not ipv6 or the incoming dns name) 
     #[serde(default = "true_option")]
-    pub use_loopback_ip_for_procs: Option<bool>
+    pub use_loopback_ip_for_procs: Option<bool>,
+
+    /// Write an access log entry for each terminated request and each tunnelled connection.
+    /// Sites can opt out individually using disable_access_log.
+    pub access_log: Option<AccessLog>
+
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum AccessLogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format, which is the Common Log Format plus referer and user-agent.
+    Combined,
+    /// One JSON object per line, including upstream and latency details.
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema)]
+pub enum AccessLogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Eq, PartialEq, Hash, JsonSchema, Default)]
+pub struct AccessLog {
+    /// Defaults to Combined.
+    pub format: Option<AccessLogFormat>,
+    /// File to write the access log to. Entries are written to stdout when this is not set.
+    pub path: Option<String>,
+    /// Rotate the file once it grows beyond this size.
+    pub max_size_in_megabytes: Option<u64>,
+    /// Rotate the file every hour or day. Defaults to Never.
+    pub rotation: Option<AccessLogRotation>,
+    /// How many rotated files to keep. Defaults to 10.
+    pub max_files: Option<u32>,
+}
 
+impl AccessLog {
+    pub fn format(&self) -> AccessLogFormat {
+        self.format.clone().unwrap_or(AccessLogFormat::Combined)
+    }
+    pub fn max_files(&self) -> u32 {
+        self.max_files.unwrap_or(10)
+    }
 }
 
 
@@ -521,6 +1386,13 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
         } else {
             formatted_toml.push(format!("ip = \"127.0.0.1\""));
         }
+        if let Some(listeners) = &self.listeners {
+            formatted_toml.push("listeners = [".to_string());
+            for listener in listeners {
+                formatted_toml.push(format!("\t{},", listener_to_toml(listener)));
+            }
+            formatted_toml.push("]".to_string());
+        }
 
         if let Some(auto_start) = self.auto_start {
             formatted_toml.push(format!("auto_start = {}", auto_start));
@@ -545,6 +1417,43 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
             formatted_toml.push(format!("odd_box_password = {:?}", odd_box_password));
         }
 
+        if let Some(metrics_token) = &self.metrics_token {
+            formatted_toml.push(format!("metrics_token = {:?}", metrics_token));
+        }
+
+        if let Some(cidrs) = &self.odd_box_allow_cidrs {
+            formatted_toml.push(string_list_to_toml("odd_box_allow_cidrs", cidrs));
+        }
+
+        if let Some(cidrs) = &self.proxy_protocol_trusted_cidrs {
+            formatted_toml.push(string_list_to_toml("proxy_protocol_trusted_cidrs", cidrs));
+        }
+
+        if let Some(v) = self.drain_timeout_in_seconds {
+            formatted_toml.push(format!("drain_timeout_in_seconds = {v}"));
+        }
+
+        if let Some(t) = &self.timeouts {
+            formatted_toml.push(timeouts_to_toml(t));
+        }
+
+        if let Some(rc) = &self.response_cache {
+            let mut parts = vec![];
+            if let Some(v) = rc.max_memory_size_in_mb {
+                parts.push(format!("max_memory_size_in_mb = {v}"));
+            }
+            if let Some(v) = rc.max_response_size_in_mb {
+                parts.push(format!("max_response_size_in_mb = {v}"));
+            }
+            if let Some(d) = &rc.disk_dir {
+                parts.push(format!("disk_dir = {:?}", d));
+            }
+            if let Some(v) = rc.max_disk_size_in_mb {
+                parts.push(format!("max_disk_size_in_mb = {v}"));
+            }
+            formatted_toml.push(format!("response_cache = {{ {} }}", parts.join(", ")));
+        }
+
         formatted_toml.push(format!("port_range_start = {}", self.port_range_start));
 
      
@@ -554,6 +1463,58 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
             formatted_toml.push(format!("lets_encrypt_account_email = \"{email}\""));
         }
 
+        if let Some(challenge) = &self.lets_encrypt_challenge {
+            formatted_toml.push(format!("lets_encrypt_challenge = \"{:?}\"", challenge));
+        }
+
+        if let Some(provider) = &self.dns_provider {
+            formatted_toml.push(dns_provider_to_toml(provider));
+        }
+
+        if let Some(url) = &self.acme_directory_url {
+            formatted_toml.push(format!("acme_directory_url = {:?}", url));
+        }
+
+        if let Some(key_id) = &self.acme_eab_key_id {
+            formatted_toml.push(format!("acme_eab_key_id = {:?}", key_id));
+        }
+
+        if let Some(hmac_key) = &self.acme_eab_hmac_key {
+            formatted_toml.push(format!("acme_eab_hmac_key = {:?}", hmac_key));
+        }
+
+        if let Some(path) = &self.acme_trusted_ca_cert_path {
+            formatted_toml.push(format!("acme_trusted_ca_cert_path = {:?}", path));
+        }
+
+        if let Some(days) = self.acme_renew_before_days {
+            formatted_toml.push(format!("acme_renew_before_days = {days}"));
+        }
+
+        if let Some(certificates) = &self.tls_certificates {
+            formatted_toml.extend(tls_certificates_to_toml(certificates));
+        }
+
+        if let Some(al) = &self.access_log {
+            let mut parts = vec![];
+            if let Some(f) = &al.format {
+                parts.push(format!("format = \"{:?}\"", f));
+            }
+            if let Some(p) = &al.path {
+                parts.push(format!("path = {:?}", p));
+            }
+            if let Some(v) = al.max_size_in_megabytes {
+                parts.push(format!("max_size_in_megabytes = {v}"));
+            }
+            if let Some(r) = &al.rotation {
+                parts.push(format!("rotation = \"{:?}\"", r));
+            }
+            if let Some(v) = al.max_files {
+                parts.push(format!("max_files = {v}"));
+            }
+            formatted_toml.push(format!("access_log = {{ {} }}", parts.join(", ")));
+        }
+
         if &self.env_vars.len() > &0 {
             formatted_toml.push("env_vars = [".to_string());
             for env_var in &self.env_vars {
@@ -581,6 +1542,19 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                 if let Some(true) = s.enable_lets_encrypt {
                     formatted_toml.push(format!("enable_lets_encrypt = true"));
                 }
+
+                if let Some(challenge) = &s.lets_encrypt_challenge {
+                    formatted_toml.push(format!("lets_encrypt_challenge = \"{:?}\"", challenge));
+                }
+                if let Some(path) = &s.tls_cert_path {
+                    formatted_toml.push(format!("tls_cert_path = {:?}", path));
+                }
+                if let Some(path) = &s.tls_key_path {
+                    formatted_toml.push(format!("tls_key_path = {:?}", path));
+                }
+                if let Some(path) = &s.tls_chain_path {
+                    formatted_toml.push(format!("tls_chain_path = {:?}", path));
+                }
                 if let Some(true) = s.render_markdown {
                     formatted_toml.push(format!("render_markdown = true"));
                 }
@@ -590,6 +1564,36 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                 if let Some(v) = s.cache_control_max_age_in_seconds {
                     formatted_toml.push(format!("cache_control_max_age_in_seconds = {}", v));
                 } 
+                if let Some(routes) = &s.routes {
+                    formatted_toml.extend(routes_to_toml(routes));
+                }
+                if let Some(rules) = &s.response_headers {
+                    formatted_toml.push(header_rules_to_toml("response_headers", rules));
+                }
+                if let Some(true) = s.disable_access_log {
+                    formatted_toml.push(format!("disable_access_log = true"));
+                }
+                if let Some(rl) = &s.rate_limit {
+                    formatted_toml.push(rate_limit_to_toml(rl));
+                }
+                if let Some(cidrs) = &s.allow_cidrs {
+                    formatted_toml.push(string_list_to_toml("allow_cidrs", cidrs));
+                }
+                if let Some(cidrs) = &s.deny_cidrs {
+                    formatted_toml.push(string_list_to_toml("deny_cidrs", cidrs));
+                }
+                if let Some(auth) = &s.basic_auth {
+                    formatted_toml.push(basic_auth_to_toml(auth));
+                }
+                if let Some(auth) = &s.forward_auth {
+                    formatted_toml.push(forward_auth_to_toml(auth));
+                }
+                if let Some(t) = &s.timeouts {
+                    formatted_toml.push(timeouts_to_toml(t));
+                }
+                if let Some(c) = &s.compression {
+                    formatted_toml.push(compression_to_toml(c));
+                }
             }
         }
         
@@ -618,21 +1622,126 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                     formatted_toml.push(format!("enable_lets_encrypt = {}", true));
                 }
 
+                if let Some(challenge) = &site.lets_encrypt_challenge {
+                    formatted_toml.push(format!("lets_encrypt_challenge = \"{:?}\"", challenge));
+                }
+                if let Some(path) = &site.tls_cert_path {
+                    formatted_toml.push(format!("tls_cert_path = {:?}", path));
+                }
+                if let Some(path) = &site.tls_key_path {
+                    formatted_toml.push(format!("tls_key_path = {:?}", path));
+                }
+                if let Some(path) = &site.tls_chain_path {
+                    formatted_toml.push(format!("tls_chain_path = {:?}", path));
+                }
 
-                formatted_toml.push("backends = [".to_string());
+                if let Some(v) = site.enable_response_cache {
+                    formatted_toml.push(format!("enable_response_cache = {v}"));
+                }
+
+                if let Some(hc) = &site.health_check {
+                    let mut parts = vec![format!("kind = \"{:?}\"", hc.kind)];
+                    if let Some(p) = &hc.path {
+                        parts.push(format!("path = {:?}", p));
+                    }
+                    if let Some(v) = hc.interval_in_seconds {
+                        parts.push(format!("interval_in_seconds = {v}"));
+                    }
+                    if let Some(v) = hc.timeout_in_milliseconds {
+                        parts.push(format!("timeout_in_milliseconds = {v}"));
+                    }
+                    if let Some(v) = hc.healthy_threshold {
+                        parts.push(format!("healthy_threshold = {v}"));
+                    }
+                    if let Some(v) = hc.unhealthy_threshold {
+                        parts.push(format!("unhealthy_threshold = {v}"));
+                    }
+                    formatted_toml.push(format!("health_check = {{ {} }}", parts.join(", ")));
+                }
+
+                if let Some(lb) = &site.load_balancing {
+                    formatted_toml.push(format!("load_balancing = \"{:?}\"", lb));
+                }
+
+                if let Some(h) = &site.consistent_hash_header {
+                    formatted_toml.push(format!("consistent_hash_header = {:?}", h));
+                }
+
+                if let Some(sa) = &site.session_affinity {
+                    let mut parts = vec![];
+                    if let Some(n) = &sa.cookie_name {
+                        parts.push(format!("cookie_name = {:?}", n));
+                    }
+                    if let Some(v) = sa.cookie_max_age_in_seconds {
+                        parts.push(format!("cookie_max_age_in_seconds = {v}"));
+                    }
+                    formatted_toml.push(format!("session_affinity = {{ {} }}", parts.join(", ")));
+                }
+
+                if let Some(r) = &site.retries {
+                    let mut parts = vec![];
+                    if let Some(v) = r.attempts {
+                        parts.push(format!("attempts = {v}"));
+                    }
+                    if let Some(v) = r.max_body_size_in_bytes {
+                        parts.push(format!("max_body_size_in_bytes = {v}"));
+                    }
+                    formatted_toml.push(format!("retries = {{ {} }}", parts.join(", ")));
+                }
+
+                if let Some(cb) = &site.circuit_breaker {
+                    let mut parts = vec![];
+                    if let Some(v) = cb.consecutive_failures {
+                        parts.push(format!("consecutive_failures = {v}"));
+                    }
+                    if let Some(v) = cb.cool_down_in_seconds {
+                        parts.push(format!("cool_down_in_seconds = {v}"));
+                    }
+                    formatted_toml.push(format!("circuit_breaker = {{ {} }}", parts.join(", ")));
+                }
+
+                if let Some(routes) = &site.routes {
+                    formatted_toml.extend(routes_to_toml(routes));
+                }
+
+                if let Some(rules) = &site.request_headers {
+                    formatted_toml.push(header_rules_to_toml("request_headers", rules));
+                }
+
+                if let Some(rules) = &site.response_headers {
+                    formatted_toml.push(header_rules_to_toml("response_headers", rules));
+                }
+
+                if let Some(true) = site.disable_access_log {
+                    formatted_toml.push(format!("disable_access_log = true"));
+                }
+
+                if let Some(rl) = &site.rate_limit {
+                    formatted_toml.push(rate_limit_to_toml(rl));
+                }
+                if let Some(cidrs) = &site.allow_cidrs {
+                    formatted_toml.push(string_list_to_toml("allow_cidrs", cidrs));
+                }
+                if let Some(cidrs) = &site.deny_cidrs {
+                    formatted_toml.push(string_list_to_toml("deny_cidrs", cidrs));
+                }
+                if let Some(auth) = &site.basic_auth {
+                    formatted_toml.push(basic_auth_to_toml(auth));
+                }
+                if let Some(auth) = &site.forward_auth {
+                    formatted_toml.push(forward_auth_to_toml(auth));
+                }
+                if let Some(t) = &site.timeouts {
+                    formatted_toml.push(timeouts_to_toml(t));
+                }
+                if let Some(c) = &site.compression {
+                    formatted_toml.push(compression_to_toml(c));
+                }
 
-                let backend_strings = site.backends.iter().map(|b| {
-                    let https = if let Some(true) = b.https { format!("https = true, ") } else { format!("") };
-                    
-                    let hints = if let Some(hints) = &b.hints {
-                        format!(", hints = [{}]",hints.iter().map(|h|format!("'{h:?}'")).collect::<Vec<String>>().join(", "))
-                    } else {
-                        String::new()
-                    };
-                    
-                    format!("\t{{ {}address=\"{}\", port={}{hints}}}",https,b.address, b.port)}
 
-                ).collect::<Vec<String>>();
+                formatted_toml.push("backends = [".to_string());
+
+                let backend_strings = site.backends.iter().map(|b| format!("\t{}", backend_to_toml(b))).collect::<Vec<String>>();
 
                 formatted_toml.push(backend_strings.join(",\n"));
 
@@ -678,6 +1787,19 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                     formatted_toml.push(format!("enable_lets_encrypt = {}", true));
                 }
 
+                if let Some(challenge) = &process.lets_encrypt_challenge {
+                    formatted_toml.push(format!("lets_encrypt_challenge = \"{:?}\"", challenge));
+                }
+                if let Some(path) = &process.tls_cert_path {
+                    formatted_toml.push(format!("tls_cert_path = {:?}", path));
+                }
+                if let Some(path) = &process.tls_key_path {
+                    formatted_toml.push(format!("tls_key_path = {:?}", path));
+                }
+                if let Some(path) = &process.tls_chain_path {
+                    formatted_toml.push(format!("tls_chain_path = {:?}", path));
+                }
+
                 if let Some(true) = process.exclude_from_start_all {
                     formatted_toml.push(format!("exclude_from_start_all = {}", true));
                 }
@@ -697,6 +1819,44 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                     formatted_toml.push(format!("terminate_tls = {}", true));
                 }
 
+                if let Some(routes) = &process.routes {
+                    formatted_toml.extend(routes_to_toml(routes));
+                }
+
+                if let Some(rules) = &process.request_headers {
+                    formatted_toml.push(header_rules_to_toml("request_headers", rules));
+                }
+
+                if let Some(rules) = &process.response_headers {
+                    formatted_toml.push(header_rules_to_toml("response_headers", rules));
+                }
+
+                if let Some(true) = process.disable_access_log {
+                    formatted_toml.push(format!("disable_access_log = true"));
+                }
+
+                if let Some(rl) = &process.rate_limit {
+                    formatted_toml.push(rate_limit_to_toml(rl));
+                }
+                if let Some(cidrs) = &process.allow_cidrs {
+                    formatted_toml.push(string_list_to_toml("allow_cidrs", cidrs));
+                }
+                if let Some(cidrs) = &process.deny_cidrs {
+                    formatted_toml.push(string_list_to_toml("deny_cidrs", cidrs));
+                }
+                if let Some(auth) = &process.basic_auth {
+                    formatted_toml.push(basic_auth_to_toml(auth));
+                }
+                if let Some(auth) = &process.forward_auth {
+                    formatted_toml.push(forward_auth_to_toml(auth));
+                }
+                if let Some(t) = &process.timeouts {
+                    formatted_toml.push(timeouts_to_toml(t));
+                }
+                if let Some(c) = &process.compression {
+                    formatted_toml.push(compression_to_toml(c));
+                }
+
 
                 if let Some(evars) = &process.env_vars {
                     formatted_toml.push("env_vars = [".to_string());
@@ -712,15 +1872,67 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
 
             }
         }
+
+        for site in self.tcp_stream.iter().flatten() {
+            formatted_toml.push("\n[[tcp_stream]]".to_string());
+            formatted_toml.push(format!("name = {:?}", site.name));
+            if let Some(ip) = site.listen_ip {
+                formatted_toml.push(format!("listen_ip = \"{ip}\""));
+            }
+            formatted_toml.push(format!("listen_port = {}", site.listen_port));
+            formatted_toml.push(format!("backends = {}", stream_backends_to_toml(&site.backends)));
+            if let Some(routes) = &site.sni_routes {
+                formatted_toml.push("sni_routes = [".to_string());
+                let route_strings = routes.iter()
+                    .map(|r| format!("\t{{ sni = {:?}, backends = {} }}", r.sni, stream_backends_to_toml(&r.backends)))
+                    .collect::<Vec<String>>();
+                formatted_toml.push(route_strings.join(",\n"));
+                formatted_toml.push("]".to_string());
+            }
+            if let Some(t) = &site.timeouts {
+                formatted_toml.push(timeouts_to_toml(t));
+            }
+        }
+
+        for site in self.udp_stream.iter().flatten() {
+            formatted_toml.push("\n[[udp_stream]]".to_string());
+            formatted_toml.push(format!("name = {:?}", site.name));
+            if let Some(ip) = site.listen_ip {
+                formatted_toml.push(format!("listen_ip = \"{ip}\""));
+            }
+            formatted_toml.push(format!("listen_port = {}", site.listen_port));
+            formatted_toml.push(format!("backends = {}", stream_backends_to_toml(&site.backends)));
+            if let Some(v) = site.session_timeout_in_seconds {
+                formatted_toml.push(format!("session_timeout_in_seconds = {v}"));
+            }
+        }
         Ok(formatted_toml.join("\n"))
     }
     fn example() -> OddBoxV3Config {
         OddBoxV3Config {
             use_loopback_ip_for_procs: None,
+            access_log: None,
             odd_box_password: None,
+            metrics_token: None,
+            odd_box_allow_cidrs: None,
+            proxy_protocol_trusted_cidrs: None,
+            drain_timeout_in_seconds: None,
+            timeouts: None,
+            response_cache: None,
+            tcp_stream: None,
+            udp_stream: None,
+            listeners: None,
             odd_box_url: None,
             dir_server: None,
             lets_encrypt_account_email: None,
+            lets_encrypt_challenge: None,
+            dns_provider: None,
+            acme_directory_url: None,
+            acme_eab_key_id: None,
+            acme_eab_hmac_key: None,
+            acme_trusted_ca_cert_path: None,
+            acme_renew_before_days: None,
+            tls_certificates: None,
             path: None,
             version: V3VersionEnum::V3,
             alpn: Some(false),
@@ -736,9 +1948,24 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
             port_range_start: 4200,
             hosted_process: Some(vec![
                 InProcessSiteConfig {
+                    routes: None,
+                    request_headers: None,
+                    response_headers: None,
+                    disable_access_log: None,
+                    rate_limit: None,
+                    allow_cidrs: None,
+                    deny_cidrs: None,
+                    basic_auth: None,
+                    forward_auth: None,
+                    timeouts: None,
+                    compression: None,
                     redirect_to_https: Some(true),
                     log_level: None,
                     enable_lets_encrypt: Some(false),
+                    lets_encrypt_challenge: None,
+                    tls_cert_path: None,
+                    tls_key_path: None,
+                    tls_chain_path: None,
                     proc_id: ProcId::new(),
                     active_port: None,
                     forward_subdomains: None,
@@ -764,10 +1991,32 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
             ]),
             remote_target: Some(vec![
                 RemoteSiteConfig { 
+                    health_check: None,
+                    load_balancing: None,
+                    consistent_hash_header: None,
+                    session_affinity: None,
+                    retries: None,
+                    circuit_breaker: None,
+                    routes: None,
+                    request_headers: None,
+                    response_headers: None,
+                    disable_access_log: None,
+                    rate_limit: None,
+                    allow_cidrs: None,
+                    deny_cidrs: None,
+                    basic_auth: None,
+                    forward_auth: None,
+                    timeouts: None,
+                    compression: None,
+                    enable_response_cache: None,
                     redirect_to_https: Some(true),
                     terminate_http: None,
                     keep_original_host_header: None,
                     enable_lets_encrypt: Some(false),
+                    lets_encrypt_challenge: None,
+                    tls_cert_path: None,
+                    tls_key_path: None,
+                    tls_chain_path: None,
                     forward_subdomains: None,
                     host_name: "lobsters.local".into(), 
                     backends: vec![
@@ -775,17 +2024,41 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                             hints: None, 
                             address: "lobste.rs".into(), 
                             port: 443, 
-                            https: Some(true)
+                            https: Some(true),
+                            weight: None,
+                            proxy_protocol: None
                         }
                     ], 
                     capture_subdomains: Some(false), 
                     terminate_tls: Some(false)
                 },
                 RemoteSiteConfig { 
+                    health_check: None,
+                    load_balancing: None,
+                    consistent_hash_header: None,
+                    session_affinity: None,
+                    retries: None,
+                    circuit_breaker: None,
+                    routes: None,
+                    request_headers: None,
+                    response_headers: None,
+                    disable_access_log: None,
+                    rate_limit: None,
+                    allow_cidrs: None,
+                    deny_cidrs: None,
+                    basic_auth: None,
+                    forward_auth: None,
+                    timeouts: None,
+                    compression: None,
+                    enable_response_cache: None,
                     redirect_to_https: Some(true),
                     terminate_http: None,
                     keep_original_host_header: None,
                     enable_lets_encrypt: Some(false),
+                    lets_encrypt_challenge: None,
+                    tls_cert_path: None,
+                    tls_key_path: None,
+                    tls_chain_path: None,
                     forward_subdomains: Some(true),                    
                     host_name: "google.local".into(), 
                     backends: vec![
@@ -793,7 +2066,9 @@ impl crate::configuration::OddBoxConfiguration<OddBoxV3Config> for OddBoxV3Confi
                             hints: None, 
                             address: "google.com".into(), 
                             port: 443, 
-                            https: Some(true)
+                            https: Some(true),
+                            weight: None,
+                            proxy_protocol: None
                         }
                     ], 
                     capture_subdomains: Some(false), 
@@ -863,10 +2138,28 @@ impl TryFrom<super::v2::OddBoxV2Config> for OddBoxV3Config{
     fn try_from(old_config: super::v2::OddBoxV2Config) -> Result<Self, Self::Error> {
         let new_config = Self {
             use_loopback_ip_for_procs: None,
+            access_log: None,
             odd_box_password: None,
+            metrics_token: None,
+            odd_box_allow_cidrs: None,
+            proxy_protocol_trusted_cidrs: None,
+            drain_timeout_in_seconds: None,
+            timeouts: None,
+            response_cache: None,
+            tcp_stream: None,
+            udp_stream: None,
+            listeners: None,
             odd_box_url: None,
             dir_server: None,
             lets_encrypt_account_email: None,
+            lets_encrypt_challenge: None,
+            dns_provider: None,
+            acme_directory_url: None,
+            acme_eab_key_id: None,
+            acme_eab_hmac_key: None,
+            acme_trusted_ca_cert_path: None,
+            acme_renew_before_days: None,
+            tls_certificates: None,
             path: None,
             version: V3VersionEnum::V3,
             alpn: Some(false), // allowing alpn would be a breaking change for h2c when using old configuration format
@@ -895,9 +2188,24 @@ impl TryFrom<super::v2::OddBoxV2Config> for OddBoxV3Config{
                 let new_hints = if new_hints.len() == 0 { None } else { Some(new_hints) };
 
                 InProcessSiteConfig {
+                    routes: None,
+                    request_headers: None,
+                    response_headers: None,
+                    disable_access_log: None,
+                    rate_limit: None,
+                    allow_cidrs: None,
+                    deny_cidrs: None,
+                    basic_auth: None,
+                    forward_auth: None,
+                    timeouts: None,
+                    compression: None,
                     redirect_to_https: None,
                     log_level: None,
                     enable_lets_encrypt: Some(false),
+                    lets_encrypt_challenge: None,
+                    tls_cert_path: None,
+                    tls_key_path: None,
+                    tls_chain_path: None,
                     proc_id: ProcId::new(),
                     active_port: None,
                     forward_subdomains: x.forward_subdomains,
@@ -921,10 +2229,32 @@ impl TryFrom<super::v2::OddBoxV2Config> for OddBoxV3Config{
             remote_target: Some(old_config.remote_target.unwrap_or_default().iter().map(|x|{
 
                 RemoteSiteConfig {
+                    health_check: None,
+                    load_balancing: None,
+                    consistent_hash_header: None,
+                    session_affinity: None,
+                    retries: None,
+                    circuit_breaker: None,
+                    routes: None,
+                    request_headers: None,
+                    response_headers: None,
+                    disable_access_log: None,
+                    rate_limit: None,
+                    allow_cidrs: None,
+                    deny_cidrs: None,
+                    basic_auth: None,
+                    forward_auth: None,
+                    timeouts: None,
+                    compression: None,
+                    enable_response_cache: None,
                     redirect_to_https: None,
                     terminate_http: x.disable_tcp_tunnel_mode, // <-- before v3 there was just disable tunnel mode, so both term tls and http get val from there now
                     keep_original_host_header: None,
                     enable_lets_encrypt: Some(false),
+                    lets_encrypt_challenge: None,
+                    tls_cert_path: None,
+                    tls_key_path: None,
+                    tls_chain_path: None,
                     terminate_tls: x.disable_tcp_tunnel_mode,
                     capture_subdomains: x.capture_subdomains,
                     forward_subdomains: x.forward_subdomains,
@@ -952,7 +2282,9 @@ impl TryFrom<super::v2::OddBoxV2Config> for OddBoxV3Config{
                             address: b.address.clone(),
                             port: b.port,
                             https: b.https,
-                            hints: new_hints
+                            hints: new_hints,
+                            weight: None,
+                            proxy_protocol: None
                         }
                     }).collect(),
                     host_name: x.host_name.clone(),                    
@@ -0,0 +1,271 @@
+// Certificates of your own, set per site with tls_cert_path/tls_key_path or globally thru tls_certificates.
+// The files are watched and reloaded when they change. When a file fails to load, the error is logged and the
+// certificate that was loaded from it before (if any) stays in use until the file is fixed.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::configuration::OddBoxConfig;
+use crate::global_state::GlobalState;
+use crate::types::proc_info::BgTaskInfo;
+
+/// Files are often replaced one at a time (certificate first, key second), so wait for things to settle before reloading.
+const RELOAD_DELAY: Duration = Duration::from_secs(2);
+/// Also reload everything now and then, in case a change was missed (network file systems do not always tell).
+const FULL_RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Where a certificate is loaded from, along with the host name of the site it was configured on (if any).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CertificateSource {
+    pub site: Option<String>,
+    pub cert_path: String,
+    pub key_path: String,
+    pub chain_path: Option<String>,
+}
+
+impl CertificateSource {
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        [&self.cert_path, &self.key_path].into_iter().chain(self.chain_path.iter())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadedCertificate {
+    pub source: CertificateSource,
+    /// The dns names of the certificate, in lower case. Wildcards are kept as is, for example "*.example.com".
+    pub names: Vec<String>,
+    /// Unix timestamp of when the certificate expires.
+    pub not_after: i64,
+    pub key: Arc<CertifiedKey>,
+}
+
+impl LoadedCertificate {
+    /// Tells if the certificate is valid for the given host name, either thru an exact name or a wildcard.
+    pub fn covers(&self, host_name: &str) -> bool {
+        self.names.iter().any(|n| name_matches(n, host_name))
+    }
+}
+
+/// Wildcards only match a single label, so "*.example.com" covers "www.example.com" but not "example.com" or "a.b.example.com".
+pub fn name_matches(name: &str, host_name: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(parent) => host_name.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => name.eq_ignore_ascii_case(host_name),
+    }
+}
+
+/// All certificates in the configuration, site certificates first.
+pub fn sources_from_config(config: &OddBoxConfig) -> Vec<CertificateSource> {
+    let site = |host_name: &String, cert_path: &Option<String>, key_path: &Option<String>, chain_path: &Option<String>| {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Some(CertificateSource {
+                site: Some(host_name.clone()),
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+                chain_path: chain_path.clone(),
+            }),
+            _ => None
+        }
+    };
+    let sites = config.hosted_process.iter().flatten().filter_map(|x| site(&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path))
+        .chain(config.remote_target.iter().flatten().filter_map(|x| site(&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path)))
+        .chain(config.dir_server.iter().flatten().filter_map(|x| site(&x.host_name, &x.tls_cert_path, &x.tls_key_path, &x.tls_chain_path)));
+    let global = config.tls_certificates.iter().flatten().map(|x| CertificateSource {
+        site: None,
+        cert_path: x.cert_path.clone(),
+        key_path: x.key_path.clone(),
+        chain_path: x.chain_path.clone(),
+    });
+    sites.chain(global).collect()
+}
+
+/// Reads and validates a certificate with its key and chain. Fails for expired certificates and keys that do not belong to the certificate.
+pub fn load(source: &CertificateSource) -> anyhow::Result<LoadedCertificate> {
+    let pem = std::fs::read_to_string(&source.cert_path).context(format!("Failed to read {}", source.cert_path))?;
+    let mut chain = crate::certs::extract_cert_from_pem_str(pem)?;
+    if chain.is_empty() {
+        anyhow::bail!("No certificates found in {}", source.cert_path);
+    }
+    if let Some(chain_path) = &source.chain_path {
+        let pem = std::fs::read_to_string(chain_path).context(format!("Failed to read {chain_path}"))?;
+        let intermediates = crate::certs::extract_cert_from_pem_str(pem)?;
+        if intermediates.is_empty() {
+            anyhow::bail!("No certificates found in {chain_path}");
+        }
+        chain.extend(intermediates);
+    }
+
+    let pem = std::fs::read_to_string(&source.key_path).context(format!("Failed to read {}", source.key_path))?;
+    let private_key = rustls_pemfile::private_key(&mut std::io::Cursor::new(pem))
+        .context(format!("Failed to parse {}", source.key_path))?
+        .ok_or(anyhow::anyhow!("No private key found in {}", source.key_path))?;
+    let signing_key = tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
+        .map_err(|e| anyhow::anyhow!("Unsupported private key in {}: {e}", source.key_path))?;
+
+    let (names, not_before, not_after) = {
+        let (_, cert) = X509Certificate::from_der(&chain[0])
+            .map_err(|e| anyhow::anyhow!("Failed to parse the certificate in {}: {e}", source.cert_path))?;
+        let mut names = vec![];
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                if let GeneralName::DNSName(n) = name {
+                    names.push(n.to_lowercase());
+                }
+            }
+        }
+        // old certificates may only have the common name
+        if names.is_empty() {
+            names.extend(cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).map(|cn| cn.to_lowercase()));
+        }
+        (names, cert.validity().not_before.timestamp(), cert.validity().not_after.timestamp())
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if not_after <= now {
+        anyhow::bail!("The certificate in {} expired on {}", source.cert_path, format_timestamp(not_after));
+    }
+    if not_before > now {
+        anyhow::bail!("The certificate in {} is not valid until {}", source.cert_path, format_timestamp(not_before));
+    }
+    if names.is_empty() {
+        anyhow::bail!("The certificate in {} has no dns names", source.cert_path);
+    }
+
+    let key = CertifiedKey::new(chain, signing_key);
+    if let Err(tokio_rustls::rustls::Error::InconsistentKeys(tokio_rustls::rustls::InconsistentKeys::KeyMismatch)) = key.keys_match() {
+        anyhow::bail!("The private key in {} does not belong to the certificate in {}", source.key_path, source.cert_path);
+    }
+
+    Ok(LoadedCertificate { source: source.clone(), names, not_after, key: Arc::new(key) })
+}
+
+fn format_timestamp(t: i64) -> String {
+    chrono::DateTime::from_timestamp(t, 0).map(|x| x.to_rfc3339()).unwrap_or_else(|| t.to_string())
+}
+
+fn absolute(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().map(|d| d.join(path)).unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+/// Loads the given certificates into the resolver, returns how many of them failed to load.
+fn reload(state: &GlobalState, sources: &[CertificateSource]) -> usize {
+    let mut failures = 0;
+    for source in sources {
+        let previous = state.cert_resolver.get_custom_cert_by_source(source);
+        match load(source) {
+            Ok(cert) => {
+                if previous.as_ref().is_some_and(|p| p.key.cert == cert.key.cert) {
+                    tracing::trace!("The certificate in {} has not changed.", source.cert_path);
+                } else {
+                    tracing::info!("Loaded the certificate in {} for {}.", source.cert_path, cert.names.join(", "));
+                }
+                state.cert_resolver.add_custom_cert(cert);
+            },
+            Err(e) => {
+                failures += 1;
+                if previous.is_some() {
+                    tracing::error!("{e:#}. Will keep using the certificate that was loaded from {} before.", source.cert_path);
+                } else {
+                    tracing::error!("{e:#}. The certificate will not be used until this is fixed.");
+                }
+            }
+        }
+    }
+    failures
+}
+
+pub async fn bg_worker_for_custom_certs(state: Arc<GlobalState>) {
+    let liveness_token = Arc::new(true);
+    crate::BG_WORKER_THREAD_MAP.insert("Custom Certificates".into(), BgTaskInfo {
+        liveness_ptr: Arc::downgrade(&liveness_token),
+        status: "Active".into()
+    });
+
+    let (mut watcher, rx) = match crate::async_watcher() {
+        Ok((watcher, rx)) => (Some(watcher), Some(rx)),
+        Err(e) => {
+            tracing::error!("Failed to watch certificate files, changes will only be picked up once an hour: {e:?}");
+            (None, None)
+        }
+    };
+
+    let mut configured : Vec<CertificateSource> = vec![];
+    // directories are watched rather than the files, as certificates are often renewed by replacing the files
+    let mut watched_dirs : HashSet<PathBuf> = HashSet::new();
+    let mut watched_files : HashSet<PathBuf> = HashSet::new();
+    let mut changed_at : Option<Instant> = None;
+    let mut last_full_reload = Instant::now();
+    let mut failures = 0;
+
+    loop {
+        if state.app_state.exit.load(std::sync::atomic::Ordering::Relaxed) {
+            break
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let sources = {
+            let guard = state.config.read().await;
+            sources_from_config(&guard)
+        };
+
+        if sources != configured {
+            state.cert_resolver.retain_custom_certs(|x| sources.contains(x));
+            watched_files = sources.iter().flat_map(|x| x.paths()).map(|x| absolute(x)).collect();
+            let dirs : HashSet<PathBuf> = watched_files.iter().filter_map(|x| x.parent().map(|p| p.to_path_buf())).collect();
+            if let Some(watcher) = &mut watcher {
+                for dir in watched_dirs.difference(&dirs) {
+                    _ = notify::Watcher::unwatch(watcher, dir);
+                }
+                for dir in dirs.difference(&watched_dirs) {
+                    if let Err(e) = notify::Watcher::watch(watcher, dir, notify::RecursiveMode::NonRecursive) {
+                        tracing::warn!("Failed to watch {} for certificate changes: {e:?}", dir.display());
+                    }
+                }
+            }
+            watched_dirs = dirs;
+            configured = sources;
+            failures = reload(&state, &configured);
+            last_full_reload = Instant::now();
+            changed_at = None;
+        }
+
+        if let Some(rx) = &rx {
+            while let Ok(event) = rx.try_recv() {
+                match event {
+                    Ok(event) => {
+                        if event.paths.iter().any(|p| watched_files.contains(p)) {
+                            changed_at = Some(Instant::now());
+                        }
+                    },
+                    Err(e) => tracing::warn!("Error while watching certificate files: {e:?}")
+                }
+            }
+        }
+
+        if changed_at.is_some_and(|t| t.elapsed() >= RELOAD_DELAY) || last_full_reload.elapsed() >= FULL_RELOAD_INTERVAL {
+            failures = reload(&state, &configured);
+            last_full_reload = Instant::now();
+            changed_at = None;
+        }
+
+        crate::BG_WORKER_THREAD_MAP.insert("Custom Certificates".into(), BgTaskInfo {
+            liveness_ptr: Arc::downgrade(&liveness_token),
+            status: if configured.is_empty() {
+                "No certificates configured".into()
+            } else {
+                format!("{} certificates ({} failing)", configured.len(), failures)
+            }
+        });
+    }
+}
@@ -17,7 +17,7 @@ use std::{
 
 
 use crate::{
-    configuration::DirServer,
+    configuration::{CompressionAlgorithm, DirServer},
     http_proxy::{create_simple_response_from_bytes, EpicResponse},
     CustomError,
 };
@@ -71,11 +71,17 @@ pub async fn handle(
         .and_then(|v| v.to_str().ok())
         .unwrap_or("no-cookies");
 
+    // the encoding is picked from what the client accepts, so it is part of the cache key even if the file ends up uncompressed.
+    let encoding = target.compression.as_ref()
+        .filter(|_| req.method() != hyper::Method::HEAD)
+        .and_then(|c| crate::compression::negotiate_for_request(c, req.headers()));
+
     let cache_key = format!(
-        "{}-{}-{}",
+        "{}-{}-{}-{}",
         target.host_name,
         cookie_sig,
-        &path_decoded.trim_end_matches('/')
+        &path_decoded.trim_end_matches('/'),
+        encoding.map(|e| e.content_encoding()).unwrap_or("identity")
     );
     tracing::trace!(%cache_key, "incoming request");
 
@@ -115,9 +121,9 @@ pub async fn handle(
     }
 
     if full_path.is_file() {
-        serve_file(&target, &req, &full_path, cache_key).await
+        serve_file(&target, &req, &full_path, cache_key, encoding).await
     } else if full_path.is_dir() {
-        serve_directory(&target, &req, &full_path, &path_decoded, cache_key).await
+        serve_directory(&target, &req, &full_path, &path_decoded, cache_key, encoding).await
     } else {
         simple_status(StatusCode::NOT_FOUND, "Not Found")
     }
@@ -174,13 +180,14 @@ fn apply_streaming_headers<B>(
     max_age: Option<u64>,
     conditional: Option<(&str, &str)>, // (etag, last_modified)
     content_length: Option<u64>,
+    vary: &'static str,
+    content_encoding: Option<CompressionAlgorithm>,
 ) -> Response<B> {
     *resp.status_mut() = status;
     let headers = resp.headers_mut();
 
     headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
-    headers.insert(header::VARY, HeaderValue::from_static("Cookie"));
-    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::VARY, HeaderValue::from_static(vary));
 
     if let Some(max_age) = max_age {
         headers.insert(
@@ -192,6 +199,11 @@ fn apply_streaming_headers<B>(
         headers.insert(header::ETAG, HeaderValue::from_str(etag).unwrap());
         headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(last_modified).unwrap());
     }
+    match content_encoding {
+        // also weakens the etag, which was made for the file as it is on disk
+        Some(e) => crate::compression::set_encoding_headers(headers, e),
+        None => { headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes")); }
+    }
     if let Some(len) = content_length {
         headers.insert(header::CONTENT_LENGTH, HeaderValue::from_str(&len.to_string()).unwrap());
     }
@@ -200,12 +212,48 @@ fn apply_streaming_headers<B>(
 
 const SMALL_FILE_MAX: u64 = 2 * 1024 * 1024; // 2 MiB
 
-// todo - possibly we could support compression here
+/// The Vary header for responses of this site, which can differ by encoding when compression is enabled.
+fn vary_for(cfg: &DirServer) -> &'static str {
+    if cfg.compression.is_some() { "Cookie, Accept-Encoding" } else { "Cookie" }
+}
+
+/// Looks for a precompressed copy of the file next to it, as in index.html.br for index.html
+async fn precompressed_sibling(full_path: &Path, encoding: CompressionAlgorithm) -> Option<(PathBuf, u64)> {
+    let mut name = full_path.file_name()?.to_os_string();
+    name.push(format!(".{}", encoding.file_extension()));
+    let sibling = full_path.with_file_name(name);
+    match tokio::fs::metadata(&sibling).await {
+        Ok(m) if m.is_file() => Some((sibling, m.len())),
+        _ => None,
+    }
+}
+
+/// Compresses generated pages and small files that are worth compressing, returning the encoding that was used if any.
+async fn compress_if_worthwhile(
+    cfg: &DirServer,
+    encoding: Option<CompressionAlgorithm>,
+    content_type: &str,
+    body: Bytes,
+) -> (Bytes, Option<CompressionAlgorithm>) {
+    let (Some(settings), Some(encoding)) = (&cfg.compression, encoding) else { return (body, None) };
+    if !crate::compression::should_compress(settings, content_type, Some(body.len() as u64)) {
+        return (body, None);
+    }
+    match crate::compression::compress_bytes(encoding, &body).await {
+        Ok(compressed) => (compressed, Some(encoding)),
+        Err(e) => {
+            tracing::warn!("Failed to compress response using {encoding:?}, sending it uncompressed: {e:?}");
+            (body, None)
+        }
+    }
+}
+
 async fn serve_file(
     cfg: &DirServer,
     req: &hyper::Request<hyper::body::Incoming>,
     full_path: &Path,
     cache_key: String,
+    encoding: Option<CompressionAlgorithm>,
 ) -> Result<EpicResponse, CustomError> {
     
     let meta = match tokio::fs::metadata(full_path).await {
@@ -242,6 +290,7 @@ async fn serve_file(
             &last_modified,
             &cache_key,
             ThemeDecision::from_req(req),
+            encoding,
         ).await;
     }
  
@@ -252,31 +301,56 @@ async fn serve_file(
     };
  
     let is_head = req.method() == hyper::Method::HEAD;
+    let vary = vary_for(cfg);
 
-    let (tx_to_client, internal_rx) = crate::http_proxy::create_response_channel(4);
-    let resp: EpicResponse = crate::http_proxy::create_stream_response(internal_rx);
+    // precompressed copies are sent as they are, other files are compressed as they are sent if they are worth it.
+    let precompressed = match encoding {
+        Some(e) => precompressed_sibling(full_path, e).await,
+        None => None,
+    };
+    let compress_on_the_fly = encoding.filter(|_| precompressed.is_none()).filter(|_| {
+        cfg.compression.as_ref().is_some_and(|c| crate::compression::should_compress(c, mime_type.as_ref(), Some(size)))
+    });
+    let content_encoding = if precompressed.is_some() { encoding } else { compress_on_the_fly };
+    let (body_path, body_size) = precompressed.unwrap_or_else(|| (full_path.to_owned(), size));
 
-    let resp = apply_streaming_headers(
-        resp,
-        StatusCode::OK,
-        mime_type.as_ref(),
-        cfg.cache_control_max_age_in_seconds,
-        Some((&*etag, &*last_modified)),
-        Some(size), 
-    );
+    let (tx_to_client, internal_rx) = crate::http_proxy::create_response_channel(4);
 
     if is_head {
-        return Ok(resp);
+        return Ok(apply_streaming_headers(
+            crate::http_proxy::create_stream_response(internal_rx),
+            StatusCode::OK,
+            mime_type.as_ref(),
+            cfg.cache_control_max_age_in_seconds,
+            Some((&*etag, &*last_modified)),
+            Some(size),
+            vary,
+            None,
+        ));
     }
 
     // -------- SMALL FILE PATH: read once, cache, and send one frame --------
-    if size <= SMALL_FILE_MAX {
+    if body_size <= SMALL_FILE_MAX {
         // tracing::info!("SENDING FULL FILE!!!! {full_path:?} ({size} bytes)");
-        let bytes = Bytes::from(
-            tokio::fs::read(full_path)
+        let mut bytes = Bytes::from(
+            tokio::fs::read(&body_path)
                 .await
                 .map_err(|e| map_io(e, "read"))?
         );
+        if let Some(e) = compress_on_the_fly {
+            bytes = crate::compression::compress_bytes(e, &bytes).await.map_err(|e| map_io(e, "compress"))?;
+        }
+
+        let resp = apply_streaming_headers(
+            crate::http_proxy::create_stream_response(internal_rx),
+            StatusCode::OK,
+            mime_type.as_ref(),
+            cfg.cache_control_max_age_in_seconds,
+            Some((&*etag, &*last_modified)),
+            Some(bytes.len() as u64),
+            vary,
+            content_encoding,
+        );
 
         // Send the whole thing as a single data frame (fast, zero-copy into the channel)
         let tx = tx_to_client.clone();
@@ -292,6 +366,8 @@ async fn serve_file(
                 mime_type.as_ref(),
                 cfg.cache_control_max_age_in_seconds,
                 Some((&etag, &last_modified)),
+                vary,
+                content_encoding,
                 // Re-read avoided: reuse the same bytes we already loaded
                 // (clone is cheap; Bytes is ref-counted)
                 bytes.clone(),
@@ -305,7 +381,19 @@ async fn serve_file(
 
     // -------- LARGE FILE PATH: pure streaming from disk, no cache --------
     // tracing::info!("STREAMING FILE!!!! {full_path:?} ({size} bytes)");
-    let path_for_task = full_path.to_owned();
+    let resp = apply_streaming_headers(
+        crate::http_proxy::create_stream_response(internal_rx),
+        StatusCode::OK,
+        mime_type.as_ref(),
+        cfg.cache_control_max_age_in_seconds,
+        Some((&*etag, &*last_modified)),
+        // the size of a file that is compressed while it is sent is not known up front
+        if compress_on_the_fly.is_some() { None } else { Some(body_size) },
+        vary,
+        content_encoding,
+    );
+
+    let path_for_task = body_path;
     tokio::spawn(async move {
         use futures_util::StreamExt;
         use tokio_util::io::ReaderStream;
@@ -318,6 +406,11 @@ async fn serve_file(
             }
         };
 
+        if let Some(e) = compress_on_the_fly {
+            crate::compression::spawn_sender(crate::compression::compress_reader(e, file), tx_to_client);
+            return;
+        }
+
         let mut reader = ReaderStream::new(file);
         while let Some(chunk_res) = reader.next().await {
             match chunk_res {
@@ -346,6 +439,7 @@ async fn serve_markdown(
     last_modified: &str,
     cache_key: &str,
     theme: ThemeDecision,
+    encoding: Option<CompressionAlgorithm>,
 ) -> Result<EpicResponse, CustomError> {
 
     let md = String::from_utf8_lossy(md_bytes).into_owned();
@@ -353,13 +447,15 @@ async fn serve_markdown(
     let html = super::markdown_to_html(theme, "markdown", &md)
         .map_err(|e| CustomError(format!("Markdown→HTML failed: {e}").into()))?;
 
-    let body = Bytes::from(html.into_bytes());
+    let (body, content_encoding) = compress_if_worthwhile(cfg, encoding, "text/html; charset=utf-8", Bytes::from(html.into_bytes())).await;
     let resp = build_response(
         StatusCode::OK,
         "text/html; charset=utf-8",
         cfg.cache_control_max_age_in_seconds,
         Some((etag, last_modified)),
-        body.clone(),
+        vary_for(cfg),
+        content_encoding,
+        body,
     )?;
     cache_response(cache_key.to_string(), "text/html; charset=utf-8", &resp);
     create_simple_response_from_bytes(resp)
@@ -375,6 +471,7 @@ async fn serve_directory(
     dir: &Path,
     req_path: &str,
     cache_key: String,
+    encoding: Option<CompressionAlgorithm>,
 ) -> Result<EpicResponse, CustomError> {
 
     // Redirect “/foo” → “/foo/”
@@ -392,7 +489,7 @@ async fn serve_directory(
     for name in ["index.html", "index.htm", "index.md"] {
         let candidate = dir.join(name);
         if candidate.is_file() {
-            return serve_file(cfg, req, &candidate, cache_key).await;
+            return serve_file(cfg, req, &candidate, cache_key, encoding).await;
         }
     }
 
@@ -405,12 +502,15 @@ async fn serve_directory(
     let req_path = req_path.to_owned();
     let listing_html = build_dir_listing(&dir, &req_path)?;
 
+    let (body, content_encoding) = compress_if_worthwhile(cfg, encoding, "text/html; charset=utf-8", Bytes::from(listing_html.into_bytes())).await;
     let resp = build_response(
         StatusCode::OK,
         "text/html; charset=utf-8",
         cfg.cache_control_max_age_in_seconds,
         None,
-        Bytes::from(listing_html.into_bytes()),
+        vary_for(cfg),
+        content_encoding,
+        body,
     )?;
     cache_response(cache_key, "text/html; charset=utf-8", &resp);
     create_simple_response_from_bytes(resp)
@@ -426,7 +526,8 @@ fn is_not_modified(
     modified: Option<SystemTime>,
 ) -> Result<bool, CustomError> {
     if let Some(hv) = req.headers().get(IF_NONE_MATCH) {
-        if hv.to_str().unwrap_or("") == etag {
+        // compared weakly, as clients are sent weak etags for compressed responses
+        if hv.to_str().unwrap_or("").split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag) {
             return Ok(true);
         }
     }
@@ -462,12 +563,14 @@ fn build_response(
     content_type: &str,
     max_age: Option<u64>,
     conditional: Option<(&str, &str)>,
+    vary: &'static str,
+    content_encoding: Option<CompressionAlgorithm>,
     body: Bytes,
 ) -> Result<Response<Bytes>, CustomError> {
     let mut builder = Response::builder()
         .status(status)
         .header("Content-Type", content_type)
-        .header("Vary", HeaderValue::from_static("Cookie"));
+        .header("Vary", HeaderValue::from_static(vary));
 
     if let Some(max_age) = max_age {
         builder = builder.header(
@@ -478,9 +581,13 @@ fn build_response(
     if let Some((etag, last_modified)) = conditional {
         builder = builder.header("ETag", etag).header("Last-Modified", last_modified);
     }
-    builder
+    let mut resp = builder
         .body(body.into())
-        .map_err(|e| CustomError(format!("failed to build response: {e}").into()))
+        .map_err(|e| CustomError(format!("failed to build response: {e}").into()))?;
+    if let Some(e) = content_encoding {
+        crate::compression::set_encoding_headers(resp.headers_mut(), e);
+    }
+    Ok(resp)
 }
 
 fn simple_status(code: StatusCode, body: &str) -> Result<EpicResponse, CustomError> {
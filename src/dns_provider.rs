@@ -0,0 +1,274 @@
+// Creates and removes the TXT records used by the ACME dns-01 challenge.
+// Providers are picked by the dns_provider section of the configuration: RFC 2136 dynamic updates for servers we
+// can talk to directly, or a command of the users own for everything else (cloud dns apis and the like).
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose, Engine};
+use futures_util::future::BoxFuture;
+use tokio::net::UdpSocket;
+
+use crate::configuration::{DnsProviderConfig, DnsProviderKind, TsigAlgorithm};
+
+const DEFAULT_PROPAGATION_DELAY: u64 = 30;
+const RECORD_TTL: u32 = 60;
+const UPDATE_ATTEMPTS: u32 = 3;
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(3);
+const EXEC_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub trait DnsProvider: Send + Sync {
+    /// Creates a TXT record with the given fully qualified name and value.
+    fn present<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+    /// Removes a record previously created by present.
+    fn cleanup<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+    /// How long it takes for a new record to be visible to the CA.
+    fn propagation_delay(&self) -> Duration;
+}
+
+pub fn from_config(config: &DnsProviderConfig) -> anyhow::Result<Box<dyn DnsProvider>> {
+    let propagation_delay = Duration::from_secs(config.propagation_delay_in_seconds.unwrap_or(DEFAULT_PROPAGATION_DELAY));
+    match config.kind {
+        DnsProviderKind::Rfc2136 => {
+            let server = config.server.clone().context("A server is required for the Rfc2136 provider.")?;
+            let tsig = match (&config.tsig_key_name, &config.tsig_secret) {
+                (Some(name), Some(secret)) => Some(TsigKey {
+                    name: name.clone(),
+                    algorithm: config.tsig_algorithm.unwrap_or(TsigAlgorithm::HmacSha256),
+                    secret: general_purpose::STANDARD.decode(secret.trim()).context("The tsig_secret must be base64 encoded.")?,
+                }),
+                (None, None) => None,
+                _ => bail!("The tsig_key_name and tsig_secret must be given together."),
+            };
+            Ok(Box::new(Rfc2136Provider { server, zone: config.zone.clone(), tsig, propagation_delay }))
+        },
+        DnsProviderKind::Exec => {
+            let command = config.command.clone().context("A command is required for the Exec provider.")?;
+            Ok(Box::new(ExecHookProvider { command, args: config.args.clone().unwrap_or_default(), propagation_delay }))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigAlgorithm {
+    fn dns_name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256.",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512.",
+        }
+    }
+    fn ring_algorithm(&self) -> ring::hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => ring::hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha512 => ring::hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// Adds and deletes records using DNS UPDATE messages (RFC 2136), signed with TSIG (RFC 8945) when a key is configured.
+#[derive(Debug, Clone)]
+pub struct Rfc2136Provider {
+    pub server: String,
+    pub zone: Option<String>,
+    pub tsig: Option<TsigKey>,
+    pub propagation_delay: Duration,
+}
+
+impl DnsProvider for Rfc2136Provider {
+    fn present<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.send_update(name, value, false))
+    }
+    fn cleanup<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.send_update(name, value, true))
+    }
+    fn propagation_delay(&self) -> Duration {
+        self.propagation_delay
+    }
+}
+
+impl Rfc2136Provider {
+
+    async fn resolve_server(&self) -> anyhow::Result<SocketAddr> {
+        if let Ok(address) = self.server.parse::<SocketAddr>() {
+            return Ok(address)
+        }
+        if let Ok(ip) = self.server.parse::<std::net::IpAddr>() {
+            return Ok(SocketAddr::new(ip, 53))
+        }
+        let mut addresses = if self.server.contains(':') {
+            tokio::net::lookup_host(self.server.as_str()).await
+        } else {
+            tokio::net::lookup_host((self.server.as_str(), 53)).await
+        }.with_context(|| format!("Failed to resolve the dns server '{}'", self.server))?;
+        addresses.next().ok_or_else(|| anyhow!("The dns server '{}' did not resolve to any address", self.server))
+    }
+
+    async fn send_update(&self, name: &str, value: &str, delete: bool) -> anyhow::Result<()> {
+        let server = self.resolve_server().await?;
+        let zone = self.zone.clone().unwrap_or_else(|| name.trim_start_matches("_acme-challenge.").to_string());
+        let id_bytes = *uuid::Uuid::new_v4().as_bytes();
+        let id = u16::from_be_bytes([id_bytes[0], id_bytes[1]]);
+        let time_signed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let message = build_update(id, &zone, name, value, delete, self.tsig.as_ref(), time_signed)?;
+
+        let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(server).await?;
+        let mut response = [0u8; 4096];
+        for attempt in 1..=UPDATE_ATTEMPTS {
+            socket.send(&message).await?;
+            match tokio::time::timeout(UPDATE_TIMEOUT, socket.recv(&mut response)).await {
+                Ok(Ok(n)) if n >= 12 && response[0..2] == id.to_be_bytes() => {
+                    let rcode = response[3] & 0x0f;
+                    if rcode != 0 {
+                        bail!("{server} refused to update {name}: {}", rcode_name(rcode))
+                    }
+                    tracing::debug!("{} {name} thru {server}", if delete { "Deleted" } else { "Created" });
+                    return Ok(())
+                },
+                Ok(Ok(_)) => tracing::debug!("Ignoring an unrelated answer from {server}"),
+                Ok(Err(e)) => return Err(e).with_context(|| format!("Failed to update {name} thru {server}")),
+                Err(_) => tracing::debug!("No answer from {server} for the update of {name} (attempt {attempt})"),
+            }
+        }
+        bail!("No answer from {server} for the update of {name}")
+    }
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        1 => "FORMERR".into(),
+        2 => "SERVFAIL".into(),
+        3 => "NXDOMAIN".into(),
+        4 => "NOTIMP".into(),
+        5 => "REFUSED".into(),
+        6 => "YXDOMAIN".into(),
+        7 => "YXRRSET".into(),
+        8 => "NXRRSET".into(),
+        9 => "NOTAUTH (is the tsig key correct?)".into(),
+        10 => "NOTZONE (is the zone correct?)".into(),
+        x => format!("rcode {x}")
+    }
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &str) -> anyhow::Result<()> {
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            bail!("The dns label '{label}' is too long")
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.to_ascii_lowercase().as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+/// Builds an UPDATE message for `zone` that adds, or deletes, the TXT record `name` with the given value.
+pub fn build_update(id: u16, zone: &str, name: &str, value: &str, delete: bool, tsig: Option<&TsigKey>, time_signed: u64) -> anyhow::Result<Vec<u8>> {
+    if value.len() > 255 {
+        bail!("TXT values longer than 255 bytes are not supported")
+    }
+    let mut message = Vec::with_capacity(256);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(5u16 << 11).to_be_bytes()); // opcode UPDATE
+    message.extend_from_slice(&1u16.to_be_bytes()); // zone
+    message.extend_from_slice(&0u16.to_be_bytes()); // prerequisites
+    message.extend_from_slice(&1u16.to_be_bytes()); // updates
+    message.extend_from_slice(&0u16.to_be_bytes()); // additional, set to one once signed
+
+    push_name(&mut message, zone)?;
+    message.extend_from_slice(&6u16.to_be_bytes()); // SOA
+    message.extend_from_slice(&1u16.to_be_bytes()); // IN
+
+    // deleting a specific record is done with class NONE and a ttl of zero (RFC 2136, 2.5.4)
+    push_name(&mut message, name)?;
+    message.extend_from_slice(&16u16.to_be_bytes()); // TXT
+    message.extend_from_slice(&(if delete { 254u16 } else { 1u16 }).to_be_bytes());
+    message.extend_from_slice(&(if delete { 0 } else { RECORD_TTL }).to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+    message.push(value.len() as u8);
+    message.extend_from_slice(value.as_bytes());
+
+    if let Some(key) = tsig {
+        sign(&mut message, id, key, time_signed)?;
+    }
+    Ok(message)
+}
+
+/// Appends a TSIG record to the message, the mac covers the message as it was and the tsig variables (RFC 8945, 4.3.3).
+fn sign(message: &mut Vec<u8>, id: u16, key: &TsigKey, time_signed: u64) -> anyhow::Result<()> {
+    let fudge = 300u16;
+    let time = &time_signed.to_be_bytes()[2..8];
+
+    let mut signed = message.clone();
+    push_name(&mut signed, &key.name)?;
+    signed.extend_from_slice(&255u16.to_be_bytes()); // ANY
+    signed.extend_from_slice(&0u32.to_be_bytes());
+    push_name(&mut signed, key.algorithm.dns_name())?;
+    signed.extend_from_slice(time);
+    signed.extend_from_slice(&fudge.to_be_bytes());
+    signed.extend_from_slice(&0u16.to_be_bytes()); // error
+    signed.extend_from_slice(&0u16.to_be_bytes()); // other len
+    let mac = ring::hmac::sign(&ring::hmac::Key::new(key.algorithm.ring_algorithm(), &key.secret), &signed);
+
+    let mut rdata = vec![];
+    push_name(&mut rdata, key.algorithm.dns_name())?;
+    rdata.extend_from_slice(time);
+    rdata.extend_from_slice(&fudge.to_be_bytes());
+    rdata.extend_from_slice(&(mac.as_ref().len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac.as_ref());
+    rdata.extend_from_slice(&id.to_be_bytes());
+    rdata.extend_from_slice(&0u16.to_be_bytes()); // error
+    rdata.extend_from_slice(&0u16.to_be_bytes()); // other len
+
+    push_name(message, &key.name)?;
+    message.extend_from_slice(&250u16.to_be_bytes()); // TSIG
+    message.extend_from_slice(&255u16.to_be_bytes()); // ANY
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+    message[10..12].copy_from_slice(&1u16.to_be_bytes());
+    Ok(())
+}
+
+/// Runs a user supplied command for each record, for dns hosts that have their own apis.
+#[derive(Debug, Clone)]
+pub struct ExecHookProvider {
+    pub command: String,
+    pub args: Vec<String>,
+    pub propagation_delay: Duration,
+}
+
+impl DnsProvider for ExecHookProvider {
+    fn present<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.run("present", name, value))
+    }
+    fn cleanup<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.run("cleanup", name, value))
+    }
+    fn propagation_delay(&self) -> Duration {
+        self.propagation_delay
+    }
+}
+
+impl ExecHookProvider {
+    async fn run(&self, action: &str, name: &str, value: &str) -> anyhow::Result<()> {
+        let output = tokio::process::Command::new(&self.command)
+            .args(&self.args).arg(action).arg(name).arg(value)
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(EXEC_TIMEOUT, output).await
+            .map_err(|_| anyhow!("'{}' did not finish within {} seconds", self.command, EXEC_TIMEOUT.as_secs()))?
+            .with_context(|| format!("Failed to run '{}'", self.command))?;
+        if !output.status.success() {
+            bail!("'{} {action} {name}' failed with {}: {}", self.command, output.status, String::from_utf8_lossy(&output.stderr).trim())
+        }
+        Ok(())
+    }
+}
@@ -30,7 +30,9 @@ impl ContainerProxyTarget {
                     address: self.target_addr.clone(),
                     port: self.port,
                     https: Some(self.tls),
-                    hints: hints
+                    hints: hints,
+                    weight: None,
+                    proxy_protocol: None
                 }
             ],
             redirect_to_https: self.redirect_to_https,
@@ -39,7 +41,29 @@ impl ContainerProxyTarget {
             terminate_http: self.terminate_http,
             forward_subdomains: self.forward_subdomains,
             enable_lets_encrypt: self.enable_lets_encrypt,
+            lets_encrypt_challenge: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_chain_path: None,
             keep_original_host_header: self.keep_original_host_header,
+            health_check: None,
+            load_balancing: None,
+            consistent_hash_header: None,
+            session_affinity: None,
+            retries: None,
+            circuit_breaker: None,
+            routes: None,
+            request_headers: None,
+            response_headers: None,
+            disable_access_log: None,
+            rate_limit: None,
+            allow_cidrs: None,
+            deny_cidrs: None,
+            basic_auth: None,
+            forward_auth: None,
+            timeouts: None,
+            compression: None,
+            enable_response_cache: None,
         }
     }
 }
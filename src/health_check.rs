@@ -0,0 +1,180 @@
+// Active health checking of remote site backends.
+// Results are stored in ProxyLiveStats::backend_health and consulted by next_backend
+// so that unhealthy backends are skipped until they recover.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::configuration::{Backend, HealthCheck, HealthCheckKind};
+use crate::global_state::GlobalState;
+use crate::types::proc_info::BgTaskInfo;
+use crate::types::proxy_state::{BackendHealth, BackendHealthKey};
+
+pub fn backend_key(host_name: &str, backend: &Backend) -> BackendHealthKey {
+    (host_name.to_string(), format!("{}:{}", backend.address, backend.port))
+}
+
+/// Backends that have never been checked (or belong to sites without a health check) are considered healthy.
+pub fn is_healthy(state: &GlobalState, host_name: &str, backend: &Backend) -> bool {
+    state.app_state.statistics.backend_health
+        .get(&backend_key(host_name, backend))
+        .map(|x| x.healthy)
+        .unwrap_or(true)
+}
+
+/// Returns (healthy, total) for sites that have health checks configured.
+pub fn summary_for_host(state: &GlobalState, host_name: &str) -> Option<(usize, usize)> {
+    let mut healthy = 0;
+    let mut total = 0;
+    for guard in state.app_state.statistics.backend_health.iter() {
+        let ((h, _), v) = guard.pair();
+        if h == host_name {
+            total += 1;
+            if v.healthy { healthy += 1 }
+        }
+    }
+    if total == 0 { None } else { Some((healthy, total)) }
+}
+
+pub fn backends_for_host(state: &GlobalState, host_name: &str) -> Vec<BackendHealth> {
+    let mut items : Vec<BackendHealth> = state.app_state.statistics.backend_health.iter()
+        .filter(|x| x.key().0 == host_name)
+        .map(|x| x.value().clone())
+        .collect();
+    items.sort_by(|a, b| (&a.address, a.port).cmp(&(&b.address, b.port)));
+    items
+}
+
+/// The client used for http health checks, which is shared by all of them since setting up tls is expensive.
+pub(crate) fn http_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        // backends are commonly using self-signed certificates, we only care about them responding.
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::redirect::Policy::none())
+        // every check opens a new connection, or a backend that stopped accepting them would still pass
+        .pool_max_idle_per_host(0)
+        .build()
+}
+
+pub(crate) async fn check_backend(client: &reqwest::Client, check: &HealthCheck, backend: &Backend) -> Result<(), String> {
+    match check.kind {
+        HealthCheckKind::Tcp => {
+            match tokio::time::timeout(
+                check.timeout(),
+                tokio::net::TcpStream::connect((backend.address.as_str(), backend.port))
+            ).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(format!("{e}")),
+                Err(_) => Err("timed out".into())
+            }
+        },
+        HealthCheckKind::Http => {
+            let scheme = if backend.https.unwrap_or_default() { "https" } else { "http" };
+            let path = check.path.clone().unwrap_or("/".into());
+            let path = if path.starts_with('/') { path } else { format!("/{path}") };
+            let url = format!("{scheme}://{}:{}{path}", backend.address, backend.port);
+            match client.get(&url).timeout(check.timeout()).send().await {
+                Ok(res) if res.status().is_success() || res.status().is_redirection() => Ok(()),
+                Ok(res) => Err(format!("unexpected status {}", res.status())),
+                Err(e) => Err(format!("{e}"))
+            }
+        }
+    }
+}
+
+pub(crate) fn record_result(state: &GlobalState, key: BackendHealthKey, backend: &Backend, check: &HealthCheck, result: Result<(), String>) {
+    let mut entry = state.app_state.statistics.backend_health.entry(key.clone()).or_insert_with(|| BackendHealth {
+        address: backend.address.clone(),
+        port: backend.port,
+        healthy: true,
+        consecutive_successes: 0,
+        consecutive_failures: 0,
+        last_error: None
+    });
+    match result {
+        Ok(_) => {
+            entry.consecutive_failures = 0;
+            entry.consecutive_successes = entry.consecutive_successes.saturating_add(1);
+            entry.last_error = None;
+            if !entry.healthy && entry.consecutive_successes >= check.healthy_threshold() {
+                entry.healthy = true;
+                tracing::info!("Backend {} for {} is healthy again and will receive traffic.", key.1, key.0);
+            }
+        },
+        Err(e) => {
+            entry.consecutive_successes = 0;
+            entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+            tracing::debug!("Health check for backend {} of {} failed: {e}", key.1, key.0);
+            entry.last_error = Some(e);
+            if entry.healthy && entry.consecutive_failures >= check.unhealthy_threshold() {
+                entry.healthy = false;
+                tracing::warn!("Backend {} for {} failed {} consecutive health checks and has been taken out of rotation.", key.1, key.0, entry.consecutive_failures);
+            }
+        }
+    }
+}
+
+pub async fn bg_worker_for_health_checks(state: Arc<GlobalState>) {
+    let liveness_token = Arc::new(true);
+    crate::BG_WORKER_THREAD_MAP.insert("Health Checks".into(), BgTaskInfo {
+        liveness_ptr: Arc::downgrade(&liveness_token),
+        status: "Active".into()
+    });
+
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to create the http client for health checks, no backends will be checked: {e:?}");
+            return;
+        }
+    };
+    let mut last_checked : HashMap<BackendHealthKey, Instant> = HashMap::new();
+
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let sites : Vec<(String, HealthCheck, Vec<Backend>)> = {
+            let guard = state.config.read().await;
+            guard.remote_target.iter().flatten()
+                .filter_map(|x| x.health_check.clone().map(|hc| (x.host_name.clone(), hc, x.backends.clone())))
+                .collect()
+        };
+
+        // forget about backends that are no longer configured or no longer have health checks enabled
+        let configured : HashSet<BackendHealthKey> = sites.iter()
+            .flat_map(|(host_name, _, backends)| backends.iter().map(|b| backend_key(host_name, b)))
+            .collect();
+        state.app_state.statistics.backend_health.retain(|k, _| configured.contains(k));
+        last_checked.retain(|k, _| configured.contains(k));
+
+        for (host_name, check, backends) in sites {
+            for backend in backends {
+                let key = backend_key(&host_name, &backend);
+                if let Some(t) = last_checked.get(&key) {
+                    if t.elapsed() < check.interval() {
+                        continue;
+                    }
+                }
+                last_checked.insert(key.clone(), Instant::now());
+                let state = state.clone();
+                let check = check.clone();
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let result = check_backend(&client, &check, &backend).await;
+                    record_result(&state, key, &backend, &check, result);
+                });
+            }
+        }
+
+        let unhealthy = state.app_state.statistics.backend_health.iter().filter(|x| !x.healthy).count();
+        crate::BG_WORKER_THREAD_MAP.insert("Health Checks".into(), BgTaskInfo {
+            liveness_ptr: Arc::downgrade(&liveness_token),
+            status: if configured.is_empty() {
+                "No health checks configured".into()
+            } else {
+                format!("Monitoring {} backends ({} unhealthy)", configured.len(), unhealthy)
+            }
+        });
+    }
+}
@@ -0,0 +1,454 @@
+// Zero-downtime upgrades (unix only). The running instance starts a new odd-box from the current binary, normally just
+// replaced by `odd-box --update`, which inherits the listening sockets and the output pipes of the hosted processes.
+// Once the new instance has picked those up, the old one drains its own connections and exits without stopping the
+// hosted processes, as they have been adopted by the new instance.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::global_state::GlobalState;
+
+/// Describes the inherited file descriptors to the new instance, as json.
+const HANDOFF_ENV_VAR: &str = "ODD_BOX_HANDOFF";
+/// How long the old instance waits for the new one to report that it is up.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the new instance waits for the inherited sockets and processes to be picked up before reporting in anyway.
+const ADOPTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketKind {
+    Tcp,
+    Udp
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HandedOverSocket {
+    kind: SocketKind,
+    addr: SocketAddr,
+    fd: i32
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HandedOverProcess {
+    host_name: String,
+    pid: u32,
+    started_at: u64,
+    port: u16,
+    stdout_fd: i32,
+    stderr_fd: i32
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Handoff {
+    sockets: Vec<HandedOverSocket>,
+    processes: Vec<HandedOverProcess>,
+    /// The new instance writes a single byte here once it is up.
+    ready_fd: i32
+}
+
+/// A hosted process that was started by the previous instance and is now ours to manage.
+#[derive(Debug)]
+pub struct AdoptedProcess {
+    pub pid: u32,
+    /// Tells the process apart from any later one that gets the same pid, see start_time.
+    pub started_at: u64,
+    pub port: u16,
+    pub stdout: std::fs::File,
+    pub stderr: std::fs::File
+}
+
+/// Keeps a socket or the output of a hosted process available for the next upgrade until it is dropped.
+pub struct Registration {
+    #[cfg(unix)]
+    registered: unix::Registered
+}
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::io::Read;
+    use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use anyhow::Context;
+    use dashmap::DashMap;
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    struct RunningProcess {
+        pid: u32,
+        started_at: u64,
+        port: u16,
+        stdout: OwnedFd,
+        stderr: OwnedFd
+    }
+
+    pub(super) enum Registered {
+        Socket(SocketKind, SocketAddr),
+        Process(String)
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            match &self.registered {
+                Registered::Socket(kind, addr) => { SOCKETS.remove(&(*kind, *addr)); },
+                Registered::Process(host_name) => { PROCESSES.remove(host_name); }
+            }
+        }
+    }
+
+    // what this instance can hand over
+    static SOCKETS: Lazy<DashMap<(SocketKind, SocketAddr), OwnedFd>> = Lazy::new(DashMap::new);
+    static PROCESSES: Lazy<DashMap<String, RunningProcess>> = Lazy::new(DashMap::new);
+    static UPGRADING: AtomicBool = AtomicBool::new(false);
+
+    // what was handed over to this instance and has not been picked up yet
+    static INHERITED_SOCKETS: Lazy<DashMap<(SocketKind, SocketAddr), OwnedFd>> = Lazy::new(DashMap::new);
+    static INHERITED_PROCESSES: Lazy<DashMap<String, AdoptedProcess>> = Lazy::new(DashMap::new);
+    static READY: Mutex<Option<UnixStream>> = Mutex::new(None);
+    static HANDOFF: Mutex<Option<String>> = Mutex::new(None);
+
+    /// Makes a listening socket available to hand over. The registration must be kept for as long as the socket is in use.
+    pub fn register_socket(kind: SocketKind, addr: SocketAddr, socket: &impl AsFd) -> Option<Registration> {
+        match socket.as_fd().try_clone_to_owned() {
+            Ok(fd) => {
+                SOCKETS.insert((kind, addr), fd);
+                Some(Registration { registered: Registered::Socket(kind, addr) })
+            },
+            Err(e) => {
+                tracing::warn!("Failed to duplicate the socket for {addr}, it will not be handed over in hot upgrades: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Makes a running hosted process available to hand over, along with the pipes its output is read from.
+    pub fn register_process(host_name: &str, pid: u32, port: u16, stdout: &impl AsFd, stderr: &impl AsFd) -> Option<Registration> {
+        let Some(started_at) = start_time(pid) else {
+            tracing::warn!("[{host_name}] Could not find the start time of pid {pid}, the process will be restarted in hot upgrades");
+            return None
+        };
+        let pipes = stdout.as_fd().try_clone_to_owned().and_then(|o| Ok((o, stderr.as_fd().try_clone_to_owned()?)));
+        match pipes {
+            Ok((stdout, stderr)) => {
+                PROCESSES.insert(host_name.to_string(), RunningProcess { pid, started_at, port, stdout, stderr });
+                Some(Registration { registered: Registered::Process(host_name.to_string()) })
+            },
+            Err(e) => {
+                tracing::warn!("[{host_name}] Failed to duplicate the output pipes, the process will be restarted in hot upgrades: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Takes the listener that the previous instance used for this address, if there was one.
+    pub fn inherited_tcp_listener(addr: SocketAddr) -> Option<std::net::TcpListener> {
+        let (_, fd) = INHERITED_SOCKETS.remove(&(SocketKind::Tcp, addr))?;
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true).ok()?;
+        tracing::info!("Reusing the tcp listener on {addr} from the previous instance");
+        Some(listener)
+    }
+
+    /// Takes the udp socket that the previous instance used for this address, if there was one.
+    pub fn inherited_udp_socket(addr: SocketAddr) -> Option<std::net::UdpSocket> {
+        let (_, fd) = INHERITED_SOCKETS.remove(&(SocketKind::Udp, addr))?;
+        let socket = std::net::UdpSocket::from(fd);
+        socket.set_nonblocking(true).ok()?;
+        tracing::info!("Reusing the udp socket on {addr} from the previous instance");
+        Some(socket)
+    }
+
+    /// Takes the process that the previous instance was running for the site, if it is still alive.
+    pub fn take_process(host_name: &str) -> Option<AdoptedProcess> {
+        let (_, process) = INHERITED_PROCESSES.remove(host_name)?;
+        if !is_running(process.pid, process.started_at) {
+            tracing::info!("[{host_name}] The process from the previous instance has stopped, starting a new one");
+            return None
+        }
+        tracing::info!("[{host_name}] Adopting the running process from the previous instance (pid {})", process.pid);
+        Some(process)
+    }
+
+    /// When the process was started, in seconds since the epoch.
+    /// Pids are reused once a process is gone, so this is what tells an adopted process apart from whatever got its pid later.
+    pub fn start_time(pid: u32) -> Option<u64> {
+        use sysinfo::{ProcessesToUpdate, System};
+        let pid = sysinfo::Pid::from_u32(pid);
+        let mut sys = System::new();
+        sys.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+        sys.process(pid).map(|p| p.start_time())
+    }
+
+    /// Tells if the process with the given pid is still the one that was started at `started_at`, and has not exited.
+    pub fn is_running(pid: u32, started_at: u64) -> bool {
+        use sysinfo::{ProcessStatus, ProcessesToUpdate, System};
+        let pid = sysinfo::Pid::from_u32(pid);
+        let mut sys = System::new();
+        sys.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+        // processes that exit before the previous instance does stay around as zombies until it is gone
+        sys.process(pid).is_some_and(|p| p.status() != ProcessStatus::Zombie && p.start_time() == started_at)
+    }
+
+    /// Takes the handoff variable out of the environment, so that hosted processes do not inherit it.
+    /// Changing the environment is not thread safe, so this must be called from main before the tokio runtime is started.
+    pub fn take_handoff_from_env() {
+        if let Ok(value) = std::env::var(HANDOFF_ENV_VAR) {
+            std::env::remove_var(HANDOFF_ENV_VAR);
+            *HANDOFF.lock().expect("handoff lock should never be poisoned") = Some(value);
+        }
+    }
+
+    /// Picks up whatever the previous instance handed over, if this instance was started by a hot upgrade.
+    /// This must run before the proxy and the hosted processes are started.
+    pub fn load_inherited() {
+        let Some(value) = HANDOFF.lock().expect("handoff lock should never be poisoned").take() else { return };
+        let handoff : Handoff = match serde_json::from_str(&value) {
+            Ok(h) => h,
+            Err(e) => {
+                tracing::error!("Ignoring the invalid {HANDOFF_ENV_VAR} variable: {e:?}");
+                return
+            }
+        };
+        // SAFETY: the previous instance made sure that these descriptors were open and inherited by us, and each is only used once.
+        unsafe {
+            for s in handoff.sockets {
+                INHERITED_SOCKETS.insert((s.kind, s.addr), OwnedFd::from_raw_fd(s.fd));
+            }
+            for p in handoff.processes {
+                INHERITED_PROCESSES.insert(p.host_name, AdoptedProcess {
+                    pid: p.pid,
+                    started_at: p.started_at,
+                    port: p.port,
+                    stdout: std::fs::File::from(OwnedFd::from_raw_fd(p.stdout_fd)),
+                    stderr: std::fs::File::from(OwnedFd::from_raw_fd(p.stderr_fd))
+                });
+            }
+            *READY.lock().expect("ready lock should never be poisoned") = Some(UnixStream::from_raw_fd(handoff.ready_fd));
+        }
+        tracing::info!("Started by a hot upgrade, taking over {} sockets and {} hosted processes", INHERITED_SOCKETS.len(), INHERITED_PROCESSES.len());
+    }
+
+    /// Lets the previous instance know that we are up, once everything it handed over has been picked up.
+    async fn report_ready(state: &GlobalState) {
+        if READY.lock().expect("ready lock should never be poisoned").is_none() {
+            return
+        }
+        let started = std::time::Instant::now();
+        while started.elapsed() < ADOPTION_TIMEOUT && !(INHERITED_SOCKETS.is_empty() && INHERITED_PROCESSES.is_empty()) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        // whatever is left is no longer part of the configuration
+        for x in INHERITED_SOCKETS.iter() {
+            tracing::info!("Closing the inherited socket for {} as it is not used by the current configuration", x.key().1);
+        }
+        INHERITED_SOCKETS.clear();
+        // processes of sites that are still configured are left for their sites to pick up, however long that takes
+        let configured : std::collections::HashSet<String> = state.config.read().await.hosted_process.iter().flatten().map(|x| x.host_name.clone()).collect();
+        let leftovers : Vec<String> = INHERITED_PROCESSES.iter().map(|x| x.key().clone()).filter(|x| !configured.contains(x)).collect();
+        for host_name in leftovers {
+            let Some((_, process)) = INHERITED_PROCESSES.remove(&host_name) else { continue };
+            tracing::warn!("[{host_name}] Stopping the inherited process (pid {}) as the site is no longer configured", process.pid);
+            _ = tokio::task::spawn_blocking(move || crate::proc_host::stop_adopted_process(process.pid, process.started_at)).await;
+        }
+        for x in INHERITED_PROCESSES.iter() {
+            tracing::info!("[{}] The inherited process (pid {}) has not been picked up yet, leaving it running for the site", x.key(), x.value().pid);
+        }
+        if let Some(mut ready) = READY.lock().expect("ready lock should never be poisoned").take() {
+            if let Err(e) = std::io::Write::write_all(&mut ready, &[1]) {
+                tracing::warn!("Failed to tell the previous instance that we are up: {e:?}");
+            }
+        }
+    }
+
+    /// The path of the binary we were started from. Linux adds a suffix to it once the file has been replaced.
+    fn current_binary() -> anyhow::Result<PathBuf> {
+        let exe = std::env::current_exe().context("could not find the path of the odd-box binary")?;
+        Ok(match exe.to_str().and_then(|x| x.strip_suffix(" (deleted)")) {
+            Some(x) => PathBuf::from(x),
+            None => exe
+        })
+    }
+
+    /// Our own arguments, minus the tui as the terminal is still in use by us while we drain.
+    fn args_for_new_instance() -> Vec<String> {
+        let mut args = vec![];
+        let mut skip_next = false;
+        for arg in std::env::args().skip(1) {
+            if skip_next {
+                skip_next = false;
+            } else if arg == "--tui" {
+                skip_next = true;
+            } else if !arg.starts_with("--tui=") {
+                args.push(arg);
+            }
+        }
+        args.push("--tui=false".into());
+        args
+    }
+
+    async fn start_new_instance() -> anyhow::Result<u32> {
+        let exe = current_binary()?;
+        let (mut ready_reader, ready_writer) = UnixStream::pair().context("could not create the readiness socket")?;
+
+        // our own copies, so that nothing we hand over can be closed (and its number reused) before the new instance is started
+        let mut keep_open = vec![];
+        let mut sockets = vec![];
+        for x in SOCKETS.iter() {
+            let fd = x.value().try_clone()?;
+            sockets.push(HandedOverSocket { kind: x.key().0, addr: x.key().1, fd: fd.as_raw_fd() });
+            keep_open.push(fd);
+        }
+        let mut processes = vec![];
+        for x in PROCESSES.iter() {
+            let (stdout, stderr) = (x.value().stdout.try_clone()?, x.value().stderr.try_clone()?);
+            processes.push(HandedOverProcess {
+                host_name: x.key().clone(),
+                pid: x.value().pid,
+                started_at: x.value().started_at,
+                port: x.value().port,
+                stdout_fd: stdout.as_raw_fd(),
+                stderr_fd: stderr.as_raw_fd()
+            });
+            keep_open.push(stdout);
+            keep_open.push(stderr);
+        }
+
+        let handoff = Handoff { sockets, processes, ready_fd: ready_writer.as_raw_fd() };
+        let mut inherited : Vec<i32> = keep_open.iter().map(|x| x.as_raw_fd()).collect();
+        inherited.push(ready_writer.as_raw_fd());
+
+        let mut command = std::process::Command::new(&exe);
+        command
+            .args(args_for_new_instance())
+            .env(HANDOFF_ENV_VAR, serde_json::to_string(&handoff)?)
+            .stdin(std::process::Stdio::null());
+        // everything we open is close-on-exec, so the flag is cleared in the new process only for what it should inherit.
+        // SAFETY: fcntl is async-signal-safe and nothing is allocated between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &inherited {
+                    nix::fcntl::fcntl(*fd, nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()))?;
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().context(format!("could not start {}", exe.display()))?;
+        drop(ready_writer);
+        drop(keep_open);
+        tracing::info!("Started {} (pid {}), waiting for it to take over..", exe.display(), child.id());
+
+        let ready = tokio::task::spawn_blocking(move || -> std::io::Result<bool> {
+            ready_reader.set_read_timeout(Some(READY_TIMEOUT))?;
+            let mut buf = [0u8; 1];
+            Ok(ready_reader.read(&mut buf)? == 1)
+        }).await;
+
+        match ready {
+            Ok(Ok(true)) => Ok(child.id()),
+            other => {
+                _ = child.kill();
+                _ = child.wait();
+                anyhow::bail!("the new instance did not come up in time: {other:?}")
+            }
+        }
+    }
+
+    /// Starts a new instance from the current binary and hands everything over to it.
+    /// Returns the pid of the new instance, after which this one drains its connections and exits.
+    pub async fn upgrade(state: &GlobalState) -> anyhow::Result<u32> {
+        if matches!(state.log_handle, crate::OddLogHandle::TUI(_)) {
+            anyhow::bail!("hot upgrades are not supported while the tui is in use, run odd-box with --tui=false to use them")
+        }
+        if state.app_state.shutdown.is_draining() {
+            anyhow::bail!("odd-box is already shutting down")
+        }
+        if UPGRADING.swap(true, Ordering::SeqCst) {
+            anyhow::bail!("a hot upgrade is already in progress")
+        }
+        let pid = match start_new_instance().await {
+            Ok(pid) => pid,
+            Err(e) => {
+                UPGRADING.store(false, Ordering::SeqCst);
+                return Err(e)
+            }
+        };
+        tracing::warn!("odd-box (pid {pid}) has taken over, draining the connections of this instance before exiting.");
+        state.app_state.shutdown.hand_over_processes();
+        crate::shutdown::request(state);
+        Ok(pid)
+    }
+
+    pub async fn bg_worker_for_hot_upgrade(state: Arc<GlobalState>) {
+        // the handler must be in place before the previous instance goes away, as SIGUSR2 would otherwise stop us.
+        let signals = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2());
+        report_ready(&state).await;
+        let mut signals = match signals {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("Failed to listen for SIGUSR2, hot upgrades can only be started thru the admin api: {e:?}");
+                return
+            }
+        };
+        while signals.recv().await.is_some() {
+            tracing::warn!("SIGUSR2 received, starting a hot upgrade..");
+            if let Err(e) = upgrade(&state).await {
+                tracing::error!("Hot upgrade failed: {e:?}");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn register_socket<T>(_kind: SocketKind, _addr: SocketAddr, _socket: &T) -> Option<Registration> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn register_process<A, B>(_host_name: &str, _pid: u32, _port: u16, _stdout: &A, _stderr: &B) -> Option<Registration> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn inherited_tcp_listener(_addr: SocketAddr) -> Option<std::net::TcpListener> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn inherited_udp_socket(_addr: SocketAddr) -> Option<std::net::UdpSocket> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn take_process(_host_name: &str) -> Option<AdoptedProcess> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn is_running(_pid: u32, _started_at: u64) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+pub fn take_handoff_from_env() {}
+
+#[cfg(not(unix))]
+pub fn load_inherited() {}
+
+#[cfg(not(unix))]
+pub async fn upgrade(_state: &GlobalState) -> anyhow::Result<u32> {
+    anyhow::bail!("hot upgrades are only supported on linux and other unix systems")
+}
+
+#[cfg(not(unix))]
+pub async fn bg_worker_for_hot_upgrade(_state: Arc<GlobalState>) {}
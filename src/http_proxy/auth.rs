@@ -0,0 +1,185 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use dashmap::DashMap;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Request, StatusCode};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use crate::configuration::{BasicAuth, ConfigWrapper, ForwardAuth};
+use super::{create_epic_string_full_body, EpicResponse};
+
+// bcrypt and argon2 are slow on purpose, so we remember which credentials have already been verified
+// instead of paying for the hash on every request. Only digests are kept, never the passwords themselves.
+static VERIFIED_CREDENTIALS: Lazy<DashMap<Vec<u8>, ()>> = Lazy::new(DashMap::new);
+const MAX_VERIFIED_CREDENTIALS: usize = 10_000;
+
+static FORWARD_AUTH_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    // redirects from the auth service are meant for the client (login pages) so we must not follow them ourselves.
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("should always be able to create the forward auth client")
+});
+
+// these describe the connection between the client and us, or between us and the auth service, not the request itself.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "te",
+    "trailer", "transfer-encoding", "upgrade", "content-length"
+];
+
+/// Finds the basic_auth and forward_auth settings of whichever site handles the given host name.
+pub fn auth_for_host<'a>(configuration: &'a ConfigWrapper, host_name: &str) -> Option<(String, Option<&'a BasicAuth>, Option<&'a ForwardAuth>)> {
+    let site = configuration.find_site(&host_name.to_ascii_lowercase())?;
+    let (basic_auth, forward_auth) = (site.basic_auth(), site.forward_auth());
+    if basic_auth.is_none() && forward_auth.is_none() {
+        return None
+    }
+    Some((site.host_name().to_string(), basic_auth, forward_auth))
+}
+
+/// Runs the basic_auth and forward_auth checks of the site that handles the given host name, in that order.
+/// Sites without any authentication configured are always let thru.
+pub async fn authorize<B>(configuration: &ConfigWrapper, host_name: &str, req: &mut Request<B>, source_addr: Option<std::net::SocketAddr>, is_https: bool) -> Result<(), EpicResponse> {
+    let Some((site, basic_auth, forward_auth)) = auth_for_host(configuration, host_name) else { return Ok(()) };
+    if let Some(basic_auth) = basic_auth {
+        check_basic_auth(&site, basic_auth, req).await?;
+    }
+    if let Some(forward_auth) = forward_auth {
+        check_forward_auth(forward_auth, req, source_addr, is_https).await?;
+    }
+    Ok(())
+}
+
+/// Checks a password against a single htpasswd style hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        use argon2::PasswordVerifier;
+        argon2::PasswordHash::new(hash)
+            .is_ok_and(|parsed| argon2::Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    } else {
+        false
+    }
+}
+
+fn credentials_from_request<B>(req: &Request<B>) -> Option<(String, String)> {
+    let value = req.headers().get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+async fn is_valid_login(auth: &BasicAuth, user: &str, password: &str) -> bool {
+    let Some(hash) = auth.users.iter()
+        .filter_map(|entry| entry.split_once(':'))
+        .find(|(name, _)| *name == user)
+        .map(|(_, hash)| hash.to_string()) else { return false };
+
+    let digest = Sha256::new()
+        .chain_update(hash.as_bytes())
+        .chain_update([0u8])
+        .chain_update(password.as_bytes())
+        .finalize()
+        .to_vec();
+    if VERIFIED_CREDENTIALS.contains_key(&digest) {
+        return true
+    }
+
+    let password = password.to_string();
+    let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await.unwrap_or(false);
+    if valid {
+        if VERIFIED_CREDENTIALS.len() >= MAX_VERIFIED_CREDENTIALS {
+            VERIFIED_CREDENTIALS.clear();
+        }
+        VERIFIED_CREDENTIALS.insert(digest, ());
+    }
+    valid
+}
+
+/// Lets the request thru if it carries valid credentials, otherwise returns a 401 response asking for them.
+/// The Authorization header is not passed on to the site, instead the user name is sent as X-Forwarded-User.
+pub async fn check_basic_auth<B>(site: &str, auth: &BasicAuth, req: &mut Request<B>) -> Result<(), EpicResponse> {
+    if let Some((user, password)) = credentials_from_request(req) {
+        if is_valid_login(auth, &user, &password).await {
+            req.headers_mut().remove(hyper::header::AUTHORIZATION);
+            if let Ok(v) = HeaderValue::from_str(&user) {
+                req.headers_mut().insert("x-forwarded-user", v);
+            }
+            return Ok(())
+        }
+        tracing::debug!("Invalid login attempt for user {user:?} on {site}");
+    }
+    let realm = auth.realm.as_deref().unwrap_or(site).replace('"', "'");
+    let mut response = EpicResponse::new(create_epic_string_full_body("401 - Unauthorized"));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    if let Ok(v) = HeaderValue::from_str(&format!("Basic realm=\"{realm}\", charset=\"UTF-8\"")) {
+        response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, v);
+    }
+    Err(response)
+}
+
+/// Asks the auth service about the request. On success the configured response headers are copied to the request,
+/// otherwise the response of the auth service is returned so that it can be sent to the client.
+pub async fn check_forward_auth<B>(auth: &ForwardAuth, req: &mut Request<B>, source_addr: Option<std::net::SocketAddr>, is_https: bool) -> Result<(), EpicResponse> {
+    let mut headers = req.headers().clone();
+    for name in HOP_BY_HOP_HEADERS.iter().chain(&["host"]) {
+        headers.remove(*name);
+    }
+    let host = req.headers().get(hyper::header::HOST).cloned()
+        .or_else(|| req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()));
+    let forwarded = [
+        ("x-forwarded-method", HeaderValue::from_str(req.method().as_str()).ok()),
+        ("x-forwarded-proto", Some(HeaderValue::from_static(if is_https { "https" } else { "http" }))),
+        ("x-forwarded-host", host),
+        ("x-forwarded-uri", req.uri().path_and_query().and_then(|p| HeaderValue::from_str(p.as_str()).ok())),
+        ("x-forwarded-for", source_addr.and_then(|a| HeaderValue::from_str(&a.ip().to_string()).ok())),
+    ];
+    for (name, value) in forwarded {
+        if let Some(v) = value {
+            headers.insert(name, v);
+        }
+    }
+
+    let result = FORWARD_AUTH_CLIENT.get(&auth.address)
+        .headers(headers)
+        .timeout(auth.timeout())
+        .send()
+        .await;
+
+    let auth_response = match result {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::warn!("Forward auth request to {} failed: {e:?}", auth.address);
+            let mut response = EpicResponse::new(create_epic_string_full_body("502 - Authentication service unavailable"));
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            return Err(response)
+        }
+    };
+
+    if auth_response.status().is_success() {
+        for name in auth.copy_response_headers.iter().flatten() {
+            let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else { continue };
+            req.headers_mut().remove(&name);
+            for value in auth_response.headers().get_all(&name) {
+                req.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        return Ok(())
+    }
+
+    let status = auth_response.status();
+    let mut response_headers = auth_response.headers().clone();
+    for name in HOP_BY_HOP_HEADERS {
+        response_headers.remove(name);
+    }
+    let body = auth_response.bytes().await.unwrap_or_default();
+    let mut response = EpicResponse::new(create_epic_string_full_body(&String::from_utf8_lossy(&body)));
+    *response.status_mut() = status;
+    *response.headers_mut() = response_headers;
+    Err(response)
+}
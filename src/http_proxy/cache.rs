@@ -0,0 +1,844 @@
+// Shared cache for the responses of remote sites that have enable_response_cache set, following the rules of RFC 9111 for shared caches.
+// Entries are kept in memory, where the least recently used ones are evicted first, and optionally also on disk so that they survive restarts.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use http_body::Frame;
+use http_body_util::{BodyExt, Either, Empty, Full};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request, StatusCode, Uri, Version};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::configuration::ResponseCache;
+use crate::CustomError;
+use super::service::RemoteForwarder;
+use super::{EpicBody, EpicResponse, ProxyRequestBody};
+
+const DEFAULT_MAX_MEMORY_SIZE_IN_MB: u64 = 64;
+const DEFAULT_MAX_RESPONSE_SIZE_IN_MB: u64 = 8;
+const DEFAULT_MAX_DISK_SIZE_IN_MB: u64 = 1024;
+const MAX_VARIANTS_PER_KEY: usize = 8;
+const MAX_HEURISTIC_FRESHNESS_IN_SECONDS: u64 = 24 * 60 * 60;
+/// Status codes that we know how to cache, and which may be cached without explicit freshness information.
+const CACHEABLE_STATUS_CODES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+/// Headers that only make sense for the connection they were received on, or that the cache sets itself.
+const UNSTORED_HEADERS: [&str; 9] = ["connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade", "age", "x-cache"];
+/// The headers that a 304 response to a client should still have.
+const NOT_MODIFIED_HEADERS: [HeaderName; 7] = [header::CACHE_CONTROL, header::CONTENT_LOCATION, header::DATE, header::ETAG, header::EXPIRES, header::LAST_MODIFIED, header::VARY];
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The Cache-Control directives of a request or response that the cache cares about.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub only_if_cached: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub max_stale: Option<u64>,
+    pub min_fresh: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        for value in headers.get_all(header::CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()) {
+            for directive in split_directives(value) {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => (name.trim().to_ascii_lowercase(), Some(argument.trim().trim_matches('"'))),
+                    None => (directive.to_ascii_lowercase(), None),
+                };
+                let seconds = argument.and_then(|a| a.parse::<u64>().ok());
+                match name.as_str() {
+                    "no-store" => cc.no_store = true,
+                    // the field-name forms of no-cache and private are treated as applying to the whole response
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "must-revalidate" => cc.must_revalidate = true,
+                    "proxy-revalidate" => cc.proxy_revalidate = true,
+                    "only-if-cached" => cc.only_if_cached = true,
+                    // invalid ages are to be treated as already stale
+                    "max-age" => cc.max_age = Some(seconds.unwrap_or(0)),
+                    "s-maxage" => cc.s_maxage = Some(seconds.unwrap_or(0)),
+                    "max-stale" => cc.max_stale = Some(seconds.unwrap_or(u64::MAX)),
+                    "min-fresh" => cc.min_fresh = seconds,
+                    "stale-while-revalidate" => cc.stale_while_revalidate = seconds,
+                    _ => {}
+                }
+            }
+        }
+        // Pragma is only looked at for clients that do not know about Cache-Control
+        let pragma_no_cache = headers.get_all(header::PRAGMA).iter()
+            .any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("no-cache")));
+        if pragma_no_cache && !headers.contains_key(header::CACHE_CONTROL) {
+            cc.no_cache = true;
+        }
+        cc
+    }
+
+    /// Stale responses must not be served without checking with the backend first. s-maxage implies proxy-revalidate.
+    fn forbids_stale(&self) -> bool {
+        self.must_revalidate || self.proxy_revalidate || self.s_maxage.is_some()
+    }
+}
+
+/// Splits a Cache-Control value in to its directives, ignoring commas in quoted arguments.
+fn split_directives(value: &str) -> Vec<&str> {
+    let mut directives = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                directives.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    directives.push(&value[start..]);
+    directives.into_iter().map(str::trim).filter(|d| !d.is_empty()).collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// For how many seconds after it was generated a response may be served from the cache without revalidation.
+/// Uses s-maxage, max-age or Expires when present, or else 10% of the time since the resource was last modified.
+pub fn freshness_lifetime(status: StatusCode, headers: &HeaderMap) -> u64 {
+    let cc = CacheControl::parse(headers);
+    if let Some(seconds) = cc.s_maxage.or(cc.max_age) {
+        return seconds
+    }
+    let date = header_date(headers, header::DATE);
+    if headers.contains_key(header::EXPIRES) {
+        // an Expires value that cannot be parsed means that the response has already expired
+        return header_date(headers, header::EXPIRES)
+            .map(|expires| expires.saturating_sub(date.unwrap_or_else(unix_now)))
+            .unwrap_or(0)
+    }
+    match header_date(headers, header::LAST_MODIFIED) {
+        Some(last_modified) if CACHEABLE_STATUS_CODES.contains(&status.as_u16()) => {
+            (date.unwrap_or_else(unix_now).saturating_sub(last_modified) / 10).min(MAX_HEURISTIC_FRESHNESS_IN_SECONDS)
+        },
+        _ => 0
+    }
+}
+
+/// Tells if the response to a GET request with the given headers may be stored.
+pub fn is_storable(request_headers: &HeaderMap, status: StatusCode, headers: &HeaderMap) -> bool {
+    let cc = CacheControl::parse(headers);
+    if !CACHEABLE_STATUS_CODES.contains(&status.as_u16()) || cc.no_store || cc.private || CacheControl::parse(request_headers).no_store {
+        return false
+    }
+    // responses that set cookies are almost always meant for a single client
+    if headers.contains_key(header::SET_COOKIE) {
+        return false
+    }
+    if request_headers.contains_key(header::AUTHORIZATION) && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some()) {
+        return false
+    }
+    let varies_by_everything = headers.get_all(header::VARY).iter()
+        .any(|v| v.to_str().is_ok_and(|v| v.split(',').any(|name| name.trim() == "*")));
+    if varies_by_everything {
+        return false
+    }
+    // a response that is never fresh is only worth keeping if it can be revalidated
+    let has_validators = headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+    has_validators || (!cc.no_cache && freshness_lifetime(status, headers) > 0)
+}
+
+/// Responses are cached per requested host and path, with different variants of the same path kept apart by their Vary headers.
+pub(crate) fn cache_key(host: &str, uri: &Uri) -> String {
+    let host = host.split(':').next().unwrap_or_default().to_ascii_lowercase();
+    format!("{host}{}", uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+}
+
+/// The values of the request headers named by the Vary header of a response.
+fn vary_values(request_headers: &HeaderMap, response_headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    let mut values = vec![];
+    for names in response_headers.get_all(header::VARY).iter().filter_map(|v| v.to_str().ok()) {
+        for name in names.split(',').map(|n| n.trim().to_ascii_lowercase()).filter(|n| !n.is_empty()) {
+            let value = request_headers.get_all(name.as_str()).iter()
+                .filter_map(|v| v.to_str().ok()).map(str::trim).collect::<Vec<_>>();
+            let value = if value.is_empty() { None } else { Some(value.join(", ")) };
+            values.push((name, value));
+        }
+    }
+    values
+}
+
+/// The age of a response when it was received, as the larger of its Age header and how long ago the backend says it was generated.
+fn initial_age(headers: &HeaderMap, received_at: u64) -> u64 {
+    let age = headers.get(header::AGE).and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(0);
+    let apparent_age = header_date(headers, header::DATE).map(|date| received_at.saturating_sub(date)).unwrap_or(0);
+    age.max(apparent_age)
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .filter(|(name, _)| !UNSTORED_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| value.to_str().ok().map(|v| (name.to_string(), v.to_string())))
+        .collect()
+}
+
+/// One cached response for a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Variant {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    /// The request headers named by the Vary header of the response, with the values they had when it was stored.
+    vary: Vec<(String, Option<String>)>,
+    /// When the response was received, in seconds since the unix epoch.
+    stored_at: u64,
+    initial_age: u64,
+    body_len: u64,
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+impl Variant {
+    pub fn new(request_headers: &HeaderMap, status: StatusCode, headers: &HeaderMap, body: Bytes) -> Self {
+        let now = unix_now();
+        Self {
+            status: status.as_u16(),
+            headers: header_pairs(headers),
+            vary: vary_values(request_headers, headers),
+            stored_at: now,
+            initial_age: initial_age(headers, now),
+            body_len: body.len() as u64,
+            body,
+        }
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        vary_values(request_headers, &self.header_map()) == self.vary
+    }
+
+    pub fn age(&self, now: u64) -> u64 {
+        self.initial_age + now.saturating_sub(self.stored_at)
+    }
+
+    fn size(&self) -> u64 {
+        self.body_len + self.headers.iter().map(|(n, v)| (n.len() + v.len()) as u64).sum::<u64>()
+    }
+
+    fn has_validators(&self) -> bool {
+        self.headers.iter().any(|(name, _)| name == "etag" || name == "last-modified")
+    }
+
+    /// Updates the stored headers with those of a 304 response from the backend, which also makes the response fresh again.
+    pub fn refreshed(&self, not_modified: &HeaderMap) -> Variant {
+        let mut headers = self.header_map();
+        for name in not_modified.keys() {
+            if UNSTORED_HEADERS.contains(&name.as_str()) || *name == header::CONTENT_LENGTH {
+                continue
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        let now = unix_now();
+        Variant {
+            headers: header_pairs(&headers),
+            stored_at: now,
+            initial_age: initial_age(not_modified, now),
+            ..self.clone()
+        }
+    }
+}
+
+/// Tells if a client that sent the given conditional headers already has the cached response.
+fn is_not_modified(request_headers: &HeaderMap, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) else { return false };
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag))
+    }
+    match (header_date(request_headers, header::IF_MODIFIED_SINCE), header_date(headers, header::LAST_MODIFIED)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false
+    }
+}
+
+/// Keeps track of which keys were used least recently, and how much space they take.
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, (u64, u64)>,
+    order: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        if let Some((_, tick)) = self.entries.get_mut(key) {
+            self.order.remove(tick);
+            self.tick += 1;
+            *tick = self.tick;
+            self.order.insert(self.tick, key.to_string());
+        }
+    }
+    fn insert(&mut self, key: String, size: u64) {
+        self.remove(&key);
+        self.tick += 1;
+        self.size += size;
+        self.entries.insert(key.clone(), (size, self.tick));
+        self.order.insert(self.tick, key);
+    }
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some((size, tick)) => {
+                self.order.remove(&tick);
+                self.size -= size;
+                true
+            },
+            None => false
+        }
+    }
+    /// Removes the least recently used keys until the total size is within the limit, and returns them.
+    fn evict_to(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.size > max_size {
+            let Some((_, key)) = self.order.pop_first() else { break };
+            if let Some((size, _)) = self.entries.remove(&key) {
+                self.size -= size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+#[derive(Default)]
+struct MemoryTier {
+    lru: Lru,
+    variants: HashMap<String, Vec<Variant>>,
+}
+
+struct DiskTier {
+    dir: PathBuf,
+    /// Set when the directory could not be used, so that we do not retry it on each request.
+    unavailable: bool,
+    lru: Lru,
+}
+
+/// What the files of the disk tier start with, followed by the bodies of the variants.
+#[derive(Serialize, Deserialize)]
+struct DiskRecord {
+    key: String,
+    variants: Vec<Variant>,
+}
+
+fn file_name(key: &str) -> String {
+    format!("{:x}.cache", Sha256::digest(key.as_bytes()))
+}
+
+fn encode(key: &str, variants: &[Variant]) -> Result<Vec<u8>, serde_json::Error> {
+    let mut data = serde_json::to_vec(&DiskRecord { key: key.to_string(), variants: variants.to_vec() })?;
+    data.push(b'\n');
+    for variant in variants {
+        data.extend_from_slice(&variant.body);
+    }
+    Ok(data)
+}
+
+fn decode(data: Bytes) -> Option<DiskRecord> {
+    let end_of_record = data.iter().position(|b| *b == b'\n')?;
+    let mut record = serde_json::from_slice::<DiskRecord>(&data[..end_of_record]).ok()?;
+    let mut offset = end_of_record + 1;
+    for variant in &mut record.variants {
+        let end = offset.checked_add(variant.body_len as usize).filter(|end| *end <= data.len())?;
+        variant.body = data.slice(offset..end);
+        offset = end;
+    }
+    Some(record)
+}
+
+/// Finds the cached responses in a directory, oldest first. Only the first line of each file needs to be read for this.
+fn scan(dir: &Path) -> std::io::Result<Lru> {
+    std::fs::create_dir_all(dir)?;
+    let mut found = vec![];
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        match path.extension().and_then(|e| e.to_str()) {
+            // left behind by writes that never completed
+            Some("tmp") => { _ = std::fs::remove_file(&path) },
+            Some("cache") => {
+                let Ok(metadata) = entry.metadata() else { continue };
+                let mut line = String::new();
+                let read = std::fs::File::open(&path)
+                    .and_then(|f| std::io::BufRead::read_line(&mut std::io::BufReader::new(f), &mut line));
+                match read.ok().and_then(|_| serde_json::from_str::<DiskRecord>(line.trim_end()).ok()) {
+                    Some(record) => found.push((metadata.modified().unwrap_or(UNIX_EPOCH), record.key, metadata.len())),
+                    None => { _ = std::fs::remove_file(&path) }
+                }
+            },
+            _ => {}
+        }
+    }
+    found.sort_by_key(|(modified, _, _)| *modified);
+    let mut lru = Lru::default();
+    for (_, key, size) in found {
+        lru.insert(key, size);
+    }
+    Ok(lru)
+}
+
+fn megabytes(value: Option<u64>, default: u64) -> u64 {
+    value.unwrap_or(default).saturating_mul(1024 * 1024)
+}
+
+/// The response cache shared by all remote sites.
+#[derive(Default)]
+pub struct SharedCache {
+    memory: Mutex<MemoryTier>,
+    disk: tokio::sync::Mutex<Option<DiskTier>>,
+    revalidating: Mutex<HashSet<String>>,
+}
+
+impl std::fmt::Debug for SharedCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (entries, size) = self.memory.lock().map(|m| (m.lru.entries.len(), m.lru.size)).unwrap_or_default();
+        f.debug_struct("SharedCache").field("entries", &entries).field("size", &size).finish()
+    }
+}
+
+impl SharedCache {
+
+    fn memory(&self) -> std::sync::MutexGuard<'_, MemoryTier> {
+        self.memory.lock().expect("response cache should not be poisoned")
+    }
+
+    pub(crate) async fn lookup(&self, settings: &ResponseCache, key: &str) -> Option<Vec<Variant>> {
+        {
+            let mut memory = self.memory();
+            if let Some(variants) = memory.variants.get(key).cloned() {
+                memory.lru.touch(key);
+                return Some(variants)
+            }
+        }
+        let variants = self.disk_get(settings, key).await?;
+        self.memory_put(settings, key, variants.clone());
+        Some(variants)
+    }
+
+    async fn store(&self, settings: &ResponseCache, key: &str, variants: Vec<Variant>) {
+        self.disk_put(settings, key, &variants).await;
+        self.memory_put(settings, key, variants);
+    }
+
+    /// Replaces the variant that has the same Vary values as the given one, keeping the most recently stored variants first.
+    pub(crate) async fn store_variant(&self, settings: &ResponseCache, key: &str, mut others: Vec<Variant>, variant: Variant) {
+        others.retain(|v| v.vary != variant.vary);
+        others.insert(0, variant);
+        others.truncate(MAX_VARIANTS_PER_KEY);
+        self.store(settings, key, others).await;
+    }
+
+    async fn remove(&self, key: &str) -> bool {
+        let in_memory = {
+            let mut memory = self.memory();
+            memory.variants.remove(key);
+            memory.lru.remove(key)
+        };
+        let on_disk = {
+            let mut disk = self.disk.lock().await;
+            match disk.as_mut() {
+                Some(tier) if tier.lru.remove(key) => Some(tier.dir.join(file_name(key))),
+                _ => None
+            }
+        };
+        if let Some(path) = &on_disk {
+            _ = tokio::fs::remove_file(path).await;
+        }
+        in_memory || on_disk.is_some()
+    }
+
+    /// Removes the cached responses for a host name, optionally only those with paths that start with the given prefix.
+    /// Returns how many urls were removed.
+    pub async fn purge(&self, host_name: &str, path_prefix: Option<&str>) -> usize {
+        let path_prefix = path_prefix.unwrap_or("/");
+        let prefix = format!("{}{}{}", host_name.to_ascii_lowercase(), if path_prefix.starts_with('/') { "" } else { "/" }, path_prefix);
+        let mut keys = self.memory().lru.entries.keys().filter(|k| k.starts_with(&prefix)).cloned().collect::<HashSet<_>>();
+        if let Some(tier) = self.disk.lock().await.as_ref() {
+            keys.extend(tier.lru.entries.keys().filter(|k| k.starts_with(&prefix)).cloned());
+        }
+        let mut removed = 0;
+        for key in keys {
+            if self.remove(&key).await {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn memory_put(&self, settings: &ResponseCache, key: &str, variants: Vec<Variant>) {
+        let size = key.len() as u64 + variants.iter().map(Variant::size).sum::<u64>();
+        let max_size = megabytes(settings.max_memory_size_in_mb, DEFAULT_MAX_MEMORY_SIZE_IN_MB);
+        let mut memory = self.memory();
+        if size > max_size {
+            memory.variants.remove(key);
+            memory.lru.remove(key);
+            return
+        }
+        memory.lru.insert(key.to_string(), size);
+        memory.variants.insert(key.to_string(), variants);
+        for evicted in memory.lru.evict_to(max_size) {
+            memory.variants.remove(&evicted);
+        }
+    }
+
+    /// The directory of the disk tier, which is loaded the first time that a disk_dir is used or when it has changed.
+    async fn disk_dir(&self, settings: &ResponseCache) -> Option<PathBuf> {
+        let dir = PathBuf::from(settings.disk_dir.as_ref()?);
+        let mut disk = self.disk.lock().await;
+        if let Some(tier) = disk.as_ref().filter(|t| t.dir == dir) {
+            return if tier.unavailable { None } else { Some(dir) }
+        }
+        let scanned_dir = dir.clone();
+        let scanned = tokio::task::spawn_blocking(move || scan(&scanned_dir)).await
+            .map_err(std::io::Error::other).and_then(|r| r);
+        match scanned {
+            Ok(lru) => {
+                tracing::info!("Loaded {} cached urls from {dir:?}", lru.entries.len());
+                *disk = Some(DiskTier { dir: dir.clone(), unavailable: false, lru });
+                Some(dir)
+            },
+            Err(e) => {
+                tracing::warn!("Cannot use {dir:?} for the response cache, only the memory cache will be used: {e:?}");
+                *disk = Some(DiskTier { dir, unavailable: true, lru: Lru::default() });
+                None
+            }
+        }
+    }
+
+    async fn disk_get(&self, settings: &ResponseCache, key: &str) -> Option<Vec<Variant>> {
+        let dir = self.disk_dir(settings).await?;
+        {
+            let mut disk = self.disk.lock().await;
+            let tier = disk.as_mut()?;
+            if !tier.lru.entries.contains_key(key) {
+                return None
+            }
+            tier.lru.touch(key);
+        }
+        let data = tokio::fs::read(dir.join(file_name(key))).await.ok();
+        match data.and_then(|d| decode(Bytes::from(d))).filter(|r| r.key == key) {
+            Some(record) => Some(record.variants),
+            None => {
+                self.remove(key).await;
+                None
+            }
+        }
+    }
+
+    async fn disk_put(&self, settings: &ResponseCache, key: &str, variants: &[Variant]) {
+        let Some(dir) = self.disk_dir(settings).await else { return };
+        let max_size = megabytes(settings.max_disk_size_in_mb, DEFAULT_MAX_DISK_SIZE_IN_MB);
+        let data = match encode(key, variants) {
+            Ok(data) if (data.len() as u64) <= max_size => data,
+            _ => return
+        };
+        let size = data.len() as u64;
+        let path = dir.join(file_name(key));
+        // written to a temporary file first so that nobody ever reads a half written entry
+        let temp_path = dir.join(format!("{}.{}.tmp", file_name(key), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let written = match tokio::fs::write(&temp_path, data).await {
+            Ok(_) => tokio::fs::rename(&temp_path, &path).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = written {
+            tracing::debug!("Failed to write cached response for {key} to {path:?}: {e:?}");
+            _ = tokio::fs::remove_file(&temp_path).await;
+            return
+        }
+        let evicted = {
+            let mut disk = self.disk.lock().await;
+            let Some(tier) = disk.as_mut().filter(|t| t.dir == dir) else { return };
+            tier.lru.insert(key.to_string(), size);
+            tier.lru.evict_to(max_size)
+        };
+        for key in evicted {
+            _ = tokio::fs::remove_file(dir.join(file_name(&key))).await;
+        }
+    }
+}
+
+/// The parts of a client request that are needed for sending it (or a conditional version of it) to the backend again.
+#[derive(Clone)]
+pub(crate) struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+impl RequestHead {
+    fn to_request(&self, headers: HeaderMap) -> Request<ProxyRequestBody> {
+        let mut req = Request::new(Empty::<Bytes>::new().map_err(|never| match never {}).boxed_unsync());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = headers;
+        req
+    }
+
+    /// The request with the client's own conditions replaced by the validators of a cached response.
+    pub fn to_conditional_request(&self, variant: &Variant) -> Request<ProxyRequestBody> {
+        let mut headers = self.headers.clone();
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        let cached = variant.header_map();
+        if let Some(etag) = cached.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = cached.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        self.to_request(headers)
+    }
+}
+
+fn full_body(bytes: Bytes) -> EpicBody {
+    Either::Right(Either::Left(Full::new(bytes)))
+}
+
+fn set_cache_status(response: &mut EpicResponse, status: &'static str) {
+    response.headers_mut().insert(HeaderName::from_static("x-cache"), HeaderValue::from_static(status));
+}
+
+/// Builds the response to a client from a cached variant, or a 304 if the client says it already has it.
+pub(crate) fn respond(head: &RequestHead, variant: &Variant, cache_status: &'static str) -> EpicResponse {
+    let headers = variant.header_map();
+    let mut response = if variant.status == 200 && is_not_modified(&head.headers, &headers) {
+        let mut response = EpicResponse::new(full_body(Bytes::new()));
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        for name in NOT_MODIFIED_HEADERS {
+            for value in headers.get_all(&name) {
+                response.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        response
+    } else {
+        let body = if head.method == Method::HEAD { Bytes::new() } else { variant.body.clone() };
+        let mut response = EpicResponse::new(full_body(body));
+        *response.status_mut() = StatusCode::from_u16(variant.status).unwrap_or(StatusCode::OK);
+        *response.headers_mut() = headers;
+        response
+    };
+    response.headers_mut().insert(header::AGE, HeaderValue::from(variant.age(unix_now())));
+    set_cache_status(&mut response, cache_status);
+    response
+}
+
+fn gateway_timeout() -> EpicResponse {
+    let mut response = EpicResponse::new(full_body(Bytes::new()));
+    *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    response
+}
+
+enum CollectedBody {
+    Complete(Bytes),
+    /// The body was larger than the limit, what was already read is sent first.
+    TooLarge(EpicBody),
+}
+
+async fn collect_with_limit(mut body: EpicBody, limit: u64) -> Result<CollectedBody, CustomError> {
+    if hyper::body::Body::size_hint(&body).lower() > limit {
+        return Ok(CollectedBody::TooLarge(body))
+    }
+    let mut buffered = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| CustomError(format!("Failed to read response body: {e:?}")))?;
+        // trailers are not kept in the cache
+        let Ok(data) = frame.into_data() else { continue };
+        buffered.extend_from_slice(&data);
+        if buffered.len() as u64 > limit {
+            let (tx, rx) = super::create_response_channel(4);
+            let read_so_far = buffered.freeze();
+            tokio::spawn(async move {
+                if tx.send(Ok(Frame::data(read_so_far))).await.is_err() {
+                    return
+                }
+                while let Some(frame) = body.frame().await {
+                    let frame = frame.map_err(|e| CustomError(format!("Failed to read response body: {e:?}")));
+                    let failed = frame.is_err();
+                    if tx.send(frame).await.is_err() || failed {
+                        break
+                    }
+                }
+            });
+            let (_, body) = super::create_stream_response(rx).into_parts();
+            return Ok(CollectedBody::TooLarge(body))
+        }
+    }
+    Ok(CollectedBody::Complete(buffered.freeze()))
+}
+
+/// Stores a response from the backend if it is allowed to, and hands it on to the client either way.
+async fn store_response(
+    forwarder: &RemoteForwarder,
+    settings: &ResponseCache,
+    key: &str,
+    head: &RequestHead,
+    others: Vec<Variant>,
+    response: EpicResponse
+) -> Result<EpicResponse, CustomError> {
+    if head.method != Method::GET || !is_storable(&head.headers, response.status(), response.headers()) {
+        let mut response = response;
+        set_cache_status(&mut response, "MISS");
+        return Ok(response)
+    }
+    let (parts, body) = response.into_parts();
+    match collect_with_limit(body, megabytes(settings.max_response_size_in_mb, DEFAULT_MAX_RESPONSE_SIZE_IN_MB)).await? {
+        CollectedBody::Complete(body) => {
+            let variant = Variant::new(&head.headers, parts.status, &parts.headers, body);
+            let mut response = respond(head, &variant, "MISS");
+            *response.extensions_mut() = parts.extensions;
+            forwarder.state.app_state.response_cache.store_variant(settings, key, others, variant).await;
+            Ok(response)
+        },
+        CollectedBody::TooLarge(body) => {
+            let mut response = EpicResponse::from_parts(parts, body);
+            set_cache_status(&mut response, "MISS");
+            Ok(response)
+        }
+    }
+}
+
+/// Checks a stale response with the backend while the client is served the stale one. Only one check per url runs at a time.
+fn revalidate_in_background(forwarder: RemoteForwarder, settings: ResponseCache, key: String, head: RequestHead, variant: Variant, others: Vec<Variant>) {
+    if !forwarder.state.app_state.response_cache.revalidating.lock().expect("response cache should not be poisoned").insert(key.clone()) {
+        return
+    }
+    tokio::spawn(async move {
+        let cache = &forwarder.state.app_state.response_cache;
+        match forwarder.forward(head.to_conditional_request(&variant)).await {
+            Ok(response) if response.status() == StatusCode::NOT_MODIFIED => {
+                cache.store_variant(&settings, &key, others, variant.refreshed(response.headers())).await;
+            },
+            Ok(response) => {
+                if let Err(e) = store_response(&forwarder, &settings, &key, &head, others, response).await {
+                    tracing::debug!("Failed to revalidate cached response for {key}: {e:?}");
+                }
+            },
+            Err(e) => tracing::debug!("Failed to revalidate cached response for {key}: {e:?}")
+        }
+        cache.revalidating.lock().expect("response cache should not be poisoned").remove(&key);
+    });
+}
+
+/// Handles a request for a remote site that has the response cache enabled.
+pub(crate) async fn serve(forwarder: RemoteForwarder, req: Request<hyper::body::Incoming>) -> Result<EpicResponse, CustomError> {
+
+    let settings = forwarder.state.config.read().await.response_cache.clone().unwrap_or_default();
+    let cache = &forwarder.state.app_state.response_cache;
+    let key = cache_key(&forwarder.req_host_name, req.uri());
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        let invalidates = !matches!(*req.method(), Method::OPTIONS | Method::TRACE);
+        let response = forwarder.forward(req.map(|b| b.boxed_unsync())).await?;
+        // whatever we have cached for the url is likely outdated after it was successfully changed
+        if invalidates && (response.status().is_success() || response.status().is_redirection()) {
+            cache.remove(&key).await;
+        }
+        return Ok(response)
+    }
+
+    let request_cc = CacheControl::parse(req.headers());
+    if request_cc.no_store || req.headers().contains_key(header::RANGE) {
+        return forwarder.forward(req.map(|b| b.boxed_unsync())).await
+    }
+
+    let head = RequestHead {
+        method: req.method().clone(),
+        uri: req.uri().clone(),
+        version: req.version(),
+        headers: req.headers().clone(),
+    };
+
+    let mut variants = cache.lookup(&settings, &key).await.unwrap_or_default();
+    let Some(position) = variants.iter().position(|v| v.matches(&head.headers)) else {
+        if request_cc.only_if_cached {
+            return Ok(gateway_timeout())
+        }
+        let response = forwarder.forward(head.to_request(head.headers.clone())).await?;
+        return store_response(&forwarder, &settings, &key, &head, variants, response).await
+    };
+    let cached = variants.remove(position);
+
+    let headers = cached.header_map();
+    let response_cc = CacheControl::parse(&headers);
+    let age = cached.age(unix_now());
+    let lifetime = if response_cc.no_cache { 0 } else { freshness_lifetime(StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK), &headers) };
+    let staleness = age.saturating_sub(lifetime);
+    let acceptable = !request_cc.no_cache
+        && !request_cc.max_age.is_some_and(|max_age| age > max_age)
+        && !request_cc.min_fresh.is_some_and(|min_fresh| lifetime.saturating_sub(age) < min_fresh);
+
+    if acceptable && age < lifetime {
+        return Ok(respond(&head, &cached, "HIT"))
+    }
+
+    if acceptable && !response_cc.forbids_stale() {
+        if request_cc.max_stale.is_some_and(|max_stale| staleness <= max_stale) {
+            return Ok(respond(&head, &cached, "STALE"))
+        }
+        if response_cc.stale_while_revalidate.is_some_and(|window| staleness <= window) {
+            let response = respond(&head, &cached, "STALE");
+            revalidate_in_background(forwarder, settings, key, head, cached, variants);
+            return Ok(response)
+        }
+    }
+
+    if request_cc.only_if_cached {
+        return Ok(gateway_timeout())
+    }
+
+    if !cached.has_validators() {
+        let response = forwarder.forward(head.to_request(head.headers.clone())).await?;
+        return store_response(&forwarder, &settings, &key, &head, variants, response).await
+    }
+
+    let response = forwarder.forward(head.to_conditional_request(&cached)).await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        let refreshed = cached.refreshed(response.headers());
+        let mut client_response = respond(&head, &refreshed, "REVALIDATED");
+        *client_response.extensions_mut() = response.into_parts().0.extensions;
+        cache.store_variant(&settings, &key, variants, refreshed).await;
+        return Ok(client_response)
+    }
+    store_response(&forwarder, &settings, &key, &head, variants, response).await
+}
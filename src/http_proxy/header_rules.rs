@@ -0,0 +1,93 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use crate::configuration::{Header, HeaderRules};
+
+/// Values available to header rule templates. These are captured from the incoming request
+/// before we start modifying it, so {host} is always the host name that the client asked for.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderTemplateValues {
+    pub client_ip: String,
+    pub host: String,
+    pub request_id: String,
+}
+
+impl HeaderTemplateValues {
+    pub fn new<B>(incoming: &hyper::Request<B>, source_addr: Option<std::net::SocketAddr>) -> Self {
+        let host = incoming.headers().get(hyper::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(':').next().unwrap_or_default().to_string())
+            .or_else(|| incoming.uri().host().map(|h| h.to_string()))
+            .unwrap_or_default();
+        Self {
+            client_ip: source_addr.map(|a| a.ip().to_string()).unwrap_or_default(),
+            host,
+            request_id: incoming.headers().get("x-request-id").and_then(|v| v.to_str().ok()).unwrap_or_default().to_string(),
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        template
+            .replace("{client_ip}", &self.client_ip)
+            .replace("{host}", &self.host)
+            .replace("{request_id}", &self.request_id)
+    }
+}
+
+/// The header rules of the site handling a request.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrites {
+    pub request: Option<HeaderRules>,
+    pub response: Option<HeaderRules>,
+    pub values: HeaderTemplateValues,
+}
+
+impl HeaderRewrites {
+    pub fn new(request: Option<&HeaderRules>, response: Option<&HeaderRules>, values: HeaderTemplateValues) -> Self {
+        Self { request: request.cloned(), response: response.cloned(), values }
+    }
+
+    pub fn apply_to_request(&self, headers: &mut HeaderMap) {
+        if let Some(rules) = &self.request {
+            apply(rules, &self.values, headers);
+        }
+    }
+
+    pub fn apply_to_response(&self, headers: &mut HeaderMap) {
+        if let Some(rules) = &self.response {
+            apply(rules, &self.values, headers);
+        }
+    }
+}
+
+fn to_header(header: &Header, values: &HeaderTemplateValues) -> Option<(HeaderName, HeaderValue)> {
+    let name = match HeaderName::from_bytes(header.name.as_bytes()) {
+        Ok(n) => n,
+        Err(e) => {
+            tracing::warn!("Ignoring header rule with invalid name {:?}: {e:?}", header.name);
+            return None
+        }
+    };
+    match HeaderValue::from_str(&values.render(&header.value)) {
+        Ok(v) => Some((name, v)),
+        Err(e) => {
+            tracing::warn!("Ignoring header rule for {name} as the value is not a valid header value: {e:?}");
+            None
+        }
+    }
+}
+
+pub fn apply(rules: &HeaderRules, values: &HeaderTemplateValues, headers: &mut HeaderMap) {
+    for name in rules.remove.iter().flatten() {
+        headers.remove(name.as_str());
+    }
+    for header in rules.set.iter().flatten() {
+        if let Some((name, value)) = to_header(header, values) {
+            headers.insert(name, value);
+        }
+    }
+    for header in rules.add.iter().flatten() {
+        if let Some((name, value)) = to_header(header, values) {
+            headers.append(name, value);
+        }
+    }
+}
@@ -1,11 +1,14 @@
 mod websockets;
 mod service;
 mod utils;
+pub(crate) mod routes;
+mod header_rules;
+pub(crate) mod auth;
+pub(crate) mod cache;
+pub use cache::{SharedCache, CacheControl, freshness_lifetime};
+pub use header_rules::{HeaderRewrites, HeaderTemplateValues};
 use std::sync::Arc;
 
-use hyper::body::Incoming;
-use hyper_rustls::HttpsConnector;
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
 pub use service::*;
 use tokio::sync::mpsc::Sender;
 pub use utils::*;
@@ -27,13 +30,17 @@ pub struct ReverseProxyService {
     pub remote_addr : Option<std::net::SocketAddr>,
     pub tx: std::sync::Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
     pub is_https:bool,
-    pub client: Client<HttpsConnector<HttpConnector>, Incoming>,
-    pub h2_client: Client<HttpsConnector<HttpConnector>, Incoming>,
+    pub client: ProxyClient,
+    pub h2_client: ProxyClient,
     pub resolved_target : Option<Arc<ReverseTcpProxyTarget>>,
     /// This is used for performance since we create the RPS on each request and we might need to read from
     /// the configuration multiple times during the request. We do not want to lock the config each time.
     pub configuration : Arc<crate::configuration::ConfigWrapper>,
     pub connection_key : ConnectionKey,
     pub sni : Option<String>,
-    pub host_header : Option<String>
+    pub host_header : Option<String>,
+    /// The listener that accepted the connection, used for enforcing its site restrictions on each request.
+    pub listener : Arc<crate::proxy::ListenerSettings>,
+    /// The rate limit token taken when the connection was accepted, it pays for the first request.
+    pub rate_limit_token : crate::rate_limiting::PrepaidToken
 }
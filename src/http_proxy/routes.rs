@@ -0,0 +1,90 @@
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use crate::configuration::{ConfigWrapper, Route};
+
+// patterns are validated when the configuration is loaded, so we only need to compile each one once.
+static COMPILED_PATH_REGEXES: Lazy<DashMap<String, Option<Regex>>> = Lazy::new(DashMap::new);
+
+fn compiled_regex(pattern: &str) -> Option<Regex> {
+    if let Some(r) = COMPILED_PATH_REGEXES.get(pattern) {
+        return r.value().clone()
+    }
+    let compiled = match Regex::new(pattern) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            tracing::warn!("Ignoring route with invalid path_regex {pattern:?}: {e}");
+            None
+        }
+    };
+    COMPILED_PATH_REGEXES.insert(pattern.to_string(), compiled.clone());
+    compiled
+}
+
+/// Finds the routes configured for whichever site handles the given host name.
+pub fn routes_for_host<'a>(configuration: &'a ConfigWrapper, host_name: &str) -> Option<&'a [Route]> {
+    configuration.find_site(host_name).and_then(|site| site.routes())
+}
+
+/// Returns the path that should be sent to the target of the route, or None if the route does not match.
+fn match_route(route: &Route, path: &str) -> Option<String> {
+    let strip = route.strip_prefix.unwrap_or_default();
+    if let Some(prefix) = &route.path_prefix {
+        let prefix = prefix.trim_end_matches('*');
+        // "/api/" should also match a request for "/api", and "/api" should not match "/apiary"
+        let rest = if let Some(rest) = path.strip_prefix(prefix).filter(|rest| prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')) {
+            rest
+        } else if prefix.ends_with('/') && path == prefix.trim_end_matches('/') {
+            ""
+        } else {
+            return None
+        };
+        Some(match (&route.rewrite, strip) {
+            (Some(rewrite), _) => format!("{rewrite}{rest}"),
+            (None, true) => rest.to_string(),
+            (None, false) => path.to_string()
+        })
+    } else if let Some(pattern) = &route.path_regex {
+        let re = compiled_regex(pattern)?;
+        if !re.is_match(path) {
+            return None
+        }
+        Some(match (&route.rewrite, strip) {
+            (Some(rewrite), _) => re.replace(path, rewrite.as_str()).into_owned(),
+            (None, true) => re.replace(path, "").into_owned(),
+            (None, false) => path.to_string()
+        })
+    } else {
+        None
+    }
+}
+
+/// Applies the first matching route to the request, rewriting its path if the route asks for it.
+/// Returns the host name of the site that should handle the request.
+pub fn apply_routes<B>(routes: &[Route], req: &mut hyper::Request<B>) -> Option<String> {
+    let path = req.uri().path().to_string();
+    for route in routes {
+        let Some(new_path) = match_route(route, &path) else { continue };
+        let new_path = if new_path.starts_with('/') { new_path } else { format!("/{new_path}") };
+        tracing::trace!("Request for {path} matched route to {}, forwarding as {new_path}", route.target);
+        if new_path != path {
+            let path_and_query = match req.uri().query() {
+                Some(q) => format!("{new_path}?{q}"),
+                None => new_path
+            };
+            let mut parts = req.uri().clone().into_parts();
+            match path_and_query.parse() {
+                Ok(pq) => {
+                    parts.path_and_query = Some(pq);
+                    match hyper::Uri::from_parts(parts) {
+                        Ok(uri) => *req.uri_mut() = uri,
+                        Err(e) => tracing::warn!("Failed to rewrite path for route to {}: {e:?}", route.target)
+                    }
+                },
+                Err(e) => tracing::warn!("Failed to rewrite path for route to {}: {e:?}", route.target)
+            }
+        }
+        return Some(route.target.clone())
+    }
+    None
+}
@@ -5,13 +5,10 @@ use std::time::Duration;
 use axum::http::{HeaderName, HeaderValue};
 use bytes::Bytes;
 use http_body::Frame;
-use http_body_util::{Either, Full, StreamBody};
+use http_body_util::{BodyExt, Either, Full, StreamBody};
 use hyper::service::Service;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
-use hyper_rustls::HttpsConnector;
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
 use tokio_stream::wrappers::ReceiverStream;
 use std::future::Future;
 use std::pin::Pin;
@@ -21,38 +18,125 @@ use crate::types::app_state::ProcState;
 use crate::types::proxy_state::ConnectionKey;
 use crate::CustomError;
 use hyper::{HeaderMap, Method, StatusCode};
-use lazy_static::lazy_static;
+use tracing::Instrument;
 use super::{ProcMessage, ReverseProxyService, WrappedNormalResponse};
 use super::proxy;
 
 
 
-lazy_static! {
-    static ref SERVER_ONE: hyper_util::server::conn::auto::Builder<TokioExecutor> =
-        hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
-}
 pub async fn serve(service:ReverseProxyService,io:GenericManagedStream) {
 
-    let result = match io {
-        // GenericManagedStream::TLS(peekable_tls_stream) =>
-        //     SERVER_ONE.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(peekable_tls_stream.managed_tls_stream), service).await,
-        GenericManagedStream::TerminatedTLS(stream) =>
-            SERVER_ONE.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(stream), service).await,
-        GenericManagedStream::TCP(peekable_tcp_stream) =>
-            SERVER_ONE.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(peekable_tcp_stream), service).await,
-
+    // when odd-box starts shutting down, h2 clients are sent a GOAWAY and h1 connections are closed once their
+    // current request is done. requests that are already in flight are allowed to finish.
+    let state = service.state.clone();
+
+    let host = service.resolved_target.as_ref().map(|t| t.host_name.clone())
+        .or_else(|| service.sni.clone())
+        .or_else(|| service.host_header.clone())
+        .unwrap_or_default();
+    let timeouts = crate::timeouts::for_host(&service.configuration, &host);
+    let source_addr = service.source_addr;
+
+    // clients that take too long to send their request headers get a 408 before they are disconnected. hyper enforces this
+    // for each http/1 request, the deadline further down covers clients that never get as far as their first request.
+    let mut server = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+    server.http1().timer(TokioTimer::new()).header_read_timeout(timeouts.request_headers);
+    // h2 clients have to answer our pings just as quickly, so connections to clients that went away do not linger.
+    server.http2().timer(TokioTimer::new())
+        .keep_alive_interval(Some(timeouts.request_headers))
+        .keep_alive_timeout(timeouts.request_headers);
+
+    // keep-alive connections are closed when they have been idle for too long, which does not count time spent waiting for backends.
+    let activity = crate::timeouts::Activity::default();
+    let got_request = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let service = {
+        let activity = activity.clone();
+        let got_request = got_request.clone();
+        hyper::service::service_fn(move |req| {
+            got_request.store(true, std::sync::atomic::Ordering::Relaxed);
+            let busy = activity.busy();
+            let f = service.call(req);
+            async move {
+                let result = f.await;
+                drop(busy);
+                result
+            }
+        })
+    };
 
+    let stream : Box<dyn ClientStream> = match io {
+        // GenericManagedStream::TLS(peekable_tls_stream) => Box::new(peekable_tls_stream.managed_tls_stream),
+        GenericManagedStream::TerminatedTLS(stream) => Box::new(stream),
+        GenericManagedStream::TCP(peekable_tcp_stream) => Box::new(peekable_tcp_stream),
+    };
+    // we keep a handle to the stream so that we can still tell the client about a timeout once hyper has given up on it
+    let stream = crate::timeouts::SharedStream::new(crate::timeouts::IdleTracked::new(stream, activity.clone()));
+    let connection = server.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(stream.clone()), service);
+    tokio::pin!(connection);
+
+    let first_request_deadline = async {
+        tokio::time::sleep(timeouts.request_headers).await;
+        if got_request.load(std::sync::atomic::Ordering::Relaxed) {
+            std::future::pending::<()>().await
+        }
     };
 
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = first_request_deadline => {
+            tracing::warn!(timeout = "request_headers", "Client at {source_addr:?} did not send a request for {host} within {}s.", timeouts.request_headers.as_secs());
+            if stream.is_h2() {
+                // sends a GOAWAY, there are no streams to wait for so this does not take long unless the client stopped reading
+                connection.as_mut().graceful_shutdown();
+                _ = tokio::time::timeout(timeouts.request_headers, connection).await;
+            } else {
+                reply_request_timeout(stream).await;
+            }
+            return
+        },
+        idle = activity.idle_for(timeouts.idle) => {
+            tracing::info!(timeout = "idle", "Closing connection for {host} after {}s without traffic.", idle.as_secs());
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        },
+        _ = state.app_state.shutdown.started() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
 
     match result {
         Ok(_) => {},
+        Err(e) if e.downcast_ref::<hyper::Error>().is_some_and(|e| e.is_timeout()) => {
+            if stream.is_h2() {
+                tracing::warn!(timeout = "keep_alive", "Client at {source_addr:?} stopped answering pings for {host}.");
+            } else {
+                tracing::warn!(timeout = "request_headers", "Client at {source_addr:?} did not send its request headers for {host} within {}s.", timeouts.request_headers.as_secs());
+                reply_request_timeout(stream).await;
+            }
+        },
         Err(e) => {
             tracing::warn!("{e:?}")
         }
     }
 }
 
+/// Anything a client connection can arrive as, so that all of them can be served the same way.
+trait ClientStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin {}
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin> ClientStream for T {}
+
+/// Lets a client that was too slow with its request headers know why we are hanging up on it.
+async fn reply_request_timeout<S: tokio::io::AsyncWrite + Unpin>(mut stream: S) {
+    use tokio::io::AsyncWriteExt;
+    let response = b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+    let reply = async {
+        stream.write_all(response).await?;
+        stream.shutdown().await
+    };
+    // a client that does not read its response either is not worth waiting for
+    _ = tokio::time::timeout(Duration::from_secs(5), reply).await;
+}
+
 type FullOrStreamBody =
     http_body_util::Either<
         Full<bytes::Bytes>,
@@ -139,6 +223,100 @@ pub async fn create_simple_response_from_incoming(res:WrappedNormalResponse) ->
     Ok(EpicResponse::from_parts(p,Either::Left(b)))
 }
 
+/// Writes the access log entry for a response. Proxied bodies are streamed, so for those the entry is
+/// handed to the body and written once it has been sent.
+fn log_access(entry: Option<crate::access_log::AccessLogEntry>, response: &mut EpicResponse) {
+    let Some(mut entry) = entry else { return };
+    entry.status = Some(response.status().as_u16());
+    if let Some(upstream) = response.extensions().get::<crate::access_log::Upstream>() {
+        entry.set_upstream(upstream);
+    }
+    match response.body_mut() {
+        Either::Left(body) => body.finish_access_log_entry_when_done(entry),
+        body => {
+            let size = hyper::body::Body::size_hint(&*body).exact().unwrap_or(0);
+            entry.finish(size)
+        }
+    }
+}
+
+/// Counts the response towards the site metrics. Like with the access log, bytes of proxied bodies are counted as they are sent.
+fn record_metrics(site_metrics: &Arc<crate::types::proxy_state::SiteMetrics>, started_at: std::time::Instant, response: &mut EpicResponse) {
+    site_metrics.record_response(response.status().as_u16(), started_at.elapsed());
+    match response.body_mut() {
+        Either::Left(body) => body.count_sent_bytes_for(site_metrics.clone()),
+        body => {
+            let size = hyper::body::Body::size_hint(&*body).exact().unwrap_or(0);
+            site_metrics.bytes_sent.fetch_add(size, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+/// Finds the configured site that the request will be handled by, for use as the site label in metrics.
+fn metrics_site_for(svc: &ReverseProxyService, req: &hyper::Request<hyper::body::Incoming>) -> String {
+    if let Some(target) = &svc.resolved_target {
+        return target.host_name.clone()
+    }
+    let host = req.headers().get("host")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(':').next())
+        .or_else(|| req.uri().host());
+    match host {
+        Some(host) => crate::metrics::site_for_host(&svc.configuration, host),
+        None => crate::metrics::UNKNOWN_SITE.to_string()
+    }
+}
+
+/// Checks the allow_cidrs and deny_cidrs of the site that the request is for.
+/// Lets-encrypt challenges are always let thru so that sites which are only reachable from internal networks can still get certificates.
+fn is_denied_access(svc: &ReverseProxyService, req: &hyper::Request<hyper::body::Incoming>) -> bool {
+    if req.uri().path().starts_with("/.well-known/acme-challenge/") {
+        return false
+    }
+    let Some(client_ip) = svc.source_addr.map(|a| a.ip()) else { return false };
+    requested_host(svc, req)
+        .and_then(|h| crate::access_control::rules_for_host(&svc.configuration, &h))
+        .is_some_and(|rules| !rules.allows(client_ip))
+}
+
+/// Takes a rate limit token for a terminated request, or the websocket upgrade of one, returning the 429 to send if there was none left.
+fn enforce_rate_limit(
+    state: &GlobalState,
+    configuration: &crate::configuration::ConfigWrapper,
+    host_name: &str,
+    source_addr: Option<std::net::SocketAddr>,
+    headers: &HeaderMap,
+    prepaid: &crate::rate_limiting::PrepaidToken
+) -> Option<EpicResponse> {
+    let (site, limit) = crate::rate_limiting::rate_limit_for_host(configuration, host_name)?;
+    let key = crate::rate_limiting::bucket_key(limit, source_addr.map(|a| a.ip()), Some(headers));
+    if prepaid.take(&site, &key) {
+        return None
+    }
+    let wait = crate::rate_limiting::try_acquire(state, &site, limit, key).err()?;
+    tracing::debug!("Rate limit exceeded for {site}, rejecting request from {source_addr:?}");
+    let mut response = EpicResponse::new(create_epic_string_full_body("429 - Too many requests"));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(crate::rate_limiting::retry_after_seconds(wait)));
+    Some(response)
+}
+
+fn requested_host(svc: &ReverseProxyService, req: &hyper::Request<hyper::body::Incoming>) -> Option<String> {
+    svc.resolved_target.as_ref().map(|t| t.host_name.as_str())
+        .or_else(|| req.headers().get("host").and_then(|v| v.to_str().ok()).and_then(|v| v.split(':').next()))
+        .or_else(|| req.uri().host())
+        .map(|h| h.to_ascii_lowercase())
+}
+
+/// Uses the request id sent by the client (or a proxy in front of us) if there is a sane one, otherwise creates a new one.
+pub(crate) fn request_id_for(headers: &hyper::HeaderMap) -> String {
+    headers.get(&*super::X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128 && v.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)))
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
 fn handle_ws(svc:ReverseProxyService,mut req:hyper::Request<hyper::body::Incoming>) -> Result<EpicResponse,CustomError> {
 
     let (response, websocket) = hyper_tungstenite::upgrade(&mut req, None)
@@ -155,15 +333,61 @@ impl<'a> Service<Request<hyper::body::Incoming>> for ReverseProxyService {
     type Error = CustomError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: hyper::Request<hyper::body::Incoming>) -> Self::Future {
+    fn call(&self, mut req: hyper::Request<hyper::body::Incoming>) -> Self::Future {
 
         // tracing::trace!("INCOMING REQ: {:?}",req);
         // tracing::trace!("VERSION: {:?}",req.version());
 
+        // the request id is passed on to the backend and returned to the client
+        // so that our logs can be matched with those of the backend.
+        let request_id = request_id_for(req.headers());
+        let request_id_header = HeaderValue::from_str(&request_id).expect("request ids should always be valid header values");
+        req.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header.clone());
+        crate::proxy::mutate_tracked_connection(&self.state, &self.connection_key, |c| c.record_request_id(request_id.clone()));
+
+        let access_log_entry = crate::access_log::is_enabled()
+            .then(|| crate::access_log::AccessLogEntry::for_request(&req, self.source_addr.map(|a| a.ip())));
+
+        if self.listener.sites.is_some() && !requested_host(self, &req).is_some_and(|h| self.listener.serves(&h)) {
+            tracing::debug!("Rejecting request from {:?} to {:?} as the site is not served on the listener at {}.", self.source_addr, req.uri(), self.listener.bind_addr);
+            let mut response = EpicResponse::new(create_epic_string_full_body("421 - Misdirected Request"));
+            *response.status_mut() = StatusCode::MISDIRECTED_REQUEST;
+            response.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+            log_access(access_log_entry, &mut response);
+            return Box::pin(async move { Ok(response) })
+        }
+
+        if is_denied_access(self, &req) {
+            tracing::debug!("Denying request from {:?} to {:?} as the client address is not allowed.", self.source_addr, req.uri());
+            let mut response = EpicResponse::new(create_epic_string_full_body("403 - Forbidden"));
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            response.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+            log_access(access_log_entry, &mut response);
+            return Box::pin(async move { Ok(response) })
+        }
+
         // handle websocket upgrades separately
         if hyper_tungstenite::is_upgrade_request(&req) {
-            let res =  handle_ws(self.clone(),req);
-            return Box::pin(async move { res })
+            let svc = self.clone();
+            let host = requested_host(self, &req).unwrap_or_default();
+            return Box::pin(async move {
+                // upgrades never reach handle_http_request, so rate limits and authentication need to be checked here as well.
+                if let Some(mut response) = enforce_rate_limit(&svc.state, &svc.configuration, &host, svc.source_addr, req.headers(), &svc.rate_limit_token) {
+                    response.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+                    log_access(access_log_entry, &mut response);
+                    return Ok(response)
+                }
+                if let Err(mut response) = super::auth::authorize(&svc.configuration, &host, &mut req, svc.source_addr, svc.is_https).await {
+                    response.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+                    log_access(access_log_entry, &mut response);
+                    return Ok(response)
+                }
+                handle_ws(svc,req).map(|mut r| {
+                    r.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+                    log_access(access_log_entry, &mut r);
+                    r
+                })
+            })
         }
 
         //handle h2 stream handler test req
@@ -259,21 +483,50 @@ impl<'a> Service<Request<hyper::body::Incoming>> for ReverseProxyService {
         };
 
 
+        let site_metrics = self.state.app_state.statistics.site_metrics(&metrics_site_for(self, &req));
+        if let Some(len) = req.headers().get(hyper::header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok()) {
+            site_metrics.bytes_received.fetch_add(len, std::sync::atomic::Ordering::Relaxed);
+        }
+        let started_at = std::time::Instant::now();
+
+        let host = requested_host(self, &req).unwrap_or_default();
+        let timeouts = crate::timeouts::for_host(&self.configuration, &host);
+        let (client, h2_client) = crate::timeouts::proxy_clients(timeouts.connect);
+
+        let compression = crate::compression::for_host(&self.configuration, &host);
+        let encoding = compression.as_ref()
+            .filter(|_| req.method() != Method::HEAD)
+            .and_then(|c| crate::compression::negotiate_for_request(c, req.headers()));
+
         // handle normal proxy path
         let f = handle_http_request(
             req,
             self.tx.clone(),
             self.state.clone(),
             self.is_https,
-            self.client.clone(),
-            self.h2_client.clone(),
+            client,
+            h2_client,
             self.resolved_target.clone(),
             self.configuration.clone(),
-            self.connection_key.clone()
-        );
+            self.connection_key.clone(),
+            self.source_addr,
+            self.rate_limit_token.clone()
+        ).instrument(tracing::info_span!("request", request_id = %request_id));
 
         return Box::pin(async move {
-            match f.await {
+            let result = match timeouts.max_request_duration {
+                Some(limit) => match tokio::time::timeout(limit, f).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        tracing::warn!(timeout = "request", "Request to {host} did not get a response within {}s.", limit.as_secs());
+                        let mut response = EpicResponse::new(create_epic_string_full_body("504 - Gateway Timeout"));
+                        *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                        Ok(response)
+                    }
+                },
+                None => f.await
+            };
+            match result {
                 Ok(mut x) => {
                     {
                         let headers = x.headers_mut();
@@ -282,14 +535,23 @@ impl<'a> Service<Request<hyper::body::Incoming>> for ReverseProxyService {
                         if let Some(h) = original_host {
                             headers.insert("X-Forwarded-Host", h);
                         }
+                        headers.insert(&*super::X_REQUEST_ID, request_id_header);
+                    }
+                    record_metrics(&site_metrics, started_at, &mut x);
+                    log_access(access_log_entry, &mut x);
+                    match &compression {
+                        Some(settings) => Ok(crate::compression::compress_response(settings, encoding, x)),
+                        None => Ok(x)
                     }
-                    Ok(x)
                 },
                 Err(e) => {
                     //Err(CustomError(format!("{e:?}")))
                     let body = create_epic_string_full_body(&format!("{e:?}"));
                     let mut response = EpicResponse::new(body);
                     *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    response.headers_mut().insert(&*super::X_REQUEST_ID, request_id_header);
+                    record_metrics(&site_metrics, started_at, &mut response);
+                    log_access(access_log_entry, &mut response);
                     Ok(response)
                 },
             }
@@ -300,27 +562,29 @@ impl<'a> Service<Request<hyper::body::Incoming>> for ReverseProxyService {
 }
 
 async fn handle_http_request(
-    req: Request<hyper::body::Incoming>,
+    mut req: Request<hyper::body::Incoming>,
     tx: Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
     state: Arc<GlobalState>,
     is_https:bool,
-    http_client:  Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
-    h2_only_client: Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
+    http_client: super::ProxyClient,
+    h2_only_client: super::ProxyClient,
     peeked_target: Option<Arc<ReverseTcpProxyTarget>>,
     configuration: Arc<crate::configuration::ConfigWrapper>,
-    connection_key: ConnectionKey
+    connection_key: ConnectionKey,
+    source_addr: Option<std::net::SocketAddr>,
+    rate_limit_token: crate::rate_limiting::PrepaidToken
 
 ) -> Result<EpicResponse, CustomError> {
 
     // This will either point to the backend hostname or whatever the caller used as hostname
-    let resolved_host_name =
+    let mut resolved_host_name =
         if let Some(t) = &peeked_target {
             t.host_name.to_string()
         } else if let Some(hh) = req.headers().get("host") {
             let hostname_and_port = hh.to_str().map_err(|e|CustomError(format!("{e:?}")))?.to_string();
-            hostname_and_port.split(":").collect::<Vec<&str>>()[0].to_owned()
+            hostname_and_port.split(":").collect::<Vec<&str>>()[0].to_ascii_lowercase()
         } else {
-            req.uri().authority().ok_or(CustomError(format!("No hostname and no Authority found")))?.host().to_string()
+            req.uri().authority().ok_or(CustomError(format!("No hostname and no Authority found")))?.host().to_ascii_lowercase()
         };
 
     let req_path = req.uri().path();
@@ -360,6 +624,10 @@ async fn handle_http_request(
 
     //tracing::trace!("Handling request from {client_ip:?} on hostname {req_host_name:?}");
 
+    if let Some(response) = enforce_rate_limit(&state, &configuration, &resolved_host_name, source_addr, req.headers(), &rate_limit_token) {
+        return Ok(response)
+    }
+
     // THIS SHOULD BE THE ONLY PLACE WE INCREMENT THE TERMINATION COUNTER
     match state.app_state.statistics.lb_access_count_per_hostname.get_mut(&resolved_host_name) {
         Some(mut guard) => {
@@ -378,6 +646,19 @@ async fn handle_http_request(
         return Ok(r)
     }
 
+    // authentication is checked against the site that was requested, before any routes can send the request elsewhere.
+    if let Err(response) = super::auth::authorize(&configuration, &resolved_host_name, &mut req, source_addr, is_https).await {
+        return Ok(response)
+    }
+
+    // path based routes are only applied once, so the target site's own routes do not come in to play here.
+    let mut peeked_target = peeked_target;
+    if let Some(target) = super::routes::routes_for_host(&configuration, &resolved_host_name).and_then(|routes| super::routes::apply_routes(routes, &mut req)) {
+        tracing::trace!("Routing request for {resolved_host_name} to {target}");
+        resolved_host_name = target;
+        peeked_target = None;
+    }
+
     // todo - cache this?
     let dir_target = if peeked_target.is_some() { None } else { configuration.dir_server.iter().flatten().find_map(|x| {
         if x.host_name == resolved_host_name {
@@ -402,8 +683,14 @@ async fn handle_http_request(
                 return Ok(rr)
             }
         }
+        let response_rules = t.response_headers.as_ref().map(|rules| (rules.clone(), super::HeaderTemplateValues::new(&req, source_addr)));
         match crate::custom_servers::directory::handle(t.clone(),req).await {
-            Ok(r) => return Ok(r),
+            Ok(mut r) => {
+                if let Some((rules, values)) = response_rules {
+                    super::header_rules::apply(&rules, &values, r.headers_mut());
+                }
+                return Ok(r)
+            },
             Err(e) => {
                 tracing::error!("Failed to handle file request: {e:?}");
                 let mut rr = EpicResponse::new(create_epic_string_full_body(&e.0));
@@ -423,7 +710,8 @@ async fn handle_http_request(
                 &rc,
                 req,http_client.clone(),
                 h2_only_client.clone(),
-                connection_key
+                connection_key,
+                source_addr
             ).await
         }
     }
@@ -563,7 +851,9 @@ async fn handle_http_request(
             // we are hosting this service so clearly it is local
             address: local_addr.to_string(),
             port: port,
-            https: Some(use_https_to_backend_target)
+            https: Some(use_https_to_backend_target),
+            weight: None,
+            proxy_protocol: None
         };
 
         let mut host_header_override = None;
@@ -591,18 +881,25 @@ async fn handle_http_request(
             tracing::trace!("Will not patch the request host header as the capture_subdomains and forward_subdomains are set to false for this in-process site config.");
         }
 
+        let header_rewrites = super::HeaderRewrites::new(
+            target_cfg.request_headers.as_ref(),
+            target_cfg.response_headers.as_ref(),
+            super::HeaderTemplateValues::new(&req, source_addr)
+        );
+
         let result =
             proxy(
                 host_header_override,
                 is_https,
                 state.clone(),
-                req,
+                req.map(|b| b.boxed_unsync()),
                 &skip_dns_for_local_target_url,
                 http_client,
                 h2_only_client,
                 use_https_to_backend_target,
                 backend,
-                &connection_key
+                &connection_key,
+                &header_rewrites
             ).await;
 
         map_result(&skip_dns_for_local_target_url,result).await
@@ -621,7 +918,8 @@ async fn handle_http_request(
                 &peeked_remote_config,
                 req,http_client.clone(),
                 h2_only_client.clone(),
-                connection_key
+                connection_key,
+                source_addr
             ).await
         }
         else if let Some(remote_target_cfg) = &configuration.remote_target.iter().flatten().find(|p|{
@@ -640,7 +938,8 @@ async fn handle_http_request(
                 remote_target_cfg,
                 req,http_client.clone(),
                 h2_only_client.clone(),
-                connection_key
+                connection_key,
+                source_addr
             ).await
         };
 
@@ -681,22 +980,170 @@ async fn perform_remote_forwarding(
     state: Arc<GlobalState>,
     remote_target_config:&crate::configuration::RemoteSiteConfig,
     req:hyper::Request<IncomingBody>,
-    client:  Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
-    h2_client: Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
-    connection_key: ConnectionKey
+    client: super::ProxyClient,
+    h2_client: super::ProxyClient,
+    connection_key: ConnectionKey,
+    source_addr: Option<std::net::SocketAddr>
+) -> Result<EpicResponse,CustomError> {
+
+    if remote_target_config.enable_response_cache.unwrap_or_default() && !req.headers().contains_key(hyper::header::UPGRADE) {
+        let forwarder = RemoteForwarder {
+            req_host_name, original_is_https, state, site: remote_target_config.clone(),
+            client, h2_client, connection_key, source_addr
+        };
+        return super::cache::serve(forwarder, req).await
+    }
+
+    forward_to_remote_site(
+        &req_host_name, original_is_https, state, remote_target_config,
+        req.map(|b| b.boxed_unsync()), client, h2_client, connection_key, source_addr
+    ).await
+}
+
+/// Everything needed for sending requests to a remote site, including after the client request is done
+/// as the response cache revalidates stale responses in the background.
+#[derive(Clone)]
+pub(crate) struct RemoteForwarder {
+    pub req_host_name: String,
+    pub original_is_https: bool,
+    pub state: Arc<GlobalState>,
+    pub site: crate::configuration::RemoteSiteConfig,
+    pub client: super::ProxyClient,
+    pub h2_client: super::ProxyClient,
+    pub connection_key: ConnectionKey,
+    pub source_addr: Option<std::net::SocketAddr>
+}
+
+impl RemoteForwarder {
+    pub async fn forward(&self, req:hyper::Request<super::ProxyRequestBody>) -> Result<EpicResponse,CustomError> {
+        forward_to_remote_site(
+            &self.req_host_name, self.original_is_https, self.state.clone(), &self.site,
+            req, self.client.clone(), self.h2_client.clone(), self.connection_key, self.source_addr
+        ).await
+    }
+}
+
+async fn forward_to_remote_site(
+    req_host_name:&str,
+    original_is_https:bool,
+    state: Arc<GlobalState>,
+    remote_target_config:&crate::configuration::RemoteSiteConfig,
+    req:hyper::Request<super::ProxyRequestBody>,
+    client: super::ProxyClient,
+    h2_client: super::ProxyClient,
+    connection_key: ConnectionKey,
+    source_addr: Option<std::net::SocketAddr>
 ) -> Result<EpicResponse,CustomError> {
 
+    let retry_policy = remote_target_config.retries.as_ref().filter(|p| is_retryable(&req, p));
+    let header_rewrites = super::HeaderRewrites::new(
+        remote_target_config.request_headers.as_ref(),
+        remote_target_config.response_headers.as_ref(),
+        super::HeaderTemplateValues::new(&req, source_addr)
+    );
+
+    let Some(retry_policy) = retry_policy else {
+        let lb_ctx = crate::load_balancing::SelectionContext {
+            client_ip: source_addr.map(|a| a.ip()),
+            headers: Some(req.headers()),
+            excluded: &[]
+        };
+        let next_backend_target = if let Some(b) = remote_target_config.next_backend(&state, crate::configuration::BackendFilter::Any, &lb_ctx).await {
+            b
+        } else {
+            return Err(CustomError("No backend found".to_string()))
+        };
+        let (result,_failed) = forward_to_backend(
+            req_host_name, original_is_https, state, remote_target_config,
+            req, client, h2_client, &connection_key, next_backend_target, &header_rewrites
+        ).await;
+        return result
+    };
+
+    // requests that may be retried are buffered so that the same request can be replayed against another backend.
+    // is_retryable has already made sure that the body is small enough for this.
+    let (parts,body) = req.into_parts();
+    let buffered_body = body.collect().await
+        .map_err(|e|CustomError(format!("Failed to read request body: {e:?}")))?
+        .to_bytes();
+
+    let max_attempts = 1 + retry_policy.attempts();
+    let mut failed_backends : Vec<crate::configuration::Backend> = vec![];
+    let mut last_result = None;
+
+    loop {
+
+        let lb_ctx = crate::load_balancing::SelectionContext {
+            client_ip: source_addr.map(|a| a.ip()),
+            headers: Some(&parts.headers),
+            excluded: &failed_backends
+        };
+
+        let next_backend_target = match remote_target_config.next_backend(&state, crate::configuration::BackendFilter::Any, &lb_ctx).await {
+            Some(b) => b,
+            // all backends have failed this request, the client gets the last error we saw
+            None => return last_result.unwrap_or(Err(CustomError("No backend found".to_string())))
+        };
+
+        let mut replayed_req = Request::new(Full::new(buffered_body.clone()).map_err(|never| match never {}).boxed_unsync());
+        *replayed_req.method_mut() = parts.method.clone();
+        *replayed_req.uri_mut() = parts.uri.clone();
+        *replayed_req.version_mut() = parts.version;
+        *replayed_req.headers_mut() = parts.headers.clone();
+
+        let (result,failed) = forward_to_backend(
+            req_host_name, original_is_https, state.clone(), remote_target_config,
+            replayed_req, client.clone(), h2_client.clone(), &connection_key, next_backend_target.clone(), &header_rewrites
+        ).await;
+
+        if !failed || failed_backends.len() + 1 >= max_attempts as usize {
+            return result
+        }
+
+        tracing::debug!("Request to backend {}:{} of {} failed, retrying with another backend.",next_backend_target.address,next_backend_target.port,remote_target_config.host_name);
+        failed_backends.push(next_backend_target);
+        last_result = Some(result);
+    }
+
+}
+
+/// GET, HEAD and OPTIONS are always fine to send again. Other methods are only retried if the site
+/// explicitly opted in to it by configuring a body size limit.
+pub(crate) fn is_retryable<B:hyper::body::Body>(req:&hyper::Request<B>, policy:&crate::configuration::RetryPolicy) -> bool {
+    if req.headers().contains_key(hyper::header::UPGRADE) {
+        return false
+    }
+    let idempotent = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !idempotent && policy.max_body_size_in_bytes.is_none() {
+        return false
+    }
+    // we need to know the size up front, chunked bodies of unknown length are never buffered.
+    match hyper::body::Body::size_hint(req.body()).exact() {
+        Some(len) => len <= policy.max_body_size(),
+        None => false
+    }
+}
+
+/// Sends the request to a specific backend of a remote site.
+/// Also returns true if the backend failed in a way that makes it worth trying another one:
+/// either we could not talk to it at all or it responded with 502, 503 or 504.
+async fn forward_to_backend(
+    req_host_name:&str,
+    original_is_https:bool,
+    state: Arc<GlobalState>,
+    remote_target_config:&crate::configuration::RemoteSiteConfig,
+    req:hyper::Request<super::ProxyRequestBody>,
+    client: super::ProxyClient,
+    h2_client: super::ProxyClient,
+    connection_key: &ConnectionKey,
+    next_backend_target: crate::configuration::Backend,
+    header_rewrites: &super::HeaderRewrites
+) -> (Result<EpicResponse,CustomError>,bool) {
 
     let mut original_path_and_query = req.uri().path_and_query()
         .and_then(|x| Some(x.as_str())).unwrap_or_default();
     if original_path_and_query == "/" { original_path_and_query = ""}
 
-    let next_backend_target = if let Some(b) = remote_target_config.next_backend(&state, crate::configuration::BackendFilter::Any).await {
-        b
-    } else {
-        return Err(CustomError("No backend found".to_string()))
-    };
-
     // if a target is marked with http, we wont try to use http
     let enforce_https = next_backend_target.https.unwrap_or_default();
 
@@ -715,7 +1162,7 @@ async fn perform_remote_forwarding(
         if remote_target_config.keep_original_host_header.unwrap_or_default() {
             None
         } else if remote_target_config.forward_subdomains.unwrap_or_default() {
-            let sub = get_subdomain(&req_host_name, &remote_target_config.host_name);
+            let sub = get_subdomain(req_host_name, &remote_target_config.host_name);
             if let Some(subdomain) = sub {
                 let r = Some(format!("{subdomain}.{}", &next_backend_target.address));
                 let original_host_header = req.headers().get("host").and_then(|h| h.to_str().ok()).unwrap_or_default();
@@ -737,6 +1184,11 @@ async fn perform_remote_forwarding(
 
     //map_result(&target_url,Err(super::ProxyError::OddBoxError(String::from("meh")))).await
 
+    let affinity_cookie = crate::session_affinity::cookie_for_response(remote_target_config, req.headers(), &next_backend_target);
+
+    // keeps track of the request so that least-connections can take terminated traffic in to account
+    let in_flight = crate::load_balancing::InFlightRequest::new(state.clone(), &remote_target_config.host_name, &next_backend_target);
+
     //tracing::info!("Incoming request to '{}' for remote proxy target {target_url}",next_backend_target.address);
     let result =
         proxy(
@@ -748,11 +1200,42 @@ async fn perform_remote_forwarding(
             client,
             h2_client,
             next_backend_target.https.unwrap_or_default(),
-            next_backend_target,
-            &connection_key
+            next_backend_target.clone(),
+            connection_key,
+            header_rewrites
         ).await;
 
-    map_result(&backend_target_url,result).await
+    let backend_failed = match &result {
+        Err(super::ProxyError::LegacyError(_)) => true,
+        Ok(super::ProxyCallResult::NormalResponse(r)) => matches!(r.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT),
+        _ => false
+    };
+
+    if backend_failed {
+        crate::outlier_detection::record_failure(&state, remote_target_config, &next_backend_target);
+    } else {
+        crate::outlier_detection::record_success(&state, remote_target_config, &next_backend_target);
+    }
+
+    let mut response = match map_result(&backend_target_url,result).await {
+        Ok(r) => r,
+        Err(e) => return (Err(e),backend_failed)
+    };
+
+    // proxied bodies are streamed, so the request is not done until the body is
+    if let Either::Left(body) = response.body_mut() {
+        body.count_in_flight_until_done(in_flight);
+    }
+
+    // no point in pinning clients to a backend that just failed them
+    if let Some(c) = affinity_cookie.filter(|_| !response.status().is_server_error()) {
+        if let Ok(v) = HeaderValue::from_str(&c) {
+            response.headers_mut().append(hyper::header::SET_COOKIE, v);
+        }
+    }
+
+    (Ok(response),backend_failed)
 
 }
 
@@ -768,6 +1251,13 @@ async fn map_result(target_url:&str,result:Result<crate::http_proxy::ProxyCallRe
         Ok(crate::http_proxy::ProxyCallResult::NormalResponse(response)) => {
                 return create_simple_response_from_incoming(response).await;
         }
+        Err(crate::http_proxy::ProxyError::LegacyError(error)) if error.is_connect() && crate::timeouts::is_timeout(&error) => {
+            tracing::warn!(timeout = "connect", "Timed out connecting to {}", &target_url);
+            Ok(Response::builder()
+                .status(StatusCode::GATEWAY_TIMEOUT)
+                .body(create_epic_string_full_body("504 - Gateway Timeout"))
+                .expect("body building always works"))
+        },
         Err(crate::http_proxy::ProxyError::LegacyError(error)) => {
             tracing::debug!("HyperLegacyError - Failed to call {}: {error:?}", &target_url);
             Ok(Response::builder()
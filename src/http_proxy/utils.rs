@@ -33,8 +33,15 @@ lazy_static! {
     ];
 
     static ref X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+    pub static ref X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
 }
 
+/// Body type used for requests that we send to backends.
+/// Usually this is just the incoming body, but requests that may be retried are buffered and replayed from memory.
+pub type ProxyRequestBody = http_body_util::combinators::UnsyncBoxBody<bytes::Bytes, hyper::Error>;
+
+pub type ProxyClient = Client<HttpsConnector<HttpConnector>, ProxyRequestBody>;
+
 pub enum ProxyCallResult {
     NormalResponse(WrappedNormalResponse),
     EpicResponse(crate::http_proxy::service::EpicResponse),
@@ -68,18 +75,23 @@ pub async fn proxy(
     host_header_override: Option<String>,
     original_connection_is_https:bool,
     state: Arc<GlobalState>,
-    mut req: hyper::Request<hyper::body::Incoming>,
+    mut req: hyper::Request<ProxyRequestBody>,
     backend_target_url: &str,  
-    http_client:  Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
-    h2_only_http_client: Client<HttpsConnector<HttpConnector>, hyper::body::Incoming>,
+    http_client: ProxyClient,
+    h2_only_http_client: ProxyClient,
     use_https_to_backend_target: bool,
     backend: crate::configuration::Backend,
     connection_key:&ConnectionKey, 
+    header_rewrites: &super::HeaderRewrites,
 ) -> Result<ProxyCallResult, ProxyError> {
 
     //let incoming_http_version = req.version();
     let request_upgrade_type = get_upgrade_type(req.headers());
     let request_upgraded = req.extensions_mut().remove::<OnUpgrade>();
+    let request_id = req.headers().get(&*X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
 
 
     // tracing::trace!(
@@ -109,7 +121,7 @@ pub async fn proxy(
             .unwrap_or(&[]);
  
     let mut proxied_request =
-        create_proxied_request(&backend_target_url, req, request_upgrade_type.as_ref(), host_header_override)?;
+        create_proxied_request(&backend_target_url, req, request_upgrade_type.as_ref(), host_header_override, header_rewrites)?;
     
     //tracing::trace!("PROXIED REQUEST: {:#?}", proxied_request);
 
@@ -166,6 +178,8 @@ pub async fn proxy(
 
     let _my_permit = crate::proxy::ACTIVE_HYPER_CLIENT_CONNECTIONS.acquire().await;
 
+    let request_sent_at = std::time::Instant::now();
+
     // todo - prevent making a connection if client already has too many tcp connections open
     let mut response = {
         client
@@ -174,6 +188,11 @@ pub async fn proxy(
             .map_err(ProxyError::LegacyError)?
     };
 
+    response.extensions_mut().insert(crate::access_log::Upstream {
+        backend: format!("{}:{}", backend.address, backend.port),
+        latency: request_sent_at.elapsed()
+    });
+
     // ^ This place right here is the only place where we are able to actually modify packets that get sent to backends
     //   and also modify responses prior to sending them to our client. Thus we will emit extra details around this traffic
     //   for making it visibly the exact request we send and what we get in response.
@@ -181,8 +200,16 @@ pub async fn proxy(
     //   bad data or modified the result somehow in a destructive way..
     
     // TODO - more structure to this type of events..
-    _ = state.global_broadcast_channel.send(crate::types::odd_box_event::GlobalEvent::SentHttpRequestToBackend(*connection_key,reqstring));
-    _ = state.global_broadcast_channel.send(crate::types::odd_box_event::GlobalEvent::GotResponseFromBackend(*connection_key,format!("{:#?}",response)));
+    _ = state.global_broadcast_channel.send(crate::types::odd_box_event::GlobalEvent::SentHttpRequestToBackend {
+        connection_key: *connection_key,
+        request_id: request_id.clone(),
+        request: reqstring
+    });
+    _ = state.global_broadcast_channel.send(crate::types::odd_box_event::GlobalEvent::GotResponseFromBackend {
+        connection_key: *connection_key,
+        request_id,
+        response: format!("{:#?}",response)
+    });
     
     
     
@@ -254,7 +281,7 @@ pub async fn proxy(
         }
     } else {
         // Got a normal response from the backend, we will just forward it to the client!       
-        let proxied_response = create_proxied_response(response);
+        let proxied_response = create_proxied_response(response, header_rewrites);
         Ok(ProxyCallResult::NormalResponse(WrappedNormalResponse::new(proxied_response,state.clone())))
     }
 }
@@ -263,7 +290,26 @@ pub async fn proxy(
 
 
 pub struct  WrappedNormalResponseBody {
-    b : Incoming
+    b : Incoming,
+    bytes_sent : u64,
+    access_log_entry : Option<crate::access_log::AccessLogEntry>,
+    site_metrics : Option<Arc<crate::types::proxy_state::SiteMetrics>>,
+    in_flight : Option<crate::load_balancing::InFlightRequest>
+}
+impl WrappedNormalResponseBody {
+    /// Bytes are added to the site metrics as they are sent rather than when the body is done,
+    /// so that long running streams show up in the metrics while they are still active.
+    pub fn count_sent_bytes_for(&mut self, site_metrics: Arc<crate::types::proxy_state::SiteMetrics>) {
+        self.site_metrics = Some(site_metrics);
+    }
+    /// The entry is written once the body has been sent (or dropped), so that it includes the size and total time.
+    pub fn finish_access_log_entry_when_done(&mut self, entry: crate::access_log::AccessLogEntry) {
+        self.access_log_entry = Some(entry);
+    }
+    /// The request keeps counting towards its backend (for least-connections) until the body has been sent (or dropped).
+    pub fn count_in_flight_until_done(&mut self, request: crate::load_balancing::InFlightRequest) {
+        self.in_flight = Some(request);
+    }
 }
 impl Drop for WrappedNormalResponseBody {
     fn drop(&mut self) {
@@ -271,7 +317,9 @@ impl Drop for WrappedNormalResponseBody {
         //     //tracing::trace!("dropping active connection due to body drop");
         //     on_drop();
         // }   
-        
+        if let Some(entry) = self.access_log_entry.take() {
+            entry.finish(self.bytes_sent);
+        }
     }
 }
 pub struct WrappedNormalResponse {
@@ -283,12 +331,16 @@ impl WrappedNormalResponse {
         (self.a,self.b)
     }
 
+    pub fn status(&self) -> StatusCode {
+        self.a.status
+    }
+
     
     pub fn new(res:Response<Incoming>,_state: Arc<GlobalState>) -> Self {
         
         let (a,b) = res.into_parts();
         Self {
-            a, b: WrappedNormalResponseBody { b }
+            a, b: WrappedNormalResponseBody { b, bytes_sent: 0, access_log_entry: None, site_metrics: None, in_flight: None }
         }
     }
 }
@@ -302,7 +354,15 @@ impl hyper::body::Body for WrappedNormalResponseBody {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         match self.b.frame().poll_unpin(cx) {
-            Poll::Ready(Some(Ok(data))) => Poll::Ready(Some(Ok(data))),
+            Poll::Ready(Some(Ok(data))) => {
+                if let Some(d) = data.data_ref() {
+                    self.bytes_sent += d.len() as u64;
+                    if let Some(m) = &self.site_metrics {
+                        m.bytes_sent.fetch_add(d.len() as u64, std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+                Poll::Ready(Some(Ok(data)))
+            },
             Poll::Ready(Some(Err(e))) => {
                 // Handle error properly here
                 tracing::error!("Error while polling frame: {:?}", e);
@@ -336,7 +396,8 @@ fn create_proxied_request<B>(
     backend_target_url: &str,
     mut request: Request<B>,
     upgrade_type: Option<&String>,
-    host_header_override: Option<String>
+    host_header_override: Option<String>,
+    header_rewrites: &super::HeaderRewrites
 ) -> Result<Request<B>, ProxyError> {
     
     // replace the uri
@@ -362,6 +423,9 @@ fn create_proxied_request<B>(
         .headers_mut()
         .remove("upgrade-insecure-requests");
 
+    // site specific header rules go before the upgrade headers so that they cannot break upgrades by accident.
+    header_rewrites.apply_to_request(request.headers_mut());
+
     // add the upgrade headers back if we are upgrading, so that the backend also knows what to do.
     if let Some(value) = upgrade_type {
         tracing::trace!("Re-populate upgrade headers! :: {value}");
@@ -409,9 +473,10 @@ fn remove_hop_headers(headers: &mut HeaderMap) {
     }
 }
 
-fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
+fn create_proxied_response<B>(mut response: Response<B>, header_rewrites: &super::HeaderRewrites) -> Response<B> {
     remove_hop_headers(response.headers_mut());
     remove_connection_headers(response.headers_mut());
+    header_rewrites.apply_to_response(response.headers_mut());
     response
 }
 
@@ -49,7 +49,12 @@ pub async fn handle_ws(req:Request<IncomingBody>,service:ReverseProxyService,ws:
     let (target_host,port,enforce_https) = match &target {
         
         crate::http_proxy::Target::Remote(x) => {
-             let next_backend = x.next_backend(&service.state, crate::configuration::BackendFilter::Any).await
+             let ctx = crate::load_balancing::SelectionContext {
+                client_ip: service.source_addr.map(|a| a.ip()),
+                headers: Some(req.headers()),
+                excluded: &[]
+             };
+             let next_backend = x.next_backend(&service.state, crate::configuration::BackendFilter::Any, &ctx).await
                 .ok_or(CustomError(format!("no backend found")))?;
              (
                 next_backend.address.clone(),
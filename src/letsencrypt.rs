@@ -1,7 +1,7 @@
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use anyhow::{bail, Context};
 use base64::engine::general_purpose;
 use base64::Engine;
@@ -14,13 +14,90 @@ use serde_json::Value;
 use tokio_rustls::rustls::sign::CertifiedKey;
 use x509_parser::prelude::{FromDer, X509Certificate};
 
+use crate::configuration::AcmeChallenge;
+use crate::dns_provider::DnsProvider;
 use crate::global_state::GlobalState;
+use crate::types::odd_box_event::{CertificateRenewalEvent, GlobalEvent};
 use crate::types::proc_info::BgTaskInfo;
 
 // TODO - move this to disk for persistance?
 lazy_static::lazy_static! {
     pub static ref CHALLENGE_MAP: DashMap<String, String> = DashMap::new();
     pub static ref DOMAIN_TO_CHALLENGE_TOKEN_MAP: DashMap<String, String> = DashMap::new();
+    /// Validation certificates for pending tls-alpn-01 challenges, by domain.
+    pub static ref TLS_ALPN_CHALLENGE_CERTS: DashMap<String, Arc<CertifiedKey>> = DashMap::new();
+    /// Domains whose certificate could not be issued or renewed on the last attempt.
+    pub static ref FAILING_RENEWALS: DashMap<String, RenewalStatus> = DashMap::new();
+}
+
+const ACME_STORAGE_DIR: &str = ".odd_box_cache/acme";
+const ACCOUNT_KEY_FILE: &str = "account_key.pem";
+const ACCOUNT_URL_FILE: &str = "account_url";
+const CERTIFICATE_FILE: &str = "certificate.pem";
+const PRIVATE_KEY_FILE: &str = "private_key.pem";
+
+pub const DEFAULT_RENEW_BEFORE_DAYS: u64 = 30;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10 * 60);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Consecutive failures after which a warning event is sent for a domain.
+const RENEWAL_WARNING_THRESHOLD: u32 = 3;
+
+#[derive(Debug, Clone, Default, Serialize, utoipa::ToSchema)]
+pub struct RenewalStatus {
+    pub consecutive_failures: u32,
+    /// Unix timestamp of the next attempt.
+    pub next_attempt: i64,
+    pub last_error: String,
+}
+
+/// The alpn protocol that lets-encrypt uses when connecting for a tls-alpn-01 challenge (RFC 8737).
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+pub const LETS_ENCRYPT_PRODUCTION_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Which acme server we get certificates from and how we register with it, from the acme_* settings of the configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AcmeSettings {
+    pub directory_url: Option<String>,
+    pub eab_key_id: Option<String>,
+    pub eab_hmac_key: Option<String>,
+    pub trusted_ca_cert_path: Option<String>,
+}
+
+impl AcmeSettings {
+    pub fn from_config(config: &crate::configuration::OddBoxV3Config) -> Self {
+        Self {
+            directory_url: config.acme_directory_url.clone(),
+            eab_key_id: config.acme_eab_key_id.clone(),
+            eab_hmac_key: config.acme_eab_hmac_key.clone(),
+            trusted_ca_cert_path: config.acme_trusted_ca_cert_path.clone(),
+        }
+    }
+    pub fn directory_url(&self) -> &str {
+        self.directory_url.as_deref().unwrap_or(LETS_ENCRYPT_PRODUCTION_DIRECTORY)
+    }
+    /// Each acme server gets a directory of its own, so that switching between for example staging and production
+    /// does not mix up accounts or certificates. It holds account_key.pem, account_url and
+    /// certificates/<domain>/{certificate.pem,private_key.pem}.
+    pub fn storage_dir(&self) -> PathBuf {
+        use sha2::Digest;
+        let url = self.directory_url();
+        let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(|h| h.to_string())).unwrap_or_else(|| "acme".into());
+        let host = host.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect::<String>();
+        let digest = sha2::Sha256::digest(url.as_bytes());
+        Path::new(ACME_STORAGE_DIR).join(format!("{host}-{}", digest.iter().take(4).map(|b| format!("{b:02x}")).collect::<String>()))
+    }
+}
+
+impl AcmeChallenge {
+    /// The challenge type as named by the acme protocol.
+    pub fn acme_name(&self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+            AcmeChallenge::Dns01 => "dns-01",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +109,15 @@ struct Directory {
     #[serde(rename = "revokeCert")]
     revoke_cert: String,
     key_change: String,
+    #[serde(default)]
+    meta: Option<DirectoryMeta>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryMeta {
+    #[serde(default)]
+    external_account_required: Option<bool>,
 }
 
 
@@ -41,12 +127,16 @@ pub struct LECertManager {
     acc_certified_key: rcgen::KeyPair,
     account_url: Option<String>,
     directory: Directory,
-    pub needs_to_register: bool
+    pub needs_to_register: bool,
+    /// What the manager was created with, so that it can be replaced when the configuration changes.
+    pub account_email: String,
+    pub settings: AcmeSettings,
 }
 impl std::fmt::Debug for LECertManager {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CertManager")
             .field("account_url", &self.account_url)
+            .field("directory_url", &self.settings.directory_url())
             .finish()
     }
 }
@@ -60,7 +150,7 @@ lazy_static::lazy_static! {
 impl LECertManager { 
 
     // Note: this method is called prior to the tracing being initialized and thus must use tracing::info for logging.
-    async fn register_acme_account(account_email:&str,client: &Client, directory: &Directory, account_key_pair: &rcgen::KeyPair) -> anyhow::Result<String> {
+    async fn register_acme_account(account_email:&str,client: &Client, directory: &Directory, account_key_pair: &rcgen::KeyPair, settings: &AcmeSettings) -> anyhow::Result<String> {
         
         let email_is_valid = EMAIL_REGEX.is_match(account_email);
         if email_is_valid == false {
@@ -68,12 +158,19 @@ impl LECertManager {
         }
 
         // Create payload for new account registration
-        let payload = json!({
+        let mut payload = json!({
             "termsOfServiceAgreed": true,
             // todo: add config option for this email
             "contact": [format!("mailto:{}",account_email)] 
         });
 
+        if let (Some(key_id), Some(hmac_key)) = (&settings.eab_key_id, &settings.eab_hmac_key) {
+            payload["externalAccountBinding"] = Self::external_account_binding(key_id, hmac_key, &directory.new_account, account_key_pair)
+                .context("creating the external account binding")?;
+        } else if directory.meta.as_ref().and_then(|m| m.external_account_required).unwrap_or_default() {
+            bail!("The acme server at {} requires External Account Binding, set acme_eab_key_id and acme_eab_hmac_key.", settings.directory_url());
+        }
+
         let nonce = Self::fetch_nonce(&client, &directory.new_nonce).await.context("fetch nonce")?;
 
         // Sign the request payload (without account URL, uses JWK instead)
@@ -119,44 +216,76 @@ impl LECertManager {
         Ok(s)
     }
 
+    /// The jws that binds our account key to an account that the CA already knows about (RFC 8555, 7.3.4).
+    /// CAs hand out the hmac key base64url encoded, but some use the standard alphabet so both are accepted.
+    pub(crate) fn external_account_binding(key_id: &str, hmac_key: &str, new_account_url: &str, account_key_pair: &rcgen::KeyPair) -> anyhow::Result<Value> {
+        let hmac_key = hmac_key.trim().trim_end_matches('=');
+        let key = if hmac_key.contains(['+', '/']) {
+            general_purpose::STANDARD_NO_PAD.decode(hmac_key)
+        } else {
+            general_purpose::URL_SAFE_NO_PAD.decode(hmac_key)
+        }.context("the acme_eab_hmac_key must be base64url encoded")?;
+
+        let protected = json!({
+            "alg": "HS256",
+            "kid": key_id,
+            "url": new_account_url,
+        });
+        let protected_b64 = general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_string(&protected)?);
+        let payload_b64 = general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_string(&Self::construct_jwk(account_key_pair)?)?);
+        let signing_input = format!("{}.{}", protected_b64, payload_b64);
+        let signature = ring::hmac::sign(&ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key), signing_input.as_bytes());
+
+        Ok(json!({
+            "protected": protected_b64,
+            "payload": payload_b64,
+            "signature": general_purpose::URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
     // Note: this method is called prior to the tracing being initialized and thus must use tracing::info for logging.
-    pub async fn new(account_email:&str) -> anyhow::Result<Self> {
+    pub async fn new(account_email:&str, settings: &AcmeSettings) -> anyhow::Result<Self> {
         
-        let client = Client::new();
-        
-        let account_key_path = ".odd_box_cache/lets_encrypt_account.key";
+        let mut client_builder = Client::builder();
+        if let Some(path) = &settings.trusted_ca_cert_path {
+            let pem = std::fs::read(path).context(format!("reading acme_trusted_ca_cert_path: {path}"))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem).context(format!("parsing acme_trusted_ca_cert_path: {path}"))? {
+                client_builder = client_builder.add_root_certificate(cert);
+            }
+        }
+        let client = client_builder.build()?;
         
-        // create the odd box cache dir if it does not exist
-        if !std::path::Path::exists(Path::new(".odd_box_cache")) {
-            std::fs::create_dir(".odd_box_cache")?;
+        let storage_dir = settings.storage_dir();
+        if settings.directory_url() == LETS_ENCRYPT_PRODUCTION_DIRECTORY {
+            if let Err(e) = migrate_legacy_storage(&storage_dir) {
+                tracing::warn!("Failed to move the lets-encrypt account and certificates to {}: {e:?}", storage_dir.display());
+            }
         }
+        std::fs::create_dir_all(&storage_dir).context(format!("creating {}", storage_dir.display()))?;
 
-        let account_key_pair = if !std::path::Path::exists(Path::new(account_key_path)) {
+        let account_key_path = storage_dir.join(ACCOUNT_KEY_FILE);
+        let account_key_pair = if !account_key_path.exists() {
             let key_pair = rcgen::KeyPair::generate()?;
-            let mut file = std::fs::File::create(account_key_path)?;
-            let bytes = key_pair.serialize_pem();
-            file.write_all(&bytes.as_bytes())?;
+            write_atomically(&account_key_path, key_pair.serialize_pem().as_bytes(), true)?;
             key_pair
         } else {
-            let key_pem = std::fs::read_to_string(account_key_path).context(format!("reading acc key file: {account_key_path}"))?;            
+            let key_pem = std::fs::read_to_string(&account_key_path).context(format!("reading acc key file: {}", account_key_path.display()))?;            
             rcgen::KeyPair::from_pem(&key_pem)?
 
         };
         
-        let directory_url = "https://acme-v02.api.letsencrypt.org/directory"; // PROD        
-        //let directory_url = "https://acme-staging-v02.api.letsencrypt.org/directory"; // STAGING        
+        let directory_url = settings.directory_url();
+        let directory = Self::fetch_directory(&client, directory_url).await.context(format!("calling fetch_directory for {directory_url}"))?;
 
-        let directory = Self::fetch_directory(&client, directory_url).await.context("calling fetch_directory")?;
-
-        let acc_url_path = "./.odd_box_cache/lets_encrypt_account_url";
-        let account_url = if std::path::Path::exists(std::path::Path::new(acc_url_path)) {
-            let account_url = std::fs::read_to_string(acc_url_path)?;
+        let acc_url_path = storage_dir.join(ACCOUNT_URL_FILE);
+        let account_url = if acc_url_path.exists() {
+            let account_url = std::fs::read_to_string(&acc_url_path)?;
             // tracing::info!("Lets-encrypt account already registered: {}", account_url);
             Some(account_url)
         } else if !account_email.is_empty() {
-            tracing::info!("Registering a new ACME account because we did not find path: {}", acc_url_path);
-            let url = Self::register_acme_account(&account_email,&client, &directory, &account_key_pair).await.context("register acme account")?;
-            std::fs::write(acc_url_path, &url)?;
+            tracing::info!("Registering a new ACME account because we did not find path: {}", acc_url_path.display());
+            let url = Self::register_acme_account(&account_email,&client, &directory, &account_key_pair, settings).await.context("register acme account")?;
+            write_atomically(&acc_url_path, url.as_bytes(), false)?;
             Some(url)
         } else {
             None
@@ -168,18 +297,38 @@ impl LECertManager {
             directory,
             // todo: either document that LE acc change in config requires a restart or add a way to reload this.
             needs_to_register: account_url.is_none(),
-            account_url
+            account_url,
+            account_email: account_email.to_string(),
+            settings: settings.clone(),
         })
     }
 
-    /// This method will try to find a certificate for the given name in the .odd_box_cache/lets_encrypt directory
-    /// before attempting to create a new certificate via lets-encrypt.
-    pub async fn get_or_create_cert(&self, domain_name: &str) -> anyhow::Result<CertifiedKey> {
+    fn certificate_dir(&self, domain_name: &str) -> PathBuf {
+        self.settings.storage_dir().join("certificates").join(domain_name)
+    }
 
-        let odd_cache_base = ".odd_box_cache/lets_encrypt";
+    /// The certificate stored for the domain by an earlier run, as long as it has not expired.
+    pub fn load_stored_cert(&self, domain_name: &str) -> anyhow::Result<Option<CertifiedKey>> {
+        let dir = self.certificate_dir(domain_name);
+        let (cert_file_path, key_file_path) = (dir.join(CERTIFICATE_FILE), dir.join(PRIVATE_KEY_FILE));
+        if !cert_file_path.exists() || !key_file_path.exists() {
+            return Ok(None)
+        }
+        tracing::trace!("Certificate and key already exist for domain: {}", domain_name);
+        let certified_key = certified_key_from_pem(std::fs::read_to_string(&cert_file_path)?, std::fs::read_to_string(&key_file_path)?)
+            .context(format!("loading {}", cert_file_path.display()))?;
+        match certificate_not_after(&certified_key) {
+            Some(not_after) if not_after > unix_now() => Ok(Some(certified_key)),
+            _ => {
+                tracing::warn!("The stored certificate for {domain_name} has expired. Will generate a new one.");
+                Ok(None)
+            }
+        }
+    }
 
-        let base_path = std::path::Path::new(odd_cache_base);
-        let host_name_cert_path = base_path.join(domain_name);
+    /// Orders a new certificate for the domain and stores it for later runs.
+    /// With include_wildcard the certificate also covers *.domain_name, which is only possible using the dns-01 challenge.
+    pub async fn issue_cert(&self, domain_name: &str, challenge_type: AcmeChallenge, include_wildcard: bool, dns_provider: Option<&dyn DnsProvider>) -> anyhow::Result<CertifiedKey> {
 
         let mut i = 0;
         while let Some(_pending_challenge) = DOMAIN_TO_CHALLENGE_TOKEN_MAP.get(domain_name) {
@@ -191,87 +340,61 @@ impl LECertManager {
             }    
         }
 
-        if let Err(e) = std::fs::create_dir_all(&host_name_cert_path) {
-            anyhow::bail!("Could not create directory: {:?}", e);
+        let mut names = vec![domain_name.to_string()];
+        if include_wildcard {
+            names.push(format!("*.{domain_name}"));
         }
 
-        let cert_file_path = format!("{odd_cache_base}/{domain_name}/{domain_name}.crt");
-        let key_file_path = format!("{odd_cache_base}/{domain_name}/{domain_name}.key");
-        
-        let mut skip_validation = false;
-
-        if std::path::Path::new(&cert_file_path).exists() && std::path::Path::new(&key_file_path).exists() {
+        tracing::trace!("Creating a new certificate for domain: {}", domain_name);
+        let issued = self.order_cert(domain_name, &names, challenge_type, dns_provider).await;
 
-            tracing::trace!("Certificate and key already exist for domain: {}", domain_name);
-            let crt_string = std::fs::read_to_string(&cert_file_path)?;
-            let key_string = std::fs::read_to_string(&key_file_path)?;
+        // Clean up the challenge cache for this domain, also when the order failed so that the next attempt starts over
+        if let Some((_k,v)) = DOMAIN_TO_CHALLENGE_TOKEN_MAP.remove(domain_name) {
+            CHALLENGE_MAP.remove(&v);
+        }
+        TLS_ALPN_CHALLENGE_CERTS.remove(domain_name);
 
-            let cert_chain = crate::certs::extract_cert_from_pem_str(crt_string)?;
-            let private_key = crate::certs::extract_priv_key_from_pem(key_string)?;
+        let (the_new_cert, the_new_key) = issued?;
+        let dir = self.certificate_dir(domain_name);
+        std::fs::create_dir_all(&dir).context(format!("Could not create directory: {}", dir.display()))?;
+        write_atomically(&dir.join(CERTIFICATE_FILE), the_new_cert.as_bytes(), false)?;
+        write_atomically(&dir.join(PRIVATE_KEY_FILE), the_new_key.as_bytes(), true)?;
+        tracing::trace!("Certificate and key saved to disk for domain: {}. Path: {}", domain_name, dir.display());
 
-            
-            let rsa_signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
-                .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
-
-            let certified_key = CertifiedKey::new(cert_chain, rsa_signing_key);
-
-            let ccc = X509Certificate::from_der(&*certified_key.end_entity_cert().unwrap()).unwrap();
-            match ccc.1.tbs_certificate.validity.time_to_expiration() {
-                Some(v)  => {
-                    let days = v.whole_days();
-                    if days < 89 {
-                        tracing::warn!("Generating a new cert for {domain_name} due to less than 30 days remaining: {days} days.");
-                        skip_validation = true;
-                    } else {
-                        tracing::warn!("The certificate for {domain_name} is valid for {days} days. Will keep using!");
-                        return Ok(certified_key);
-                    }
-                },
-                None => {
-                    tracing::warn!("The certificate for {domain_name} has expired. Will generate a new one.");
-                }
-            }
-
-            
-        }
+        certified_key_from_pem(the_new_cert, the_new_key)
+    }
 
-        tracing::trace!("Certificate not found, creating a new certificate for domain: {}", domain_name);
+    /// Takes an order thru its challenges and returns the new certificate chain and private key, both as pem.
+    async fn order_cert(&self, domain_name: &str, names: &[String], challenge_type: AcmeChallenge, dns_provider: Option<&dyn DnsProvider>) -> anyhow::Result<(String,String)> {
 
-        let (auth_url,finalize_url,order_url) = self.create_order(&self.directory,  domain_name).await.context("create order failed")?;
+        let (auth_urls,finalize_url,order_url) = self.create_order(&self.directory, names).await.context("create order failed")?;
 
-        if skip_validation {
-            tracing::warn!("Skipping challenge validation for domain {} as we have already completed challenges once.", domain_name);
-        } else {
-            tracing::trace!("Calling handle_http_01_challenge method with URL: {}", auth_url);
-            let challenge_url = self.handle_http_01_challenge(&auth_url,domain_name).await?;
-            tracing::trace!("Challenge accepted, waiting for order to be valid - url: {}", challenge_url);
-            self.poll_order_status_util_valid(&challenge_url).await?;
+        // one authorization per identifier, so the wildcard gets its own (dns-01) challenge.
+        // authorizations that are still valid from earlier orders are not validated again.
+        let mut dns_records = vec![];
+        let validated = async {
+            for auth_url in &auth_urls {
+                tracing::trace!("Calling handle_challenge method with URL: {}", auth_url);
+                let challenge_url = self.handle_challenge(auth_url,domain_name,challenge_type,dns_provider,&mut dns_records).await?;
+                tracing::trace!("Challenge accepted, waiting for order to be valid - url: {}", challenge_url);
+                self.poll_order_status_util_valid(&challenge_url).await?;
+            }
+            anyhow::Ok(())
+        }.await;
+        if let Some(provider) = dns_provider {
+            for (record_name, record_value) in dns_records {
+                if let Err(e) = provider.cleanup(&record_name, &record_value).await {
+                    tracing::warn!("Failed to remove the dns-01 challenge record {record_name}: {e:?}");
+                }
+            }
         }
+        validated?;
 
-        let priv_key = self.finalize_order(&finalize_url, domain_name).await.context("finalizing the order of a new cert")?;
+        let priv_key = self.finalize_order(&finalize_url, names).await.context("finalizing the order of a new cert")?;
         self.poll_order_status_util_valid(&order_url).await?;
 
         let the_new_cert = self.fetch_certificate(&order_url).await.context("fetching new certificate")?;
-        let the_new_key = priv_key.serialize_pem();
-        
-        std::fs::write(&cert_file_path, &the_new_cert)?;
-        std::fs::write(&key_file_path, &the_new_key)?;
-        
-        // Clean up the challenge cache for this domain
-        if let Some((_k,v)) = DOMAIN_TO_CHALLENGE_TOKEN_MAP.remove(domain_name) {
-            CHALLENGE_MAP.remove(&v);
-        }
-        
-        tracing::trace!("Certificate and key saved to disk for domain: {}. Path: {}", domain_name, key_file_path);
-
-        let cert_chain = crate::certs::extract_cert_from_pem_str(the_new_cert)?;
-        let private_key = crate::certs::extract_priv_key_from_pem(the_new_key)?;
-
-        let rsa_signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
-            .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
-        let certified_key = CertifiedKey::new(cert_chain, rsa_signing_key);
-
-        Ok(certified_key)
+        Ok((the_new_cert, priv_key.serialize_pem()))
     }
     
 
@@ -283,15 +406,15 @@ impl LECertManager {
         Ok(json)
     }
 
-    /// returns (auth_url,finalize_url,order_url)
-    async fn create_order(&self, directory: &Directory, domain: &str) -> anyhow::Result<(String,String,String)> {
+    /// returns (auth_urls,finalize_url,order_url), there is one authorization url for each of the given names.
+    async fn create_order(&self, directory: &Directory, names: &[String]) -> anyhow::Result<(Vec<String>,String,String)> {
         
         let nonce = Self::fetch_nonce(&self.client,&directory.new_nonce).await?;
         let payload = json!({
-            "identifiers": [{
+            "identifiers": names.iter().map(|name| json!({
                 "type": "dns",
-                "value": domain,
-            }]
+                "value": name,
+            })).collect::<Vec<Value>>()
         });
 
         let signed_request = Self::sign_request(&self.acc_certified_key, Some(&payload), &nonce, &directory.new_order, self.account_url.as_ref())?;
@@ -319,20 +442,21 @@ impl LECertManager {
                 .ok_or_else(|| anyhow::anyhow!("Finalize URL not found"))?
                 .to_string();
 
-            let auth_url = body["authorizations"]
-                .get(0)
-                .and_then(|url| url.as_str())
-                .ok_or_else(|| anyhow::anyhow!("Authorization URL not found"))?
-                .to_string();
+            let auth_urls = body["authorizations"]
+                .as_array()
+                .map(|urls| urls.iter().filter_map(|url| url.as_str()).map(|url| url.to_string()).collect::<Vec<String>>())
+                .filter(|urls| !urls.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Authorization URL not found"))?;
 
             tracing::trace!("LE Order URL: {}", order_url);
-            Ok((auth_url,finalize_url,order_url))
+            Ok((auth_urls,finalize_url,order_url))
         } else {
             anyhow::bail!("Failed to create order")
         }
     }
 
-    async fn handle_http_01_challenge(&self, auth_url: &str, domain_name:&str) -> anyhow::Result<String> {
+    /// Records created for dns-01 challenges are added to dns_records, it is up to the caller to remove them once done.
+    async fn handle_challenge(&self, auth_url: &str, domain_name:&str, challenge_type: AcmeChallenge, dns_provider: Option<&dyn DnsProvider>, dns_records: &mut Vec<(String,String)>) -> anyhow::Result<String> {
         
         tracing::trace!("Calling LE challenge url: {}", auth_url);
 
@@ -346,30 +470,25 @@ impl LECertManager {
             anyhow::bail!("Failed to fetch authorization details");
         }
         let body: serde_json::Value = res.json().await?;
+        let challenge_name = challenge_type.acme_name();
         let challenge = body["challenges"]
             .as_array()
             .and_then(|challenges| {
             challenges
                 .iter()
-                .find(|ch| ch["type"] == "http-01")
+                .find(|ch| ch["type"] == challenge_name)
             })
-            .ok_or_else(|| anyhow::Error::msg("No HTTP-01 challenge found"))?;
+            .ok_or_else(|| anyhow::anyhow!("No {challenge_name} challenge found"))?;
 
         if let Some(status) = challenge.get("status") {
             if status == "valid" {
-                if let Some(url) = body.get("challenges")
-                    .and_then(|challenges| challenges.get(0))
-                    .and_then(|challenge| challenge.get("validationRecord"))
-                    .and_then(|validation_record| validation_record.get(0))
-                    .and_then(|record| record.get("url"))
-                    .and_then(|url| url.as_str())
-                {
+                if let Some(url) = challenge.get("url").and_then(|url| url.as_str()) {
                     return Ok(url.to_string());
                 } else {
                     return Err(anyhow::anyhow!("URL not found in the expected JSON structure"));
                 }
             } else {
-                tracing::trace!("Got new http-01 challenge with status {:?}", status);
+                tracing::trace!("Got new {challenge_name} challenge with status {:?}", status);
             }
         } else {
             tracing::trace!("Challenge status not found in JSON");
@@ -382,9 +501,30 @@ impl LECertManager {
 
         let key_authorization = self.generate_key_authorization(token)?;
 
-        tracing::trace!("saving challenge token {:?} and key auth {:?}", token,key_authorization);
- 
-        CHALLENGE_MAP.insert(token.to_string(), key_authorization.clone());
+        match challenge_type {
+            AcmeChallenge::Http01 => {
+                tracing::trace!("saving challenge token {:?} and key auth {:?}", token,key_authorization);
+                CHALLENGE_MAP.insert(token.to_string(), key_authorization.clone());
+            },
+            AcmeChallenge::TlsAlpn01 => {
+                tracing::trace!("creating tls-alpn-01 validation certificate for {domain_name}");
+                let validation_cert = Self::create_tls_alpn_01_cert(domain_name, &key_authorization)?;
+                TLS_ALPN_CHALLENGE_CERTS.insert(domain_name.to_string(), Arc::new(validation_cert));
+            },
+            AcmeChallenge::Dns01 => {
+                use sha2::Digest;
+                let provider = dns_provider.ok_or_else(|| anyhow::anyhow!("The Dns01 challenge requires a dns_provider to be configured"))?;
+                // wildcard authorizations are for the base domain, so both share the same record name
+                let identifier = body["identifier"]["value"].as_str().unwrap_or(domain_name);
+                let record_name = format!("_acme-challenge.{identifier}");
+                let record_value = general_purpose::URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(key_authorization.as_bytes()));
+                provider.present(&record_name, &record_value).await.context(format!("creating the dns-01 challenge record {record_name}"))?;
+                dns_records.push((record_name.clone(), record_value));
+                let delay = provider.propagation_delay();
+                tracing::info!("Created {record_name}, waiting {} seconds for it to propagate.", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+        }
         
         tracing::trace!("storing challenge for host: {}", domain_name);
         
@@ -473,12 +613,29 @@ impl LECertManager {
     }
     
     
-    fn create_csr(domain:&str) -> anyhow::Result<(String,KeyPair)> {
+    /// The self-signed certificate that we present to lets-encrypt when it connects with the acme-tls/1 alpn protocol.
+    /// It must be for the domain being validated and carry the sha-256 digest of the key authorization in a critical
+    /// acmeIdentifier extension (RFC 8737).
+    pub(crate) fn create_tls_alpn_01_cert(domain:&str, key_authorization:&str) -> anyhow::Result<CertifiedKey> {
+        use sha2::Digest;
+        let key_pair = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![domain.to_string()])?;
+        let digest = sha2::Sha256::digest(key_authorization.as_bytes());
+        params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(&digest)];
+        let cert = params.self_signed(&key_pair)?;
+
+        let private_key = rustls::pki_types::PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
+        let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
+            .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
+        Ok(CertifiedKey::new(vec![cert.der().clone()], signing_key))
+    }
+
+    fn create_csr(names:&[String]) -> anyhow::Result<(String,KeyPair)> {
         let key_pair = KeyPair::generate()?;
 
-        let mut params = CertificateParams::default();
+        let mut params = CertificateParams::new(names.to_vec())?;
         params.distinguished_name = DistinguishedName::new();
-        params.distinguished_name.push(DnType::CommonName, domain);
+        params.distinguished_name.push(DnType::CommonName, names.first().map(|x| x.as_str()).unwrap_or_default());
 
         let serialized = params.serialize_request(&key_pair)?;
 
@@ -496,10 +653,10 @@ impl LECertManager {
 
     }
         
-    async fn finalize_order(&self, finalize_url: &str, domain_name:&str) -> anyhow::Result<KeyPair> {
+    async fn finalize_order(&self, finalize_url: &str, names:&[String]) -> anyhow::Result<KeyPair> {
 
         let nonce = Self::fetch_nonce(&self.client, &self.directory.new_nonce).await?;
-        let (csr,kvp) = Self::create_csr(domain_name)?; 
+        let (csr,kvp) = Self::create_csr(names)?; 
 
         let payload = json!({
             "csr": csr
@@ -691,7 +848,120 @@ impl LECertManager {
 
 }
 
+/// Tells if the certificate lists all of the given names among its subject alternative names.
+fn certificate_has_names(cert: &CertifiedKey, names: &[&str]) -> bool {
+    let Some(der) = cert.end_entity_cert().ok() else { return false };
+    let Ok((_, cert)) = X509Certificate::from_der(&*der) else { return false };
+    let Ok(Some(san)) = cert.subject_alternative_name() else { return false };
+    names.iter().all(|name| san.value.general_names.iter().any(|n| {
+        matches!(n, x509_parser::extensions::GeneralName::DNSName(n) if n.eq_ignore_ascii_case(name))
+    }))
+}
+
+/// The notAfter of the end entity certificate, as a unix timestamp.
+pub fn certificate_not_after(cert: &CertifiedKey) -> Option<i64> {
+    let der = cert.end_entity_cert().ok()?;
+    let (_, cert) = X509Certificate::from_der(&*der).ok()?;
+    Some(cert.tbs_certificate.validity.not_after.timestamp())
+}
+
+fn certified_key_from_pem(cert_pem: String, key_pem: String) -> anyhow::Result<CertifiedKey> {
+    let cert_chain = crate::certs::extract_cert_from_pem_str(cert_pem)?;
+    let private_key = crate::certs::extract_priv_key_from_pem(key_pem)?;
+    let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&private_key)
+        .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
+    Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+/// Tells if a certificate that expires at not_after should be renewed at the time now.
+pub fn is_due_for_renewal(not_after: i64, now: i64, renew_before_days: u64) -> bool {
+    not_after - now < (renew_before_days as i64).saturating_mul(24 * 60 * 60)
+}
+
+/// How long to wait before the next attempt after the given number of consecutive failures.
+/// The delay doubles with each failure up to a day, and jitter (0 to 1) spreads it by 20% either way
+/// so that domains which failed together do not keep retrying together.
+pub fn retry_delay(consecutive_failures: u32, jitter: f64) -> Duration {
+    let doublings = consecutive_failures.saturating_sub(1).min(16);
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << doublings).min(RETRY_MAX_DELAY);
+    delay.mul_f64(0.8 + 0.4 * jitter.clamp(0.0, 1.0))
+}
+
+/// Writes to a temporary file that is then renamed, so that a crash never leaves a truncated certificate or key behind.
+fn write_atomically(path: &Path, contents: &[u8], private: bool) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(&temp_path).context(format!("creating {}", temp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path).context(format!("replacing {}", path.display()))?;
+    Ok(())
+}
 
+/// Moves the account and certificates from where versions that only supported lets-encrypt production kept them.
+fn migrate_legacy_storage(storage_dir: &Path) -> anyhow::Result<()> {
+    let legacy_key = Path::new(".odd_box_cache/lets_encrypt_account.key");
+    if !legacy_key.exists() || storage_dir.join(ACCOUNT_KEY_FILE).exists() {
+        return Ok(())
+    }
+    tracing::info!("Moving the lets-encrypt account and certificates to {}", storage_dir.display());
+    std::fs::create_dir_all(storage_dir.join("certificates"))?;
+    std::fs::rename(legacy_key, storage_dir.join(ACCOUNT_KEY_FILE))?;
+    let legacy_url = Path::new(".odd_box_cache/lets_encrypt_account_url");
+    if legacy_url.exists() {
+        std::fs::rename(legacy_url, storage_dir.join(ACCOUNT_URL_FILE))?;
+    }
+    for entry in std::fs::read_dir(".odd_box_cache/lets_encrypt").into_iter().flatten().flatten() {
+        let domain = entry.file_name().to_string_lossy().to_string();
+        let (cert, key) = (entry.path().join(format!("{domain}.crt")), entry.path().join(format!("{domain}.key")));
+        if cert.exists() && key.exists() {
+            let dir = storage_dir.join("certificates").join(&domain);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::rename(cert, dir.join(CERTIFICATE_FILE))?;
+            std::fs::rename(key, dir.join(PRIVATE_KEY_FILE))?;
+        }
+    }
+    Ok(())
+}
+
+/// Schedules the next attempt for a domain whose certificate could not be issued,
+/// and sends a warning event once it has failed too many times in a row.
+fn record_renewal_failure(state: &GlobalState, domain_name: &str, not_after: Option<i64>, error: &anyhow::Error) {
+    let jitter = uuid::Uuid::new_v4().as_u128() as f64 / u128::MAX as f64;
+    let mut status = FAILING_RENEWALS.entry(domain_name.to_string()).or_default();
+    status.consecutive_failures += 1;
+    let delay = retry_delay(status.consecutive_failures, jitter);
+    status.next_attempt = unix_now() + delay.as_secs() as i64;
+    status.last_error = format!("{error:#}");
+    let consecutive_failures = status.consecutive_failures;
+    drop(status);
+
+    tracing::error!("Failed to generate certificate for domain: {}. Will try again in {} minutes. {error:?}", domain_name, delay.as_secs() / 60);
+    if consecutive_failures >= RENEWAL_WARNING_THRESHOLD {
+        let expires = not_after.map(|t| format!(" and expires in {} days", (t - unix_now()) / 86400)).unwrap_or_default();
+        tracing::warn!("The certificate for {domain_name} has failed to renew {consecutive_failures} times in a row{expires}.");
+        _ = state.global_broadcast_channel.send(GlobalEvent::CertificateRenewalFailing(CertificateRenewalEvent {
+            domain: domain_name.to_string(),
+            consecutive_failures,
+            not_after,
+            error: format!("{error:#}")
+        }));
+    }
+}
 
 pub async fn bg_worker_for_lets_encrypt_certs(state: Arc<GlobalState>) {
     let liveness_token = Arc::new(true);
@@ -709,9 +979,10 @@ pub async fn bg_worker_for_lets_encrypt_certs(state: Arc<GlobalState>) {
 
         tokio::time::sleep(Duration::from_secs(5)).await;
         {
-            let mail = {
-                if let Some(e) = state.config.read().await.lets_encrypt_account_email.clone() {
-                    e
+            let (mail, acme_settings) = {
+                let config_guard = state.config.read().await;
+                if let Some(e) = config_guard.lets_encrypt_account_email.clone() {
+                    (e, AcmeSettings::from_config(&config_guard))
                 } else {
                     
                     crate::BG_WORKER_THREAD_MAP.insert("Lets Encrypt".into(), BgTaskInfo {
@@ -725,8 +996,18 @@ pub async fn bg_worker_for_lets_encrypt_certs(state: Arc<GlobalState>) {
             };
 
             let mut lem_guard = state.cert_resolver.lets_encrypt_manager.write().await;
+            if let Some(current) = lem_guard.as_ref() {
+                if current.account_email != mail || current.settings != acme_settings {
+                    tracing::info!("The acme account settings have changed, setting up the account at {} again.", acme_settings.directory_url());
+                    // certificates from another CA are not what the configuration asks for anymore
+                    if current.settings.directory_url() != acme_settings.directory_url() {
+                        state.cert_resolver.clear_lets_encrypt_signed_certs();
+                    }
+                    lem_guard.take();
+                }
+            }
             if lem_guard.is_none() {
-                match LECertManager::new(&mail).await {
+                match LECertManager::new(&mail, &acme_settings).await {
                     Ok(v) => lem_guard.replace(v) ,
                     Err(e) => {
                         tracing::warn!("Failed to create lets-encrypt manager: {e:?}");
@@ -743,57 +1024,112 @@ pub async fn bg_worker_for_lets_encrypt_certs(state: Arc<GlobalState>) {
             // TODO: should filter out local sites so we do not try to create certs for things like test.localhost or test.localtest.me etc.
             //       but instead write a warning about it.
             let state_guard = state.config.read().await;
+            let global_challenge = state_guard.lets_encrypt_challenge.unwrap_or(AcmeChallenge::Http01);
+            // (host name, challenge, include wildcard) - sites capturing subdomains get a wildcard certificate when using dns-01
+            let site_for = |host_name: &String, site_challenge: Option<AcmeChallenge>, capture_subdomains: Option<bool>| {
+                let challenge = site_challenge.unwrap_or(global_challenge);
+                (host_name.clone(), challenge, challenge == AcmeChallenge::Dns01 && capture_subdomains.unwrap_or_default())
+            };
             let mut all_sites_with_lets_encrypt_enabled = 
                 state_guard.remote_target
                     .iter()
                     .flatten()
-                    .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|x.host_name.clone())
+                    .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|site_for(&x.host_name,x.lets_encrypt_challenge,x.capture_subdomains))
                 .chain(
                     state_guard.hosted_process
                         .iter()
                         .flatten()
-                        .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|x.host_name.clone())
+                        .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|site_for(&x.host_name,x.lets_encrypt_challenge,x.capture_subdomains))
                 ).chain(
                     state_guard.dir_server
                         .iter()
                         .flatten()
-                        .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|x.host_name.clone())
-                ).collect::<Vec<String>>();
+                        .filter(|x|x.enable_lets_encrypt.unwrap_or(false)).map(|x|site_for(&x.host_name,x.lets_encrypt_challenge,x.capture_subdomains))
+                ).collect::<Vec<(String,AcmeChallenge,bool)>>();
             
             if let Some(ourl) = state_guard.odd_box_url.as_ref() {
-                all_sites_with_lets_encrypt_enabled.push(ourl.clone());
+                all_sites_with_lets_encrypt_enabled.push((ourl.clone(),global_challenge,false));
             }
 
             // todo : we should probably just do a dns lookup to see if local instead of this shit
-            all_sites_with_lets_encrypt_enabled.retain(|x| { 
+            all_sites_with_lets_encrypt_enabled.retain(|(x,_,_)| { 
                 !(x.ends_with(".localtest.me") || x.ends_with(".localhost") || x == "localhost")
             });
 
+            let renew_before_days = state_guard.acme_renew_before_days.unwrap_or(DEFAULT_RENEW_BEFORE_DAYS);
+
+            let dns_provider = match state_guard.dns_provider.as_ref().map(crate::dns_provider::from_config) {
+                Some(Ok(provider)) => Some(provider),
+                Some(Err(e)) => {
+                    tracing::warn!("The dns_provider is not usable, Dns01 certificates will fail: {e:?}");
+                    None
+                },
+                None => None
+            };
+
             drop(state_guard);
 
             
 
-            all_sites_with_lets_encrypt_enabled.sort();
-            all_sites_with_lets_encrypt_enabled.dedup();    
+            all_sites_with_lets_encrypt_enabled.sort_by(|a,b| a.0.cmp(&b.0));
+            all_sites_with_lets_encrypt_enabled.dedup_by(|a,b| a.0 == b.0);    
+            FAILING_RENEWALS.retain(|domain,_| all_sites_with_lets_encrypt_enabled.iter().any(|(x,_,_)| x == domain));
 
             let guard = state.cert_resolver.lets_encrypt_manager.read().await;
             
             if let Some(mgr) = guard.as_ref() {
-                for domain_name in all_sites_with_lets_encrypt_enabled {
+                for (domain_name,challenge_type,include_wildcard) in all_sites_with_lets_encrypt_enabled {
                 
-                    if let Some(_) = state.cert_resolver.get_lets_encrypt_signed_cert_from_mem_cache(&domain_name) {
-                        tracing::info!("LE CERT IS OK FOR: {}", domain_name);
+                    let wildcard_name = format!("*.{domain_name}");
+                    let mut names = vec![domain_name.as_str()];
+                    if include_wildcard {
+                        names.push(&wildcard_name);
+                    }
+
+                    // certificates issued before a restart are picked up from disk
+                    if state.cert_resolver.get_lets_encrypt_signed_cert_from_mem_cache(&domain_name).is_none() {
+                        match mgr.load_stored_cert(&domain_name) {
+                            Ok(Some(v)) => {
+                                if include_wildcard && certificate_has_names(&v, &names) {
+                                    state.cert_resolver.add_lets_encrypt_signed_cert_to_mem_cache(&wildcard_name, v.clone());
+                                }
+                                state.cert_resolver.add_lets_encrypt_signed_cert_to_mem_cache(&domain_name, v);
+                            },
+                            Ok(None) => {},
+                            Err(e) => tracing::warn!("Failed to load the stored certificate for {domain_name}: {e:?}")
+                        }
+                    }
+
+                    // the current certificate keeps being used until its replacement has been issued
+                    let now = unix_now();
+                    let current = state.cert_resolver.get_lets_encrypt_signed_cert_from_mem_cache(&domain_name);
+                    let not_after = current.as_deref().and_then(certificate_not_after);
+                    match (&current, not_after) {
+                        (Some(cert), Some(not_after)) if certificate_has_names(cert, &names) && !is_due_for_renewal(not_after, now, renew_before_days) => {
+                            tracing::trace!("The certificate for {domain_name} is valid for {} more days.", (not_after - now) / 86400);
+                            FAILING_RENEWALS.remove(&domain_name);
+                            continue;
+                        },
+                        (Some(_), Some(not_after)) => {
+                            tracing::info!("Renewing the certificate for {domain_name}, it expires in {} days.", (not_after - now) / 86400);
+                        },
+                        _ => {}
+                    }
+
+                    if FAILING_RENEWALS.get(&domain_name).is_some_and(|x| x.next_attempt > now) {
                         continue;
                     }
                     
-                    match mgr.get_or_create_cert(&domain_name).await.context(format!("generating lets-encrypt cert for site {}",domain_name)) {
+                    match mgr.issue_cert(&domain_name,challenge_type,include_wildcard,dns_provider.as_deref()).await.context(format!("generating lets-encrypt cert for site {}",domain_name)) {
                         Ok(v) => {
+                            if include_wildcard {
+                                state.cert_resolver.add_lets_encrypt_signed_cert_to_mem_cache(&wildcard_name, v.clone());
+                            }
                             state.cert_resolver.add_lets_encrypt_signed_cert_to_mem_cache(&domain_name, v);  
+                            FAILING_RENEWALS.remove(&domain_name);
                             generated_count += 1;         
                         }
-                        Err(e) => {
-                            tracing::error!("Failed to generate certificate for domain: {}. {e:?}", domain_name);
-                        } 
+                        Err(e) => record_renewal_failure(&state, &domain_name, not_after, &e)
                     }
                         
                 
@@ -806,7 +1142,7 @@ pub async fn bg_worker_for_lets_encrypt_certs(state: Arc<GlobalState>) {
 
             crate::BG_WORKER_THREAD_MAP.insert("Lets Encrypt".into(), BgTaskInfo {
                 liveness_ptr: Arc::downgrade(&liveness_token),
-                status: format!("Generated: {generated_count} - Pending: {active_challenges_count}.")
+                status: format!("Generated: {generated_count} - Pending: {active_challenges_count} - Failing: {}.", FAILING_RENEWALS.len())
             }); // we dont need to clean this up if we exit, there is a cleanup task that will do it.
         
             drop(guard);
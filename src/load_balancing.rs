@@ -0,0 +1,192 @@
+// Backend selection for remote sites.
+// The same selectors are used when tunnelling tcp connections and when terminating http requests,
+// the only difference being that tunnelled connections have no request headers to look at.
+
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::configuration::{Backend, LoadBalancing, RemoteSiteConfig};
+use crate::global_state::GlobalState;
+use crate::types::proxy_state::{BackendHealthKey, OutgoingTunnelType};
+
+/// Information about the incoming connection or request that selectors may use to pick a backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SelectionContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    /// Only available when we are terminating http.
+    pub headers: Option<&'a hyper::HeaderMap>,
+    /// Backends that must not be picked, such as ones that already failed the current request.
+    pub excluded: &'a [Backend],
+}
+
+impl<'a> SelectionContext<'a> {
+    pub fn from_client_ip(client_ip: IpAddr) -> Self {
+        Self { client_ip: Some(client_ip), headers: None, excluded: &[] }
+    }
+}
+
+pub trait BackendSelector {
+    /// Picks one of the candidates, which have already been filtered for health and protocol support.
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], ctx: &SelectionContext) -> Option<&'b Backend>;
+}
+
+pub fn selector_for(strategy: Option<&LoadBalancing>) -> &'static (dyn BackendSelector + Send + Sync) {
+    match strategy {
+        None | Some(LoadBalancing::RoundRobin) => &RoundRobin,
+        Some(LoadBalancing::WeightedRoundRobin) => &WeightedRoundRobin,
+        Some(LoadBalancing::LeastConnections) => &LeastConnections,
+        Some(LoadBalancing::RandomOfTwo) => &RandomOfTwo,
+        Some(LoadBalancing::ConsistentHash) => &ConsistentHash,
+    }
+}
+
+fn access_count(state: &GlobalState, host_name: &str) -> usize {
+    state.app_state.statistics.lb_access_count_per_hostname
+        .get(host_name)
+        .map(|x| x.load(Ordering::SeqCst))
+        .unwrap_or(0)
+}
+
+/// Counts tunnels from active_connections plus terminated requests that are still waiting on a response.
+fn connection_counts(state: &GlobalState, host_name: &str, candidates: &[&Backend]) -> Vec<usize> {
+    let mut counts = vec![0; candidates.len()];
+    for guard in state.app_state.statistics.active_connections.iter() {
+        let (target, backend) = match &guard.value().outgoing_tunnel_type {
+            Some(OutgoingTunnelType::TLS(t, b)) | Some(OutgoingTunnelType::Raw(t, b)) => (t, b),
+            _ => continue
+        };
+        if target.host_name != host_name { continue }
+        if let Some(i) = candidates.iter().position(|c| c.address == backend.address && c.port == backend.port) {
+            counts[i] += 1;
+        }
+    }
+    for (i, c) in candidates.iter().enumerate() {
+        if let Some(n) = state.app_state.statistics.in_flight_requests_per_backend.get(&crate::health_check::backend_key(host_name, c)) {
+            counts[i] += n.load(Ordering::SeqCst);
+        }
+    }
+    counts
+}
+
+fn random_u64() -> u64 {
+    // RandomState is seeded randomly per thread and bumped for each instance, which is plenty for spreading load.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    std::time::SystemTime::now().hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct RoundRobin;
+impl BackendSelector for RoundRobin {
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], _ctx: &SelectionContext) -> Option<&'b Backend> {
+        if candidates.is_empty() { return None }
+        candidates.get(access_count(state, &site.host_name) % candidates.len()).copied()
+    }
+}
+
+pub struct WeightedRoundRobin;
+impl BackendSelector for WeightedRoundRobin {
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], ctx: &SelectionContext) -> Option<&'b Backend> {
+        let total : usize = candidates.iter().map(|b| b.weight.unwrap_or(1) as usize).sum();
+        if total == 0 {
+            return RoundRobin.select(state, site, candidates, ctx)
+        }
+        let mut n = access_count(state, &site.host_name) % total;
+        for b in candidates {
+            let w = b.weight.unwrap_or(1) as usize;
+            if n < w { return Some(*b) }
+            n -= w;
+        }
+        None
+    }
+}
+
+pub struct LeastConnections;
+impl BackendSelector for LeastConnections {
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], ctx: &SelectionContext) -> Option<&'b Backend> {
+        let counts = connection_counts(state, &site.host_name, candidates);
+        let least = *counts.iter().min()?;
+        // spread ties using the round-robin counter so that idle backends do not all go to the first one
+        let tied : Vec<&'b Backend> = candidates.iter().zip(counts.iter())
+            .filter(|(_, c)| **c == least)
+            .map(|(b, _)| *b)
+            .collect();
+        RoundRobin.select(state, site, &tied, ctx)
+    }
+}
+
+pub struct RandomOfTwo;
+impl BackendSelector for RandomOfTwo {
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], _ctx: &SelectionContext) -> Option<&'b Backend> {
+        match candidates.len() {
+            0 => None,
+            1 => Some(candidates[0]),
+            len => {
+                let r = random_u64();
+                let a = (r % len as u64) as usize;
+                // pick the second one among the remaining backends so that we never compare a backend with itself
+                let b = (a + 1 + ((r >> 32) % (len as u64 - 1)) as usize) % len;
+                let pair = [candidates[a], candidates[b]];
+                let counts = connection_counts(state, &site.host_name, &pair);
+                Some(if counts[1] < counts[0] { pair[1] } else { pair[0] })
+            }
+        }
+    }
+}
+
+/// Rendezvous hashing: each backend gets a score based on the key and the one with the highest score wins.
+/// When a backend goes away only the clients that were mapped to it are moved elsewhere.
+pub struct ConsistentHash;
+impl BackendSelector for ConsistentHash {
+    fn select<'b>(&self, state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], ctx: &SelectionContext) -> Option<&'b Backend> {
+        let header_value = site.consistent_hash_header.as_ref()
+            .and_then(|name| ctx.headers.and_then(|h| h.get(name.as_str())))
+            .map(|v| v.as_bytes().to_vec());
+        let key = match (header_value, ctx.client_ip) {
+            (Some(v), _) => v,
+            (None, Some(ip)) => ip.to_string().into_bytes(),
+            (None, None) => return RoundRobin.select(state, site, candidates, ctx)
+        };
+        candidates.iter().max_by_key(|b| fnv1a(&[&key, b.address.as_bytes(), &b.port.to_be_bytes()])).copied()
+    }
+}
+
+/// 64-bit FNV-1a over the given parts. Unlike the hashers in std, its output is fixed, so clients keep being sent
+/// to the same backend after restarts and upgrades of odd-box.
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        // the length is mixed in so that moving bytes from one part to the next changes the hash
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Counts a terminated request towards its backend for as long as it is alive.
+pub struct InFlightRequest {
+    state: Arc<GlobalState>,
+    key: BackendHealthKey
+}
+
+impl InFlightRequest {
+    pub fn new(state: Arc<GlobalState>, host_name: &str, backend: &Backend) -> Self {
+        let key = crate::health_check::backend_key(host_name, backend);
+        state.app_state.statistics.in_flight_requests_per_backend
+            .entry(key.clone())
+            .or_insert_with(|| AtomicUsize::new(0))
+            .fetch_add(1, Ordering::SeqCst);
+        Self { state, key }
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        if let Some(n) = self.state.app_state.statistics.in_flight_requests_per_backend.get(&self.key) {
+            n.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
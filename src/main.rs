@@ -62,8 +62,24 @@ use types::app_state::AppState;
 use lazy_static::lazy_static;
  
 mod letsencrypt;
+mod dns_provider;
+mod custom_certs;
 mod custom_servers;
 mod docker;
+mod health_check;
+mod load_balancing;
+mod session_affinity;
+mod outlier_detection;
+mod access_log;
+mod metrics;
+mod rate_limiting;
+mod access_control;
+mod proxy_protocol;
+mod stream_proxy;
+mod shutdown;
+mod hot_upgrade;
+mod timeouts;
+mod compression;
 
 #[cfg(test)]
 mod tests;
@@ -138,7 +154,7 @@ pub mod global_state {
         // returns None if the target does not match fully or subdomain. 
         // returns Some(Some(subdomain_name)) if the target matches the subdomain
         // returns Some(None) if the target matches fully
-        fn filter_fun(req_host_name:&str,target_host_name:&str,allow_subdomains:bool) -> Option<Option<String>> {
+        pub fn filter_fun(req_host_name:&str,target_host_name:&str,allow_subdomains:bool) -> Option<Option<String>> {
             
             let parsed_name = if req_host_name.contains(":") {
                 req_host_name.split(":").next().expect("if something contains a colon and we split the thing there must be at least one part")
@@ -236,7 +252,9 @@ pub mod global_state {
                             // and possibly invalidate sessions in some cases.
                             address:  local_addr.to_string(), //y.host_name.to_owned(), // --- configurable
                             https: y.https,
-                            port: y.active_port.unwrap_or_default()
+                            port: y.active_port.unwrap_or_default(),
+                            weight: None,
+                            proxy_protocol: None
                         }],
                         host_name: y.host_name.to_string(),
                         is_hosted: true,
@@ -499,9 +517,14 @@ fn initialize_configuration(args:&Args) -> anyhow::Result<(ConfigWrapper,OddBoxC
     Ok((config,original_version,was_upgraded))
 }
 
-#[tokio::main(flavor="multi_thread")]
+fn main() -> anyhow::Result<()> {
+    // changing the environment is not thread safe, so this has to happen before the runtime starts its threads
+    crate::hot_upgrade::take_handoff_from_env();
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(async_main())
+}
+
 #[tracing::instrument()]
-async fn main() -> anyhow::Result<()> {
+async fn async_main() -> anyhow::Result<()> {
 
     match rustls::crypto::ring::default_provider().install_default() {
         Ok(_) => {},
@@ -524,6 +547,7 @@ async fn main() -> anyhow::Result<()> {
     }
     
     let tui_flag = args.tui.unwrap_or(true);
+    crate::access_log::set_tui_active(tui_flag);
 
     if args.init {
         generate_config(Some("odd-box.toml"),false)?;
@@ -646,8 +670,17 @@ async fn main() -> anyhow::Result<()> {
     
     let global_state = Arc::new(global_state);
 
+    // has to happen before the proxy and the hosted processes are started, so that they can pick up what was handed over to us
+    crate::hot_upgrade::load_inherited();
+
     tokio::task::spawn(crate::letsencrypt::bg_worker_for_lets_encrypt_certs(global_state.clone()));
+    tokio::task::spawn(crate::custom_certs::bg_worker_for_custom_certs(global_state.clone()));
     tokio::task::spawn(crate::observer::run(global_state.clone()));
+    tokio::task::spawn(crate::health_check::bg_worker_for_health_checks(global_state.clone()));
+    tokio::task::spawn(crate::access_log::bg_worker_for_access_log(global_state.clone()));
+    tokio::task::spawn(crate::stream_proxy::bg_worker_for_stream_sites(global_state.clone()));
+    tokio::task::spawn(crate::shutdown::bg_worker_for_shutdown(global_state.clone()));
+    tokio::task::spawn(crate::hot_upgrade::bg_worker_for_hot_upgrade(global_state.clone()));
 
     // Spawn thread cleaner (removes dead threads from the proc_thread_map)
     let cleanup_thread = tokio::spawn(generic_cleanup_thread(global_state.clone()));
@@ -673,7 +706,7 @@ async fn main() -> anyhow::Result<()> {
         let cstate = global_state.clone();
         ctrlc::set_handler(move || {
             tracing::warn!("Ctrl-C received. Shutting down..");
-            cstate.app_state.exit.store(true, std::sync::atomic::Ordering::SeqCst);
+            crate::shutdown::request(&cstate);
         }).expect("Error setting Ctrl-C handler");
 
         // ^ Note that we only set this while not in tui mode. In tui mode we have a separate handler for this.
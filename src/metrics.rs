@@ -0,0 +1,162 @@
+// Prometheus text exposition of the live statistics, served by the admin api at /metrics.
+// Only configured sites are used as label values so that random host headers cannot blow up the series count.
+
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::configuration::ConfigWrapper;
+use crate::global_state::GlobalState;
+use crate::types::app_state::ProcState;
+use crate::types::connection_type::ConnectionType;
+use crate::types::proxy_state::{SiteMetrics, LATENCY_BUCKETS_IN_SECONDS};
+
+/// Used for requests that did not match any configured site.
+pub const UNKNOWN_SITE: &str = "unknown";
+
+/// Finds the host_name of the configured site that handles requests for the given host name.
+pub fn site_for_host(configuration: &ConfigWrapper, host_name: &str) -> String {
+    configuration.find_site(host_name)
+        .map(|site| site.host_name().to_string())
+        .unwrap_or_else(|| UNKNOWN_SITE.to_string())
+}
+
+fn connection_type_label(connection_type: &ConnectionType) -> &'static str {
+    match connection_type {
+        ConnectionType::TlsTerminatedWithOutgoingClearText(..) => "tls_terminated_with_outgoing_clear_text",
+        ConnectionType::TlsTerminatedWithOutgoingTLS(..) => "tls_terminated_with_outgoing_tls",
+        ConnectionType::TlsTermination => "tls_termination",
+        ConnectionType::TlsPassthru(..) => "tls_passthru",
+        ConnectionType::HttpPassthru(..) => "http_passthru",
+        ConnectionType::HttpTermination => "http_termination",
+        ConnectionType::HttpWithOutgoingTLS(..) => "http_with_outgoing_tls",
+        ConnectionType::TcpStream(..) => "tcp_stream",
+        ConnectionType::UdpStream(..) => "udp_stream",
+        ConnectionType::PendingInit => "pending_init",
+        ConnectionType::Invalid(_) => "invalid",
+    }
+}
+
+fn proc_state_label(state: &ProcState) -> &'static str {
+    match state {
+        ProcState::Faulty => "faulty",
+        ProcState::Stopped => "stopped",
+        ProcState::Starting => "starting",
+        ProcState::Stopping => "stopping",
+        ProcState::Running => "running",
+        ProcState::Remote => "remote",
+        ProcState::DirServer => "dir_server",
+        ProcState::Docker => "docker",
+    }
+}
+
+/// Escapes a label value as described by the text exposition format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_latency_histogram(out: &mut String, site: &str, metrics: &SiteMetrics) {
+    let mut cumulative = 0;
+    for (i, upper_bound) in LATENCY_BUCKETS_IN_SECONDS.iter().enumerate() {
+        cumulative += metrics.latency.buckets[i].load(Ordering::Relaxed);
+        _ = writeln!(out, "odd_box_request_duration_seconds_bucket{{site=\"{site}\",le=\"{upper_bound}\"}} {cumulative}");
+    }
+    let count = metrics.latency.count.load(Ordering::Relaxed);
+    let sum = metrics.latency.sum_in_microseconds.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    _ = writeln!(out, "odd_box_request_duration_seconds_bucket{{site=\"{site}\",le=\"+Inf\"}} {count}");
+    _ = writeln!(out, "odd_box_request_duration_seconds_sum{{site=\"{site}\"}} {sum}");
+    _ = writeln!(out, "odd_box_request_duration_seconds_count{{site=\"{site}\"}} {count}");
+}
+
+pub fn render(state: &GlobalState) -> String {
+    let stats = &state.app_state.statistics;
+    let mut out = String::new();
+
+    let mut sites : Vec<(String, std::sync::Arc<SiteMetrics>)> = stats.site_metrics.iter()
+        .map(|x| (escape(x.key()), x.value().clone()))
+        .collect();
+    sites.sort_by(|a, b| a.0.cmp(&b.0));
+
+    header(&mut out, "odd_box_requests_total", "counter", "Terminated http requests by site and response status class.");
+    for (site, metrics) in &sites {
+        for (i, n) in metrics.responses_by_status_class.iter().enumerate() {
+            _ = writeln!(out, "odd_box_requests_total{{site=\"{site}\",status=\"{}xx\"}} {}", i + 1, n.load(Ordering::Relaxed));
+        }
+    }
+
+    header(&mut out, "odd_box_request_duration_seconds", "histogram", "Time until the response headers of terminated http requests were ready.");
+    for (site, metrics) in &sites {
+        write_latency_histogram(&mut out, site, metrics);
+    }
+
+    header(&mut out, "odd_box_received_bytes_total", "counter", "Bytes received from clients, by site.");
+    for (site, metrics) in &sites {
+        _ = writeln!(out, "odd_box_received_bytes_total{{site=\"{site}\"}} {}", metrics.bytes_received.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "odd_box_sent_bytes_total", "counter", "Bytes sent to clients, by site.");
+    for (site, metrics) in &sites {
+        _ = writeln!(out, "odd_box_sent_bytes_total{{site=\"{site}\"}} {}", metrics.bytes_sent.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "odd_box_accepted_tcp_connections_total", "counter", "Tcp connections accepted since odd-box was started.");
+    _ = writeln!(out, "odd_box_accepted_tcp_connections_total {}", stats.total_accepted_tcp_connections.load(Ordering::Relaxed));
+
+    let mut active_connections = std::collections::BTreeMap::<&'static str, u64>::new();
+    for guard in stats.active_connections.iter() {
+        *active_connections.entry(connection_type_label(&guard.value().get_connection_type())).or_default() += 1;
+    }
+    header(&mut out, "odd_box_active_connections", "gauge", "Currently open tcp connections by connection type.");
+    for (connection_type, n) in active_connections {
+        _ = writeln!(out, "odd_box_active_connections{{type=\"{connection_type}\"}} {n}");
+    }
+
+    let mut restarts : Vec<(String, u64)> = stats.process_restarts.iter()
+        .map(|x| (escape(x.key()), x.value().load(Ordering::Relaxed)))
+        .collect();
+    restarts.sort();
+    header(&mut out, "odd_box_process_restarts_total", "counter", "Automatic restarts of hosted processes after they stopped unexpectedly.");
+    for (site, n) in restarts {
+        _ = writeln!(out, "odd_box_process_restarts_total{{site=\"{site}\"}} {n}");
+    }
+
+    let mut rate_limited : Vec<(String, u64)> = stats.rate_limited_requests.iter()
+        .map(|x| (escape(x.key()), x.value().load(Ordering::Relaxed)))
+        .collect();
+    rate_limited.sort();
+    header(&mut out, "odd_box_rate_limited_total", "counter", "Requests and tunnelled connections rejected by rate limiting.");
+    for (site, n) in rate_limited {
+        _ = writeln!(out, "odd_box_rate_limited_total{{site=\"{site}\"}} {n}");
+    }
+
+    let mut site_states : Vec<(String, &'static str)> = state.app_state.site_status_map.iter()
+        .map(|x| (escape(x.key()), proc_state_label(x.value())))
+        .collect();
+    site_states.sort();
+    header(&mut out, "odd_box_site_state", "gauge", "Current state of each site, the series with value 1 is the active one.");
+    for (site, current) in site_states {
+        _ = writeln!(out, "odd_box_site_state{{site=\"{site}\",state=\"{current}\"}} 1");
+    }
+
+    let mut certs = state.cert_resolver.cached_cert_expiry();
+    certs.sort();
+    header(&mut out, "odd_box_certificate_expiry_timestamp_seconds", "gauge", "Unix time at which each loaded certificate expires.");
+    for (domain, kind, not_after) in certs {
+        _ = writeln!(out, "odd_box_certificate_expiry_timestamp_seconds{{domain=\"{}\",kind=\"{kind}\"}} {not_after}", escape(&domain));
+    }
+
+    let mut failing_renewals : Vec<(String, u32)> = crate::letsencrypt::FAILING_RENEWALS.iter()
+        .map(|x| (escape(x.key()), x.value().consecutive_failures))
+        .collect();
+    failing_renewals.sort();
+    header(&mut out, "odd_box_certificate_renewal_failures", "gauge", "Consecutive failed attempts to issue or renew the certificate of each domain.");
+    for (domain, n) in failing_renewals {
+        _ = writeln!(out, "odd_box_certificate_renewal_failures{{domain=\"{domain}\"}} {n}");
+    }
+
+    out
+}
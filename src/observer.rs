@@ -30,12 +30,13 @@ pub async fn run(state:Arc<GlobalState>) {
                 continue
             }
             match msg {
-                GotResponseFromBackend(k,data) => {
-                    observer.push_extra(&k, &format!("Odd-Box got a response from backend service: {}",&data),false); // outgoing: false
+                GotResponseFromBackend { connection_key, request_id, response } => {
+                    observer.push_extra(&connection_key, &format!("Odd-Box got a response from backend service (request id: {request_id}): {}",&response),false); // outgoing: false
                 },
-                SentHttpRequestToBackend(k,data) => {
-                   observer.push_extra(&k, &format!("Odd-Box sent a request to the backend service - {}",&data),true); // outgoing: true
+                SentHttpRequestToBackend { connection_key, request_id, request } => {
+                   observer.push_extra(&connection_key, &format!("Odd-Box sent a request to the backend service (request id: {request_id}) - {}",&request),true); // outgoing: true
                 }
+                BackendEjected(_) | BackendRestored(_) | CertificateRenewalFailing(_) => {}
                 TcpEvent(TCPEvent::Close(key)) => {
                     _ = observer.tcp_connections.remove(&key);
                 }
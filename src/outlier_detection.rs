@@ -0,0 +1,53 @@
+// Passive outlier detection (circuit breaking) for remote site backends.
+// Unlike the active health checks, this only looks at the outcome of real traffic.
+
+use std::time::Instant;
+
+use crate::configuration::{Backend, RemoteSiteConfig};
+use crate::global_state::GlobalState;
+use crate::types::odd_box_event::{BackendOutlierEvent, GlobalEvent};
+
+pub fn is_ejected(state: &GlobalState, host_name: &str, backend: &Backend) -> bool {
+    state.app_state.statistics.backend_outliers
+        .get(&crate::health_check::backend_key(host_name, backend))
+        .and_then(|x| x.ejected_until)
+        .is_some_and(|until| Instant::now() < until)
+}
+
+pub fn record_success(state: &GlobalState, site: &RemoteSiteConfig, backend: &Backend) {
+    let Some(cb) = &site.circuit_breaker else { return };
+    let key = crate::health_check::backend_key(&site.host_name, backend);
+    if let Some((_, previous)) = state.app_state.statistics.backend_outliers.remove(&key) {
+        if previous.ejected_until.is_some() {
+            tracing::info!("Backend {} for {} is responding again after being ejected by the circuit breaker.", key.1, key.0);
+            _ = state.global_broadcast_channel.send(GlobalEvent::BackendRestored(BackendOutlierEvent {
+                host_name: key.0,
+                backend: key.1,
+                consecutive_failures: previous.consecutive_failures,
+                cool_down_in_seconds: cb.cool_down().as_secs()
+            }));
+        }
+    }
+}
+
+pub fn record_failure(state: &GlobalState, site: &RemoteSiteConfig, backend: &Backend) {
+    let Some(cb) = &site.circuit_breaker else { return };
+    let key = crate::health_check::backend_key(&site.host_name, backend);
+    let mut entry = state.app_state.statistics.backend_outliers.entry(key.clone()).or_default();
+    entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+    let currently_ejected = entry.ejected_until.is_some_and(|until| Instant::now() < until);
+    // once the cool-down has passed the failure count is still above the threshold,
+    // so a backend that fails its first request after coming back is ejected again right away.
+    if !currently_ejected && entry.consecutive_failures >= cb.consecutive_failures() {
+        entry.ejected_until = Some(Instant::now() + cb.cool_down());
+        let consecutive_failures = entry.consecutive_failures;
+        drop(entry);
+        tracing::warn!("Backend {} for {} failed {consecutive_failures} times in a row and has been ejected for {:?}.", key.1, key.0, cb.cool_down());
+        _ = state.global_broadcast_channel.send(GlobalEvent::BackendEjected(BackendOutlierEvent {
+            host_name: key.0,
+            backend: key.1,
+            consecutive_failures,
+            cool_down_in_seconds: cb.cool_down().as_secs()
+        }));
+    }
+}
@@ -27,11 +27,37 @@ pub fn graceful_stop_pid_only(
     include_direct_children: bool,
     total_timeout: Duration,
 ) -> io::Result<ExitStatus> {
+    let parent_pid = parent.id() as i32;
+    stop_with_signals(parent_pid, include_direct_children, total_timeout, || parent.try_wait())
+}
+
+/// Stops a process that was started by a previous odd-box instance before a hot upgrade.
+/// It is not our child so we can only tell that it has stopped by looking for it, and by its start time that the pid has not been reused.
+pub fn stop_adopted_process(pid: u32, started_at: u64) {
+    #[cfg(unix)]
+    {
+        if !crate::hot_upgrade::is_running(pid, started_at) {
+            return
+        }
+        _ = stop_with_signals(pid as i32, true, Duration::from_secs(5), || Ok((!crate::hot_upgrade::is_running(pid, started_at)).then_some(())));
+    }
+    #[cfg(not(unix))]
+    {
+        tracing::warn!("Not able to stop adopted process {pid} on this platform");
+    }
+}
+
+#[cfg(unix)]
+fn stop_with_signals<T>(
+    parent_pid: i32,
+    include_direct_children: bool,
+    total_timeout: Duration,
+    mut has_stopped: impl FnMut() -> io::Result<Option<T>>,
+) -> io::Result<T> {
     use nix::sys::signal::{kill, Signal::{SIGINT, SIGKILL, SIGTERM}};
     use nix::unistd::Pid;
     use sysinfo::{ProcessRefreshKind, RefreshKind, System};
 
-    let parent_pid = parent.id() as i32;
 
     // Snapshot direct children once (optional), PID-by-PID only.
     let child_pids: Vec<i32> = if include_direct_children {
@@ -64,7 +90,7 @@ pub fn graceful_stop_pid_only(
     // Phase 1: SIGINT (Ctrl-C)
     for &cpid in &child_pids { send(cpid, SIGINT); }
     send(parent_pid, SIGINT);
-    if let Some(st) = wait_with_deadline(&mut parent, t_int)? {
+    if let Some(st) = wait_with_deadline(&mut has_stopped, t_int)? {
         tracing::info!("Stopped the process using sigint (ctrl-c)");
         return Ok(st);
     }
@@ -72,7 +98,7 @@ pub fn graceful_stop_pid_only(
     // Phase 2: SIGTERM
     for &cpid in &child_pids { send(cpid, SIGTERM); }
     send(parent_pid, SIGTERM);
-    if let Some(st) = wait_with_deadline(&mut parent, t_term)? {
+    if let Some(st) = wait_with_deadline(&mut has_stopped, t_term)? {
         tracing::info!("Stopped the process using sigterm");
         return Ok(st);
     }
@@ -80,7 +106,7 @@ pub fn graceful_stop_pid_only(
     // Phase 3: SIGKILL (last resort)
     for &cpid in &child_pids { send(cpid, SIGKILL); }
     send(parent_pid, SIGKILL);
-    if let Some(st) = wait_with_deadline(&mut parent, t_kill)? {
+    if let Some(st) = wait_with_deadline(&mut has_stopped, t_kill)? {
         tracing::warn!("Stopped the process using sigkill - this may leave resources allocated");
         return Ok(st);
     }
@@ -88,7 +114,7 @@ pub fn graceful_stop_pid_only(
     // Not really expecting to get here so lets just see if
     // perhaps we already stopped while we were sending signals.
     // Just doing a one-shot check here to not hang around forever
-    if let Some(st) = parent.try_wait()? {
+    if let Some(st) = has_stopped()? {
         return Ok(st);
     }
 
@@ -96,10 +122,10 @@ pub fn graceful_stop_pid_only(
 }
 
 #[cfg(unix)]
-fn wait_with_deadline(child: &mut Child, dur: Duration) -> io::Result<Option<ExitStatus>> {
+fn wait_with_deadline<T>(has_stopped: &mut impl FnMut() -> io::Result<Option<T>>, dur: Duration) -> io::Result<Option<T>> {
     let start = Instant::now();
     loop {
-        if let Some(st) = child.try_wait()? {
+        if let Some(st) = has_stopped()? {
             return Ok(Some(st));
         }
         if start.elapsed() >= dur {
@@ -110,6 +136,56 @@ fn wait_with_deadline(child: &mut Child, dur: Duration) -> io::Result<Option<Exi
 }
 
 
+/// A hosted process, either started by us or adopted from the previous instance after a hot upgrade.
+enum HostedChild {
+    Spawned(Child),
+    Adopted { pid: u32, started_at: u64, stdout: Option<std::fs::File>, stderr: Option<std::fs::File> }
+}
+
+impl HostedChild {
+    fn is_running(&mut self) -> bool {
+        match self {
+            HostedChild::Spawned(child) => matches!(child.try_wait(), Ok(None)),
+            HostedChild::Adopted { pid, started_at, .. } => crate::hot_upgrade::is_running(*pid, *started_at)
+        }
+    }
+
+    /// Some apps (iisexpress for example) want a q on stdin before they stop. Adopted processes have no stdin for us to write to.
+    fn send_quit(&mut self) {
+        if let HostedChild::Spawned(child) = self {
+            if let Some(mut stdin) = child.stdin.take() {
+                _ = stdin.write_all(b"q");
+            }
+        }
+    }
+
+    /// Takes the output pipes of the process and registers them so that the process can be handed over in a hot upgrade.
+    fn take_output(&mut self, host_name: &str, port: u16) -> (Box<dyn std::io::Read + Send>, Box<dyn std::io::Read + Send>, Option<crate::hot_upgrade::Registration>) {
+        match self {
+            HostedChild::Spawned(child) => {
+                let stdout = child.stdout.take().expect("Failed to capture stdout");
+                let stderr = child.stderr.take().expect("Failed to capture stderr");
+                let registration = crate::hot_upgrade::register_process(host_name, child.id(), port, &stdout, &stderr);
+                (Box::new(stdout), Box::new(stderr), registration)
+            },
+            HostedChild::Adopted { pid, stdout, stderr, .. } => {
+                let stdout = stdout.take().expect("Failed to capture stdout");
+                let stderr = stderr.take().expect("Failed to capture stderr");
+                let registration = crate::hot_upgrade::register_process(host_name, *pid, port, &stdout, &stderr);
+                (Box::new(stdout), Box::new(stderr), registration)
+            }
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            HostedChild::Spawned(child) => child.id(),
+            HostedChild::Adopted { pid, .. } => *pid
+        }
+    }
+}
+
+
 pub async fn host(
     mut resolved_proc: crate::configuration::FullyResolvedInProcessSiteConfig,
     mut rcv:tokio::sync::broadcast::Receiver<ProcMessage>,
@@ -209,8 +285,21 @@ pub async fn host(
     }
 
 
+    fn stop_hosted_child(child: HostedChild) {
+        match child {
+            HostedChild::Spawned(child) => kill_process_and_its_children(child),
+            HostedChild::Adopted { pid, started_at, .. } => stop_adopted_process(pid, started_at)
+        }
+    }
+
     let mut missing_bin: bool = false;
 
+    // after a hot upgrade the process may still be running from the previous instance, in which case we take it over
+    let mut adopted = crate::hot_upgrade::take_process(&resolved_proc.host_name);
+    if adopted.is_some() {
+        enabled = true;
+    }
+
     loop {
 
         if missing_bin {
@@ -238,6 +327,11 @@ pub async fn host(
         tokio::time::sleep(Duration::from_millis(200)).await;
         let mut time_to_sleep_ms_after_each_iteration = 500;
 
+        if state.app_state.shutdown.processes_handed_over() {
+            tracing::debug!("leaving {} to the new instance",&resolved_proc.host_name);
+            return
+        }
+
         let exit = state.app_state.exit.load(std::sync::atomic::Ordering::SeqCst) == true;
 
         if exit {
@@ -308,11 +402,16 @@ pub async fn host(
         // just to make sure we havnt messed up timing-wise and selected the same port for two different processes
         // we will always call this function to get a new port (or keep the old one if we are the only one using it)
 
-        let mut guard = state.config.write().await;
-        if let Ok(p) = guard.set_active_port(&mut resolved_proc) {
-            resolved_proc.active_port = Some(p);
+        if let Some(adopted) = &adopted {
+            // the process is already listening on its port, so it would not look free to set_active_port
+            resolved_proc.active_port = Some(adopted.port);
+        } else {
+            let mut guard = state.config.write().await;
+            if let Ok(p) = guard.set_active_port(&mut resolved_proc) {
+                resolved_proc.active_port = Some(p);
+            }
+            drop(guard);
         }
-        drop(guard);
 
         if resolved_proc.active_port.is_none() {
             let ms = 3000;
@@ -393,26 +492,33 @@ pub async fn host(
         #[cfg(target_os="windows")]
         use std::os::windows::process::CommandExt;
 
-        #[cfg(target_os = "windows")]
-        let cmd = Command::new(resolved_bin_path)
-            .args(pre_resolved_args)
-            .envs(&process_specific_environment_variables)
-            .current_dir(&workdir)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .stdin(Stdio::null())
-            // dont want windows to let child take over our keyboard input and such
-            .creation_flags(DETACHED_PROCESS).spawn();
-
-        #[cfg(not(target_os = "windows"))]
-        let cmd = Command::new(resolved_bin_path)
-            .args(pre_resolved_args)
-            .envs(&process_specific_environment_variables)
-            .current_dir(&workdir)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .stdin(Stdio::null())
-            .spawn();
+        let cmd = if let Some(adopted) = adopted.take() {
+            Ok(HostedChild::Adopted { pid: adopted.pid, started_at: adopted.started_at, stdout: Some(adopted.stdout), stderr: Some(adopted.stderr) })
+        } else {
+
+            #[cfg(target_os = "windows")]
+            let cmd = Command::new(resolved_bin_path)
+                .args(pre_resolved_args)
+                .envs(&process_specific_environment_variables)
+                .current_dir(&workdir)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .stdin(Stdio::null())
+                // dont want windows to let child take over our keyboard input and such
+                .creation_flags(DETACHED_PROCESS).spawn();
+
+            #[cfg(not(target_os = "windows"))]
+            let cmd = Command::new(resolved_bin_path)
+                .args(pre_resolved_args)
+                .envs(&process_specific_environment_variables)
+                .current_dir(&workdir)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .stdin(Stdio::null())
+                .spawn();
+
+            cmd.map(HostedChild::Spawned)
+        };
 
         match cmd {
             Ok(mut child) => {
@@ -436,8 +542,7 @@ pub async fn host(
 
                 //let stdin = child.stdin.take().expect("Failed to capture stdin");
 
-                let stdout = child.stdout.take().expect("Failed to capture stdout");
-                let stderr = child.stderr.take().expect("Failed to capture stderr");
+                let (stdout, stderr, _handoff_registration) = child.take_output(&resolved_proc.host_name, resolved_proc.active_port.unwrap_or_default());
 
 
                 let stdout_reader = std::io::BufReader::new(stdout);
@@ -537,13 +642,18 @@ pub async fn host(
                     }
                 });
 
-                while let Ok(None) = child.try_wait() {
+                while child.is_running() {
+
+                    if state.app_state.shutdown.processes_handed_over() {
+                        tracing::info!("[{}] Leaving the process running for the new odd-box instance", resolved_proc.host_name);
+                        return
+                    }
 
                     let exit = state.app_state.exit.load(std::sync::atomic::Ordering::SeqCst) == true;
                     if exit {
                         tracing::info!("[{}] Stopping due to app exit", resolved_proc.host_name);
                         previous_update = update_status(&previous_update,&resolved_proc.host_name, &my_id,&state,ProcState::Stopping,"stopping..exiting");
-                        kill_process_and_its_children(child);
+                        stop_hosted_child(child);
                         break
                     }
 
@@ -561,7 +671,7 @@ pub async fn host(
                         if live_proc_config.get_id() != &resolved_proc.proc_id {
                             tracing::warn!("[{}] Stopping due to having been replaced by a new process with the same name", resolved_proc.host_name);
                             previous_update = update_status(&previous_update,&resolved_proc.host_name, &my_id,&state,ProcState::Stopping,"stopping due to being replaced");
-                            kill_process_and_its_children(child);
+                            stop_hosted_child(child);
                             break
                         }
                         resolved_proc.log_format = live_proc_config.log_format;
@@ -577,10 +687,8 @@ pub async fn host(
                                 if acceptable_names.contains(&s) {
                                     tracing::warn!("[{}] Dropping due to having been deleted by proxy.", resolved_proc.host_name);
                                     state.app_state.site_status_map.remove(&resolved_proc.host_name);
-                                    if let Some(mut stdin) = child.stdin.take() {
-                                        _ = stdin.write_all(b"q");
-                                    }
-                                    kill_process_and_its_children(child);
+                                    child.send_quit();
+                                    stop_hosted_child(child);
                                     // inform sender that we actually stopped the process and that we are exiting our loop
                                     match sender.send(0).await {
                                         Ok(_) => {},
@@ -615,10 +723,8 @@ pub async fn host(
                                 if item.marked_for_removal {
                                     tracing::warn!("Detected mark of removal, leaving main loop for {}",resolved_proc.host_name);
                                     _ = update_status(&previous_update,&resolved_proc.host_name, &my_id,&state,ProcState::Stopping,"stopping due to marked for removal");
-                                    if let Some(mut stdin) = child.stdin.take() {
-                                        _ = stdin.write_all(b"q");
-                                    }
-                                    kill_process_and_its_children(child);
+                                    child.send_quit();
+                                    stop_hosted_child(child);
                                     return;
                                 }
                             },
@@ -635,10 +741,8 @@ pub async fn host(
 
                         previous_update = update_status(&previous_update,&resolved_proc.host_name, &my_id,&state,ProcState::Stopping,"stopping because not enabled");
 
-                        if let Some(mut stdin) = child.stdin.take() {
-                            _ = stdin.write_all(b"q");
-                        }
-                        kill_process_and_its_children(child);
+                        child.send_quit();
+                        stop_hosted_child(child);
                         break;
                     }
 
@@ -658,6 +762,10 @@ pub async fn host(
         if enabled {
             if !state.app_state.exit.load(std::sync::atomic::Ordering::SeqCst) {
                 tracing::warn!("[{}] Stopped unexpectedly.. Will automatically restart the process in 5 seconds unless stopped.",resolved_proc.host_name);
+                state.app_state.statistics.process_restarts
+                    .entry(resolved_proc.host_name.clone())
+                    .or_default()
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 previous_update = update_status(&previous_update,&resolved_proc.host_name, &my_id,&state,ProcState::Faulty,"something is wrong with the process..");
                 time_to_sleep_ms_after_each_iteration = 5000; // wait 5 seconds before restarting but NOT in here as we have a lock
             } else {
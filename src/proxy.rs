@@ -1,4 +1,5 @@
 use core::panic;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -15,9 +16,11 @@ use tokio_rustls::TlsAcceptor;
 use crate::api::OddBoxAPI;
 use crate::configuration::Hint;
 use crate::configuration::ConfigWrapper;
+use crate::configuration::ListenerProtocol;
 use crate::global_state::GlobalState;
 use crate::http_proxy::ProcMessage;
 use crate::http_proxy::ReverseProxyService;
+use crate::http_proxy::ProxyClient;
 use crate::tcp_proxy;
 use crate::http_proxy;
 use crate::tcp_proxy::DataType;
@@ -35,8 +38,52 @@ use crate::types::proxy_state::ProxyActiveTCPConnection;
 use tokio_util::sync::CancellationToken;
 use tokio::task::JoinHandle;
 
+/// Where and how a listener accepts connections. These come from the listeners list when it is set,
+/// otherwise from the ip, http_port and tls_port settings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListenerSettings {
+    pub bind_addr: SocketAddr,
+    pub protocol: ListenerProtocol,
+    pub ipv6_only: bool,
+    pub sites: Option<Vec<String>>,
+}
+
+impl ListenerSettings {
+    /// Tells if requests for the given host name may be served on this listener.
+    pub fn serves(&self, host_name: &str) -> bool {
+        let Some(sites) = &self.sites else { return true };
+        sites.iter().any(|site| host_name.eq_ignore_ascii_case(site) || host_name.to_ascii_lowercase().ends_with(&format!(".{}", site.to_ascii_lowercase())))
+    }
+}
+
+pub(crate) fn wanted_listeners(config: &ConfigWrapper, initial_bind_addr: SocketAddr, initial_bind_addr_tls: SocketAddr) -> Vec<ListenerSettings> {
+    let srv_ip = config.ip.clone().unwrap_or(initial_bind_addr.ip());
+    match &config.listeners {
+        Some(listeners) => listeners.iter().map(|l| ListenerSettings {
+            bind_addr: SocketAddr::new(l.ip.unwrap_or(srv_ip), l.port),
+            protocol: l.protocol.unwrap_or(ListenerProtocol::Auto),
+            ipv6_only: l.ipv6_only.unwrap_or_default(),
+            sites: l.sites.clone(),
+        }).collect(),
+        None => vec![
+            ListenerSettings {
+                bind_addr: SocketAddr::new(srv_ip, config.http_port.unwrap_or(initial_bind_addr.port())),
+                protocol: ListenerProtocol::Http,
+                ipv6_only: false,
+                sites: None
+            },
+            ListenerSettings {
+                bind_addr: SocketAddr::new(srv_ip, config.tls_port.unwrap_or(initial_bind_addr_tls.port())),
+                protocol: ListenerProtocol::Https,
+                ipv6_only: false,
+                sites: None
+            }
+        ]
+    }
+}
+
 // This is the main entrypoint of the reverse proxy listener...
-// It sets up the two tcp sockets and routes traffic either to the terminating proxy service (hyper)
+// It sets up the tcp sockets and routes traffic either to the terminating proxy service (hyper)
 // or thru the managed tunnel mode where we do not terminate http traffic (but possibly do terminate tls depending on config).
 pub async fn listen(
     cfg: Arc<RwLock<ConfigWrapper>>, 
@@ -47,162 +94,102 @@ pub async fn listen(
     shutdown_signal: Arc<Notify>
 ) {
     
-    let mut previous_bind_addr = initial_bind_addr;
-    let mut previous_bind_addr_tls = initial_bind_addr_tls;
-
-    let mut http_cancel_token: Option<CancellationToken> = None;
-    let mut https_cancel_token: Option<CancellationToken> = None;
-
-    let mut http_task : Option<JoinHandle<()>> = None;
-    let mut https_task : Option<JoinHandle<()>> = None;
+    let mut running : HashMap<ListenerSettings, (CancellationToken, JoinHandle<()>)> = HashMap::new();
 
     loop {
+
+        // During a graceful shutdown we stop accepting new connections while the accepted ones run to completion on their own tasks
+        if state.app_state.shutdown.is_draining() {
+            for (settings, (token, task)) in running.drain() {
+                token.cancel();
+                _ = task.await;
+                tracing::info!("Stopped accepting connections on {}.", settings.bind_addr);
+            }
+            return;
+        }
         
         // Read the current configuration
-        let (current_bind_addr, current_bind_addr_tls) = {
-
-            let config_read = cfg.read().await;
-
-            let srv_ip = config_read.ip.clone().unwrap_or(initial_bind_addr.ip());
-
-            let srv_port: u16 = config_read.http_port.unwrap_or(previous_bind_addr.port());
-            let srv_tls_port: u16 = config_read.tls_port.unwrap_or(previous_bind_addr_tls.port());
+        let wanted = wanted_listeners(&*cfg.read().await, initial_bind_addr, initial_bind_addr_tls);
 
-            let http_bind_addr = SocketAddr::new(srv_ip, srv_port);
-            let https_bind_addr = SocketAddr::new(srv_ip, srv_tls_port);
-
-            (http_bind_addr, https_bind_addr)
-        };
-
-        // Check if the bind addresses have changed or if this is the first iteration
-        if http_task.is_none() || https_task.is_none() || previous_bind_addr != current_bind_addr || previous_bind_addr_tls != current_bind_addr_tls {
-
-            // Addresses have changed; need to restart the listeners
-            if let Some(token) = http_cancel_token.take() {
-                tracing::info!("http port has changed from {} to {}, shutting down http listener..",previous_bind_addr.port(),current_bind_addr.port());
-                token.cancel(); 
-                if let Some(http_task) = http_task.take() {
-                    tracing::info!("waiting for http task to finish..");
-                    http_task.await.expect("http task failed");
-                    
-                    tracing::info!("http task finished.");
-                }
-                
-            }         
-
-            if let Some(token) = https_cancel_token.take() {
-                tracing::info!("https port has changed from {} to {}, shutting down http listener..",previous_bind_addr_tls.port(),current_bind_addr_tls.port());
+        // Listeners whose settings have changed (or that are no longer configured) are shut down and started again below
+        let stale : Vec<ListenerSettings> = running.keys().filter(|x| !wanted.contains(x)).cloned().collect();
+        for settings in stale {
+            if let Some((token, task)) = running.remove(&settings) {
+                tracing::info!("settings for the {:?} listener on {} have changed, shutting it down..", settings.protocol, settings.bind_addr);
                 token.cancel();
-                if let Some(https_task) = https_task.take() {
-                    tracing::info!("waiting for https task to finish..");
-                    https_task.await.expect("http task failed");
-                    tracing::info!("https task finished.");
-                }
+                tracing::info!("waiting for the listener task to finish..");
+                task.await.expect("listener task failed");
+                tracing::info!("listener task finished.");
             }
+        }
 
-            
-
-            let client_tls_config = tokio_rustls::rustls::ClientConfig::builder_with_protocol_versions(tokio_rustls::rustls::ALL_VERSIONS)
-                // todo - add support for accepting self-signed certificates etc
-                // .dangerous()
-                // .with_custom_certificate_verifier(verifier)
-                .with_native_roots()
-                .expect("must be able to create tls configuration")
-                .with_no_client_auth();
-
-            let https_builder = hyper_rustls::HttpsConnectorBuilder::new()
-                .with_tls_config(client_tls_config);
-            
-            let connector: hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector> = 
-                https_builder.https_or_http().enable_all_versions().build();
-
-            let executor = hyper_util::rt::TokioExecutor::new();
-
-            let client = hyper_util::client::legacy::Client::builder(executor.clone())
-                .http2_only(false) 
-                .build(connector.clone());
-
-            let h2_client = hyper_util::client::legacy::Client::builder(executor)
-                .http2_only(true)
-                .build(connector);
-
-            let terminating_proxy_service = ReverseProxyService {
-                source_addr: None, // this will be set when the connection is accepted 
-                connection_key: 0,
-                configuration: Arc::new(cfg.read().await.clone()),
-                resolved_target: None,
-                state: state.clone(), 
-                remote_addr: None, 
-                tx: tx.clone(), 
-                is_https: false,
-                client,
-                h2_client,
-                host_header: None,
-                sni: None
-            };
-
-            let new_http_cancel_token = CancellationToken::new();
-            let new_https_cancel_token = CancellationToken::new();
-
-            // Start new listeners with the new bind addresses
-            let http_future = listen_http(
-                current_bind_addr,
-                tx.clone(),
-                state.clone(),
-                terminating_proxy_service.clone(),
-                shutdown_signal.clone(),
-                new_http_cancel_token.clone()
-            );
-
-            let https_future = listen_https(
-                current_bind_addr_tls,
-                tx.clone(),
-                state.clone(),
-                terminating_proxy_service.clone(),
-                shutdown_signal.clone(),
-                new_https_cancel_token.clone(),
-            );
-
-            let cloned_ct = new_http_cancel_token.clone();
-            http_task = Some(tokio::spawn(async move {
-                tokio::select! {
-                    x = http_future => {match x {
-                        Ok(_) => {},
-                        Err(e) => {
-                            tracing::error!("{:?}",e)
-                        }
-                    }},
-                    _ = cloned_ct.cancelled() => {
-                        tracing::warn!("http listener cancelled");
-                    },
-                }
-            }));
-
-            let cloned_ct2 = new_https_cancel_token.clone();
-            https_task = Some(tokio::spawn(async move {
-                tokio::select! {
-                    x = https_future => {match x {
-                        Ok(_) => {},
-                        Err(e) => {
-                            tracing::error!("{:?}",e)
+        let new_listeners : Vec<ListenerSettings> = wanted.into_iter().filter(|x| !running.contains_key(x)).collect();
+
+        if !new_listeners.is_empty() {
+
+            // sites with a connect timeout of their own get other clients when their requests are handled
+            let global_timeouts = crate::timeouts::EffectiveTimeouts::resolve(cfg.read().await.timeouts.as_ref(), None);
+            let (client, h2_client) = crate::timeouts::proxy_clients(global_timeouts.connect);
+
+            for settings in new_listeners {
+
+                let settings_arc = Arc::new(settings.clone());
+
+                let terminating_proxy_service = ReverseProxyService {
+                    source_addr: None, // this will be set when the connection is accepted 
+                    connection_key: 0,
+                    configuration: Arc::new(cfg.read().await.clone()),
+                    resolved_target: None,
+                    state: state.clone(), 
+                    remote_addr: None, 
+                    tx: tx.clone(), 
+                    is_https: false,
+                    client: client.clone(),
+                    h2_client: h2_client.clone(),
+                    host_header: None,
+                    sni: None,
+                    listener: settings_arc.clone(),
+                    rate_limit_token: Default::default()
+                };
+
+                let cancel_token = CancellationToken::new();
+
+                // Start the new listener with its own settings
+                let future = {
+                    let (tx, state, shutdown_signal, cancel_token) = (tx.clone(), state.clone(), shutdown_signal.clone(), cancel_token.clone());
+                    async move {
+                        match settings_arc.protocol {
+                            ListenerProtocol::Http => listen_http(settings_arc, tx, state, terminating_proxy_service, shutdown_signal, cancel_token).await,
+                            _ => listen_https(settings_arc, tx, state, terminating_proxy_service, shutdown_signal, cancel_token).await
                         }
-                    }},
-                    _ = cloned_ct2.cancelled() => {
-                        tracing::warn!("https listener cancelled");
-                    },
-                }
-            }));
-
-            http_cancel_token = Some(new_http_cancel_token.clone());
-            https_cancel_token = Some(new_https_cancel_token);
+                    }
+                };
+
+                let cloned_ct = cancel_token.clone();
+                let bind_addr = settings.bind_addr;
+                let task = tokio::spawn(async move {
+                    tokio::select! {
+                        x = future => {match x {
+                            Ok(_) => {},
+                            Err(e) => {
+                                tracing::error!("{:?}",e)
+                            }
+                        }},
+                        _ = cloned_ct.cancelled() => {
+                            tracing::warn!("listener on {bind_addr} cancelled");
+                        },
+                    }
+                });
 
-            previous_bind_addr = current_bind_addr;
-            previous_bind_addr_tls = current_bind_addr_tls;
-            
+                running.insert(settings, (cancel_token, task));
+            }
         }
 
         // Sleep for a while before checking again
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {},
+            _ = state.app_state.shutdown.started() => {}
+        }
     }
 }
 
@@ -212,27 +199,54 @@ lazy_static! {
     
 } 
 
+/// Creates the clients used for proxying terminated http requests to backends, one for any version and one for h2 only.
+pub fn create_proxy_clients(connect_timeout: Duration) -> (ProxyClient, ProxyClient) {
+
+    let client_tls_config = tokio_rustls::rustls::ClientConfig::builder_with_protocol_versions(tokio_rustls::rustls::ALL_VERSIONS)
+        // todo - add support for accepting self-signed certificates etc
+        // .dangerous()
+        // .with_custom_certificate_verifier(verifier)
+        .with_native_roots()
+        .expect("must be able to create tls configuration")
+        .with_no_client_auth();
+
+    let mut http_connector = hyper_util::client::legacy::connect::HttpConnector::new();
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(Some(connect_timeout));
+
+    let connector: hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector> = 
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(client_tls_config)
+            .https_or_http()
+            .enable_all_versions()
+            .wrap_connector(http_connector);
+
+    let executor = hyper_util::rt::TokioExecutor::new();
+
+    let client = hyper_util::client::legacy::Client::builder(executor.clone())
+        .http2_only(false) 
+        .build(connector.clone());
+
+    let h2_client = hyper_util::client::legacy::Client::builder(executor)
+        .http2_only(true)
+        .build(connector);
+
+    (client, h2_client)
+}
+
+fn bind_tcp_listener(settings: &ListenerSettings) -> anyhow::Result<std::net::TcpListener> {
 
-async fn listen_http(
-    bind_addr: SocketAddr,
-    tx: std::sync::Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
-    state: Arc<GlobalState>,
-    terminating_service_template: ReverseProxyService,
-    _shutdown_signal: Arc<Notify> ,
-    cancel_token: CancellationToken,
-) -> anyhow::Result<()> {
-    
     use socket2::{Domain,Type};
-    
-    let socket = Socket::new(Domain::for_address(bind_addr), Type::STREAM, Some(Protocol::TCP)).expect("should always be possible to create a tcp socket for tls");
-    match socket.set_only_v6(false) {
+
+    let bind_addr = settings.bind_addr;
+    let socket = Socket::new(Domain::for_address(bind_addr), Type::STREAM, Some(Protocol::TCP)).expect("should always be possible to create a tcp socket");
+    match socket.set_only_v6(settings.ipv6_only) {
         Ok(_) => {},
         Err(e) => tracing::trace!("Failed to set_only_vs: {e:?}")
     };
 
     // note: we reuse here as we want to be able to run multiple instances of odd-box at the same time.
-    // if nothing else it lets us launch a newer version of the server without stopping the old, so that we can upgrade
-    // to a later version without downtime.
+    // hot upgrades hand the listener itself over, but this still lets a newer version be started next to the old one.
 
     #[cfg(not(target_os = "windows"))]
     match socket.set_reuse_port(true) {
@@ -246,10 +260,28 @@ async fn listen_http(
         Err(e) => tracing::warn!("Failed to set_reuse_address: {e:?}")
     }
     socket.bind(&bind_addr.into()).context(format!("Attempt to bind to port {:?} failed.",bind_addr))?;
-    socket.listen(1024).context("must be able to bind http listener.")?;
+    socket.listen(1024).context(format!("must be able to listen on {bind_addr}."))?;
     let listener: std::net::TcpListener = socket.into();
-    listener.set_nonblocking(true).context("must be able to set_nonblocking on http listener")?;
+    listener.set_nonblocking(true).context(format!("must be able to set_nonblocking on the listener for {bind_addr}"))?;
+    Ok(listener)
+}
+
+async fn listen_http(
+    settings: Arc<ListenerSettings>,
+    tx: std::sync::Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
+    state: Arc<GlobalState>,
+    terminating_service_template: ReverseProxyService,
+    _shutdown_signal: Arc<Notify> ,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<()> {
+    
+    let bind_addr = settings.bind_addr;
+    let listener = match crate::hot_upgrade::inherited_tcp_listener(bind_addr) {
+        Some(l) => l,
+        None => bind_tcp_listener(&settings)?
+    };
     let tokio_listener = tokio::net::TcpListener::from_std(listener).context("we must be able to listen to http port..")?;
+    let _handoff_registration = crate::hot_upgrade::register_socket(crate::hot_upgrade::SocketKind::Tcp, bind_addr, &tokio_listener);
     
     loop {
 
@@ -289,8 +321,9 @@ async fn listen_http(
                         service.remote_addr = Some(source_addr);   
                         let tx = tx.clone();
                         let state = state.clone();
+                        let settings = settings.clone();
                         tokio::spawn(async move {           
-                            handle_new_tcp_stream(None, service,tcp_stream, source_addr,tx.clone(),state.clone(),None)
+                            handle_new_tcp_stream(None, service,tcp_stream, source_addr,tx.clone(),state.clone(),None,settings)
                                 .await;
                             ACTIVE_TCP_CONNECTIONS_SEMAPHORE.add_permits(1);
                         });
@@ -313,7 +346,7 @@ async fn listen_http(
 
 
 async fn listen_https(
-    bind_addr: SocketAddr,
+    settings: Arc<ListenerSettings>,
     tx: std::sync::Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
     state: Arc<GlobalState>,
     terminating_service_template: ReverseProxyService,
@@ -321,37 +354,13 @@ async fn listen_https(
     cancel_token: CancellationToken,
 ) -> anyhow::Result<()> {
 
-    use socket2::{Domain,Type};
-
-    let socket = Socket::new(Domain::for_address(bind_addr), Type::STREAM, Some(Protocol::TCP)).expect("should always be possible to create a tcp socket for tls");
-    match socket.set_only_v6(false) {
-        Ok(_) => {},
-        Err(e) => tracing::trace!("Failed to set_only_vs: {e:?}")
+    let bind_addr = settings.bind_addr;
+    let listener = match crate::hot_upgrade::inherited_tcp_listener(bind_addr) {
+        Some(l) => l,
+        None => bind_tcp_listener(&settings)?
     };
-    
-    // note: we reuse here as we want to be able to run multiple instances of odd-box at the same time.
-    // if nothing else it lets us launch a newer version of the server without stopping the old, so that we can upgrade
-    // to a later version without downtime.
-    
-    match socket.set_reuse_address(true) { // annoying as hell otherwise for quick resets
-        Ok(_) => {},
-        Err(e) => tracing::warn!("Failed to set_reuse_address: {e:?}")
-    }
-
-    #[cfg(not(target_os = "windows"))]
-    match socket.set_reuse_port(true) {
-        Ok(_) => {},
-        Err(e) => {
-            tracing::warn!("Not able to reuse port. This means odd-box cannot be updated in place with zero-downtime. {e:?}")
-        }
-    }
-
-    socket.bind(&bind_addr.into()).context(format!("Attempt to bind to port {:?} failed.",bind_addr))?;
-    socket.listen(1024).context("we must be able to listen to https addr socket..")?;
-
-    let listener: std::net::TcpListener = socket.into();
-    listener.set_nonblocking(true).context("must be able to set_nonblocking on https listener")?;
     let tokio_listener = tokio::net::TcpListener::from_std(listener).context("we must be able to listen to https port..")?;
+    let _handoff_registration = crate::hot_upgrade::register_socket(crate::hot_upgrade::SocketKind::Tcp, bind_addr, &tokio_listener);
     
     let mut rustls_config = 
         tokio_rustls::rustls::ServerConfig::builder()
@@ -360,8 +369,11 @@ async fn listen_https(
     
     if let Some(true) = state.config.read().await.alpn {
         rustls_config.alpn_protocols.push("h2".into());
-        rustls_config.alpn_protocols.push("http/1.1".into());
     }
+    rustls_config.alpn_protocols.push("http/1.1".into());
+    // only ever picked by lets-encrypt validating a tls-alpn-01 challenge, since no other client offers it.
+    // it is offered even when alpn is disabled, in which case clients that ask for alpn get http/1.1 as they would have assumed anyway.
+    rustls_config.alpn_protocols.push(crate::letsencrypt::ACME_TLS_ALPN_PROTOCOL.into());
 
     let arced_tls_config = std::sync::Arc::new(rustls_config);
 
@@ -406,8 +418,9 @@ async fn listen_https(
                         let tx = tx.clone();
                         let arced_tls_config = Some(arced_tls_config.clone());
                         let state = state.clone();
+                        let settings = settings.clone();
                         tokio::spawn(async move {               
-                            handle_new_tcp_stream(arced_tls_config,service,tcp_stream, source_addr,tx.clone(),state.clone(),Some(api))
+                            handle_new_tcp_stream(arced_tls_config,service,tcp_stream, source_addr,tx.clone(),state.clone(),Some(api),settings)
                                 .await;
                             ACTIVE_TCP_CONNECTIONS_SEMAPHORE.add_permits(1);
                         });
@@ -437,17 +450,53 @@ async fn listen_https(
 async fn handle_new_tcp_stream(
     rustls_config: Option<std::sync::Arc<tokio_rustls::rustls::ServerConfig>>,
     mut fresh_service_template_with_source_info: ReverseProxyService,
-    tcp_stream: TcpStream,
+    mut tcp_stream: TcpStream,
     source_addr:SocketAddr,
     tx: std::sync::Arc<tokio::sync::broadcast::Sender<ProcMessage>>,
     state: Arc<GlobalState>,
-    api: Option<OddBoxAPI>
+    api: Option<OddBoxAPI>,
+    listener: Arc<ListenerSettings>
 ) {
 
+    let Ok(mut local_addr) = tcp_stream.local_addr() else {
+        tracing::warn!("Failed to read the local address of a connection from {source_addr}, dropping it.");
+        return;
+    };
+
+    // when we are behind a load balancer, the real client address is sent in a PROXY header at the start of the connection.
+    // we only look for it on connections from trusted sources since anyone else could use it to spoof their address.
+    let mut source_addr = source_addr;
+    let mut got_proxy_header = false;
+    let trusted_proxies = state.config.read().await.proxy_protocol_trusted_cidrs.clone();
+    let is_trusted_proxy = trusted_proxies.as_deref()
+        .is_some_and(|cidrs| crate::access_control::AccessRules { allow: Some(cidrs), deny: None }.allows(source_addr.ip()));
+    if is_trusted_proxy {
+        match crate::proxy_protocol::read_header(&mut tcp_stream).await {
+            Ok(Some((client, destination))) => {
+                tracing::trace!("Got PROXY header from {source_addr}, the real client is {client}.");
+                source_addr = client;
+                local_addr = destination;
+                got_proxy_header = true;
+                fresh_service_template_with_source_info.remote_addr = Some(client);
+            },
+            Ok(None) => {},
+            Err(e) => {
+                tracing::debug!("Dropping connection from {source_addr} due to an invalid PROXY header: {e:?}");
+                return;
+            }
+        }
+    }
+
     let mut peekable_tcp_stream = GenericManagedStream::from_tcp_stream(tcp_stream,state.clone());
     let peek_result =  peekable_tcp_stream.peek_managed_stream(source_addr).await;
     peekable_tcp_stream.seal();
     peekable_tcp_stream.track();
+    if got_proxy_header {
+        peekable_tcp_stream.update_tracked_info(|x| {
+            x.client_socket_address = Some(source_addr);
+            x.client_addr_string = format!("{source_addr:?}");
+        });
+    }
     fresh_service_template_with_source_info.connection_key = *peekable_tcp_stream.get_id();
     fresh_service_template_with_source_info.source_addr = Some(source_addr);
 
@@ -489,15 +538,32 @@ async fn handle_new_tcp_stream(
             });
     
             let is_tls = typ == DataType::TLS;
-            let ourl = state.config.read().await.odd_box_url.clone().unwrap_or(String::from("!"));
+
+            // auto listeners handle clear text connections the same way as the plain http listeners do
+            let (rustls_config, api) = if listener.protocol == ListenerProtocol::Auto && !is_tls { (None, None) } else { (rustls_config, api) };
+
+            if listener.sites.is_some() && !h2_authority_or_h1_host_header.as_ref().or(sni.as_ref()).is_some_and(|h| listener.serves(h)) {
+                tracing::debug!("Dropping connection from {source_addr:?} for {:?} as the site is not served on the listener at {}.", h2_authority_or_h1_host_header.as_ref().or(sni.as_ref()), listener.bind_addr);
+                return;
+            }
+            let (ourl, odd_box_allow_cidrs) = {
+                let cfg = state.config.read().await;
+                (cfg.odd_box_url.clone().unwrap_or(String::from("!")), cfg.odd_box_allow_cidrs.clone())
+            };
+            let admin_access_allowed = crate::access_control::AccessRules { allow: odd_box_allow_cidrs.as_deref(), deny: None }.allows(source_addr.ip());
             match h2_authority_or_h1_host_header.as_ref().map(|x| x.as_str()) {
                 Some("oddbox.localhost") |
                 Some("odd-box.localhost") |
                 Some("localhost") => {
                     if let Some(api) = api {
+                        if !admin_access_allowed {
+                            tracing::debug!("Dropping connection to the admin interface from {source_addr:?} as it is not in odd_box_allow_cidrs.");
+                            return;
+                        }
                         //peekable_tcp_stream.do_not_observe();
                         peekable_tcp_stream.update_tracked_info(|x| {
                             x.http_terminated = true;
+                            x.is_admin_interface = true;
                         });
                         _ = api.handle_stream(peekable_tcp_stream,rustls_config).await;
                         return;
@@ -507,9 +573,14 @@ async fn handle_new_tcp_stream(
                     if x == &ourl {
                         tracing::trace!("handling incoming request from '{source_addr:?}' to odd-box system services thru odd-box-url: '{x}'.");
                         if let Some(api) = api {
+                            if !admin_access_allowed {
+                                tracing::debug!("Dropping connection to the admin interface from {source_addr:?} as it is not in odd_box_allow_cidrs.");
+                                return;
+                            }
                             //peekable_tcp_stream.do_not_observe();
                             peekable_tcp_stream.update_tracked_info(|x| {
                                 x.http_terminated = true;
+                                x.is_admin_interface = true;
                             });
                             _ = api.handle_stream(peekable_tcp_stream,rustls_config).await;
                             return;
@@ -533,7 +604,41 @@ async fn handle_new_tcp_stream(
 
                 let cloned_target = target.clone();
 
+                // connections from denied clients are dropped before anything else happens, so they can not start hosted processes either.
+                // sites using lets-encrypt are the exception, the terminating proxy lets the challenges thru and answers anything else with a 403.
+                let access_denied = crate::access_control::rules_for_target(&target).is_some_and(|r| !r.allows(source_addr.ip()));
+                if access_denied {
+                    let uses_lets_encrypt = target.hosted_target_config.as_ref().and_then(|x| x.enable_lets_encrypt)
+                        .or_else(|| target.remote_target_config.as_ref().and_then(|x| x.enable_lets_encrypt))
+                        .unwrap_or_default();
+                    if !uses_lets_encrypt {
+                        tracing::debug!("Dropping connection from {source_addr:?} to {} as the client address is not allowed.", target.host_name);
+                        return;
+                    }
+                    fresh_service_template_with_source_info.resolved_target = Some(target.clone());
+                    return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info,
+                        FallbackReason::HttpTerminationEnforced("The client address is not allowed, only lets-encrypt challenges will be answered.".into())).await;
+                }
+
                 fresh_service_template_with_source_info.resolved_target = Some(cloned_target.clone());
+
+                // rate limits are checked before we terminate tls or pick a backend, so limited clients can not start hosted processes either.
+                // connections that we can terminate are handed to the terminating proxy which answers with a 429, passthru tls is just dropped.
+                if let Some(limit) = target.rate_limit() {
+                    let key = crate::rate_limiting::bucket_key(limit, Some(source_addr.ip()), None);
+                    let is_passthru_tls = is_tls && (rustls_config.is_none() || !target.must_terminate_tls());
+                    if is_passthru_tls {
+                        if crate::rate_limiting::try_acquire(&state, &target.host_name, limit, key).is_err() {
+                            tracing::debug!("Rate limit exceeded for {}, dropping tls connection from {source_addr:?}.", target.host_name);
+                            return;
+                        }
+                    } else if crate::rate_limiting::take_token(&state, &target.host_name, limit, key.clone()).is_ok() {
+                        fresh_service_template_with_source_info.rate_limit_token = crate::rate_limiting::PrepaidToken::new(target.host_name.clone(), key);
+                    } else {
+                        return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info,
+                            FallbackReason::HttpTerminationEnforced("The rate limit is exceeded, the terminating proxy will answer with a 429.".into())).await;
+                    }
+                }
                 
                 if target.is_hosted {
                     
@@ -557,6 +662,24 @@ async fn handle_new_tcp_stream(
                             return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
                                 FallbackReason::HttpTerminationEnforced(format!("Lets encrypt is enabled for the site"))).await;
                         }
+
+                        if cfg.routes.as_ref().is_some_and(|r| !r.is_empty()) {
+                            tracing::trace!("Path based routes are configured for the hosted site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Path based routes are configured for the site."))).await;
+                        }
+
+                        if cfg.request_headers.is_some() || cfg.response_headers.is_some() {
+                            tracing::trace!("Header rules are configured for the hosted site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Header rules are configured for the site."))).await;
+                        }
+
+                        if cfg.basic_auth.is_some() || cfg.forward_auth.is_some() {
+                            tracing::trace!("Authentication is configured for the hosted site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Authentication is configured for the site."))).await;
+                        }
                         
                        
 
@@ -610,6 +733,7 @@ async fn handle_new_tcp_stream(
                         is_tls,
                         state.clone(),
                         source_addr, 
+                        local_addr,
                         rustls_config.clone(),
                         target_host_name,
                         http_version,
@@ -655,6 +779,24 @@ async fn handle_new_tcp_stream(
                             return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
                                 FallbackReason::HttpTerminationEnforced(format!("Lets encrypt is enabled for the site"))).await;
                         }
+
+                        if cfg.routes.as_ref().is_some_and(|r| !r.is_empty()) {
+                            tracing::trace!("Path based routes are configured for the remote site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Path based routes are configured for the site."))).await;
+                        }
+
+                        if cfg.request_headers.is_some() || cfg.response_headers.is_some() {
+                            tracing::trace!("Header rules are configured for the remote site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Header rules are configured for the site."))).await;
+                        }
+
+                        if cfg.basic_auth.is_some() || cfg.forward_auth.is_some() {
+                            tracing::trace!("Authentication is configured for the remote site: {}, falling back to http terminating mode.", target.host_name);
+                            return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, 
+                                FallbackReason::HttpTerminationEnforced(format!("Authentication is configured for the site."))).await;
+                        }
                         
                         // FOR REMOTE TARGETS WE NORMALLY WANT TO SEND THE BACKEND HOST NAME AS THE HOST HEADER.
                         // IF NOT EXPLICITLY SET TO TRUE, THEN WE WILL THUS NEED TO USE FALLBACK (level 7) HTTP TERMINATION 
@@ -703,7 +845,7 @@ async fn handle_new_tcp_stream(
                         cloned_target, 
                         is_tls,
                         state.clone(),
-                        source_addr,rustls_config.clone(),
+                        source_addr,local_addr,rustls_config.clone(),
                         target_host_name,
                         http_version,
                         is_h2c_upgrade
@@ -734,6 +876,7 @@ async fn handle_new_tcp_stream(
         Err(e) => {
             match e {
                 tcp_proxy::PeekError::H2PriorKnowledgeNeedsToBeTerminated => {
+                    let rustls_config = if listener.protocol == ListenerProtocol::Auto { None } else { rustls_config };
                     return use_fallback_mode(rustls_config, peekable_tcp_stream, fresh_service_template_with_source_info, FallbackReason::H2CPriorKnowledge).await;
                 },
                 tcp_proxy::PeekError::StreamIsClosed => {
@@ -816,6 +959,10 @@ async fn use_fallback_mode(
                             
                     let tls_acceptor = TlsAcceptor::from(tls_cfg.clone());
                     match tls_acceptor.accept(peekable_tcp_stream).await {
+                        Ok(tls_stream) if tls_stream.get_ref().1.alpn_protocol() == Some(crate::letsencrypt::ACME_TLS_ALPN_PROTOCOL) => {
+                            // the handshake is all there is to a tls-alpn-01 validation
+                            tracing::trace!("Closing tls-alpn-01 validation connection");
+                        },
                         Ok(tls_stream) => {
                             let sni = tls_stream.get_ref().1.server_name().map(|x|x.to_string());
                             fresh_service_template_with_source_info.is_https = true;
@@ -0,0 +1,200 @@
+// PROXY protocol (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) support.
+// Load balancers in front of odd-box can prepend a header with the real client address to each connection,
+// and odd-box can do the same for backends when it tunnels connections to them.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use crate::configuration::ProxyProtocolVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+/// Signature, version/command, family and length.
+const V2_FIXED_LEN: usize = 16;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// More data is needed to tell whether there is a header and how long it is.
+    Incomplete,
+    /// The connection does not start with a PROXY header.
+    NotProxy,
+    /// A header of `len` bytes. The addresses are missing for health checks (v2 LOCAL) and unknown protocols.
+    Header { len: usize, source: Option<SocketAddr>, destination: Option<SocketAddr> },
+}
+
+/// Looks at the start of a connection and tells if it is a PROXY header (v1 or v2).
+pub fn parse(buf: &[u8]) -> Result<Parsed, String> {
+    if buf.len() < V1_PREFIX.len() && V1_PREFIX.starts_with(buf) || buf.len() < V2_SIGNATURE.len() && V2_SIGNATURE.starts_with(buf) {
+        return Ok(Parsed::Incomplete)
+    }
+    if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else {
+        Ok(Parsed::NotProxy)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Parsed, String> {
+    let searchable = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searchable.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() < V1_MAX_LEN {
+            return Ok(Parsed::Incomplete)
+        }
+        return Err("PROXY v1 header is not terminated".into())
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| "PROXY v1 header is not valid ascii".to_string())?;
+    let len = end + 2;
+    let parts : Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(Parsed::Header { len, source: None, destination: None }),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let address = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+                let ip : IpAddr = ip.parse().map_err(|e| format!("invalid address in PROXY v1 header: {e}"))?;
+                let port : u16 = port.parse().map_err(|e| format!("invalid port in PROXY v1 header: {e}"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            let (source, destination) = (address(src, src_port)?, address(dst, dst_port)?);
+            let ipv4 = *family == "TCP4";
+            if source.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+                return Err(format!("addresses in PROXY v1 header do not match the {family} family"))
+            }
+            Ok(Parsed::Header { len, source: Some(source), destination: Some(destination) })
+        },
+        _ => Err(format!("unsupported PROXY v1 header: {line:?}"))
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed, String> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(Parsed::Incomplete)
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0F;
+    if version != 2 {
+        return Err(format!("unsupported PROXY header version {version}"))
+    }
+    let len = V2_FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    // LOCAL is used by load balancers for their own health checks, the socket address is the real one.
+    if command == 0 {
+        return Ok(Parsed::Header { len, source: None, destination: None })
+    }
+    if command != 1 {
+        return Err(format!("unsupported PROXY v2 command {command}"))
+    }
+    let address_len = match buf[13] >> 4 {
+        1 => 12,
+        2 => 36,
+        // AF_UNSPEC and AF_UNIX do not carry anything we can use
+        _ => return Ok(Parsed::Header { len, source: None, destination: None })
+    };
+    if len < V2_FIXED_LEN + address_len {
+        return Err("PROXY v2 header is too short for its address family".into())
+    }
+    if buf.len() < V2_FIXED_LEN + address_len {
+        return Ok(Parsed::Incomplete)
+    }
+    let a = &buf[V2_FIXED_LEN..];
+    let (source, destination) = if address_len == 12 {
+        let src = IpAddr::from(<[u8; 4]>::try_from(&a[0..4]).expect("slice has the right length"));
+        let dst = IpAddr::from(<[u8; 4]>::try_from(&a[4..8]).expect("slice has the right length"));
+        (SocketAddr::new(src, u16::from_be_bytes([a[8], a[9]])), SocketAddr::new(dst, u16::from_be_bytes([a[10], a[11]])))
+    } else {
+        let src = IpAddr::from(<[u8; 16]>::try_from(&a[0..16]).expect("slice has the right length"));
+        let dst = IpAddr::from(<[u8; 16]>::try_from(&a[16..32]).expect("slice has the right length"));
+        (SocketAddr::new(src, u16::from_be_bytes([a[32], a[33]])), SocketAddr::new(dst, u16::from_be_bytes([a[34], a[35]])))
+    };
+    Ok(Parsed::Header { len, source: Some(source), destination: Some(destination) })
+}
+
+/// Reads a PROXY header from the start of the stream, if there is one, and returns the client and destination addresses it carries.
+/// Only the header itself is consumed so the rest of the stream can be peeked at and proxied as usual.
+pub async fn read_header(stream: &mut TcpStream) -> anyhow::Result<Option<(SocketAddr, SocketAddr)>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_without_timeout(stream)).await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for the PROXY header"))?
+}
+
+async fn read_header_without_timeout(stream: &mut TcpStream) -> anyhow::Result<Option<(SocketAddr, SocketAddr)>> {
+    // the first data that arrives is enough to tell if the connection can start with a header at all
+    let mut start = [0u8; V2_SIGNATURE.len()];
+    let n = stream.peek(&mut start).await?;
+    if n == 0 {
+        anyhow::bail!("connection closed before the PROXY header was received")
+    }
+    if parse(&start[..n]).map_err(anyhow::Error::msg)? == Parsed::NotProxy {
+        return Ok(None)
+    }
+    // after that the header is read without going past its end, which for v1 means a byte at a time until the CRLF
+    let mut header = Vec::with_capacity(V1_MAX_LEN);
+    loop {
+        match parse(&header).map_err(anyhow::Error::msg)? {
+            Parsed::Incomplete => {
+                let wanted = if header.len() >= V2_FIXED_LEN && header.starts_with(V2_SIGNATURE) {
+                    (V2_FIXED_LEN + u16::from_be_bytes([header[14], header[15]]) as usize).saturating_sub(header.len()).max(1)
+                } else {
+                    1
+                };
+                let read = header.len();
+                header.resize(read + wanted, 0);
+                stream.read_exact(&mut header[read..]).await?;
+            },
+            Parsed::NotProxy => anyhow::bail!("connection started like a PROXY header but was something else"),
+            Parsed::Header { len, source, destination } => {
+                // v2 headers can carry extensions after the addresses, which we have no use for
+                let mut rest = vec![0u8; len.saturating_sub(header.len())];
+                stream.read_exact(&mut rest).await?;
+                return Ok(source.zip(destination))
+            }
+        }
+    }
+}
+
+/// Creates the header sent to backends in front of tunnelled connections.
+pub fn encode_header(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    // both addresses must be of the same family, so mixed connections are sent as ipv6.
+    let (src_ip, dst_ip) = match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d)))
+    };
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {family} {src_ip} {dst_ip} {} {}\r\n", source.port(), destination.port()).into_bytes()
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x21); // version 2, PROXY command
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    header.push(0x11); // AF_INET, STREAM
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&s.octets());
+                    header.extend_from_slice(&d.octets());
+                },
+                (s, d) => {
+                    header.push(0x21); // AF_INET6, STREAM
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&to_ipv6(s).octets());
+                    header.extend_from_slice(&to_ipv6(d).octets());
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6
+    }
+}
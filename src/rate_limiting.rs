@@ -0,0 +1,110 @@
+// Token bucket rate limiting for sites that have a rate_limit configured.
+// Connections take a token when they are accepted, before any tls is terminated or hosted process started.
+// Tunnelled connections are not charged again since we cannot see the individual requests inside of them, while
+// terminated http requests take a token each - the first one on a connection uses the token taken when it was accepted.
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::configuration::{ConfigWrapper, RateLimit, RateLimitKey};
+use crate::global_state::GlobalState;
+use crate::types::proxy_state::TokenBucket;
+
+lazy_static::lazy_static! {
+    static ref LAST_CLEANUP: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// Finds the rate limit of whichever site handles the given host name, along with the host_name of that site.
+pub fn rate_limit_for_host<'a>(configuration: &'a ConfigWrapper, host_name: &str) -> Option<(String, &'a RateLimit)> {
+    let site = configuration.find_site(host_name)?;
+    site.rate_limit().map(|limit| (site.host_name().to_string(), limit))
+}
+
+/// The key of the bucket that a request or connection is counted against.
+/// Headers are only available when terminating http, so tunnels always fall back to the client ip.
+pub fn bucket_key(limit: &RateLimit, client_ip: Option<IpAddr>, headers: Option<&hyper::HeaderMap>) -> String {
+    let by_ip = || client_ip.map(|ip| format!("ip:{ip}")).unwrap_or_default();
+    match limit.key.as_ref().unwrap_or(&RateLimitKey::ClientIp) {
+        RateLimitKey::ClientIp => by_ip(),
+        RateLimitKey::Site => String::new(),
+        RateLimitKey::Header => limit.header.as_ref()
+            .and_then(|name| headers.and_then(|h| h.get(name.as_str())))
+            .map(|v| format!("header:{}", String::from_utf8_lossy(v.as_bytes())))
+            .unwrap_or_else(by_ip)
+    }
+}
+
+/// Takes a token from the bucket. If the bucket is empty, the request is counted as limited
+/// and the time until the next token becomes available is returned.
+pub fn try_acquire(state: &GlobalState, host_name: &str, limit: &RateLimit, key: String) -> Result<(), Duration> {
+    let result = take_token(state, host_name, limit, key);
+    if result.is_err() {
+        state.app_state.statistics.rate_limited_requests
+            .entry(host_name.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Same as try_acquire, except that an empty bucket is not counted as a limited request.
+/// Used for connections that are handed to the terminating proxy, which does the counting when it answers with a 429.
+pub fn take_token(state: &GlobalState, host_name: &str, limit: &RateLimit, key: String) -> Result<(), Duration> {
+    remove_full_buckets(state);
+    let now = Instant::now();
+    let burst = limit.burst();
+    let rate = limit.tokens_per_second();
+    let mut bucket = state.app_state.statistics.rate_limit_buckets
+        .entry((host_name.to_string(), key))
+        .or_insert_with(|| TokenBucket { tokens: burst, last_refill: now, full_at: now });
+    let refilled = now.saturating_duration_since(bucket.last_refill).as_secs_f64() * rate;
+    bucket.tokens = (bucket.tokens + refilled).min(burst);
+    bucket.last_refill = now;
+    let result = if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    };
+    bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / rate);
+    result
+}
+
+/// The token a connection took when it was accepted, which pays for the first terminated request on it
+/// as long as that request is counted against the same bucket.
+#[derive(Debug, Clone, Default)]
+pub struct PrepaidToken(Arc<Mutex<Option<(String, String)>>>);
+
+impl PrepaidToken {
+    pub fn new(host_name: String, key: String) -> Self {
+        Self(Arc::new(Mutex::new(Some((host_name, key)))))
+    }
+    /// Uses up the token if it was taken from the bucket of the given site and key.
+    pub fn take(&self, host_name: &str, key: &str) -> bool {
+        let mut token = self.0.lock().expect("prepaid token should not be poisoned");
+        let matches = token.as_ref().is_some_and(|(h, k)| h == host_name && k == key);
+        if matches {
+            *token = None;
+        }
+        matches
+    }
+}
+
+/// Value for the Retry-After header, which only supports whole seconds.
+pub fn retry_after_seconds(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+// a full bucket behaves exactly like a missing one, so there is no reason to keep them around
+// for every client that has ever connected.
+fn remove_full_buckets(state: &GlobalState) {
+    let Ok(mut last_cleanup) = LAST_CLEANUP.try_lock() else { return };
+    if last_cleanup.elapsed() < Duration::from_secs(60) {
+        return
+    }
+    *last_cleanup = Instant::now();
+    let now = Instant::now();
+    state.app_state.statistics.rate_limit_buckets.retain(|_, b| b.full_at > now);
+}
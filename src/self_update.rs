@@ -20,6 +20,8 @@ fn update_from_github(target_tag:&str,current_version:&str) -> anyhow::Result<()
         .build()?
         .update()?;
     println!("Update status: `{}`!", status.version());
+    #[cfg(unix)]
+    println!("Running instances can switch to the new version without downtime: send them SIGUSR2 or POST to /api/upgrade.");
     Ok(())
 }
 
@@ -0,0 +1,121 @@
+// Sticky sessions for remote sites.
+// Terminated http traffic carries a signed cookie naming the backend it was sent to,
+// while tunnelled connections (where we cannot see or set cookies) are pinned by hashing the client ip.
+
+use std::io::Write;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+
+use crate::configuration::{Backend, RemoteSiteConfig, SessionAffinity};
+use crate::global_state::GlobalState;
+use crate::load_balancing::{BackendSelector, ConsistentHash, SelectionContext};
+
+lazy_static::lazy_static! {
+    static ref SIGNING_KEY: hmac::Key = load_or_create_signing_key();
+}
+
+// The key is persisted so that pinned clients stay on their backend when odd-box restarts.
+fn load_or_create_signing_key() -> hmac::Key {
+    let key_path = ".odd_box_cache/session_affinity.key";
+    if let Ok(bytes) = std::fs::read(key_path) {
+        if bytes.len() == 32 {
+            return hmac::Key::new(hmac::HMAC_SHA256, &bytes);
+        }
+        tracing::warn!("Ignoring invalid session affinity key in {key_path}, a new one will be generated.");
+    }
+    let rng = ring::rand::SystemRandom::new();
+    let bytes : [u8; 32] = ring::rand::generate(&rng).expect("system rng should always be available").expose();
+    let persisted = std::fs::create_dir_all(".odd_box_cache")
+        .and_then(|_| {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            // anyone who can read the key can forge cookies pinning clients to any backend
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(key_path)
+        })
+        .and_then(|mut f| {
+            // the mode only applies to new files, an invalid key left behind may have been readable by others
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            }
+            f.write_all(&bytes)
+        });
+    if let Err(e) = persisted {
+        tracing::warn!("Failed to persist session affinity key, sticky sessions will not survive a restart: {e:?}");
+    }
+    hmac::Key::new(hmac::HMAC_SHA256, &bytes)
+}
+
+fn backend_id(backend: &Backend) -> String {
+    format!("{}:{}", backend.address, backend.port)
+}
+
+fn sign(host_name: &str, id: &str) -> String {
+    let tag = hmac::sign(&SIGNING_KEY, format!("{host_name}|{id}").as_bytes());
+    URL_SAFE_NO_PAD.encode(tag.as_ref())
+}
+
+/// Returns the backend id ("address:port") from the affinity cookie if the cookie was signed by us for this site.
+fn pinned_backend_id(site: &RemoteSiteConfig, affinity: &SessionAffinity, headers: &hyper::HeaderMap) -> Option<String> {
+    let cookie_name = affinity.cookie_name();
+    let value = headers.get_all(hyper::header::COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| cookie::Cookie::parse(pair.trim()).ok())
+        .find(|c| c.name() == cookie_name)
+        .map(|c| c.value().to_string())?;
+    let (encoded_id, signature) = value.split_once('.')?;
+    let id = String::from_utf8(URL_SAFE_NO_PAD.decode(encoded_id).ok()?).ok()?;
+    let tag = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    hmac::verify(&SIGNING_KEY, format!("{}|{id}", site.host_name).as_bytes(), &tag).ok()?;
+    Some(id)
+}
+
+/// Picks the pinned backend among the candidates, if any. Returning None means that the normal
+/// load balancing strategy should be used, for example because the client has not been pinned yet
+/// or because the backend it was pinned to is no longer available.
+pub fn select_pinned<'b>(state: &GlobalState, site: &RemoteSiteConfig, candidates: &[&'b Backend], ctx: &SelectionContext) -> Option<&'b Backend> {
+    let affinity = site.session_affinity.as_ref()?;
+    match ctx.headers {
+        Some(headers) => {
+            let id = pinned_backend_id(site, affinity, headers)?;
+            let pinned = candidates.iter().find(|b| backend_id(b) == id).copied();
+            if pinned.is_none() {
+                tracing::debug!("Backend {id} pinned by session cookie is no longer available for {}, picking a new one.", site.host_name);
+            }
+            pinned
+        },
+        None => {
+            // the source port changes for each connection so only the ip is useful for pinning.
+            // rendezvous hashing means clients only move when their own backend goes away.
+            let ip_only = SelectionContext { headers: None, ..*ctx };
+            ctx.client_ip.and_then(|_| ConsistentHash.select(state, site, candidates, &ip_only))
+        }
+    }
+}
+
+/// Creates a Set-Cookie value pinning the client to the backend, unless the request already carried a valid cookie for it.
+pub fn cookie_for_response(site: &RemoteSiteConfig, request_headers: &hyper::HeaderMap, backend: &Backend) -> Option<String> {
+    let affinity = site.session_affinity.as_ref()?;
+    let id = backend_id(backend);
+    if pinned_backend_id(site, affinity, request_headers).as_deref() == Some(id.as_str()) {
+        return None
+    }
+    let value = format!("{}.{}", URL_SAFE_NO_PAD.encode(id.as_bytes()), sign(&site.host_name, &id));
+    let mut cookie = cookie::Cookie::build((affinity.cookie_name().to_string(), value))
+        .path("/")
+        .http_only(true)
+        .same_site(cookie::SameSite::Lax);
+    if let Some(secs) = affinity.cookie_max_age_in_seconds {
+        cookie = cookie.max_age(cookie::time::Duration::seconds(secs as i64));
+    }
+    Some(cookie.build().to_string())
+}
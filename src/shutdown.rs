@@ -0,0 +1,97 @@
+// Graceful shutdown. Once requested, the listeners stop accepting new connections and those that are already open
+// get until the drain deadline to finish. Only then is app_state.exit set, which stops the hosted processes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::global_state::GlobalState;
+
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct ShutdownState {
+    draining: tokio::sync::watch::Sender<bool>,
+    deadline: OnceLock<Instant>,
+    processes_handed_over: AtomicBool,
+}
+
+impl Default for ShutdownState {
+    fn default() -> Self {
+        Self { draining: tokio::sync::watch::Sender::new(false), deadline: OnceLock::new(), processes_handed_over: AtomicBool::new(false) }
+    }
+}
+
+impl ShutdownState {
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+    /// Resolves once a graceful shutdown has started, right away if it already has.
+    pub async fn started(&self) {
+        let mut receiver = self.draining.subscribe();
+        // the sender lives as long as self, so this can only return once the value is true
+        _ = receiver.wait_for(|d| *d).await;
+    }
+    /// Time left until the remaining connections are cut off, once the drain has started.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline.get().map(|d| d.saturating_duration_since(Instant::now()))
+    }
+    /// Set by hot upgrades. The hosted processes then belong to the new instance and are left running when we exit.
+    pub fn hand_over_processes(&self) {
+        self.processes_handed_over.store(true, Ordering::SeqCst);
+    }
+    pub fn processes_handed_over(&self) -> bool {
+        self.processes_handed_over.load(Ordering::SeqCst)
+    }
+}
+
+/// Starts draining connections. If a shutdown is already in progress the rest of the drain is skipped instead,
+/// so that asking twice (pressing ctrl-c again for example) stops odd-box right away.
+/// This does not need a tokio runtime as it is also called from the ctrl-c handler thread.
+pub fn request(state: &GlobalState) {
+    if state.app_state.shutdown.draining.send_replace(true) {
+        tracing::warn!("Shutdown requested again, skipping the rest of the connection drain.");
+        state.app_state.exit.store(true, Ordering::SeqCst);
+    } else {
+        tracing::warn!("Shutdown requested, no longer accepting new connections.");
+    }
+}
+
+/// Tracked client connections that are still open. Udp sessions end as soon as their listener stops, so they do not hold up the drain.
+/// Connections to the admin interface are left out, as the event streams of the web ui would otherwise keep us waiting for the whole drain timeout.
+pub fn open_connections(state: &GlobalState) -> usize {
+    state.app_state.statistics.active_connections.iter().filter(|x| !x.value().is_admin_interface).count()
+}
+
+pub async fn bg_worker_for_shutdown(state: Arc<GlobalState>) {
+    state.app_state.shutdown.started().await;
+
+    let timeout = state.config.read().await.drain_timeout_in_seconds.map(Duration::from_secs).unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+    let deadline = Instant::now() + timeout;
+    _ = state.app_state.shutdown.deadline.set(deadline);
+    tracing::warn!("Waiting up to {}s for {} open connections to finish..", timeout.as_secs(), open_connections(&state));
+
+    // progress is logged every few seconds so that it also reaches the admin interface thru the event stream
+    let mut last_report = Instant::now();
+    loop {
+        if state.app_state.exit.load(Ordering::SeqCst) {
+            return
+        }
+        let open = open_connections(&state);
+        if open == 0 {
+            tracing::info!("All connections have finished.");
+            break;
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("Drain deadline reached with {open} connections still open, they will be closed.");
+            break;
+        }
+        if last_report.elapsed() >= Duration::from_secs(5) {
+            tracing::info!("Waiting for {open} open connections to finish ({}s left)..", deadline.saturating_duration_since(Instant::now()).as_secs());
+            last_report = Instant::now();
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    state.app_state.exit.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,409 @@
+// Listeners for tcp_stream and udp_stream sites. These run on ports of their own next to the http and tls listeners
+// and forward traffic without looking at it, except for reading the SNI when a tcp_stream site has sni_routes.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::configuration::{Backend, TcpStreamSite, UdpStreamSite};
+use crate::global_state::GlobalState;
+use crate::tcp_proxy::{GenericManagedStream, ManagedStream, Peekable, TlsClientHello, TlsClientHelloError};
+use crate::types::proc_info::BgTaskInfo;
+use crate::types::proxy_state::{ConnectionKey, OutgoingTunnelType, ProxyActiveTCPConnection};
+
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Datagrams from new clients are dropped once a site has this many sessions, each session holds a socket of its own.
+const MAX_UDP_SESSIONS_PER_SITE: usize = 1024;
+/// Datagrams from a client that its session has not forwarded yet, such as while the backend is being looked up.
+/// Any more are dropped, the same as when a socket buffer is full.
+const MAX_QUEUED_DATAGRAMS_PER_SESSION: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref ROUND_ROBIN_COUNTERS: DashMap<String, AtomicUsize> = DashMap::new();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum StreamSite {
+    Tcp(TcpStreamSite),
+    Udp(UdpStreamSite),
+}
+
+impl StreamSite {
+    fn name(&self) -> &str {
+        match self {
+            StreamSite::Tcp(x) => &x.name,
+            StreamSite::Udp(x) => &x.name,
+        }
+    }
+}
+
+/// Starts a listener for each configured stream site and restarts them when their configuration changes.
+pub async fn bg_worker_for_stream_sites(state: Arc<GlobalState>) {
+    let liveness_token = Arc::new(true);
+    let mut running : HashMap<(SocketAddr, StreamSite), (CancellationToken, JoinHandle<()>)> = HashMap::new();
+
+    loop {
+        // open tcp connections keep running on their own tasks when the listeners stop, while udp sessions end with them
+        if state.app_state.exit.load(Ordering::SeqCst) || state.app_state.shutdown.is_draining() {
+            for (_, (token, _)) in running.drain() {
+                token.cancel();
+            }
+            break;
+        }
+
+        let wanted : Vec<(SocketAddr, StreamSite)> = {
+            let guard = state.config.read().await;
+            let default_ip = guard.ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            guard.tcp_stream.iter().flatten()
+                .map(|x| (SocketAddr::new(x.listen_ip.unwrap_or(default_ip), x.listen_port), StreamSite::Tcp(x.clone())))
+                .chain(guard.udp_stream.iter().flatten()
+                    .map(|x| (SocketAddr::new(x.listen_ip.unwrap_or(default_ip), x.listen_port), StreamSite::Udp(x.clone()))))
+                .collect()
+        };
+
+        // listeners are restarted from scratch when anything about their site changes.
+        // failed listeners (typically because the port is taken) are removed here as well so that they are retried.
+        let stale : Vec<(SocketAddr, StreamSite)> = running.iter()
+            .filter(|(key, (_, task))| task.is_finished() || !wanted.contains(*key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            if let Some((token, task)) = running.remove(&key) {
+                token.cancel();
+                _ = task.await;
+            }
+        }
+
+        for key in wanted {
+            if running.contains_key(&key) {
+                continue;
+            }
+            let token = CancellationToken::new();
+            let (bind_addr, site) = key.clone();
+            let task = match site {
+                StreamSite::Tcp(site) => tokio::spawn(run_tcp_listener(site, bind_addr, state.clone(), token.clone())),
+                StreamSite::Udp(site) => tokio::spawn(run_udp_listener(site, bind_addr, state.clone(), token.clone())),
+            };
+            running.insert(key, (token, task));
+        }
+
+        crate::BG_WORKER_THREAD_MAP.insert("Stream Sites".into(), BgTaskInfo {
+            liveness_ptr: Arc::downgrade(&liveness_token),
+            status: if running.is_empty() {
+                "No stream sites configured".into()
+            } else {
+                let mut names : Vec<String> = running.keys().map(|(addr, site)| format!("{} ({addr})", site.name())).collect();
+                names.sort();
+                format!("Listening for {}", names.join(", "))
+            }
+        });
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {},
+            _ = state.app_state.shutdown.started() => {}
+        }
+    }
+}
+
+/// Backends in round robin order, starting with a different one for each call.
+fn backends_in_order<'a>(site_name: &str, backends: &'a [Backend]) -> impl Iterator<Item = &'a Backend> {
+    let start = if backends.is_empty() {
+        0
+    } else {
+        ROUND_ROBIN_COUNTERS.entry(site_name.to_string()).or_default().fetch_add(1, Ordering::Relaxed) % backends.len()
+    };
+    backends[start..].iter().chain(backends[..start].iter())
+}
+
+pub(crate) async fn run_tcp_listener(site: TcpStreamSite, bind_addr: SocketAddr, state: Arc<GlobalState>, token: CancellationToken) {
+    let listener = match crate::hot_upgrade::inherited_tcp_listener(bind_addr) {
+        Some(l) => TcpListener::from_std(l),
+        None => TcpListener::bind(bind_addr).await
+    };
+    let listener = match listener {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::warn!("tcp_stream '{}' failed to listen on {bind_addr}: {e:?}", site.name);
+            return
+        }
+    };
+    tracing::info!("tcp_stream '{}' is listening on {bind_addr}", site.name);
+    let _handoff_registration = crate::hot_upgrade::register_socket(crate::hot_upgrade::SocketKind::Tcp, bind_addr, &listener);
+    let site = Arc::new(site);
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            x = listener.accept() => match x {
+                Ok((stream, client_addr)) => {
+                    tokio::spawn(handle_tcp_connection(site.clone(), stream, client_addr, state.clone()));
+                },
+                Err(e) => tracing::warn!("tcp_stream '{}' failed to accept a connection: {e:?}", site.name)
+            }
+        }
+    }
+    tracing::info!("tcp_stream '{}' stopped listening on {bind_addr}", site.name);
+}
+
+/// Waits for the client hello, if the client speaks tls, and returns the SNI from it.
+async fn peek_sni(stream: &mut ManagedStream<TcpStream>) -> Option<String> {
+    let deadline = tokio::time::Instant::now() + SNI_PEEK_TIMEOUT;
+    loop {
+        tokio::time::timeout_at(deadline, stream.stream.readable()).await.ok()?.ok()?;
+        let (closed, buf) = stream.peek_async().await.ok()?;
+        if buf.is_empty() {
+            if closed { return None }
+            continue;
+        }
+        if buf[0] != 0x16 {
+            return None
+        }
+        match TlsClientHello::try_from(&buf[..]) {
+            Ok(hello) => {
+                stream.is_tls = true;
+                return hello.read_sni_hostname().ok()
+            },
+            Err(TlsClientHelloError::MessageIncomplete(_)) if !closed => continue,
+            Err(_) => return None
+        }
+    }
+}
+
+async fn connect_to_any<'a>(site_name: &str, backends: &'a [Backend], connect_timeout: Duration) -> Option<(&'a Backend, TcpStream)> {
+    for backend in backends_in_order(site_name, backends) {
+        match tokio::time::timeout(connect_timeout, TcpStream::connect((backend.address.as_str(), backend.port))).await {
+            Ok(Ok(stream)) => return Some((backend, stream)),
+            Ok(Err(e)) => tracing::debug!("{site_name} failed to connect to {}:{}: {e:?}", backend.address, backend.port),
+            Err(_) => tracing::debug!("{site_name} timed out connecting to {}:{}", backend.address, backend.port)
+        }
+    }
+    None
+}
+
+async fn handle_tcp_connection(site: Arc<TcpStreamSite>, stream: TcpStream, client_addr: SocketAddr, state: Arc<GlobalState>) {
+    let local_addr = stream.local_addr().unwrap_or(client_addr);
+    let mut managed_stream = ManagedStream::from_tcp_stream(stream, state.clone());
+    let sni = if site.sni_routes.as_ref().is_some_and(|r| !r.is_empty()) {
+        peek_sni(&mut managed_stream).await
+    } else {
+        None
+    };
+    managed_stream.seal();
+    let mut client_stream = GenericManagedStream::TCP(managed_stream);
+    client_stream.track();
+    _ = state.app_state.statistics.total_accepted_tcp_connections.fetch_add(1, Ordering::SeqCst);
+
+    let backends = sni.as_deref()
+        .and_then(|sni| site.sni_routes.iter().flatten().find(|r| r.sni.eq_ignore_ascii_case(sni)))
+        .map(|r| r.backends.as_slice())
+        .unwrap_or(site.backends.as_slice());
+
+    let timeouts = crate::timeouts::EffectiveTimeouts::resolve(state.config.read().await.timeouts.as_ref(), site.timeouts.as_ref());
+    let Some((backend, mut backend_stream)) = connect_to_any(&site.name, backends, timeouts.connect).await else {
+        tracing::warn!("tcp_stream '{}' could not reach any backend for the connection from {client_addr} (sni: {sni:?})", site.name);
+        return
+    };
+
+    if let Some(version) = backend.proxy_protocol {
+        use tokio::io::AsyncWriteExt;
+        let header = crate::proxy_protocol::encode_header(version, client_addr, local_addr);
+        if let Err(e) = backend_stream.write_all(&header).await {
+            tracing::warn!("tcp_stream '{}' failed to send PROXY header to {}:{}: {e:?}", site.name, backend.address, backend.port);
+            return
+        }
+    }
+
+    client_stream.update_tracked_info(|x| {
+        x.incoming_sni = sni.clone();
+        x.outgoing_tunnel_type = Some(OutgoingTunnelType::TcpStream(site.name.clone(), backend.clone()));
+    });
+
+    let GenericManagedStream::TCP(managed_stream) = &mut client_stream else { return };
+    match crate::timeouts::copy_bidirectional_with_idle_timeout(managed_stream, &mut backend_stream, timeouts.idle, &site.name).await {
+        Ok((bytes_from_client, bytes_from_backend)) => {
+            let site_metrics = state.app_state.statistics.site_metrics(&site.name);
+            site_metrics.bytes_received.fetch_add(bytes_from_client, Ordering::Relaxed);
+            site_metrics.bytes_sent.fetch_add(bytes_from_backend, Ordering::Relaxed);
+        },
+        Err(e) => tracing::trace!("tcp_stream '{}' connection from {client_addr} ended with error: {e:?}", site.name)
+    }
+}
+
+pub(crate) async fn run_udp_listener(site: UdpStreamSite, bind_addr: SocketAddr, state: Arc<GlobalState>, token: CancellationToken) {
+    let socket = match crate::hot_upgrade::inherited_udp_socket(bind_addr) {
+        Some(s) => UdpSocket::from_std(s),
+        None => UdpSocket::bind(bind_addr).await
+    };
+    let socket = match socket {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            tracing::warn!("udp_stream '{}' failed to listen on {bind_addr}: {e:?}", site.name);
+            return
+        }
+    };
+    tracing::info!("udp_stream '{}' is listening on {bind_addr}", site.name);
+    let _handoff_registration = crate::hot_upgrade::register_socket(crate::hot_upgrade::SocketKind::Udp, bind_addr, &*socket);
+    let site = Arc::new(site);
+    let sessions : Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>> = Arc::new(DashMap::new());
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, client_addr) = tokio::select! {
+            _ = token.cancelled() => break,
+            x = socket.recv_from(&mut buf) => match x {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::trace!("udp_stream '{}' failed to receive a datagram: {e:?}", site.name);
+                    continue;
+                }
+            }
+        };
+        let existing = sessions.get(&client_addr).map(|x| x.value().clone());
+        let session = match existing {
+            Some(s) => s,
+            None => {
+                if sessions.len() >= MAX_UDP_SESSIONS_PER_SITE {
+                    tracing::debug!("udp_stream '{}' has too many sessions, dropping datagram from {client_addr}", site.name);
+                    continue;
+                }
+                start_udp_session(&site, client_addr, &socket, &sessions, &state, &token)
+            }
+        };
+        // nothing is awaited here, so that one slow session can not hold up the datagrams of the others
+        if let Err(e) = session.try_send(buf[..n].to_vec()) {
+            tracing::trace!("udp_stream '{}' dropped a datagram from {client_addr}: {e:?}", site.name);
+        }
+    }
+    // the sessions are stopped by the cancelled token, which releases the port so that it can be bound again right away.
+    tracing::info!("udp_stream '{}' stopped listening on {bind_addr}", site.name);
+}
+
+/// Starts the session for a new client and returns where to queue its datagrams. The backend is looked up and connected to
+/// on the task of the session, datagrams that arrive in the meantime wait in the queue.
+fn start_udp_session(
+    site: &Arc<UdpStreamSite>,
+    client_addr: SocketAddr,
+    listener: &Arc<UdpSocket>,
+    sessions: &Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    state: &Arc<GlobalState>,
+    token: &CancellationToken
+) -> mpsc::Sender<Vec<u8>> {
+    let (sender, datagrams) = mpsc::channel(MAX_QUEUED_DATAGRAMS_PER_SESSION);
+    sessions.insert(client_addr, sender.clone());
+    tokio::spawn(run_udp_session(site.clone(), client_addr, listener.clone(), sessions.clone(), state.clone(), token.child_token(), datagrams));
+    sender
+}
+
+async fn connect_udp_backend(site: &UdpStreamSite, client_addr: SocketAddr) -> Option<(Backend, UdpSocket)> {
+    for backend in backends_in_order(&site.name, &site.backends) {
+        let Some(backend_addr) = tokio::net::lookup_host((backend.address.as_str(), backend.port)).await.ok().and_then(|mut x| x.next()) else {
+            tracing::debug!("udp_stream '{}' failed to resolve {}:{}", site.name, backend.address, backend.port);
+            continue;
+        };
+        let local : SocketAddr = if backend_addr.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+        let upstream = match UdpSocket::bind(local).await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("udp_stream '{}' failed to create a socket for {client_addr}: {e:?}", site.name);
+                return None
+            }
+        };
+        match upstream.connect(backend_addr).await {
+            Ok(_) => return Some((backend.clone(), upstream)),
+            Err(e) => tracing::debug!("udp_stream '{}' failed to connect to {backend_addr}: {e:?}", site.name)
+        }
+    }
+    tracing::warn!("udp_stream '{}' could not reach any backend for {client_addr}", site.name);
+    None
+}
+
+async fn run_udp_session(
+    site: Arc<UdpStreamSite>,
+    client_addr: SocketAddr,
+    listener: Arc<UdpSocket>,
+    sessions: Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    state: Arc<GlobalState>,
+    token: CancellationToken,
+    mut datagrams: mpsc::Receiver<Vec<u8>>
+) {
+    let connected = tokio::select! {
+        _ = token.cancelled() => None,
+        x = connect_udp_backend(&site, client_addr) => x
+    };
+    let Some((backend, upstream)) = connected else {
+        // the next datagram from the client starts over with a new session
+        sessions.remove(&client_addr);
+        return
+    };
+
+    // the session shows up in the connections view for as long as this is alive
+    let connection_key = Arc::new(crate::generate_unique_id());
+    crate::proxy::add_or_update_connection(state.clone(), ProxyActiveTCPConnection {
+        incoming_sni: None,
+        client_socket_address: Some(client_addr),
+        odd_box_socket: listener.local_addr().ok(),
+        connection_key_pointer: Arc::downgrade(&connection_key),
+        connection_key: *connection_key,
+        client_addr_string: format!("{client_addr:?}"),
+        incoming_connection_uses_tls: false,
+        tls_terminated: false,
+        http_terminated: false,
+        is_admin_interface: false,
+        recent_request_ids: vec![],
+        version: 2,
+        resolved_connection_type: None,
+        resolved_connection_type_description: None,
+        is_grpc: None,
+        http_version: None,
+        is_websocket: None,
+        outgoing_tunnel_type: Some(OutgoingTunnelType::UdpStream(site.name.clone(), backend))
+    });
+
+    let timeout = site.session_timeout();
+    let mut last_active = Instant::now();
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let idle_for = last_active.elapsed();
+        if idle_for >= timeout {
+            break;
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            // nothing went either way, so the loop ends unless something did in the meantime
+            _ = tokio::time::sleep(timeout - idle_for) => continue,
+            x = datagrams.recv() => {
+                let Some(datagram) = x else { break };
+                last_active = Instant::now();
+                match upstream.send(&datagram).await {
+                    Ok(sent) => {
+                        state.app_state.statistics.site_metrics(&site.name).bytes_received.fetch_add(sent as u64, Ordering::Relaxed);
+                    },
+                    Err(e) => tracing::trace!("udp_stream '{}' failed to forward a datagram from {client_addr}: {e:?}", site.name)
+                }
+            },
+            x = upstream.recv(&mut buf) => match x {
+                Ok(n) => {
+                    last_active = Instant::now();
+                    match listener.send_to(&buf[..n], client_addr).await {
+                        Ok(sent) => {
+                            state.app_state.statistics.site_metrics(&site.name).bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+                        },
+                        Err(e) => tracing::trace!("udp_stream '{}' failed to send a reply to {client_addr}: {e:?}", site.name)
+                    }
+                },
+                Err(e) => {
+                    tracing::trace!("udp_stream '{}' session for {client_addr} failed: {e:?}", site.name);
+                    break;
+                }
+            }
+        }
+    }
+    sessions.remove(&client_addr);
+    crate::proxy::del_connection(state, &connection_key);
+}
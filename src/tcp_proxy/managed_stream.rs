@@ -220,6 +220,8 @@ impl GenericManagedStream {
                         incoming_connection_uses_tls: peekable_tcp_stream.is_tls,
                         tls_terminated: peekable_tcp_stream.is_tls_terminated,
                         http_terminated: peekable_tcp_stream.is_http_terminated,
+                        is_admin_interface: false,
+                        recent_request_ids: vec![],
                         outgoing_tunnel_type: None, // <-- no tunnel attached yet as we might still end up terminating each http request.
                         version: 1
 
@@ -250,6 +252,8 @@ impl GenericManagedStream {
                         incoming_connection_uses_tls: true,
                         tls_terminated: true,
                         http_terminated: managed_stream.is_http_terminated,
+                        is_admin_interface: false,
+                        recent_request_ids: vec![],
                         outgoing_tunnel_type: None,
                         version: 1
 
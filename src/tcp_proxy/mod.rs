@@ -10,4 +10,5 @@ pub use managed_stream::ManagedStream;
 pub use managed_stream::GenericManagedStream;
 
 pub use managed_stream::Peekable;
+pub use tls::client_hello::{TlsClientHello, TlsClientHelloError};
 pub use tcp::*;
@@ -70,6 +70,17 @@ impl ReverseTcpProxyTarget {
             .unwrap_or(false)
     }
 
+    /// Tells if incoming tls connections have to be terminated before they can be tunnelled to the backends of this target.
+    /// That is the case when the site says so, or when none of its backends speak tls, otherwise tls is passed thru as is.
+    pub fn must_terminate_tls(&self) -> bool {
+        self.terminate_tls || !self.backends.iter().any(|x|x.https.unwrap_or_default())
+    }
+
+    pub fn rate_limit(&self) -> Option<&crate::configuration::RateLimit> {
+        self.remote_target_config.as_ref().and_then(|x| x.rate_limit.as_ref())
+            .or_else(|| self.hosted_target_config.as_ref().and_then(|x| x.rate_limit.as_ref()))
+    }
+
 }
 #[allow(dead_code)]
 pub struct ReverseTcpProxy {
@@ -114,6 +125,7 @@ impl ReverseTcpProxy {
         incoming_traffic_is_tls:bool,
         state: Arc<GlobalState>,
         client_address: SocketAddr,
+        local_address: SocketAddr,
         rustls_config : Option<Arc<rustls::ServerConfig>>,
         incoming_host_header_or_sni: String,
         http_version: Option<Version>,
@@ -121,35 +133,7 @@ impl ReverseTcpProxy {
     ) -> anyhow::Result<(),TunnelError> {
 
 
-        let terminate_incoming = if target.terminate_tls  {
-            // this means we should always terminate incoming tls connections
-            incoming_traffic_is_tls
-        } else {
-
-            if incoming_traffic_is_tls {
-
-                let at_least_one_backend_is_tls= target.backends.iter().any(|x|x.https.unwrap_or_default());
-
-                if at_least_one_backend_is_tls {
-                    // - incoming is tls
-                    // - we are allowed to tunnel
-                    // - all backends are tls
-                    // - we should be fine to tunnel the incoming connection without termination
-                    false 
-                } else {
-                    // - incoming is tls
-                    // - we are allowed to tunnel
-                    // - there is no backend that speaks tls
-                    // - we must terminate the incoming connection and forward the clear text data to the backend
-                    true
-                }
-
-            } else {
-                false // incoming is clear text... so nothing to terminate here...
-               
-            }
-           
-        };
+        let terminate_incoming = incoming_traffic_is_tls && target.must_terminate_tls();
 
         let (client_tls_is_terminated,possibly_terminated_stream,backend_filter) = if terminate_incoming {
 
@@ -164,6 +148,11 @@ impl ReverseTcpProxy {
                             
                     let tls_acceptor = TlsAcceptor::from(tls_cfg.clone());
                     match tls_acceptor.accept(peekable_tcp_stream).await {
+                        Ok(tls_stream) if tls_stream.get_ref().1.alpn_protocol() == Some(crate::letsencrypt::ACME_TLS_ALPN_PROTOCOL) => {
+                            // the handshake is all there is to a tls-alpn-01 validation
+                            tracing::trace!("Closing tls-alpn-01 validation connection");
+                            return Ok(())
+                        },
                         Ok(mut tls_stream) => {
                             tracing::trace!("Terminated TLS connection established!");
                             tls_stream.get_mut().0.is_tls_terminated = true;
@@ -224,7 +213,8 @@ impl ReverseTcpProxy {
         let backend = 
             match (&target.remote_target_config,&target.hosted_target_config) {
                 (Some(rem_conf),None) => {
-                    rem_conf.next_backend(&state, backend_filter.clone()).await
+                    let ctx = crate::load_balancing::SelectionContext::from_client_ip(client_address.ip());
+                    rem_conf.next_backend(&state, backend_filter.clone(), &ctx).await
                 },
                 (None,Some(proc_conf)) => {
                     proc_conf.next_backend(&state, backend_filter.clone()).await
@@ -256,8 +246,37 @@ impl ReverseTcpProxy {
             }
         };
 
-        match TcpStream::connect(resolved_address.clone()).await {
-            Ok(rem_stream) => {
+        let site_timeouts = target.remote_target_config.as_ref().and_then(|x| x.timeouts.as_ref())
+            .or_else(|| target.hosted_target_config.as_ref().and_then(|x| x.timeouts.as_ref()));
+        let timeouts = crate::timeouts::EffectiveTimeouts::resolve(state.config.read().await.timeouts.as_ref(), site_timeouts);
+
+        let connect_started = std::time::Instant::now();
+        let connect_result = match tokio::time::timeout(timeouts.connect, TcpStream::connect(resolved_address.clone())).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out connecting to the backend"))
+        };
+        match connect_result {
+            Ok(mut rem_stream) => {
+
+                // the PROXY header goes first, before any tls handshake with the backend.
+                if let Some(version) = backend.proxy_protocol {
+                    use tokio::io::AsyncWriteExt;
+                    let header = crate::proxy_protocol::encode_header(version, client_address, local_address);
+                    if let Err(e) = rem_stream.write_all(&header).await {
+                        warn!("failed to send PROXY header to {resolved_address} for {host} --> {e:?}",host=target.host_name);
+                        return Ok(())
+                    }
+                }
+
+                let access_log_entry = crate::access_log::is_enabled().then(|| {
+                    let mut entry = crate::access_log::AccessLogEntry::for_tunnel(&incoming_host_header_or_sni, client_address.ip(), incoming_traffic_is_tls);
+                    entry.set_upstream(&crate::access_log::Upstream { backend: resolved_address.clone(), latency: connect_started.elapsed() });
+                    entry
+                });
+
+                if let Some(rem_conf) = &target.remote_target_config {
+                    crate::outlier_detection::record_success(&state, rem_conf, &backend);
+                }
 
                 // THIS SHOULD BE THE ONLY PLACE WE INCREMENT THE TUNNEL COUNTER
                 match state.app_state.statistics.lb_access_count_per_hostname.get_mut(&target.host_name) {
@@ -289,13 +308,23 @@ impl ReverseTcpProxy {
                         backend_is_tls,
                         server_name_for_tls,
                         erect_tls_tunnel_to_backend,
-                        incoming_traffic_is_tls
+                        incoming_traffic_is_tls,
+                        timeouts.idle,
+                        &target.host_name
                     ).await {
-                        Ok(_) => {
-                            
+                        Ok((bytes_from_client, bytes_from_backend)) => {
+                            let site_metrics = state.app_state.statistics.site_metrics(&target.host_name);
+                            site_metrics.bytes_received.fetch_add(bytes_from_client, std::sync::atomic::Ordering::Relaxed);
+                            site_metrics.bytes_sent.fetch_add(bytes_from_backend, std::sync::atomic::Ordering::Relaxed);
+                            if let Some(entry) = access_log_entry {
+                                entry.finish(bytes_from_backend);
+                            }
                         },
                         Err(e) => {
                             tracing::warn!("Tunnel failed with error: {:?}",e);
+                            if let Some(entry) = access_log_entry {
+                                entry.finish(0);
+                            }
                         }
                     }
                    
@@ -305,7 +334,20 @@ impl ReverseTcpProxy {
                    tracing::warn!("failed to read socket peer address..");
                 }
             },
-            Err(e) => warn!("failed to connect to target {host} (using addr: {resolved_address}) --> {e:?}",host=target.host_name),
+            Err(e) => {
+                if let Some(rem_conf) = &target.remote_target_config {
+                    crate::outlier_detection::record_failure(&state, rem_conf, &backend);
+                }
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    warn!(timeout = "connect", "timed out connecting to target {host} (using addr: {resolved_address}) after {}s",timeouts.connect.as_secs(),host=target.host_name);
+                    let can_respond = !incoming_traffic_is_tls && http_version != Some(Version::HTTP_2);
+                    if can_respond {
+                        write_response_and_close(possibly_terminated_stream, "HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+                    }
+                } else {
+                    warn!("failed to connect to target {host} (using addr: {resolved_address}) --> {e:?}",host=target.host_name)
+                }
+            },
         }
 
         Ok(())
@@ -319,15 +361,31 @@ impl ReverseTcpProxy {
 // proxy between original client and remote backend
 use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
 use rustls::pki_types::ServerName;
+async fn write_response_and_close(mut stream: GenericManagedStream, response: &str) {
+    use tokio::io::AsyncWriteExt;
+    let result = match &mut stream {
+        GenericManagedStream::TCP(s) => s.write_all(response.as_bytes()).await.and(s.shutdown().await),
+        GenericManagedStream::TerminatedTLS(s) => s.write_all(response.as_bytes()).await.and(s.shutdown().await),
+    };
+    if let Err(e) = result {
+        tracing::trace!("Failed to send response to client before closing the connection: {e:?}");
+    }
+}
+
 async fn run_managed_bidirectional_tunnel(
     ref mut original_client_stream: GenericManagedStream,
     mut stream_connected_to_some_backend: TcpStream,
     backend_is_tls: bool,
     server_name: Option<String>,
     erect_tls_tunnel: bool,
-    incoming_traffic_is_tls: bool
-) -> Result<(), Box<dyn std::error::Error>> {
+    incoming_traffic_is_tls: bool,
+    idle_timeout: Option<std::time::Duration>,
+    host_name: &str
+) -> Result<(u64,u64), Box<dyn std::error::Error>> {
     
+    // (bytes from client, bytes from backend)
+    let mut transferred = (0,0);
+
 
     if backend_is_tls && erect_tls_tunnel {
 
@@ -360,8 +418,8 @@ async fn run_managed_bidirectional_tunnel(
         
         match original_client_stream {
             GenericManagedStream::TerminatedTLS(peekable_tls_stream) => {     
-                match tokio::io::copy_bidirectional( peekable_tls_stream, &mut backend_tls_stream).await {
-                    Ok((_bytes_from_client, _bytes_from_backend)) => {}
+                match crate::timeouts::copy_bidirectional_with_idle_timeout(peekable_tls_stream, &mut backend_tls_stream, idle_timeout, host_name).await {
+                    Ok(t) => transferred = t,
                     Err(e) => {
                         tracing::warn!("Stream failed with error: {:?}", e);
                     }
@@ -370,8 +428,8 @@ async fn run_managed_bidirectional_tunnel(
             },
             GenericManagedStream::TCP(peekable_tcp_stream) => {
                 tracing::trace!("Tunneling from cleartext to tls");
-                match tokio::io::copy_bidirectional(peekable_tcp_stream, &mut backend_tls_stream).await {
-                    Ok((_bytes_from_client, _bytes_from_backend)) => {}
+                match crate::timeouts::copy_bidirectional_with_idle_timeout(peekable_tcp_stream, &mut backend_tls_stream, idle_timeout, host_name).await {
+                    Ok(t) => transferred = t,
                     Err(e) => {
                         tracing::warn!("Stream failed with error: {:?}", e);
                     }
@@ -391,10 +449,8 @@ async fn run_managed_bidirectional_tunnel(
                 }
 
                 // Proxy data between the original client and the backend
-                match tokio::io::copy_bidirectional(peekable_tls_stream, &mut stream_connected_to_some_backend).await {
-                    Ok((_bytes_from_client, _bytes_from_backend)) => {
-                        // Optionally handle the number of bytes transferred
-                    }
+                match crate::timeouts::copy_bidirectional_with_idle_timeout(peekable_tls_stream, &mut stream_connected_to_some_backend, idle_timeout, host_name).await {
+                    Ok(t) => transferred = t,
                     Err(e) => {
                         tracing::warn!("Stream failed with error: {:?}", e);
                     }
@@ -410,10 +466,8 @@ async fn run_managed_bidirectional_tunnel(
                 }
                 
                 // Proxy data between the original client and the backend
-                match tokio::io::copy_bidirectional(peekable_tcp_stream, &mut stream_connected_to_some_backend).await {
-                    Ok((_bytes_from_client, _bytes_from_backend)) => {
-                        // Optionally handle the number of bytes transferred
-                    }
+                match crate::timeouts::copy_bidirectional_with_idle_timeout(peekable_tcp_stream, &mut stream_connected_to_some_backend, idle_timeout, host_name).await {
+                    Ok(t) => transferred = t,
                     Err(e) => {
                         tracing::warn!("Stream failed with error: {:?}", e);
                     }
@@ -423,7 +477,7 @@ async fn run_managed_bidirectional_tunnel(
         }
     }
     
-    Ok(())
+    Ok(transferred)
 }
 
 
@@ -295,3 +295,19 @@ fn init_cfg_is_valid() {
 fn init_filled_cfg_is_valid() { 
     crate::generate_config(None,true).expect("should be able to create initial filled config");
 }
+
+#[test] pub fn site_timeouts_override_global_ones() {
+    let global = crate::configuration::v3::Timeouts { connect_in_seconds: Some(5), idle_in_seconds: Some(60), ..Default::default() };
+    let site = crate::configuration::v3::Timeouts { idle_in_seconds: Some(3600), ..Default::default() };
+    let t = crate::timeouts::EffectiveTimeouts::resolve(Some(&global), Some(&site));
+    assert_eq!(t.connect, std::time::Duration::from_secs(5));
+    assert_eq!(t.idle, Some(std::time::Duration::from_secs(3600)));
+    assert_eq!(t.request_headers, std::time::Duration::from_secs(30));
+    assert_eq!(t.max_request_duration, None);
+}
+
+#[test] pub fn idle_timeout_is_off_unless_configured() {
+    let t = crate::timeouts::EffectiveTimeouts::resolve(None, None);
+    assert_eq!(t.idle, None);
+    assert_eq!(t.max_request_duration, None);
+}
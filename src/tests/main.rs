@@ -5,3 +5,1475 @@ fn test_src_tests_main_rs_1() -> anyhow::Result<()> {
 }
 
 
+
+#[test]
+pub fn site_metrics_count_status_classes_and_latency_buckets() {
+    use std::sync::atomic::Ordering;
+    let metrics = crate::types::proxy_state::SiteMetrics::default();
+    metrics.record_response(200, std::time::Duration::from_millis(3));
+    metrics.record_response(204, std::time::Duration::from_millis(30));
+    metrics.record_response(503, std::time::Duration::from_secs(60));
+    assert_eq!(metrics.responses_by_status_class[1].load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.responses_by_status_class[4].load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.latency.buckets[0].load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.latency.buckets[3].load(Ordering::Relaxed), 1);
+    // slower than the largest bucket only shows up in the +Inf bucket, ie. the total count
+    assert_eq!(metrics.latency.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum::<u64>(), 2);
+    assert_eq!(metrics.latency.count.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+pub fn access_rules_match_cidrs() {
+    use crate::access_control::{AccessRules, Cidr};
+    let cidr : Cidr = "10.1.0.0/16".parse().expect("should parse ipv4 ranges");
+    assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+    assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+    // ipv4 clients on dual stack sockets show up as mapped ipv6 addresses
+    assert!(cidr.contains("::ffff:10.1.0.1".parse().unwrap()));
+    assert!("fd00::/8".parse::<Cidr>().expect("should parse ipv6 ranges").contains("fd12::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+
+    let allow : Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+    let deny : Vec<Cidr> = vec!["10.66.0.0/16".parse().unwrap()];
+    let rules = AccessRules { allow: Some(allow.as_slice()), deny: Some(deny.as_slice()) };
+    assert!(rules.allows("10.1.1.1".parse().unwrap()));
+    assert!(!rules.allows("10.66.1.1".parse().unwrap()));
+    assert!(!rules.allows("192.168.1.1".parse().unwrap()));
+}
+
+#[test]
+pub fn address_ranges_are_parsed_when_the_configuration_is_loaded() {
+    #[derive(serde::Deserialize, serde::Serialize)]
+    struct Ranges { cidrs: Vec<crate::access_control::Cidr> }
+    let ranges : Ranges = toml::from_str(r#"cidrs = ["10.0.0.0/8", " 192.168.1.7 ", "fd00::/8"]"#).expect("valid ranges should parse");
+    assert!(ranges.cidrs[1].contains("192.168.1.7".parse().unwrap()));
+    // written back the same way they are configured
+    assert_eq!(toml::to_string(&ranges).unwrap().trim(), r#"cidrs = ["10.0.0.0/8", "192.168.1.7", "fd00::/8"]"#);
+    let invalid = toml::from_str::<Ranges>(r#"cidrs = ["10.0.0.0/33"]"#).err().expect("invalid ranges should be rejected");
+    assert!(invalid.to_string().contains("invalid prefix length in '10.0.0.0/33'"), "{invalid}");
+}
+
+#[test]
+pub fn proxy_protocol_headers_round_trip() {
+    use crate::configuration::ProxyProtocolVersion;
+    use crate::proxy_protocol::{encode_header, parse, Parsed};
+    let source : std::net::SocketAddr = "203.0.113.7:51234".parse().unwrap();
+    let destination : std::net::SocketAddr = "10.0.0.1:443".parse().unwrap();
+    for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+        let mut header = encode_header(version, source, destination);
+        let len = header.len();
+        assert_eq!(parse(&header[..5]), Ok(Parsed::Incomplete));
+        // the header must be found even when the client already sent data after it
+        header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert_eq!(parse(&header), Ok(Parsed::Header { len, source: Some(source), destination: Some(destination) }));
+    }
+    assert_eq!(encode_header(ProxyProtocolVersion::V1, source, destination), b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n");
+    assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Ok(Parsed::Header { len: 15, source: None, destination: None }));
+    assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Ok(Parsed::NotProxy));
+    assert!(parse(b"PROXY TCP4 nope 10.0.0.1 1 2\r\n").is_err());
+    // the addresses must be of the family that the header says they are
+    assert!(parse(b"PROXY TCP4 2001:db8::1 10.0.0.1 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP6 2001:db8::1 10.0.0.1 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n").is_ok());
+}
+
+#[tokio::test]
+pub async fn proxy_protocol_headers_are_read_without_consuming_what_follows() {
+    use crate::configuration::ProxyProtocolVersion;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let source : std::net::SocketAddr = "203.0.113.7:51234".parse().unwrap();
+    let destination : std::net::SocketAddr = "10.0.0.1:443".parse().unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let request = b"GET / HTTP/1.1\r\n";
+    for header in [
+        Some(crate::proxy_protocol::encode_header(ProxyProtocolVersion::V1, source, destination)),
+        Some(crate::proxy_protocol::encode_header(ProxyProtocolVersion::V2, source, destination)),
+        None
+    ] {
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let sent = header.clone().unwrap_or_default();
+        // the header arrives in two parts, with the request right behind it
+        client.write_all(&sent[..sent.len().min(3)]).await.unwrap();
+        let reading = tokio::spawn(async move {
+            let result = crate::proxy_protocol::read_header(&mut server).await;
+            (result, server)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        client.write_all(&sent[sent.len().min(3)..]).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let (result, mut server) = reading.await.unwrap();
+        let expected = header.map(|_| (source, destination));
+        assert_eq!(result.expect("the header should be read"), expected);
+        let mut rest = [0u8; 16];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, request);
+    }
+}
+
+#[test]
+pub fn listeners_only_serve_their_sites() {
+    use crate::configuration::ListenerProtocol;
+    use crate::proxy::ListenerSettings;
+    let unrestricted = ListenerSettings { bind_addr: "[::]:443".parse().expect("valid address"), protocol: ListenerProtocol::Https, ipv6_only: false, sites: None };
+    assert!(unrestricted.serves("anything.example.com"));
+    let restricted = ListenerSettings { sites: Some(vec!["example.com".into()]), ..unrestricted };
+    assert!(restricted.serves("example.com"));
+    assert!(restricted.serves("Blog.Example.com"));
+    assert!(!restricted.serves("notexample.com"));
+    assert!(!restricted.serves("odd-box.internal"));
+}
+
+#[test]
+pub fn compression_is_negotiated_by_q_value_then_preference() {
+    use crate::compression::negotiate;
+    use crate::configuration::CompressionAlgorithm::*;
+    let preference = [Brotli, Zstd, Gzip];
+    assert_eq!(negotiate("gzip, deflate, br, zstd", &preference), Some(Brotli));
+    assert_eq!(negotiate("gzip;q=1.0, br;q=0.5", &preference), Some(Gzip));
+    assert_eq!(negotiate("br;q=0, *", &preference), Some(Zstd));
+    assert_eq!(negotiate("x-gzip", &[Gzip]), Some(Gzip));
+    assert_eq!(negotiate("identity", &preference), None);
+    assert_eq!(negotiate("br", &[Gzip]), None);
+}
+
+#[tokio::test]
+pub async fn dir_servers_send_precompressed_siblings_with_their_encoding() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let dir = std::env::temp_dir().join(format!("odd-box-dir-server-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = std::fs::canonicalize(&dir).unwrap();
+    std::fs::write(dir.join("app.js"), "console.log('plain')").unwrap();
+    std::fs::write(dir.join("app.js.br"), "brotli bytes").unwrap();
+    std::fs::write(dir.join("app.js.gz"), "gzip bytes").unwrap();
+    let site = crate::configuration::DirServer {
+        dir: dir.to_string_lossy().into(),
+        // the served files are cached by host name, so every run gets its own
+        host_name: format!("{}.localtest.me", uuid::Uuid::new_v4()),
+        compression: Some(crate::configuration::Compression::default()),
+        ..Default::default()
+    };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let site = site.clone();
+            let service = hyper::service::service_fn(move |req| crate::custom_servers::directory::handle(site.clone(), req));
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(hyper_util::rt::TokioIo::new(stream), service));
+        }
+    });
+    let get = move |accept_encoding: Option<&'static str>| async move {
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let accept_encoding = accept_encoding.map(|e| format!("Accept-Encoding: {e}\r\n")).unwrap_or_default();
+        client.write_all(format!("GET /app.js HTTP/1.1\r\nHost: localhost\r\n{accept_encoding}Connection: close\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).to_lowercase()
+    };
+
+    for (accept_encoding, encoding, body) in [("br", "br", "brotli bytes"), ("gzip", "gzip", "gzip bytes"), ("gzip;q=0.5, br", "br", "brotli bytes")] {
+        let response = get(Some(accept_encoding)).await;
+        assert!(response.starts_with("http/1.1 200"), "{response}");
+        assert!(response.contains(&format!("content-encoding: {encoding}\r\n")), "{response}");
+        assert!(response.contains("vary: cookie, accept-encoding\r\n"), "{response}");
+        // the etag of the file is only weakly valid for its compressed copies
+        assert!(response.contains("etag: w/\""), "{response}");
+        assert!(response.ends_with(&format!("\r\n\r\n{body}")), "{response}");
+    }
+    let response = get(None).await;
+    assert!(!response.contains("content-encoding"), "{response}");
+    assert!(response.contains("etag: \""), "{response}");
+    assert!(response.ends_with("\r\n\r\nconsole.log('plain')"), "{response}");
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+pub fn cached_response_freshness_follows_cache_control() {
+    use crate::http_proxy::{freshness_lifetime, CacheControl};
+    use hyper::{HeaderMap, StatusCode};
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, hyper::header::HeaderValue::from_static(value));
+        }
+        headers
+    };
+
+    let cc = CacheControl::parse(&headers(&[("cache-control", "public, max-age=60, stale-while-revalidate=30"), ("cache-control", "s-maxage=\"120\"")]));
+    assert!(cc.public && !cc.no_store);
+    assert_eq!((cc.max_age, cc.s_maxage, cc.stale_while_revalidate), (Some(60), Some(120), Some(30)));
+    assert!(CacheControl::parse(&headers(&[("pragma", "no-cache")])).no_cache);
+    assert!(!CacheControl::parse(&headers(&[("pragma", "no-cache"), ("cache-control", "max-age=5")])).no_cache);
+
+    assert_eq!(freshness_lifetime(StatusCode::OK, &headers(&[("cache-control", "max-age=60, s-maxage=120")])), 120);
+    assert_eq!(freshness_lifetime(StatusCode::OK, &headers(&[("cache-control", "max-age=oops")])), 0);
+    assert_eq!(freshness_lifetime(StatusCode::OK, &headers(&[
+        ("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "Sun, 06 Nov 1994 09:49:37 GMT")
+    ])), 3600);
+    assert_eq!(freshness_lifetime(StatusCode::OK, &headers(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "0")])), 0);
+    // without explicit freshness 10% of the time since the last modification is used
+    assert_eq!(freshness_lifetime(StatusCode::OK, &headers(&[
+        ("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("last-modified", "Sun, 06 Nov 1994 07:49:37 GMT")
+    ])), 360);
+    assert_eq!(freshness_lifetime(StatusCode::PARTIAL_CONTENT, &headers(&[
+        ("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("last-modified", "Sun, 06 Nov 1994 07:49:37 GMT")
+    ])), 0);
+}
+
+fn headers_from(pairs: &[(&str, &str)]) -> hyper::HeaderMap {
+    let mut headers = hyper::HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+    }
+    headers
+}
+
+fn cached_get(uri: &str, headers: hyper::HeaderMap) -> crate::http_proxy::cache::RequestHead {
+    crate::http_proxy::cache::RequestHead { method: hyper::Method::GET, uri: uri.parse().unwrap(), version: hyper::Version::HTTP_11, headers }
+}
+
+fn fresh_variant(body_size: usize) -> crate::http_proxy::cache::Variant {
+    crate::http_proxy::cache::Variant::new(&hyper::HeaderMap::new(), hyper::StatusCode::OK, &headers_from(&[("cache-control", "max-age=60")]), bytes::Bytes::from(vec![0u8; body_size]))
+}
+
+#[test]
+pub fn stale_cached_responses_are_refreshed_by_a_304_from_the_backend() {
+    use crate::http_proxy::cache::{respond, Variant};
+    use crate::http_proxy::freshness_lifetime;
+    use hyper::{header, StatusCode};
+    let last_modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+    let stored = Variant::new(&hyper::HeaderMap::new(), StatusCode::OK, &headers_from(&[
+        ("cache-control", "max-age=0"), ("etag", "\"v1\""), ("last-modified", last_modified), ("content-type", "text/plain")
+    ]), bytes::Bytes::from_static(b"hello"));
+    assert_eq!(freshness_lifetime(StatusCode::OK, &stored.header_map()), 0);
+
+    // the conditions of the client are replaced by the validators of the cached response
+    let head = cached_get("/page", headers_from(&[("if-none-match", "\"something-else\""), ("accept", "text/plain")]));
+    let conditional = head.to_conditional_request(&stored);
+    assert_eq!(conditional.headers()[header::IF_NONE_MATCH], "\"v1\"");
+    assert_eq!(conditional.headers()[header::IF_MODIFIED_SINCE], last_modified);
+    assert_eq!(conditional.headers()[header::ACCEPT], "text/plain");
+    let dated = Variant::new(&hyper::HeaderMap::new(), StatusCode::OK, &headers_from(&[("last-modified", last_modified)]), bytes::Bytes::new());
+    let conditional = head.to_conditional_request(&dated);
+    assert!(!conditional.headers().contains_key(header::IF_NONE_MATCH));
+    assert_eq!(conditional.headers()[header::IF_MODIFIED_SINCE], last_modified);
+
+    // the 304 from the backend updates the stored headers and makes the response fresh again, the body is kept
+    let refreshed = stored.refreshed(&headers_from(&[("cache-control", "max-age=60"), ("etag", "\"v1\""), ("content-length", "0")]));
+    let headers = refreshed.header_map();
+    assert_eq!(headers[header::CACHE_CONTROL], "max-age=60");
+    assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+    assert!(!headers.contains_key(header::CONTENT_LENGTH));
+    assert_eq!(refreshed.body, stored.body);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert!(refreshed.age(now) < freshness_lifetime(StatusCode::OK, &headers));
+
+    let response = respond(&head, &refreshed, "REVALIDATED");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-cache"], "REVALIDATED");
+    // clients that already have the response are sent a 304 themselves, whether they ask by etag or by date
+    for conditions in [[("if-none-match", "W/\"v1\"")], [("if-modified-since", last_modified)]] {
+        let response = respond(&cached_get("/page", headers_from(&conditions)), &refreshed, "REVALIDATED");
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+    }
+}
+
+#[tokio::test]
+pub async fn cached_variants_are_kept_apart_by_the_vary_headers() {
+    use crate::http_proxy::cache::{cache_key, Variant};
+    let cache = crate::http_proxy::SharedCache::default();
+    let settings = crate::configuration::ResponseCache::default();
+    let key = cache_key("Vary.localtest.me:8080", &"/style.css".parse().unwrap());
+    assert_eq!(key, "vary.localtest.me/style.css");
+
+    let response_headers = headers_from(&[("cache-control", "max-age=60"), ("vary", "Accept-Encoding, Accept-Language")]);
+    let gzip = headers_from(&[("accept-encoding", "gzip")]);
+    let br = headers_from(&[("accept-encoding", "br")]);
+    for (request, body) in [(&gzip, "gzipped"), (&br, "brotli"), (&gzip, "gzipped again")] {
+        let variant = Variant::new(request, hyper::StatusCode::OK, &response_headers, bytes::Bytes::from(body));
+        let others = cache.lookup(&settings, &key).await.unwrap_or_default();
+        cache.store_variant(&settings, &key, others, variant).await;
+    }
+    let variants = cache.lookup(&settings, &key).await.expect("the variants should be cached");
+    // storing a variant again replaces the one with the same values
+    assert_eq!(variants.len(), 2);
+    let body_for = |request: &hyper::HeaderMap| variants.iter().find(|v| v.matches(request)).map(|v| v.body.clone());
+    assert_eq!(body_for(&gzip).as_deref(), Some(&b"gzipped again"[..]));
+    assert_eq!(body_for(&br).as_deref(), Some(&b"brotli"[..]));
+    // a header that was missing when the response was stored has to be missing now too
+    assert_eq!(body_for(&headers_from(&[("accept-encoding", "gzip"), ("accept-language", "sv")])), None);
+    assert_eq!(body_for(&hyper::HeaderMap::new()), None);
+}
+
+#[tokio::test]
+pub async fn the_least_recently_used_responses_are_evicted_to_stay_under_the_memory_limit() {
+    let cache = crate::http_proxy::SharedCache::default();
+    let settings = crate::configuration::ResponseCache { max_memory_size_in_mb: Some(1), ..Default::default() };
+    let kb = 1024;
+    cache.store_variant(&settings, "lru.localtest.me/a", vec![], fresh_variant(400 * kb)).await;
+    cache.store_variant(&settings, "lru.localtest.me/b", vec![], fresh_variant(400 * kb)).await;
+    // using a leaves b as the least recently used
+    assert!(cache.lookup(&settings, "lru.localtest.me/a").await.is_some());
+    cache.store_variant(&settings, "lru.localtest.me/c", vec![], fresh_variant(400 * kb)).await;
+    assert!(cache.lookup(&settings, "lru.localtest.me/b").await.is_none());
+    assert!(cache.lookup(&settings, "lru.localtest.me/a").await.is_some());
+    assert!(cache.lookup(&settings, "lru.localtest.me/c").await.is_some());
+
+    // responses that could never fit are not stored, and do not push anything else out
+    cache.store_variant(&settings, "lru.localtest.me/huge", vec![], fresh_variant(2 * 1024 * kb)).await;
+    assert!(cache.lookup(&settings, "lru.localtest.me/huge").await.is_none());
+    assert!(cache.lookup(&settings, "lru.localtest.me/a").await.is_some());
+    assert!(cache.lookup(&settings, "lru.localtest.me/c").await.is_some());
+}
+
+#[tokio::test]
+pub async fn cached_responses_on_disk_survive_a_restart() {
+    use crate::http_proxy::SharedCache;
+    let dir = std::env::temp_dir().join(format!("odd-box-response-cache-{}", uuid::Uuid::new_v4()));
+    let settings = crate::configuration::ResponseCache { disk_dir: Some(dir.to_string_lossy().into()), ..Default::default() };
+    let key = "disk.localtest.me/page";
+    let request = headers_from(&[("accept-encoding", "gzip")]);
+    let variant = crate::http_proxy::cache::Variant::new(&request, hyper::StatusCode::OK, &headers_from(&[
+        ("cache-control", "max-age=60"), ("etag", "\"v1\""), ("vary", "accept-encoding")
+    ]), bytes::Bytes::from_static(b"kept on disk"));
+    SharedCache::default().store_variant(&settings, key, vec![], variant).await;
+
+    // a new cache finds the response in the directory
+    let restarted = SharedCache::default();
+    let variants = restarted.lookup(&settings, key).await.expect("the response should be loaded from disk");
+    assert_eq!(variants.len(), 1);
+    assert_eq!(variants[0].status, 200);
+    assert_eq!(&variants[0].body[..], b"kept on disk");
+    assert_eq!(variants[0].header_map()[hyper::header::ETAG], "\"v1\"");
+    assert!(variants[0].matches(&request));
+
+    // purging removes the file as well
+    assert_eq!(restarted.purge("disk.localtest.me", None).await, 1);
+    assert!(SharedCache::default().lookup(&settings, key).await.is_none());
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+pub async fn cached_responses_are_purged_by_host_and_path_thru_the_admin_api() {
+    use crate::configuration::OddBoxConfiguration;
+    use tower::ServiceExt;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let settings = crate::configuration::ResponseCache::default();
+    for key in ["a.localtest.me/assets/app.js", "a.localtest.me/assets/app.css", "a.localtest.me/index.html", "b.localtest.me/assets/app.js"] {
+        state.app_state.response_cache.store_variant(&settings, key, vec![], fresh_variant(10)).await;
+    }
+    for (query, purged) in [("hostname=a.localtest.me&path=/assets/", 2), ("hostname=A.localtest.me", 1), ("hostname=a.localtest.me", 0)] {
+        let request = axum::http::Request::delete(format!("/api/cache?{query}")).body(axum::body::Body::empty()).unwrap();
+        let response = crate::api::controllers::routes(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result : crate::api::controllers::cache::PurgeResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.purged, purged, "{query}");
+    }
+    // other hosts are left alone
+    assert!(state.app_state.response_cache.lookup(&settings, "b.localtest.me/assets/app.js").await.is_some());
+}
+
+#[tokio::test]
+pub async fn rfc2136_provider_sends_signed_updates() {
+    use crate::configuration::TsigAlgorithm;
+    use crate::dns_provider::{DnsProvider, Rfc2136Provider, TsigKey};
+
+    // stand-in dns server that accepts the first update and refuses the second
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let provider = Rfc2136Provider {
+        server: server.local_addr().unwrap().to_string(),
+        zone: Some("example.com".into()),
+        tsig: Some(TsigKey { name: "odd-box".into(), algorithm: TsigAlgorithm::HmacSha256, secret: b"not so secret".to_vec() }),
+        propagation_delay: std::time::Duration::ZERO,
+    };
+    let stand_in = tokio::spawn(async move {
+        let mut requests = vec![];
+        let mut buf = [0u8; 1024];
+        for rcode in [0u8, 5u8] {
+            let (n, client) = server.recv_from(&mut buf).await.unwrap();
+            let request = buf[..n].to_vec();
+            let mut response = request[..12].to_vec();
+            response[2] = 0x80 | (request[2] & 0x78); // a response with the opcode of the request
+            response[3] = rcode;
+            server.send_to(&response, client).await.unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+
+    provider.present("_acme-challenge.example.com", "the-key-authorization-digest").await.unwrap();
+    let refused = provider.cleanup("_acme-challenge.example.com", "the-key-authorization-digest").await.unwrap_err();
+    assert!(refused.to_string().contains("REFUSED"), "{refused}");
+
+    let requests = stand_in.await.unwrap();
+    for (request, class) in requests.iter().zip([1u16, 254u16]) {
+        assert_eq!((request[2] >> 3) & 0x0f, 5, "opcode should be UPDATE");
+        assert_eq!(&request[4..12], &[0, 1, 0, 0, 0, 1, 0, 1], "one zone, one update and the tsig record");
+        // zone section: example.com SOA IN
+        assert_eq!(&request[12..29], b"\x07example\x03com\x00\x00\x06\x00\x01");
+        // update section: the TXT record, with class IN to add it and NONE to delete it
+        let name = b"\x0f_acme-challenge\x07example\x03com\x00";
+        assert_eq!(&request[29..29 + name.len()], name);
+        let rr = &request[29 + name.len()..];
+        assert_eq!(&rr[0..4], &[0, 16, (class >> 8) as u8, class as u8]);
+        assert_eq!(&rr[10..47], b"\x1cthe-key-authorization-digest\x07odd-box");
+        assert!(request.windows(12).any(|w| w == b"\x0bhmac-sha256"));
+    }
+}
+
+#[test]
+pub fn external_account_binding_is_signed_with_the_eab_key() {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    let account_key = rcgen::KeyPair::generate().unwrap();
+    let hmac_key = b"a key handed out by the CA";
+    let binding = crate::letsencrypt::LECertManager::external_account_binding(
+        "kid-1", &URL_SAFE_NO_PAD.encode(hmac_key), "https://ca.example.com/acme/new-account", &account_key
+    ).unwrap();
+
+    let field = |name: &str| binding[name].as_str().unwrap().to_string();
+    let protected : serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(field("protected")).unwrap()).unwrap();
+    assert_eq!(protected, serde_json::json!({ "alg": "HS256", "kid": "kid-1", "url": "https://ca.example.com/acme/new-account" }));
+    let jwk : serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(field("payload")).unwrap()).unwrap();
+    assert_eq!((jwk["kty"].as_str(), jwk["crv"].as_str()), (Some("EC"), Some("P-256")));
+
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, hmac_key);
+    let signing_input = format!("{}.{}", field("protected"), field("payload"));
+    ring::hmac::verify(&key, signing_input.as_bytes(), &URL_SAFE_NO_PAD.decode(field("signature")).unwrap()).unwrap();
+}
+
+#[test]
+pub fn tls_alpn_01_certificates_carry_the_key_authorization_digest() {
+    use sha2::Digest;
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+    let key_authorization = "token.thumbprint";
+    let key = crate::letsencrypt::LECertManager::create_tls_alpn_01_cert("www.example.com", key_authorization).unwrap();
+    let (_, cert) = X509Certificate::from_der(&key.cert[0]).unwrap();
+
+    let san = cert.subject_alternative_name().unwrap().expect("the certificate should have a subject alternative name");
+    assert_eq!(san.value.general_names, vec![GeneralName::DNSName("www.example.com")]);
+
+    // RFC 8737: a critical id-pe-acmeIdentifier extension holding the sha-256 digest of the key authorization as an octet string
+    let acme_identifier = cert.extensions().iter().find(|x| x.oid.to_id_string() == "1.3.6.1.5.5.7.1.31").expect("the certificate should have an acmeIdentifier");
+    assert!(acme_identifier.critical);
+    let digest = sha2::Sha256::digest(key_authorization.as_bytes());
+    assert_eq!(&acme_identifier.value[..2], &[0x04, 0x20]);
+    assert_eq!(&acme_identifier.value[2..], digest.as_slice());
+}
+
+#[test]
+pub fn certificate_renewals_start_at_the_threshold_and_back_off() {
+    use crate::letsencrypt::{is_due_for_renewal, retry_delay};
+    use std::time::Duration;
+    let day = 24 * 60 * 60;
+    let now = 1_700_000_000;
+    assert!(!is_due_for_renewal(now + 31 * day, now, 30));
+    assert!(is_due_for_renewal(now + 29 * day, now, 30));
+    assert!(is_due_for_renewal(now - day, now, 30));
+
+    // doubling from ten minutes with 20% jitter either way, capped at a day
+    assert_eq!(retry_delay(1, 0.5), Duration::from_secs(600));
+    assert_eq!(retry_delay(2, 0.5), Duration::from_secs(1200));
+    assert_eq!(retry_delay(4, 0.5), Duration::from_secs(4800));
+    assert_eq!(retry_delay(1, 0.0), Duration::from_secs(480));
+    assert_eq!(retry_delay(1, 1.0), Duration::from_secs(720));
+    assert_eq!(retry_delay(9, 0.5), Duration::from_secs(day as u64));
+    assert_eq!(retry_delay(u32::MAX, 0.5), Duration::from_secs(day as u64));
+}
+
+#[test]
+pub fn custom_certificates_are_validated_and_matched_by_name() {
+    use crate::custom_certs::{load, CertificateSource};
+    let dir = std::env::temp_dir().join(format!("odd-box-custom-certs-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, pem: String| {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path.display().to_string()
+    };
+    let source = |site: Option<&str>, cert_path: &str, key_path: &str| CertificateSource {
+        site: site.map(|x| x.to_string()), cert_path: cert_path.into(), key_path: key_path.into(), chain_path: None
+    };
+
+    let wildcard = rcgen::generate_simple_self_signed(vec!["*.example.com".into()]).unwrap();
+    let wildcard_cert = write("wildcard.pem", wildcard.cert.pem());
+    let wildcard_key = write("wildcard.key", wildcard.key_pair.serialize_pem());
+    let www = rcgen::generate_simple_self_signed(vec!["www.example.com".into()]).unwrap();
+    let www_cert = write("www.pem", www.cert.pem());
+    let www_key = write("www.key", www.key_pair.serialize_pem());
+
+    let other_key = write("other.key", rcgen::KeyPair::generate().unwrap().serialize_pem());
+    let mismatch = load(&source(None, &wildcard_cert, &other_key)).unwrap_err().to_string();
+    assert!(mismatch.contains("does not belong to the certificate"), "{mismatch}");
+
+    let mut params = rcgen::CertificateParams::new(vec!["old.example.com".to_string()]).unwrap();
+    params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+    params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+    let expired_key = rcgen::KeyPair::generate().unwrap();
+    let expired_cert = write("expired.pem", params.self_signed(&expired_key).unwrap().pem());
+    let expired_key = write("expired.key", expired_key.serialize_pem());
+    let expired = load(&source(None, &expired_cert, &expired_key)).unwrap_err().to_string();
+    assert!(expired.contains("expired on 2001-01-01"), "{expired}");
+
+    let resolver = crate::certs::DynamicCertResolver::new(false);
+    let global = load(&source(None, &wildcard_cert, &wildcard_key)).unwrap();
+    assert_eq!(global.names, vec!["*.example.com".to_string()]);
+    let site = load(&source(Some("www.example.com"), &www_cert, &www_key)).unwrap();
+    resolver.add_custom_cert(global.clone());
+    resolver.add_custom_cert(site.clone());
+
+    let picked = |host_name: &str| resolver.get_custom_cert(host_name);
+    assert!(std::sync::Arc::ptr_eq(&picked("www.example.com").unwrap(), &site.key));
+    assert!(std::sync::Arc::ptr_eq(&picked("api.example.com").unwrap(), &global.key));
+    assert!(picked("example.com").is_none());
+    assert!(picked("a.b.example.com").is_none());
+    assert!(picked("example.org").is_none());
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+pub fn adopted_processes_are_only_stopped_while_their_pid_is_not_reused() {
+    let mut child = std::process::Command::new("sleep").arg("30").spawn().expect("should be able to run sleep");
+    let pid = child.id();
+    let started_at = crate::hot_upgrade::start_time(pid).expect("should find the start time of a running process");
+    assert!(crate::hot_upgrade::is_running(pid, started_at));
+    // a different start time means that the pid now belongs to some other process, which must be left alone
+    assert!(!crate::hot_upgrade::is_running(pid, started_at + 1));
+    crate::proc_host::stop_adopted_process(pid, started_at + 1);
+    assert!(matches!(child.try_wait(), Ok(None)));
+
+    crate::proc_host::stop_adopted_process(pid, started_at);
+    assert!(child.wait().is_ok());
+    assert!(!crate::hot_upgrade::is_running(pid, started_at));
+}
+
+fn test_state(config: crate::configuration::OddBoxConfig) -> std::sync::Arc<crate::global_state::GlobalState> {
+    std::sync::Arc::new(crate::global_state::GlobalState::new(
+        std::sync::Arc::new(crate::types::app_state::AppState::new()),
+        std::sync::Arc::new(tokio::sync::RwLock::new(crate::configuration::ConfigWrapper::new(config))),
+        tokio::sync::broadcast::channel(16).0,
+        std::sync::Arc::new(crate::certs::DynamicCertResolver::new(false)),
+        tokio::sync::broadcast::channel(16).0,
+        tokio::sync::broadcast::channel(16).0,
+        crate::OddLogHandle::None
+    ))
+}
+
+fn test_connection(key: u64, is_admin_interface: bool) -> crate::types::proxy_state::ProxyActiveTCPConnection {
+    crate::types::proxy_state::ProxyActiveTCPConnection {
+        incoming_sni: None,
+        client_socket_address: None,
+        odd_box_socket: None,
+        connection_key_pointer: std::sync::Weak::new(),
+        connection_key: key,
+        client_addr_string: "127.0.0.1:5000".into(),
+        incoming_connection_uses_tls: false,
+        tls_terminated: false,
+        http_terminated: true,
+        is_admin_interface,
+        recent_request_ids: vec![],
+        version: 1,
+        resolved_connection_type: None,
+        resolved_connection_type_description: None,
+        is_grpc: None,
+        http_version: None,
+        is_websocket: None,
+        outgoing_tunnel_type: None
+    }
+}
+
+#[test]
+pub fn shutdown_only_waits_for_client_connections() {
+    use crate::configuration::OddBoxConfiguration;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let connections = &state.app_state.statistics.active_connections;
+    connections.insert(1, test_connection(1, false));
+    connections.insert(2, test_connection(2, true));
+    connections.insert(3, test_connection(3, false));
+    assert_eq!(crate::shutdown::open_connections(&state), 2);
+    connections.remove(&1);
+    connections.remove(&3);
+    // the event stream of the admin ui stays open until we exit, it must not hold up the drain
+    assert_eq!(crate::shutdown::open_connections(&state), 0);
+}
+
+fn request_headers(request_id: Option<&str>) -> hyper::HeaderMap {
+    let mut headers = hyper::HeaderMap::new();
+    if let Some(id) = request_id {
+        headers.insert(&*crate::http_proxy::X_REQUEST_ID, hyper::header::HeaderValue::from_str(id).unwrap());
+    }
+    headers
+}
+
+#[test]
+pub fn request_id_from_the_client_is_kept_when_sane() {
+    let id = crate::http_proxy::request_id_for(&request_headers(Some("abc-123_x.y:z")));
+    assert_eq!(id, "abc-123_x.y:z");
+}
+
+#[test]
+pub fn request_id_from_the_client_is_replaced_when_not_sane() {
+    let too_long = "a".repeat(129);
+    for bad in ["", "has space", "semi;colon", "ÅÄÖ", too_long.as_str()] {
+        let Ok(value) = hyper::header::HeaderValue::from_bytes(bad.as_bytes()) else { continue };
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(&*crate::http_proxy::X_REQUEST_ID, value);
+        let id = crate::http_proxy::request_id_for(&headers);
+        assert_ne!(id, bad);
+        assert!(uuid::Uuid::parse_str(&id).is_ok(), "expected a new uuid for {bad:?}, got {id}");
+    }
+    // exactly at the limit is still fine
+    let longest = "a".repeat(128);
+    assert_eq!(crate::http_proxy::request_id_for(&request_headers(Some(&longest))), longest);
+}
+
+#[test]
+pub fn request_id_is_generated_when_missing() {
+    let first = crate::http_proxy::request_id_for(&request_headers(None));
+    let second = crate::http_proxy::request_id_for(&request_headers(None));
+    assert!(uuid::Uuid::parse_str(&first).is_ok());
+    assert_ne!(first, second);
+}
+
+#[test]
+pub fn connections_only_keep_the_latest_request_ids() {
+    let mut connection = test_connection(1, false);
+    for i in 0..crate::types::proxy_state::MAX_RECENT_REQUEST_IDS + 3 {
+        connection.record_request_id(format!("req-{i}"));
+    }
+    assert_eq!(connection.recent_request_ids.len(), crate::types::proxy_state::MAX_RECENT_REQUEST_IDS);
+    assert_eq!(connection.recent_request_ids.first().unwrap(), "req-3");
+    assert_eq!(connection.recent_request_ids.last().unwrap(), &format!("req-{}", crate::types::proxy_state::MAX_RECENT_REQUEST_IDS + 2));
+}
+
+fn test_backend(address: &str, port: u16) -> crate::configuration::Backend {
+    crate::configuration::Backend { address: address.into(), port, https: None, hints: None, weight: None, proxy_protocol: None }
+}
+
+#[test]
+pub fn backends_leave_and_rejoin_rotation_at_the_health_check_thresholds() {
+    use crate::configuration::OddBoxConfiguration;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let check = crate::configuration::HealthCheck {
+        kind: crate::configuration::HealthCheckKind::Tcp,
+        path: None,
+        interval_in_seconds: None,
+        timeout_in_milliseconds: None,
+        healthy_threshold: Some(2),
+        unhealthy_threshold: Some(3)
+    };
+    let backend = test_backend("10.0.0.1", 8080);
+    let record = |result: Result<(), String>| {
+        crate::health_check::record_result(&state, crate::health_check::backend_key("a.localtest.me", &backend), &backend, &check, result)
+    };
+    // never checked means healthy
+    assert!(crate::health_check::is_healthy(&state, "a.localtest.me", &backend));
+    record(Err("refused".into()));
+    record(Err("refused".into()));
+    assert!(crate::health_check::is_healthy(&state, "a.localtest.me", &backend));
+    record(Err("refused".into()));
+    assert!(!crate::health_check::is_healthy(&state, "a.localtest.me", &backend));
+    assert_eq!(crate::health_check::summary_for_host(&state, "a.localtest.me"), Some((0, 1)));
+    // a single success is not enough to be trusted again
+    record(Ok(()));
+    assert!(!crate::health_check::is_healthy(&state, "a.localtest.me", &backend));
+    record(Ok(()));
+    assert!(crate::health_check::is_healthy(&state, "a.localtest.me", &backend));
+    assert_eq!(crate::health_check::backends_for_host(&state, "a.localtest.me")[0].last_error, None);
+    // other sites using the same backend are tracked separately
+    assert_eq!(crate::health_check::summary_for_host(&state, "b.localtest.me"), None);
+}
+
+#[tokio::test]
+pub async fn tcp_health_checks_only_pass_when_the_backend_accepts_connections() {
+    let check = crate::configuration::HealthCheck {
+        kind: crate::configuration::HealthCheckKind::Tcp,
+        path: None,
+        interval_in_seconds: None,
+        timeout_in_milliseconds: Some(1000),
+        healthy_threshold: None,
+        unhealthy_threshold: None
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = test_backend("127.0.0.1", listener.local_addr().unwrap().port());
+    let client = crate::health_check::http_client().unwrap();
+    assert_eq!(crate::health_check::check_backend(&client, &check, &backend).await, Ok(()));
+    drop(listener);
+    assert!(crate::health_check::check_backend(&client, &check, &backend).await.is_err());
+}
+
+fn test_site(host_name: &str, backends: Vec<crate::configuration::Backend>) -> crate::configuration::RemoteSiteConfig {
+    crate::configuration::RemoteSiteConfig { host_name: host_name.into(), backends, ..Default::default() }
+}
+
+fn set_access_count(state: &crate::global_state::GlobalState, host_name: &str, count: usize) {
+    state.app_state.statistics.lb_access_count_per_hostname.insert(host_name.into(), std::sync::atomic::AtomicUsize::new(count));
+}
+
+#[test]
+pub fn weighted_round_robin_sends_traffic_in_proportion_to_the_weights() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::BackendSelector;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = test_site("lb.localtest.me", vec![
+        crate::configuration::Backend { weight: Some(3), ..test_backend("10.0.0.1", 80) },
+        test_backend("10.0.0.2", 80),
+        crate::configuration::Backend { weight: Some(2), ..test_backend("10.0.0.3", 80) },
+    ]);
+    let candidates : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+    let picks : Vec<String> = (0..12).map(|i| {
+        set_access_count(&state, &site.host_name, i);
+        crate::load_balancing::WeightedRoundRobin.select(&state, &site, &candidates, &Default::default()).unwrap().address.clone()
+    }).collect();
+    assert_eq!(picks.iter().filter(|x| *x == "10.0.0.1").count(), 6);
+    assert_eq!(picks.iter().filter(|x| *x == "10.0.0.2").count(), 2);
+    assert_eq!(picks.iter().filter(|x| *x == "10.0.0.3").count(), 4);
+}
+
+#[test]
+pub fn least_connections_counts_requests_until_they_are_done() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::BackendSelector;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = test_site("lb.localtest.me", vec![test_backend("10.0.0.1", 80), test_backend("10.0.0.2", 80), test_backend("10.0.0.3", 80)]);
+    let candidates : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+    let select = || crate::load_balancing::LeastConnections.select(&state, &site, &candidates, &Default::default()).unwrap().address.clone();
+    let busy = [
+        crate::load_balancing::InFlightRequest::new(state.clone(), &site.host_name, &site.backends[0]),
+        crate::load_balancing::InFlightRequest::new(state.clone(), &site.host_name, &site.backends[0]),
+        crate::load_balancing::InFlightRequest::new(state.clone(), &site.host_name, &site.backends[2]),
+    ];
+    assert_eq!(select(), "10.0.0.2");
+    let also_busy = crate::load_balancing::InFlightRequest::new(state.clone(), &site.host_name, &site.backends[1]);
+    let _more = crate::load_balancing::InFlightRequest::new(state.clone(), &site.host_name, &site.backends[1]);
+    assert_eq!(select(), "10.0.0.3");
+    drop(busy);
+    drop(also_busy);
+    assert_eq!(select(), "10.0.0.1");
+}
+
+#[test]
+pub fn consistent_hash_only_moves_the_clients_of_a_removed_backend() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::{BackendSelector, SelectionContext};
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = test_site("lb.localtest.me", (1..=4).map(|i| test_backend(&format!("10.0.0.{i}"), 80)).collect());
+    let all : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+    let without_first : Vec<&crate::configuration::Backend> = site.backends.iter().skip(1).collect();
+    let pick = |candidates: &[&crate::configuration::Backend], client: u8| {
+        let ctx = SelectionContext::from_client_ip(std::net::IpAddr::from([192, 168, 1, client]));
+        crate::load_balancing::ConsistentHash.select(&state, &site, candidates, &ctx).unwrap().address.clone()
+    };
+    let mut moved = 0;
+    for client in 0..100 {
+        let before = pick(&all, client);
+        assert_eq!(before, pick(&all, client), "the same client should always get the same backend");
+        let after = pick(&without_first, client);
+        if before == "10.0.0.1" { moved += 1 } else { assert_eq!(before, after) }
+    }
+    assert!(moved > 0 && moved < 100);
+}
+
+#[test]
+pub fn consistent_hash_prefers_the_configured_header_over_the_client_ip() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::{BackendSelector, SelectionContext};
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = crate::configuration::RemoteSiteConfig {
+        consistent_hash_header: Some("x-api-key".into()),
+        ..test_site("lb.localtest.me", (1..=4).map(|i| test_backend(&format!("10.0.0.{i}"), 80)).collect())
+    };
+    let candidates : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert("x-api-key", hyper::header::HeaderValue::from_static("customer-1"));
+    let picks : std::collections::HashSet<String> = (0..50).map(|client| {
+        let ctx = SelectionContext { headers: Some(&headers), ..SelectionContext::from_client_ip(std::net::IpAddr::from([10, 1, 1, client])) };
+        crate::load_balancing::ConsistentHash.select(&state, &site, &candidates, &ctx).unwrap().address.clone()
+    }).collect();
+    assert_eq!(picks.len(), 1);
+}
+
+#[test]
+pub fn fnv1a_is_stable_and_keeps_parts_apart() {
+    // pinned so that a change of the algorithm, which would move clients around, does not go unnoticed
+    assert_eq!(crate::load_balancing::fnv1a(&[]), 0xcbf29ce484222325);
+    assert_eq!(crate::load_balancing::fnv1a(&[b"10.0.0.1", b"10.0.0.2"]), 0x0b4532598d7ddc8c);
+    assert_ne!(crate::load_balancing::fnv1a(&[b"10.0.0.1", b"10.0.0.2"]), crate::load_balancing::fnv1a(&[b"10.0.0.1b", b"0.0.0.2"]));
+}
+
+fn cookie_header(set_cookie: &str) -> hyper::HeaderMap {
+    let pair = set_cookie.split(';').next().unwrap();
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(hyper::header::COOKIE, hyper::header::HeaderValue::from_str(&format!("other=1; {pair}")).unwrap());
+    headers
+}
+
+#[test]
+pub fn session_affinity_cookies_pin_clients_to_their_backend() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::SelectionContext;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = crate::configuration::RemoteSiteConfig {
+        session_affinity: Some(crate::configuration::SessionAffinity { cookie_name: Some("pinned".into()), cookie_max_age_in_seconds: Some(60) }),
+        ..test_site("sticky.localtest.me", vec![test_backend("10.0.0.1", 80), test_backend("10.0.0.2", 80)])
+    };
+    let candidates : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+
+    let set_cookie = crate::session_affinity::cookie_for_response(&site, &hyper::HeaderMap::new(), &site.backends[1]).expect("new clients should get a cookie");
+    assert!(set_cookie.starts_with("pinned="));
+    assert!(set_cookie.contains("HttpOnly") && set_cookie.contains("Max-Age=60"));
+
+    let headers = cookie_header(&set_cookie);
+    let ctx = SelectionContext { headers: Some(&headers), ..Default::default() };
+    assert_eq!(crate::session_affinity::select_pinned(&state, &site, &candidates, &ctx).map(|b| b.address.as_str()), Some("10.0.0.2"));
+    // no need to send the same cookie again
+    assert_eq!(crate::session_affinity::cookie_for_response(&site, &headers, &site.backends[1]), None);
+    // but a client that ended up elsewhere is pinned to its new backend
+    assert!(crate::session_affinity::cookie_for_response(&site, &headers, &site.backends[0]).is_some());
+
+    // once the backend is gone the normal strategy takes over
+    assert!(crate::session_affinity::select_pinned(&state, &site, &candidates[..1], &ctx).is_none());
+}
+
+#[test]
+pub fn session_affinity_cookies_must_be_signed_for_the_site() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::load_balancing::SelectionContext;
+    use base64::Engine;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let backends = vec![test_backend("10.0.0.1", 80), test_backend("10.0.0.2", 80)];
+    let site = crate::configuration::RemoteSiteConfig {
+        session_affinity: Some(crate::configuration::SessionAffinity::default()),
+        ..test_site("sticky.localtest.me", backends.clone())
+    };
+    let other_site = crate::configuration::RemoteSiteConfig { host_name: "other.localtest.me".into(), ..site.clone() };
+    let candidates : Vec<&crate::configuration::Backend> = site.backends.iter().collect();
+    let pinned = |headers: &hyper::HeaderMap| {
+        let ctx = SelectionContext { headers: Some(headers), ..Default::default() };
+        crate::session_affinity::select_pinned(&state, &site, &candidates, &ctx).map(|b| b.address.clone())
+    };
+
+    let set_cookie = crate::session_affinity::cookie_for_response(&site, &hyper::HeaderMap::new(), &site.backends[0]).unwrap();
+    assert_eq!(pinned(&cookie_header(&set_cookie)), Some("10.0.0.1".into()));
+
+    // swapping the backend while keeping the signature
+    let (_, signature) = set_cookie.split(';').next().unwrap().split_once('.').unwrap();
+    let forged_id = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode("10.0.0.2:80");
+    assert_eq!(pinned(&cookie_header(&format!("odd_box_backend={forged_id}.{signature}"))), None);
+    assert_eq!(pinned(&cookie_header("odd_box_backend=garbage")), None);
+
+    // cookies handed out by another site are not accepted either
+    let foreign = crate::session_affinity::cookie_for_response(&other_site, &hyper::HeaderMap::new(), &site.backends[1]).unwrap();
+    assert_eq!(pinned(&cookie_header(&foreign)), None);
+}
+
+#[test]
+pub fn requests_are_only_retried_when_they_can_safely_be_sent_again() {
+    use http_body_util::{Empty, Full};
+    let request = |method: hyper::Method, body: &'static [u8]| hyper::Request::builder().method(method).uri("/").body(Full::new(bytes::Bytes::from_static(body))).unwrap();
+    let default_policy = crate::configuration::RetryPolicy::default();
+    let with_bodies = crate::configuration::RetryPolicy { attempts: Some(2), max_body_size_in_bytes: Some(4) };
+
+    assert!(crate::http_proxy::is_retryable(&hyper::Request::get("/").body(Empty::<bytes::Bytes>::new()).unwrap(), &default_policy));
+    assert!(crate::http_proxy::is_retryable(&request(hyper::Method::HEAD, b""), &default_policy));
+    assert!(!crate::http_proxy::is_retryable(&request(hyper::Method::GET, b"abc"), &default_policy));
+    assert!(!crate::http_proxy::is_retryable(&request(hyper::Method::POST, b""), &default_policy));
+    // opting in to bodies also opts in to methods that are not idempotent
+    assert!(crate::http_proxy::is_retryable(&request(hyper::Method::POST, b"abcd"), &with_bodies));
+    assert!(!crate::http_proxy::is_retryable(&request(hyper::Method::POST, b"abcde"), &with_bodies));
+    let upgrade = hyper::Request::get("/").header(hyper::header::UPGRADE, "websocket").body(Empty::<bytes::Bytes>::new()).unwrap();
+    assert!(!crate::http_proxy::is_retryable(&upgrade, &with_bodies));
+}
+
+#[tokio::test]
+pub async fn circuit_breaker_ejects_failing_backends_until_they_respond_again() {
+    use crate::configuration::{BackendFilter, OddBoxConfiguration};
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let mut events = state.global_broadcast_channel.subscribe();
+    let site = crate::configuration::RemoteSiteConfig {
+        circuit_breaker: Some(crate::configuration::CircuitBreaker { consecutive_failures: Some(3), cool_down_in_seconds: Some(60) }),
+        ..test_site("cb.localtest.me", vec![test_backend("10.0.0.1", 80), test_backend("10.0.0.2", 80)])
+    };
+    let no_exclusions = crate::load_balancing::SelectionContext::default();
+    let tried = [test_backend("10.0.0.2", 80)];
+    let retry = crate::load_balancing::SelectionContext { excluded: &tried, ..Default::default() };
+
+    crate::outlier_detection::record_failure(&state, &site, &site.backends[0]);
+    crate::outlier_detection::record_failure(&state, &site, &site.backends[0]);
+    // a success in between starts the count over
+    crate::outlier_detection::record_success(&state, &site, &site.backends[0]);
+    crate::outlier_detection::record_failure(&state, &site, &site.backends[0]);
+    crate::outlier_detection::record_failure(&state, &site, &site.backends[0]);
+    assert!(!crate::outlier_detection::is_ejected(&state, &site.host_name, &site.backends[0]));
+    crate::outlier_detection::record_failure(&state, &site, &site.backends[0]);
+    assert!(crate::outlier_detection::is_ejected(&state, &site.host_name, &site.backends[0]));
+    assert!(matches!(events.try_recv(), Ok(crate::types::odd_box_event::GlobalEvent::BackendEjected(e)) if e.consecutive_failures == 3));
+
+    // every attempt goes to the remaining backend, and a retry that already tried it falls back to the ejected one
+    for _ in 0..3 {
+        assert_eq!(site.next_backend(&state, BackendFilter::Any, &no_exclusions).await.map(|b| b.address).as_deref(), Some("10.0.0.2"));
+    }
+    assert_eq!(site.next_backend(&state, BackendFilter::Any, &retry).await.map(|b| b.address).as_deref(), Some("10.0.0.1"));
+
+    crate::outlier_detection::record_success(&state, &site, &site.backends[0]);
+    assert!(!crate::outlier_detection::is_ejected(&state, &site.host_name, &site.backends[0]));
+    assert!(matches!(events.try_recv(), Ok(crate::types::odd_box_event::GlobalEvent::BackendRestored(_))));
+    assert_eq!(site.next_backend(&state, BackendFilter::Any, &retry).await.map(|b| b.address).as_deref(), Some("10.0.0.1"));
+}
+
+#[tokio::test]
+pub async fn requests_still_go_somewhere_when_every_backend_has_been_ejected() {
+    use crate::configuration::{BackendFilter, OddBoxConfiguration};
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let site = crate::configuration::RemoteSiteConfig {
+        circuit_breaker: Some(crate::configuration::CircuitBreaker { consecutive_failures: Some(1), cool_down_in_seconds: Some(60) }),
+        ..test_site("all-ejected.localtest.me", vec![test_backend("10.0.0.1", 80), test_backend("10.0.0.2", 80)])
+    };
+    for backend in &site.backends {
+        crate::outlier_detection::record_failure(&state, &site, backend);
+        assert!(crate::outlier_detection::is_ejected(&state, &site.host_name, backend));
+    }
+    let no_exclusions = crate::load_balancing::SelectionContext::default();
+    let mut picked = std::collections::HashSet::new();
+    for count in 0..4 {
+        set_access_count(&state, &site.host_name, count);
+        let backend = site.next_backend(&state, BackendFilter::Any, &no_exclusions).await.expect("ejected backends should be used when there is nothing else");
+        picked.insert(backend.address);
+    }
+    // still load balanced between all of them
+    assert_eq!(picked.len(), 2);
+    // but backends that do not match the filter are not brought back
+    assert_eq!(site.next_backend(&state, BackendFilter::Http2, &no_exclusions).await, None);
+}
+
+fn routed(routes: &[crate::configuration::Route], uri: &str) -> (Option<String>, String) {
+    let mut req = hyper::Request::get(uri).body(()).unwrap();
+    let target = crate::http_proxy::routes::apply_routes(routes, &mut req);
+    (target, req.uri().to_string())
+}
+
+#[test]
+pub fn path_prefix_routes_only_match_whole_segments() {
+    use crate::configuration::Route;
+    let routes = [
+        Route { path_prefix: Some("/api".into()), target: "api.localtest.me".into(), ..Default::default() },
+        Route { path_prefix: Some("/static/*".into()), target: "files.localtest.me".into(), strip_prefix: Some(true), ..Default::default() },
+    ];
+    assert_eq!(routed(&routes, "/api"), (Some("api.localtest.me".into()), "/api".into()));
+    assert_eq!(routed(&routes, "/api/users?page=2"), (Some("api.localtest.me".into()), "/api/users?page=2".into()));
+    assert_eq!(routed(&routes, "/apiary"), (None, "/apiary".into()));
+    assert_eq!(routed(&routes, "/api-internal/x"), (None, "/api-internal/x".into()));
+    // a prefix with a trailing slash also covers the path without it
+    assert_eq!(routed(&routes, "/static"), (Some("files.localtest.me".into()), "/".into()));
+    assert_eq!(routed(&routes, "/static/css/site.css?v=3"), (Some("files.localtest.me".into()), "/css/site.css?v=3".into()));
+}
+
+#[test]
+pub fn routes_rewrite_paths_and_the_first_match_wins() {
+    use crate::configuration::Route;
+    let routes = [
+        Route { path_prefix: Some("/api/".into()), target: "v2.localtest.me".into(), rewrite: Some("/v2/".into()), ..Default::default() },
+        Route { path_regex: Some("^/legacy/(?P<rest>.*)$".into()), target: "old.localtest.me".into(), rewrite: Some("/archive/$rest".into()), ..Default::default() },
+        Route { path_regex: Some("^/v[0-9]+/".into()), target: "versioned.localtest.me".into(), strip_prefix: Some(true), ..Default::default() },
+        Route { path_prefix: Some("/".into()), target: "catch-all.localtest.me".into(), ..Default::default() },
+    ];
+    assert_eq!(routed(&routes, "/api/users"), (Some("v2.localtest.me".into()), "/v2/users".into()));
+    assert_eq!(routed(&routes, "/legacy/a/b?c=d"), (Some("old.localtest.me".into()), "/archive/a/b?c=d".into()));
+    assert_eq!(routed(&routes, "/v3/items"), (Some("versioned.localtest.me".into()), "/items".into()));
+    assert_eq!(routed(&routes, "/anything"), (Some("catch-all.localtest.me".into()), "/anything".into()));
+    // invalid patterns never match rather than failing the request
+    let broken = [Route { path_regex: Some("(".into()), target: "x.localtest.me".into(), ..Default::default() }];
+    assert_eq!(routed(&broken, "/("), (None, "/(".into()));
+}
+
+#[test]
+pub fn header_rules_remove_then_set_then_add_with_placeholders_filled_in() {
+    use crate::configuration::{Header, HeaderRules};
+    let incoming = hyper::Request::get("/")
+        .header("host", "app.localtest.me:8443")
+        .header("x-request-id", "req-1")
+        .body(()).unwrap();
+    let values = crate::http_proxy::HeaderTemplateValues::new(&incoming, Some("192.168.1.20:50000".parse().unwrap()));
+    assert_eq!((values.client_ip.as_str(), values.host.as_str(), values.request_id.as_str()), ("192.168.1.20", "app.localtest.me", "req-1"));
+
+    let header = |name: &str, value: &str| Header { name: name.into(), value: value.into() };
+    let rewrites = crate::http_proxy::HeaderRewrites::new(
+        Some(&HeaderRules {
+            remove: Some(vec!["X-Forwarded-For".into()]),
+            set: Some(vec![header("X-Forwarded-For", "{client_ip}"), header("X-Trace", "{request_id}@{host}")]),
+            add: Some(vec![header("Via", "odd-box"), header("bad name", "ignored")]),
+        }),
+        Some(&HeaderRules { remove: Some(vec!["server".into()]), ..Default::default() }),
+        values
+    );
+
+    let mut request_headers = hyper::HeaderMap::new();
+    request_headers.insert("x-forwarded-for", "6.6.6.6".parse().unwrap());
+    request_headers.insert("via", "some-cdn".parse().unwrap());
+    request_headers.insert("server", "kept on requests".parse().unwrap());
+    rewrites.apply_to_request(&mut request_headers);
+    assert_eq!(request_headers.get_all("x-forwarded-for").iter().collect::<Vec<_>>(), ["192.168.1.20"]);
+    assert_eq!(request_headers["x-trace"], "req-1@app.localtest.me");
+    assert_eq!(request_headers.get_all("via").iter().collect::<Vec<_>>(), ["some-cdn", "odd-box"]);
+    assert!(request_headers.contains_key("server"));
+    assert_eq!(request_headers.len(), 5);
+
+    let mut response_headers = hyper::HeaderMap::new();
+    response_headers.insert("server", "nginx".parse().unwrap());
+    rewrites.apply_to_response(&mut response_headers);
+    assert!(response_headers.is_empty());
+}
+
+#[test]
+pub fn access_log_entries_follow_the_configured_format() {
+    use crate::configuration::AccessLogFormat;
+    let req = hyper::Request::get("/search?q=odd")
+        .header("host", "app.localtest.me:443")
+        .header("referer", "https://example.com/")
+        .header("user-agent", "curl \"quoted\"")
+        .header("x-request-id", "req-1")
+        .body(()).unwrap();
+    let mut entry = crate::access_log::AccessLogEntry::for_request(&req, Some("192.168.1.20".parse().unwrap()));
+    entry.status = Some(200);
+    entry.bytes_sent = 1234;
+    entry.set_upstream(&crate::access_log::Upstream { backend: "10.0.0.1:8080".into(), latency: std::time::Duration::from_millis(15) });
+
+    let common = entry.format(&AccessLogFormat::Common);
+    assert!(common.starts_with("192.168.1.20 - - ["), "{common}");
+    assert!(common.ends_with("] \"GET /search?q=odd HTTP/1.1\" 200 1234"), "{common}");
+    let combined = entry.format(&AccessLogFormat::Combined);
+    assert_eq!(combined, format!("{common} \"https://example.com/\" \"curl \\\"quoted\\\"\""));
+
+    let json : serde_json::Value = serde_json::from_str(&entry.format(&AccessLogFormat::Json)).unwrap();
+    assert_eq!(json["kind"], "http");
+    assert_eq!(json["host"], "app.localtest.me");
+    assert_eq!(json["request_id"], "req-1");
+    assert_eq!(json["upstream"], "10.0.0.1:8080");
+    assert!((json["upstream_latency_ms"].as_f64().unwrap() - 15.0).abs() < 0.001);
+
+    let tunnel = crate::access_log::AccessLogEntry::for_tunnel("db.localtest.me", "10.1.1.1".parse().unwrap(), true);
+    assert!(tunnel.format(&AccessLogFormat::Common).ends_with("] \"TUNNEL db.localtest.me TLS\" - 0"));
+}
+
+#[test]
+pub fn the_access_log_needs_a_path_while_the_tui_runs() {
+    let to_stdout = crate::configuration::AccessLog::default();
+    assert!(crate::access_log::validate(&to_stdout, true).is_err());
+    assert!(crate::access_log::validate(&to_stdout, false).is_ok());
+    let to_file = crate::configuration::AccessLog { path: Some("logs/access.log".into()), ..Default::default() };
+    assert!(crate::access_log::validate(&to_file, true).is_ok());
+}
+
+#[test]
+pub fn only_the_newest_rotated_access_logs_are_kept() {
+    let dir = std::env::temp_dir().join(format!("odd-box-access-log-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+    for name in ["access.log", "access.log.20240101-000000", "access.log.20240102-000000", "access.log.20240103-000000", "access.log.20240103-000000-1", "other.log.20240101-000000"] {
+        std::fs::write(dir.join(name), "x").unwrap();
+    }
+    crate::access_log::remove_old_files(path.to_str().unwrap(), 2);
+    let mut left : Vec<String> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    left.sort();
+    assert_eq!(left, ["access.log", "access.log.20240103-000000", "access.log.20240103-000000-1", "other.log.20240101-000000"]);
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+pub fn token_buckets_allow_a_burst_and_then_refill_at_the_configured_rate() {
+    use crate::configuration::OddBoxConfiguration;
+    use crate::rate_limiting::try_acquire;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let limit = crate::configuration::RateLimit { key: None, header: None, requests: 1, per_seconds: Some(60), burst: Some(2) };
+    let host = "limited.localtest.me";
+
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    let wait = try_acquire(&state, host, &limit, "ip:10.0.0.1".into()).expect_err("the burst should be used up");
+    assert!(wait > std::time::Duration::from_secs(59) && wait <= std::time::Duration::from_secs(60), "{wait:?}");
+    assert_eq!(crate::rate_limiting::retry_after_seconds(wait), 60);
+    // other clients have buckets of their own
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.2".into()), Ok(()));
+
+    let rewind = |by: std::time::Duration| {
+        let mut bucket = state.app_state.statistics.rate_limit_buckets.get_mut(&(host.to_string(), "ip:10.0.0.1".to_string())).unwrap();
+        bucket.last_refill -= by;
+    };
+    // half a token is not enough
+    rewind(std::time::Duration::from_secs(30));
+    let wait = try_acquire(&state, host, &limit, "ip:10.0.0.1".into()).expect_err("half a token should not be enough");
+    assert!(wait > std::time::Duration::from_secs(29) && wait <= std::time::Duration::from_secs(30), "{wait:?}");
+    rewind(std::time::Duration::from_secs(30));
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    // and waiting for a long time does not allow more than the burst
+    rewind(std::time::Duration::from_secs(600));
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    assert_eq!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    assert!(try_acquire(&state, host, &limit, "ip:10.0.0.1".into()).is_err());
+
+    let limited = state.app_state.statistics.rate_limited_requests.get(host).unwrap().load(std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(limited, 3);
+    assert_eq!(crate::rate_limiting::retry_after_seconds(std::time::Duration::from_millis(10)), 1);
+}
+
+#[test]
+pub fn rate_limit_buckets_are_keyed_by_ip_site_or_header() {
+    use crate::configuration::{RateLimit, RateLimitKey};
+    use crate::rate_limiting::bucket_key;
+    let ip = Some("10.0.0.1".parse().unwrap());
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert("x-api-key", "customer-1".parse().unwrap());
+    let limit = |key| RateLimit { key, header: Some("X-Api-Key".into()), requests: 10, per_seconds: None, burst: None };
+
+    assert_eq!(bucket_key(&limit(None), ip, Some(&headers)), "ip:10.0.0.1");
+    assert_eq!(bucket_key(&limit(Some(RateLimitKey::Site)), ip, Some(&headers)), "");
+    assert_eq!(bucket_key(&limit(Some(RateLimitKey::Header)), ip, Some(&headers)), "header:customer-1");
+    // requests without the header and tunnels share the bucket of their ip
+    assert_eq!(bucket_key(&limit(Some(RateLimitKey::Header)), ip, Some(&hyper::HeaderMap::new())), "ip:10.0.0.1");
+    assert_eq!(bucket_key(&limit(Some(RateLimitKey::Header)), ip, None), "ip:10.0.0.1");
+}
+
+#[test]
+pub fn the_token_taken_for_a_connection_pays_for_its_first_request_only() {
+    use crate::configuration::OddBoxConfiguration;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+    let limit = crate::configuration::RateLimit { key: None, header: None, requests: 1, per_seconds: Some(60), burst: Some(1) };
+    let host = "prepaid.localtest.me";
+
+    assert_eq!(crate::rate_limiting::take_token(&state, host, &limit, "ip:10.0.0.1".into()), Ok(()));
+    let token = crate::rate_limiting::PrepaidToken::new(host.into(), "ip:10.0.0.1".into());
+    // a request counted against another bucket has to pay for itself
+    assert!(!token.take(host, "header:customer-1"));
+    assert!(token.take(host, "ip:10.0.0.1"));
+    assert!(!token.take(host, "ip:10.0.0.1"));
+    assert!(!crate::rate_limiting::PrepaidToken::default().take(host, "ip:10.0.0.1"));
+
+    // connections handed on to the terminating proxy are not counted as limited until their requests are
+    assert!(crate::rate_limiting::take_token(&state, host, &limit, "ip:10.0.0.1".into()).is_err());
+    assert!(state.app_state.statistics.rate_limited_requests.get(host).is_none());
+    assert!(crate::rate_limiting::try_acquire(&state, host, &limit, "ip:10.0.0.1".into()).is_err());
+    assert_eq!(state.app_state.statistics.rate_limited_requests.get(host).unwrap().load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+fn argon2_hash(password: &str) -> String {
+    use argon2::PasswordHasher;
+    let salt = argon2::password_hash::SaltString::from_b64("b2RkLWJveC1zYWx0").unwrap();
+    argon2::Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+#[test]
+pub fn basic_auth_passwords_are_checked_against_bcrypt_and_argon2_hashes() {
+    use crate::http_proxy::auth::verify_password;
+    let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+    assert!(verify_password("secret", &bcrypt_hash));
+    assert!(!verify_password("Secret", &bcrypt_hash));
+    // htpasswd writes $2y$ hashes
+    assert!(verify_password("secret", &bcrypt_hash.replacen("$2b$", "$2y$", 1)));
+
+    let argon2_hash = argon2_hash("secret");
+    assert!(argon2_hash.starts_with("$argon2id$"));
+    assert!(verify_password("secret", &argon2_hash));
+    assert!(!verify_password("secret ", &argon2_hash));
+
+    // plain text and other hash formats are never accepted
+    assert!(!verify_password("secret", "secret"));
+    assert!(!verify_password("secret", "$apr1$abc$def"));
+    assert!(!verify_password("secret", "$2y$broken"));
+}
+
+#[tokio::test]
+pub async fn basic_auth_lets_valid_logins_thru_and_asks_everyone_else_for_credentials() {
+    use base64::Engine;
+    let auth = crate::configuration::BasicAuth {
+        realm: Some("the \"private\" area".into()),
+        users: vec![format!("alice:{}", bcrypt::hash("wonderland", 4).unwrap()), format!("bob:{}", argon2_hash("builder"))],
+    };
+    let request = |authorization: Option<String>| {
+        let mut req = hyper::Request::get("/").body(()).unwrap();
+        if let Some(v) = authorization {
+            req.headers_mut().insert(hyper::header::AUTHORIZATION, v.parse().unwrap());
+        }
+        req
+    };
+    let basic = |credentials: &str| Some(format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials)));
+
+    for (credentials, user) in [("alice:wonderland", "alice"), ("bob:builder", "bob")] {
+        let mut req = request(basic(credentials));
+        // twice, the second time the verified credentials are remembered
+        for _ in 0..2 {
+            assert!(crate::http_proxy::auth::check_basic_auth("site.localtest.me", &auth, &mut req).await.is_ok());
+        }
+        assert!(!req.headers().contains_key(hyper::header::AUTHORIZATION), "the credentials should not be passed on");
+        assert_eq!(req.headers()["x-forwarded-user"], user);
+    }
+
+    for authorization in [None, basic("alice:builder"), basic("carol:wonderland"), basic("alice"), Some("Bearer alice:wonderland".into())] {
+        let mut req = request(authorization.clone());
+        let response = crate::http_proxy::auth::check_basic_auth("site.localtest.me", &auth, &mut req).await.expect_err(&format!("{authorization:?} should be rejected"));
+        assert_eq!(response.status(), hyper::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[hyper::header::WWW_AUTHENTICATE], "Basic realm=\"the 'private' area\", charset=\"UTF-8\"");
+    }
+}
+
+fn free_local_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+pub async fn tcp_stream_sites_forward_to_a_working_backend_and_close_idle_connections() {
+    use crate::configuration::OddBoxConfiguration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+
+    let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = backend.accept().await else { break };
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    let listen_port = free_local_port();
+    let site = crate::configuration::TcpStreamSite {
+        name: "echo".into(),
+        listen_ip: Some("127.0.0.1".parse().unwrap()),
+        listen_port,
+        // nothing listens on the first backend, so the connection moves on to the second one
+        backends: vec![test_backend("127.0.0.1", free_local_port()), test_backend("127.0.0.1", backend_port)],
+        sni_routes: None,
+        timeouts: Some(crate::configuration::Timeouts { idle_in_seconds: Some(1), ..Default::default() }),
+    };
+    let token = tokio_util::sync::CancellationToken::new();
+    let listener = tokio::spawn(crate::stream_proxy::run_tcp_listener(site, ([127, 0, 0, 1], listen_port).into(), state.clone(), token.clone()));
+
+    let mut client = loop {
+        match tokio::net::TcpStream::connect(("127.0.0.1", listen_port)).await {
+            Ok(c) => break c,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await
+        }
+    };
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // the connection is closed once nothing has gone either way for the configured idle timeout
+    let n = tokio::time::timeout(std::time::Duration::from_secs(5), client.read(&mut buf)).await.expect("idle connections should be closed").unwrap_or(0);
+    assert_eq!(n, 0);
+
+    token.cancel();
+    listener.await.unwrap();
+}
+
+#[tokio::test]
+pub async fn udp_stream_sites_keep_a_session_per_client_until_it_goes_quiet() {
+    use crate::configuration::OddBoxConfiguration;
+    let state = test_state(crate::configuration::OddBoxConfig::example());
+
+    // replies with the datagram prefixed by the address it came from, so that we can tell sessions apart
+    let backend = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        while let Ok((n, from)) = backend.recv_from(&mut buf).await {
+            _ = backend.send_to(format!("{from}|{}", String::from_utf8_lossy(&buf[..n])).as_bytes(), from).await;
+        }
+    });
+
+    let listen_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let site = crate::configuration::UdpStreamSite {
+        name: "udp-echo".into(),
+        listen_ip: Some("127.0.0.1".parse().unwrap()),
+        listen_port,
+        backends: vec![test_backend("127.0.0.1", backend_port)],
+        session_timeout_in_seconds: Some(1),
+    };
+    let token = tokio_util::sync::CancellationToken::new();
+    let listener = tokio::spawn(crate::stream_proxy::run_udp_listener(site, ([127, 0, 0, 1], listen_port).into(), state.clone(), token.clone()));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let ask = |client: std::sync::Arc<tokio::net::UdpSocket>, message: &'static str| async move {
+        client.send_to(message.as_bytes(), ("127.0.0.1", listen_port)).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let n = tokio::time::timeout(std::time::Duration::from_secs(2), client.recv(&mut buf)).await.expect("should get a reply").unwrap();
+        let reply = String::from_utf8_lossy(&buf[..n]).to_string();
+        let (session, echoed) = reply.split_once('|').unwrap();
+        assert_eq!(echoed, message);
+        session.to_string()
+    };
+    let first = std::sync::Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let second = std::sync::Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let first_session = ask(first.clone(), "a").await;
+    assert_eq!(ask(first.clone(), "b").await, first_session);
+    assert_ne!(ask(second.clone(), "c").await, first_session);
+    assert_eq!(state.app_state.statistics.active_connections.len(), 2);
+
+    // quiet sessions are closed, and the client gets a new one when it speaks up again
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert_eq!(state.app_state.statistics.active_connections.len(), 0);
+    assert_ne!(ask(first.clone(), "d").await, first_session);
+
+    token.cancel();
+    listener.await.unwrap();
+}
+
+#[test]
+pub fn listeners_default_to_the_http_and_tls_ports_unless_listed() {
+    use crate::configuration::{Listener, ListenerProtocol, OddBoxConfiguration};
+    use crate::proxy::{wanted_listeners, ListenerSettings};
+    let initial_http : std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let initial_tls : std::net::SocketAddr = "127.0.0.1:4343".parse().unwrap();
+    let mut config = crate::configuration::OddBoxConfig::example();
+    config.ip = Some("10.0.0.5".parse().unwrap());
+    config.http_port = Some(80);
+    config.tls_port = None;
+    config.listeners = None;
+    assert_eq!(wanted_listeners(&crate::configuration::ConfigWrapper::new(config.clone()), initial_http, initial_tls), vec![
+        ListenerSettings { bind_addr: "10.0.0.5:80".parse().unwrap(), protocol: ListenerProtocol::Http, ipv6_only: false, sites: None },
+        ListenerSettings { bind_addr: "10.0.0.5:4343".parse().unwrap(), protocol: ListenerProtocol::Https, ipv6_only: false, sites: None },
+    ]);
+
+    // once listeners are listed, the http and tls ports no longer apply
+    config.listeners = Some(vec![
+        Listener { ip: Some("::".parse().unwrap()), port: 443, protocol: Some(ListenerProtocol::Https), ipv6_only: Some(true), sites: Some(vec!["example.com".into()]) },
+        Listener { ip: None, port: 8443, protocol: None, ipv6_only: None, sites: None },
+    ]);
+    assert_eq!(wanted_listeners(&crate::configuration::ConfigWrapper::new(config), initial_http, initial_tls), vec![
+        ListenerSettings { bind_addr: "[::]:443".parse().unwrap(), protocol: ListenerProtocol::Https, ipv6_only: true, sites: Some(vec!["example.com".into()]) },
+        ListenerSettings { bind_addr: "10.0.0.5:8443".parse().unwrap(), protocol: ListenerProtocol::Auto, ipv6_only: false, sites: None },
+    ]);
+}
+
+#[tokio::test]
+pub async fn tunnels_are_closed_after_the_idle_timeout_but_not_while_traffic_flows() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let (mut client, mut client_side) = tokio::io::duplex(1024);
+    let (mut backend_side, mut backend) = tokio::io::duplex(1024);
+    let started = std::time::Instant::now();
+    let tunnel = tokio::spawn(async move {
+        crate::timeouts::copy_bidirectional_with_idle_timeout(&mut client_side, &mut backend_side, Some(std::time::Duration::from_secs(1)), "idle.localtest.me").await
+    });
+
+    // keep talking for longer than the timeout
+    let mut buf = [0u8; 4];
+    for _ in 0..6 {
+        client.write_all(b"ping").await.unwrap();
+        backend.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    }
+    assert!(!tunnel.is_finished());
+
+    let (from_client, from_backend) = tokio::time::timeout(std::time::Duration::from_secs(5), tunnel).await
+        .expect("the tunnel should close once it goes quiet").unwrap().unwrap();
+    assert_eq!((from_client, from_backend), (24, 0));
+    assert!(started.elapsed() >= std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+pub async fn connections_waiting_on_a_backend_are_not_idle() {
+    let activity = crate::timeouts::Activity::default();
+    let busy = activity.busy();
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(2500), activity.idle_for(Some(std::time::Duration::from_secs(1)))).await.is_err());
+    drop(busy);
+    // the wait ends the moment the backend responded, so the clock starts over from there
+    let started = std::time::Instant::now();
+    let idle = activity.idle_for(Some(std::time::Duration::from_secs(1))).await;
+    assert_eq!(idle, std::time::Duration::from_secs(1));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+    // no timeout means never idle
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(100), activity.idle_for(None)).await.is_err());
+}
+
+/// Serves a single connection to a local port the way the http listeners do, with a one second request header timeout.
+async fn serve_one_connection_with_short_header_timeout() -> u16 {
+    use crate::configuration::OddBoxConfiguration;
+    // the proxy clients need a crypto provider, whichever test gets to install it first wins
+    _ = rustls::crypto::ring::default_provider().install_default();
+    let mut config = crate::configuration::OddBoxConfig::example();
+    config.timeouts = Some(crate::configuration::Timeouts { request_headers_in_seconds: Some(1), ..Default::default() });
+    let configuration = std::sync::Arc::new(crate::configuration::ConfigWrapper::new(config.clone()));
+    let state = test_state(config);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, source_addr) = listener.accept().await.unwrap();
+        let (client, h2_client) = crate::proxy::create_proxy_clients(std::time::Duration::from_secs(1));
+        let service = crate::http_proxy::ReverseProxyService {
+            source_addr: Some(source_addr),
+            state: state.clone(),
+            remote_addr: None,
+            tx: std::sync::Arc::new(tokio::sync::broadcast::channel(16).0),
+            is_https: false,
+            client,
+            h2_client,
+            resolved_target: None,
+            configuration,
+            connection_key: 0,
+            sni: None,
+            host_header: None,
+            listener: std::sync::Arc::new(crate::proxy::ListenerSettings {
+                bind_addr: ([127, 0, 0, 1], port).into(),
+                protocol: crate::configuration::ListenerProtocol::Http,
+                ipv6_only: false,
+                sites: None,
+            }),
+            rate_limit_token: Default::default(),
+        };
+        crate::http_proxy::serve(service, crate::tcp_proxy::GenericManagedStream::from_tcp_stream(stream, state)).await;
+    });
+    port
+}
+
+#[tokio::test]
+pub async fn slow_clients_are_told_their_request_timed_out() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let port = serve_one_connection_with_short_header_timeout().await;
+    let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHo").await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(std::time::Duration::from_secs(5), client.read_to_end(&mut response)).await
+        .expect("the connection should be closed after the header timeout").unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+    assert!(response.to_lowercase().contains("connection: close"), "{response}");
+}
+
+#[tokio::test]
+pub async fn h2_clients_that_never_send_a_request_are_sent_away() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let port = serve_one_connection_with_short_header_timeout().await;
+    let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    // the connection preface followed by an empty SETTINGS frame, and then nothing
+    client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+    client.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).await.unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(std::time::Duration::from_secs(5), client.read_to_end(&mut received)).await
+        .expect("the connection should be closed after the header timeout").unwrap();
+    let mut frame_types = vec![];
+    let mut rest = &received[..];
+    while rest.len() >= 9 {
+        let length = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        frame_types.push(rest[3]);
+        rest = &rest[(9 + length).min(rest.len())..];
+    }
+    // 0x7 is GOAWAY
+    assert!(frame_types.contains(&0x7), "{frame_types:?}");
+}
+
+#[test]
+pub fn timeouts_are_recognized_anywhere_in_the_error_chain() {
+    let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out");
+    let wrapped = anyhow::Error::new(timed_out).context("failed to reach the backend");
+    assert!(crate::timeouts::is_timeout(wrapped.as_ref()));
+    let refused = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+    assert!(!crate::timeouts::is_timeout(&refused));
+}
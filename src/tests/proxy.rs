@@ -132,6 +132,8 @@ async fn proxy_test_requests_thru_proxy_receive_correct_x_forwarded_host_header(
                 port: backend_port,
                 https: Some(false),
                 hints: None,
+                weight: None,
+                proxy_protocol: None,
             } ],
             keep_original_host_header: Some(true),
             terminate_http: Some(true),
@@ -0,0 +1,240 @@
+// User configurable timeouts for backend connects, request headers, idle connections and request duration.
+// Sites inherit whatever they do not set from the global timeouts, and anything not set anywhere uses the defaults below.
+// There is no default for the idle timeout and the request duration, connections and requests may take as long as they like unless configured.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::configuration::{ConfigWrapper, Timeouts};
+use crate::http_proxy::ProxyClient;
+
+const DEFAULT_CONNECT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_HEADERS: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveTimeouts {
+    pub connect: Duration,
+    pub request_headers: Duration,
+    pub idle: Option<Duration>,
+    /// Only covers the time until the response headers arrive, the body may take as long as it likes after that.
+    pub max_request_duration: Option<Duration>,
+}
+
+impl EffectiveTimeouts {
+    /// Combines the timeouts of a site with the global ones, the site wins for each setting it has.
+    pub fn resolve(global: Option<&Timeouts>, site: Option<&Timeouts>) -> Self {
+        let pick = |f: fn(&Timeouts) -> Option<u64>| {
+            site.and_then(f).or_else(|| global.and_then(f)).map(|s| Duration::from_secs(s.max(1)))
+        };
+        Self {
+            connect: pick(|t| t.connect_in_seconds).unwrap_or(DEFAULT_CONNECT),
+            request_headers: pick(|t| t.request_headers_in_seconds).unwrap_or(DEFAULT_REQUEST_HEADERS),
+            idle: pick(|t| t.idle_in_seconds),
+            max_request_duration: pick(|t| t.max_request_duration_in_seconds),
+        }
+    }
+}
+
+/// Finds the timeouts of whichever site handles the given host name, or the global ones if no site does.
+pub fn for_host(configuration: &ConfigWrapper, host_name: &str) -> EffectiveTimeouts {
+    let site = configuration.find_site(host_name);
+    EffectiveTimeouts::resolve(configuration.timeouts.as_ref(), site.as_ref().and_then(|x| x.timeouts()))
+}
+
+lazy_static! {
+    static ref PROXY_CLIENTS: Mutex<HashMap<Duration, (ProxyClient, ProxyClient)>> = Mutex::new(HashMap::new());
+}
+
+/// The http and h2-only clients used for backends with the given connect timeout.
+/// There are only ever a handful of distinct timeouts so the clients (and their connection pools) are kept around.
+pub fn proxy_clients(connect_timeout: Duration) -> (ProxyClient, ProxyClient) {
+    let mut clients = PROXY_CLIENTS.lock().expect("proxy client cache should not be poisoned");
+    clients.entry(connect_timeout).or_insert_with(|| crate::proxy::create_proxy_clients(connect_timeout)).clone()
+}
+
+/// Tells if an error, or anything in its chain of sources, is an io timeout.
+pub fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) {
+            return true
+        }
+        current = e.source();
+    }
+    false
+}
+
+/// Shared between a stream and whoever keeps an eye on how long it has been quiet.
+#[derive(Debug, Clone)]
+pub struct Activity {
+    started: Instant,
+    last_activity_in_ms: Arc<AtomicU64>,
+    busy: Arc<AtomicU64>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self { started: Instant::now(), last_activity_in_ms: Arc::new(AtomicU64::new(0)), busy: Arc::new(AtomicU64::new(0)) }
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        self.last_activity_in_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+    /// The connection is not considered idle while the returned guard is alive, used while waiting for backends to respond.
+    pub fn busy(&self) -> BusyGuard {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        BusyGuard(self.clone())
+    }
+    /// Resolves once nothing has been read or written for the given duration, returning that duration.
+    /// Never resolves when there is no timeout.
+    pub async fn idle_for(&self, timeout: Option<Duration>) -> Duration {
+        let Some(timeout) = timeout else {
+            return std::future::pending().await
+        };
+        loop {
+            let last = Duration::from_millis(self.last_activity_in_ms.load(Ordering::Relaxed));
+            let quiet_for = self.started.elapsed().saturating_sub(last);
+            if quiet_for >= timeout && self.busy.load(Ordering::Relaxed) == 0 {
+                return timeout
+            }
+            tokio::time::sleep(timeout.saturating_sub(quiet_for).max(Duration::from_secs(1))).await;
+        }
+    }
+}
+
+pub struct BusyGuard(Activity);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Records the time of the last read or write on the wrapped stream, along with how much went each way.
+pub struct IdleTracked<S> {
+    inner: S,
+    activity: Activity,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl<S> IdleTracked<S> {
+    pub fn new(inner: S, activity: Activity) -> Self {
+        Self { inner, activity, bytes_read: 0, bytes_written: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.bytes_read += n as u64;
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.bytes_written += n as u64;
+                self.activity.touch();
+            }
+        }
+        result
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Same as tokio::io::copy_bidirectional, except that the streams are closed once no data has gone either way for `idle` (if set).
+/// Returns the bytes sent from `client` and from `backend`, also when closed for being idle.
+pub async fn copy_bidirectional_with_idle_timeout<A, B>(client: &mut A, backend: &mut B, idle: Option<Duration>, host_name: &str) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::default();
+    let mut client = IdleTracked::new(client, activity.clone());
+    let result = tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut client, backend) => Ok(result),
+        idle = activity.idle_for(idle) => Err(idle),
+    };
+    match result {
+        Ok(result) => result,
+        Err(idle) => {
+            tracing::info!(timeout = "idle", "Closing tunnel to {host_name} after {}s without traffic.", idle.as_secs());
+            Ok((client.bytes_read, client.bytes_written))
+        }
+    }
+}
+
+/// A stream that can be handed to hyper while we hold on to another handle of it,
+/// so that we can still answer a client after hyper has given up on its connection.
+pub struct SharedStream<S>(Arc<Mutex<SharedStreamInner<S>>>);
+
+struct SharedStreamInner<S> {
+    stream: S,
+    preface: Vec<u8>,
+}
+
+impl<S> Clone for SharedStream<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S> SharedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self(Arc::new(Mutex::new(SharedStreamInner { stream, preface: Vec::new() })))
+    }
+    /// Tells if the client opened the connection with the http2 connection preface.
+    pub fn is_h2(&self) -> bool {
+        self.0.lock().expect("shared stream should not be poisoned").preface.starts_with(b"PRI ")
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SharedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut inner = self.0.lock().expect("shared stream should not be poisoned");
+        let before = buf.filled().len();
+        let result = Pin::new(&mut inner.stream).poll_read(cx, buf);
+        let wanted = 4usize.saturating_sub(inner.preface.len());
+        if wanted > 0 {
+            let read = &buf.filled()[before..];
+            inner.preface.extend_from_slice(&read[..read.len().min(wanted)]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SharedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0.lock().expect("shared stream should not be poisoned").stream).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0.lock().expect("shared stream should not be poisoned").stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0.lock().expect("shared stream should not be poisoned").stream).poll_shutdown(cx)
+    }
+}
@@ -19,6 +19,8 @@ impl Display for ConnectionType {
             ConnectionType::HttpPassthru(target,backend) => write!(f, "HTTP Passthru to {}:{} ({})",backend.address,backend.port,target.host_name),
             ConnectionType::HttpTermination => write!(f, "HTTP Terminated"),
             ConnectionType::HttpWithOutgoingTLS(target,backend) => write!(f, "HTTP to HTTPS Tunnel -> {}:{} ({})",backend.address,backend.port,target.host_name),
+            ConnectionType::TcpStream(site,backend) => write!(f, "TCP Stream to {}:{} ({})",backend.address,backend.port,site),
+            ConnectionType::UdpStream(site,backend) => write!(f, "UDP Stream to {}:{} ({})",backend.address,backend.port,site),
             ConnectionType::PendingInit => write!(f, "Initializing.."),
             ConnectionType::Invalid(e) => write!(f, "Invalid: {e}"),
         }
@@ -40,7 +42,7 @@ pub fn draw(
     }
 
 
-    let headers = [  "Source", "In/Out","Description"];
+    let headers = [  "Source", "In/Out","Description","Last Request Id"];
     
     let mut rows = global_state.app_state.statistics.active_connections.iter().map(|guard| {
         
@@ -73,10 +75,13 @@ pub fn draw(
             format!("not tracked")
         };
 
+        let last_request_id = active_connection.recent_request_ids.last().cloned().unwrap_or_else(|| "-".into());
+
         vec![
              src,
              trans,
              connection_type, 
+             last_request_id,
          ]    
     }).collect::<Vec<Vec<String>>>();
     
@@ -136,9 +141,10 @@ pub fn draw(
     ).height(1);
 
     let widths = [
-        Constraint::Percentage(40),
-        Constraint::Percentage(20),
-        Constraint::Percentage(40),      
+        Constraint::Percentage(30),
+        Constraint::Percentage(15),
+        Constraint::Percentage(30),
+        Constraint::Percentage(25),
     ];
     
     let table = Table::new(table_rows, widths.clone())
@@ -215,8 +215,8 @@ pub async fn run(
                             kind: _,
                             state:_
                         }) => {
-                            state.app_state.exit.store(true, std::sync::atomic::Ordering::SeqCst);
-                            break;
+                            // the ui keeps running while connections are drained and the hosted processes are stopped
+                            crate::shutdown::request(&state);
                         },
                         Event::Mouse(mouse) => {
 
@@ -415,8 +415,7 @@ pub async fn run(
                                     KeyCode::Esc | KeyCode::Char('q')  => {
                                         {
                                             tracing::warn!("User requested exit");
-                                            state.app_state.exit.store(true, std::sync::atomic::Ordering::SeqCst);
-                                            break;
+                                            crate::shutdown::request(&state);
                                         }
 
                                     },
@@ -918,7 +917,11 @@ fn draw_ui<B: ratatui::backend::Backend>(
                     &ProcState::Starting => ratatui::text::Span::styled(format!(" {:?}",state),s),
                     &ProcState::Stopped => ratatui::text::Span::styled(format!(" {:?}",state),s),
                     &ProcState::Stopping => ratatui::text::Span::styled(format!(" {:?}..",state),s),
-                    &ProcState::Remote => ratatui::text::Span::styled(format!(" {:?}",state),s),
+                    &ProcState::Remote => match crate::health_check::summary_for_host(&global_state, id) {
+                        Some((healthy,total)) if healthy < total => ratatui::text::Span::styled(format!(" {state:?} ({healthy}/{total} healthy)"),s.fg(Color::LightRed)),
+                        Some((healthy,total)) => ratatui::text::Span::styled(format!(" {state:?} ({healthy}/{total} healthy)"),s),
+                        None => ratatui::text::Span::styled(format!(" {:?}",state),s)
+                    },
                     &ProcState::DirServer => ratatui::text::Span::styled(format!(" {:?}",state),s),
                     &ProcState::Docker => ratatui::text::Span::styled(format!(" {:?}",state),s)
 
@@ -992,6 +995,16 @@ fn draw_ui<B: ratatui::backend::Backend>(
 
     help_bar_text.push(ratatui::text::Span::raw("| t: theme"));
 
+    if global_state.app_state.exit.load(std::sync::atomic::Ordering::SeqCst) {
+        help_bar_text = vec![ratatui::text::Span::styled("Shutting down: stopping hosted processes..", Style::default().fg(Color::LightYellow))];
+    } else if global_state.app_state.shutdown.is_draining() {
+        let seconds_left = global_state.app_state.shutdown.time_left().unwrap_or_default().as_secs();
+        help_bar_text = vec![ratatui::text::Span::styled(
+            format!("Shutting down: waiting for {} open connections ({seconds_left}s left) | q: Quit now", crate::shutdown::open_connections(&global_state)),
+            Style::default().fg(Color::LightYellow)
+        )];
+    }
+
     // // DEBUG
     // help_bar_text.push(ratatui::text::Span::raw(format!("| DBG: {}",
     //     app_state.dbg
@@ -79,9 +79,32 @@ pub fn draw(
     //         - for terminated traffic we can most likely add support for getting this data from the ManagedStream implementation
 
 
+    // the lets-encrypt certificates that expire first, and those that fail to renew
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let mut certs : Vec<(String, i64)> = global_state.cert_resolver.cached_cert_expiry().into_iter()
+        .filter(|(_, kind, _)| *kind == "lets_encrypt")
+        .map(|(domain, _, not_after)| (domain, not_after))
+        .collect();
+    certs.sort_by_key(|(_, not_after)| *not_after);
+    let failures = |domain: &str| crate::letsencrypt::FAILING_RENEWALS.get(domain)
+        .map(|x| format!(" - renewal failed {} times", x.consecutive_failures)).unwrap_or_default();
+    let mut cert_lines = vec![format!("Lets-encrypt certificates: {}", certs.len())];
+    for (domain, not_after) in certs.iter().take(5) {
+        cert_lines.push(format!("  {domain} expires in {} days{}", (not_after - now).div_euclid(86400), failures(domain)));
+    }
+    let waiting : Vec<(String, u32)> = crate::letsencrypt::FAILING_RENEWALS.iter()
+        .filter(|x| !certs.iter().any(|(domain, _)| domain == x.key()))
+        .map(|x| (x.key().clone(), x.value().consecutive_failures))
+        .collect();
+    for (domain, consecutive_failures) in waiting.iter().take(5) {
+        cert_lines.push(format!("  {domain} has no certificate yet - failed {consecutive_failures} times"));
+    }
+    let cert_line_count = cert_lines.len() as i32;
+    f.render_widget(Paragraph::new(cert_lines.join("\n")).style(style), area.offset(Offset { x: 4, y: 5 }));
+
     f.render_widget(
         Paragraph::new(Text::styled("... This page will have more data in the future :-)", Style::default().fg(Color::DarkGray))),
-        area.offset(Offset { x: 4, y: 5 })
+        area.offset(Offset { x: 4, y: 6 + cert_line_count })
             
     );
 
@@ -20,8 +20,11 @@ pub enum ProcState {
 pub struct AppState {
     pub enable_global_traffic_inspection: AtomicBool,
     pub exit: AtomicBool,
+    /// Set when a graceful shutdown has been requested, before exit is set.
+    pub shutdown: crate::shutdown::ShutdownState,
     pub site_status_map: Arc<dashmap::DashMap<String,ProcState>>,
     pub statistics : Arc<ProxyLiveStats>,
+    pub response_cache : crate::http_proxy::SharedCache,
 }
 
 impl AppState {
@@ -35,10 +38,19 @@ impl AppState {
             statistics : Arc::new(ProxyLiveStats { 
                 total_accepted_tcp_connections: AtomicUsize::new(0),
                 lb_access_count_per_hostname: dashmap::DashMap::new(),
-                active_connections: dashmap::DashMap::new()
+                active_connections: dashmap::DashMap::new(),
+                backend_health: dashmap::DashMap::new(),
+                in_flight_requests_per_backend: dashmap::DashMap::new(),
+                backend_outliers: dashmap::DashMap::new(),
+                site_metrics: dashmap::DashMap::new(),
+                process_restarts: dashmap::DashMap::new(),
+                rate_limit_buckets: dashmap::DashMap::new(),
+                rate_limited_requests: dashmap::DashMap::new()
                 
             }),
             exit: AtomicBool::new(false),
+            shutdown: crate::shutdown::ShutdownState::default(),
+            response_cache: crate::http_proxy::SharedCache::default(),
             //view_mode: ViewMode::Console,
         };
 
@@ -36,6 +36,12 @@ pub enum ConnectionType {
     /// client and backend. No per-http request routing-logic happening in here.
     HttpWithOutgoingTLS(ReverseTcpProxyTarget,Backend),
 
+    /// Tcp connection on the port of a tcp_stream site, forwarded as is to a backend.
+    TcpStream(String,Backend),
+
+    /// Udp session (datagrams from a single client address) on the port of a udp_stream site.
+    UdpStream(String,Backend),
+
     /// Before we have detected enough information about the incoming tcp connection
     PendingInit,
 
@@ -13,8 +13,42 @@ pub enum GlobalEvent {
     // when we send actual response to client or receive from client, that will end up as a tcpevent instead.
     // here we just make it possible to observe actual communication between odd-box and the backend in the case
     // where we may have modified the packets.
-    SentHttpRequestToBackend(ConnectionKey,String), // TODO - use better structure
-    GotResponseFromBackend(ConnectionKey,String)    // TODO - use better structure
+    SentHttpRequestToBackend {
+        connection_key : ConnectionKey,
+        /// The X-Request-Id of the request, also found in the recent_request_ids of the connection.
+        request_id : String,
+        request : String
+    },
+    GotResponseFromBackend {
+        connection_key : ConnectionKey,
+        request_id : String,
+        response : String
+    },
+
+    /// The circuit breaker took a backend out of rotation after too many consecutive failures.
+    BackendEjected(BackendOutlierEvent),
+    /// A backend that was ejected by the circuit breaker has successfully handled traffic again.
+    BackendRestored(BackendOutlierEvent),
+    /// Issuing or renewing the certificate of a domain has failed several times in a row.
+    CertificateRenewalFailing(CertificateRenewalEvent)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateRenewalEvent {
+    pub domain : String,
+    pub consecutive_failures : u32,
+    /// Unix timestamp at which the current certificate expires, if there is one.
+    pub not_after : Option<i64>,
+    pub error : String
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendOutlierEvent {
+    pub host_name : String,
+    /// address:port
+    pub backend : String,
+    pub consecutive_failures : u32,
+    pub cool_down_in_seconds : u64
 }
 
 #[derive(Debug, Clone, Serialize)]
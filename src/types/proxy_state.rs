@@ -1,5 +1,5 @@
-use std::sync::Weak;
-use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use crate::{configuration::Backend, tcp_proxy::ReverseTcpProxyTarget};
 
@@ -16,20 +16,128 @@ pub struct ProxyLiveStats {
     /// This is NOT meant for statistical use but internally to do basic loadbalancing, 
     /// it just happens to live here for convenience
     pub lb_access_count_per_hostname : dashmap::DashMap<String,AtomicUsize>,
+
+    /// Maintained by the health check worker for sites that have a health_check configured.
+    /// Backends without an entry here are assumed to be healthy.
+    pub backend_health : dashmap::DashMap<BackendHealthKey,BackendHealth>,
+
+    /// Number of terminated http requests currently waiting on each backend.
+    /// Tunnelled connections are not counted here as they can be found in active_connections.
+    pub in_flight_requests_per_backend : dashmap::DashMap<BackendHealthKey,AtomicUsize>,
+
+    /// Maintained from real traffic for sites that have a circuit_breaker configured.
+    pub backend_outliers : dashmap::DashMap<BackendHealthKey,BackendOutlierState>,
+
+    /// Traffic counters exported by the /metrics endpoint, keyed by the host_name of the configured site.
+    pub site_metrics : dashmap::DashMap<String,Arc<SiteMetrics>>,
+
+    /// Number of times each hosted process has been restarted after stopping unexpectedly.
+    pub process_restarts : dashmap::DashMap<String,AtomicU64>,
+
+    /// Token buckets for sites with a rate_limit, keyed by the site host_name and the bucket key (client ip, header value..)
+    pub rate_limit_buckets : dashmap::DashMap<(String,String),TokenBucket>,
+
+    /// Requests and tunnelled connections rejected by rate limiting, by site.
+    pub rate_limited_requests : dashmap::DashMap<String,AtomicU64>,
     
 }
 
+impl ProxyLiveStats {
+    pub fn site_metrics(&self, host_name: &str) -> Arc<SiteMetrics> {
+        if let Some(m) = self.site_metrics.get(host_name) {
+            return m.clone()
+        }
+        self.site_metrics.entry(host_name.to_string()).or_default().clone()
+    }
+}
+
+/// How many request ids are kept per connection, see ProxyActiveTCPConnection::recent_request_ids.
+pub const MAX_RECENT_REQUEST_IDS : usize = 8;
+
+/// Upper bounds of the request latency histogram buckets.
+pub const LATENCY_BUCKETS_IN_SECONDS : [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug,Default)]
+pub struct LatencyHistogram {
+    /// Non-cumulative counts for each bucket in LATENCY_BUCKETS_IN_SECONDS.
+    pub buckets : [AtomicU64; 11],
+    pub count : AtomicU64,
+    pub sum_in_microseconds : AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, duration: std::time::Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS_IN_SECONDS.iter().position(|b| secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_in_microseconds.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct SiteMetrics {
+    /// Terminated requests by status class, 1xx at index 0 thru 5xx at index 4.
+    pub responses_by_status_class : [AtomicU64; 5],
+    /// Time until the response headers were ready, for terminated requests.
+    pub latency : LatencyHistogram,
+    pub bytes_received : AtomicU64,
+    pub bytes_sent : AtomicU64,
+}
+
+impl SiteMetrics {
+    pub fn record_response(&self, status: u16, latency: std::time::Duration) {
+        if (100..600).contains(&status) {
+            self.responses_by_status_class[(status / 100 - 1) as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(latency);
+    }
+}
+
+/// (host_name, "address:port")
+pub type BackendHealthKey = (String,String);
+
+#[derive(Debug,Clone,Serialize,ToSchema)]
+pub struct BackendHealth {
+    pub address : String,
+    pub port : u16,
+    pub healthy : bool,
+    pub consecutive_successes : u32,
+    pub consecutive_failures : u32,
+    pub last_error : Option<String>
+}
+
+
+#[derive(Debug,Clone,Default)]
+pub struct BackendOutlierState {
+    pub consecutive_failures : u32,
+    pub ejected_until : Option<std::time::Instant>
+}
+
+#[derive(Debug,Clone)]
+pub struct TokenBucket {
+    pub tokens : f64,
+    pub last_refill : std::time::Instant,
+    /// When the bucket will be full again if no more tokens are taken.
+    pub full_at : std::time::Instant
+}
 
 pub type ConnectionKey = u64;
 
 use serde::Serialize;
+use utoipa::ToSchema;
 
 use super::connection_type::ConnectionType;
 
 #[derive(Debug,Clone,Serialize)]
 pub enum OutgoingTunnelType {
     TLS(ReverseTcpProxyTarget,Backend),
-    Raw(ReverseTcpProxyTarget,Backend)
+    Raw(ReverseTcpProxyTarget,Backend),
+    /// Connection accepted by a tcp_stream site, identified by its name.
+    TcpStream(String,Backend),
+    /// Session of a udp_stream site, identified by its name.
+    UdpStream(String,Backend)
 }
 
 #[derive(Debug,Clone,Serialize)]
@@ -48,6 +156,11 @@ pub struct ProxyActiveTCPConnection {
     pub tls_terminated: bool,
     /// This means we have terminated the http (and tls if used) connection and have established a new http(s) connection to the target
     pub http_terminated: bool,
+    /// Connections to the admin interface (and api) of odd-box itself rather than to one of the sites.
+    pub is_admin_interface: bool,
+    /// The X-Request-Id of the latest requests that were terminated on this connection, newest last.
+    /// Lets you find the connection that a request id from the logs of a backend belongs to.
+    pub recent_request_ids: Vec<String>,
 
     pub version : u64,
     pub resolved_connection_type: Option<ConnectionType>,
@@ -64,6 +177,12 @@ pub struct ProxyActiveTCPConnection {
 }
 
 impl ProxyActiveTCPConnection {
+    pub fn record_request_id(&mut self, request_id: String) {
+        if self.recent_request_ids.len() >= MAX_RECENT_REQUEST_IDS {
+            self.recent_request_ids.remove(0);
+        }
+        self.recent_request_ids.push(request_id);
+    }
     pub fn get_connection_type(&self) -> ConnectionType {
         if self.version == 1 {
             return ConnectionType::PendingInit
@@ -75,6 +194,12 @@ impl ProxyActiveTCPConnection {
             self.http_terminated,
             &self.outgoing_tunnel_type,
         ) {
+            // === STREAM SITES =============================
+
+            // These never look at the data, no matter if it is tls or not
+            (_,_,_,Some(OutgoingTunnelType::TcpStream(site,backend))) => ConnectionType::TcpStream(site.clone(),backend.clone()),
+            (_,_,_,Some(OutgoingTunnelType::UdpStream(site,backend))) => ConnectionType::UdpStream(site.clone(),backend.clone()),
+
             // === HTTP =====================================
             
             // Clear incoming with a raw tunnel established
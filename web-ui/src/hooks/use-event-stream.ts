@@ -17,6 +17,8 @@ export type TTcpEvent = {
     tcp_peer_addr: string
     connection_key: number
     client_addr: string
+    /** X-Request-Id of the latest requests on the connection, newest last */
+    recent_request_ids: string[]
     target: {
       remote_target_config: any
       hosted_target_config: InProcessSiteConfig